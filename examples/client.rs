@@ -25,7 +25,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             // Rust slice, so we write the data through the raw pointer directly.
             if items < count { count = items };
             for i in 0..count {
-                *p.offset(i as isize) = item;
+                *p.add(i) = item;
             }
             println!("Sending {} items of {}, in total {}", count, item, (count as f64) * item);
             count
@@ -26,7 +26,7 @@ struct State {
 impl State {
     fn add_receiver(&mut self) -> Result<(u64, File, File, File), Box<dyn Error>> {
         // Create a receiver in shared memory.
-        let mut r = Receiver::new(CAPACITY as usize)?;
+        let mut r = Receiver::new(CAPACITY)?;
         let m = r.memfd().as_file().try_clone()?;
         let e = r.empty_signal().try_clone()?;
         let f = r.full_signal().try_clone()?;
@@ -43,7 +43,7 @@ impl State {
                     // and the untrusted process restrictions, we cannot convert them into a
                     // Rust slice, so we read the data from the raw pointer directly.
                     for i in 0..count {
-                        s += *ptr.offset(i as isize);
+                        s += *ptr.add(i);
                     }
                     *sum.lock().unwrap() += s;
                     count
@@ -0,0 +1,28 @@
+//! Attaches a framed receiver to a ringbuffer holding the input, as left behind by a
+//! hostile sender, and parses every frame in it.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shmem_ipc::framed::Receiver;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    // The first two bytes are the capacity in bytes, the rest is the memory area.
+    let capacity = u16::from_le_bytes([data[0], data[1]]) as usize;
+    let mut r = match Receiver::fuzz_open(capacity, &data[2..]) {
+        Ok(r) => r,
+        Err(_) => return,
+    };
+    // Every message takes at least one word, so this ends with the ringbuffer empty.
+    for _ in 0..=capacity {
+        match r.recv() {
+            Ok(Some(m)) => {
+                let _ = m.data().len();
+            }
+            _ => break,
+        }
+    }
+});
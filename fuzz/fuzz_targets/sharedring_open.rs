@@ -0,0 +1,32 @@
+//! Attaches to a ringbuffer holding the input, as left behind by a hostile sender, and
+//! receives everything in it.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shmem_ipc::sharedring::Receiver;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    // The first two bytes are the capacity, the rest is the memory area.
+    let capacity = u16::from_le_bytes([data[0], data[1]]) as usize;
+    let mut r = match Receiver::<u64>::fuzz_open(capacity, &data[2..]) {
+        Ok(r) => r,
+        Err(_) => return,
+    };
+    // Every lap hands back at least one item, so this ends with the ringbuffer empty.
+    for _ in 0..=capacity {
+        let status = r.receive_raw(|p, count| {
+            for i in 0..count {
+                let _ = unsafe { std::ptr::read_volatile(p.add(i)) };
+            }
+            count
+        });
+        match status {
+            Ok(s) if s.remaining > 0 => {}
+            _ => break,
+        }
+    }
+});
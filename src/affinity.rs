@@ -0,0 +1,146 @@
+//! Pinning threads to CPUs, and checking that those CPUs are isolated.
+//!
+//! Polling a ringbuffer in a loop instead of waiting on its eventfd gives the lowest
+//! latency, but only if the polling thread has a CPU to itself: one the scheduler does not
+//! move it off (`pin_current_thread` or `spawn_pinned`), that no other tasks are placed on
+//! (`isolcpus`), and that the timer tick does not interrupt (`nohz_full`). `isolation`
+//! tells whether the kernel was booted that way.
+//!
+//! # Example
+//! ```rust,no_run
+//! use shmem_ipc::affinity;
+//! let cpus = [3];
+//! let report = affinity::isolation(&cpus).unwrap();
+//! if !report.is_isolated() {
+//!     eprintln!("CPU 3 is not isolated, expect jitter: {:?}", report);
+//! }
+//! let poller = affinity::spawn_pinned(&cpus, || {
+//!     // poll the ringbuffer
+//! }).unwrap();
+//! poller.join().unwrap();
+//! ```
+
+use crate::Error;
+use std::thread::JoinHandle;
+
+/// Parses a CPU list as in sysfs, e g "0-3,8". Anything unparsable is left out.
+fn parse_cpu_list(s: &str) -> Vec<usize> {
+    let mut cpus = vec![];
+    for part in s.trim().split(',') {
+        let mut ends = part.splitn(2, '-').map(|x| x.trim().parse::<usize>());
+        match (ends.next(), ends.next()) {
+            (Some(Ok(a)), None) => cpus.push(a),
+            (Some(Ok(a)), Some(Ok(b))) if a <= b => cpus.extend(a..=b),
+            _ => {}
+        }
+    }
+    cpus
+}
+
+fn read_cpu_list(name: &str) -> Vec<usize> {
+    std::fs::read_to_string(format!("/sys/devices/system/cpu/{}", name))
+        .map(|s| parse_cpu_list(&s))
+        .unwrap_or_default()
+}
+
+/// Restricts the calling thread to the given CPUs.
+///
+/// Fails with `EINVAL` if the list is empty, or none of the CPUs are allowed for this
+/// process, e g by its cpuset.
+pub fn pin_current_thread(cpus: &[usize]) -> Result<(), Error> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let max = 8 * std::mem::size_of::<libc::cpu_set_t>();
+    if cpus.is_empty() || cpus.iter().any(|c| *c >= max) {
+        Err(std::io::Error::from_raw_os_error(libc::EINVAL))?
+    }
+    for c in cpus {
+        unsafe { libc::CPU_SET(*c, &mut set) };
+    }
+    let r = unsafe { libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) };
+    if r < 0 {
+        Err(std::io::Error::last_os_error())?
+    }
+    Ok(())
+}
+
+/// The CPUs the calling thread may run on.
+pub fn current_affinity() -> Result<Vec<usize>, Error> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let r = unsafe { libc::sched_getaffinity(0, std::mem::size_of_val(&set), &mut set) };
+    if r < 0 {
+        Err(std::io::Error::last_os_error())?
+    }
+    let max = 8 * std::mem::size_of::<libc::cpu_set_t>();
+    Ok((0..max)
+        .filter(|c| unsafe { libc::CPU_ISSET(*c, &set) })
+        .collect())
+}
+
+/// Spawns a thread, e g one that polls ringbuffers, pinned to the given CPUs from the
+/// start.
+///
+/// New threads inherit the affinity of the thread creating them, so this briefly pins the
+/// calling thread too, and restores its affinity afterwards.
+pub fn spawn_pinned<F, T>(cpus: &[usize], f: F) -> Result<JoinHandle<T>, Error>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let old = current_affinity()?;
+    pin_current_thread(cpus)?;
+    let handle = std::thread::Builder::new()
+        .name("shmem-ipc pinned".into())
+        .spawn(f);
+    pin_current_thread(&old)?;
+    Ok(handle?)
+}
+
+/// How isolated some CPUs are, see `isolation`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Isolation {
+    /// The CPUs that are not in `isolcpus`, so the scheduler places other tasks on them.
+    pub not_isolated: Vec<usize>,
+    /// The CPUs that are not in `nohz_full`, so the timer tick interrupts them.
+    pub not_nohz_full: Vec<usize>,
+    /// The CPUs this thread may not run on at all.
+    pub not_allowed: Vec<usize>,
+}
+
+impl Isolation {
+    /// True if all CPUs are isolated, tickless and allowed.
+    pub fn is_isolated(&self) -> bool {
+        self.not_isolated.is_empty() && self.not_nohz_full.is_empty() && self.not_allowed.is_empty()
+    }
+}
+
+/// Checks whether the kernel isolates the given CPUs, for use at channel setup.
+pub fn isolation(cpus: &[usize]) -> Result<Isolation, Error> {
+    let isolated = read_cpu_list("isolated");
+    let nohz_full = read_cpu_list("nohz_full");
+    let allowed = current_affinity()?;
+    let missing = |list: &[usize]| -> Vec<usize> {
+        cpus.iter().copied().filter(|c| !list.contains(c)).collect()
+    };
+    Ok(Isolation {
+        not_isolated: missing(&isolated),
+        not_nohz_full: missing(&nohz_full),
+        not_allowed: missing(&allowed),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pinning() {
+        assert_eq!(parse_cpu_list("0-2,5, 7-7\n"), [0, 1, 2, 5, 7]);
+        assert!(parse_cpu_list("(null)\n").is_empty());
+        let cpu = current_affinity().unwrap()[0];
+        let t = spawn_pinned(&[cpu], current_affinity).unwrap();
+        assert_eq!(t.join().unwrap().unwrap(), [cpu]);
+        assert!(spawn_pinned(&[], || ()).is_err());
+        let report = isolation(&[cpu]).unwrap();
+        assert!(report.not_allowed.is_empty());
+    }
+}
@@ -0,0 +1,41 @@
+//! Building data structures inside shared memory.
+//!
+//! Processes map the same memfd at different addresses, so pointers stored in shared memory
+//! are useless to the other side. `ShmPtr` stores an offset into the mapping instead, which
+//! is resolved against the mapping of whoever reads it, and checked to be in bounds, since
+//! the offset might have been written by an untrusted peer.
+//!
+//! `Segment` is a memfd with a simple arena allocator, to put such structures in.
+//! `ShmArc` is a value in a memfd of its own, with a reference count shared between
+//! processes.
+//!
+//! # Example
+//! ```rust
+//! use shmem_ipc::alloc::{Segment, ShmPtr};
+//! #[derive(Copy, Clone, zerocopy::AsBytes, zerocopy::FromBytes)]
+//! #[repr(C)]
+//! struct Node {
+//!     value: u64,
+//!     next: ShmPtr<Node>,
+//! }
+//! let seg = Segment::new(4096).unwrap();
+//! let tail = seg.alloc(Node { value: 2, next: ShmPtr::null() }).unwrap().unwrap();
+//! let head = seg.alloc(Node { value: 1, next: tail.ptr() }).unwrap().unwrap();
+//! // On the other side, given the offset of the head
+//! let mut p = ShmPtr::<Node>::from_offset(head.ptr().offset());
+//! let mut sum = 0;
+//! while !p.is_null() {
+//!     let node = seg.read(p).unwrap();
+//!     sum += node.value;
+//!     p = node.next;
+//! }
+//! assert_eq!(sum, 3);
+//! ```
+
+mod arc;
+mod ptr;
+mod segment;
+
+pub use self::arc::ShmArc;
+pub use self::ptr::ShmPtr;
+pub use self::segment::{Segment, ShmBox};
@@ -0,0 +1,199 @@
+//! Reference counting across processes.
+
+use crate::mem::{mfd, mmap};
+use crate::Error;
+use std::fs::File;
+use std::marker::PhantomData;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// Room for the counts in front of the value.
+const HEADER_SIZE: usize = 64;
+
+#[repr(C)]
+struct ArcHeader {
+    strong: AtomicU64,
+    /// Pid of the creator while it holds its handle, zero after it has dropped it.
+    owner: AtomicU32,
+    /// Set when someone found that the creator died without dropping its handle.
+    poisoned: AtomicU32,
+}
+
+/// A `T` in a memfd of its own, with a reference count shared by all processes holding it.
+///
+/// When the last handle in any process is dropped, the memory is given back, even if
+/// file descriptors to the memfd are still around somewhere. If the creator crashes
+/// instead of dropping its handle, the value is poisoned: it might have been half written.
+///
+/// The count is in shared memory, so an untrusted peer can make the memory go away early;
+/// the value then reads as zeroes.
+pub struct ShmArc<T> {
+    memfd: mfd::Memfd,
+    mmap: mmap::MmapRaw,
+    owner: bool,
+    _charge: Option<crate::quota::Charge>,
+    _phantom: PhantomData<T>,
+}
+
+fn arc_bytes<T>() -> usize {
+    HEADER_SIZE + std::mem::size_of::<T>()
+}
+
+impl<T: Copy + zerocopy::AsBytes + zerocopy::FromBytes> ShmArc<T> {
+    /// Creates a new value with a reference count of one.
+    pub fn new(value: T) -> Result<Self, Error> {
+        if std::mem::align_of::<T>() > HEADER_SIZE {
+            Err(crate::ringbuf::Error::BufUnaligned)?
+        }
+        let bytes = arc_bytes::<T>();
+        let charge = crate::quota::Charge::new(None, bytes as u64)?;
+        let memfd = crate::mem::CreateOptions::default().create(std::any::type_name::<T>())?;
+        memfd.as_file().set_len(bytes as u64)?;
+        let mut arc = Self::attach(memfd)?;
+        arc._charge = Some(charge);
+        arc.owner = true;
+        arc.header().strong.store(1, Ordering::Release);
+        arc.header()
+            .owner
+            .store(std::process::id(), Ordering::Release);
+        arc.set(value);
+        Ok(arc)
+    }
+
+    fn attach(memfd: mfd::Memfd) -> Result<Self, Error> {
+        let bytes = arc_bytes::<T>();
+        let mmap = crate::mem::raw_memfd(&memfd, bytes)?;
+        if (memfd.as_file().metadata()?.len() as usize) < bytes {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        Ok(ShmArc {
+            memfd,
+            mmap,
+            owner: false,
+            _charge: None,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Takes a new reference for another process, and returns the file descriptor to pass
+    /// to it.
+    ///
+    /// The other process must turn it into a handle with `from_shared` exactly once, or the
+    /// reference is leaked.
+    pub fn share(&self) -> Result<File, Error> {
+        let file = self.memfd.as_file().try_clone()?;
+        self.header().strong.fetch_add(1, Ordering::AcqRel);
+        Ok(file)
+    }
+
+    /// Takes over a reference made by `share` in another process.
+    pub fn from_shared(memfd: File) -> Result<Self, Error> {
+        Self::attach(crate::mem::memfd_from_file(memfd)?)
+    }
+
+    /// Another handle to the same value, in this process.
+    pub fn try_clone(&self) -> Result<Self, Error> {
+        Self::from_shared(self.share()?)
+    }
+
+    fn header(&self) -> &ArcHeader {
+        unsafe { &*(self.mmap.as_ptr() as *const ArcHeader) }
+    }
+
+    fn value(&self) -> *mut T {
+        unsafe { self.mmap.as_mut_ptr().add(HEADER_SIZE) as *mut T }
+    }
+
+    /// Reads the value.
+    pub fn get(&self) -> T {
+        unsafe { std::ptr::read_volatile(self.value()) }
+    }
+
+    /// Writes the value, which all other handles see too.
+    pub fn set(&self, value: T) {
+        unsafe { std::ptr::write_volatile(self.value(), value) }
+    }
+
+    /// Number of handles in all processes, as far as the shared count goes.
+    pub fn strong_count(&self) -> u64 {
+        self.header().strong.load(Ordering::Acquire)
+    }
+
+    /// Returns true if the creator died without dropping its handle.
+    ///
+    /// The first handle to notice takes over the creator's reference, so that it does not
+    /// keep the memory alive forever.
+    pub fn is_poisoned(&self) -> bool {
+        let h = self.header();
+        if h.poisoned.load(Ordering::Acquire) != 0 {
+            return true;
+        }
+        let owner = h.owner.load(Ordering::Acquire);
+        if owner == 0 || crate::unix::process_alive(owner) {
+            return false;
+        }
+        if h.owner
+            .compare_exchange(owner, 0, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            h.poisoned.store(1, Ordering::Release);
+            // Cannot be the last reference, since we hold one too.
+            h.strong.fetch_sub(1, Ordering::AcqRel);
+        }
+        true
+    }
+
+    /// The file descriptor of the memfd.
+    pub fn memfd(&self) -> &mfd::Memfd {
+        &self.memfd
+    }
+}
+
+impl<T> Drop for ShmArc<T> {
+    fn drop(&mut self) {
+        let h = unsafe { &*(self.mmap.as_ptr() as *const ArcHeader) };
+        if self.owner {
+            let _ = h.owner.compare_exchange(
+                std::process::id(),
+                0,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            );
+        }
+        // A peer might have messed up the count; don't wrap around.
+        let prev = h
+            .strong
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |c| c.checked_sub(1));
+        if prev == Ok(1) {
+            unsafe {
+                libc::fallocate(
+                    self.memfd.as_raw_fd(),
+                    libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                    0,
+                    self.mmap.len() as libc::off_t,
+                )
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_count() {
+        let a = ShmArc::new(5u64).unwrap();
+        let file = a.share().unwrap();
+        let b = ShmArc::<u64>::from_shared(file).unwrap();
+        assert_eq!((a.strong_count(), b.get()), (2, 5));
+        b.set(6);
+        drop(a);
+        assert_eq!((b.strong_count(), b.get(), b.is_poisoned()), (1, 6, false));
+        let file = b.memfd().as_file().try_clone().unwrap();
+        drop(b);
+        // Memory was given back, so it reads as zeroes.
+        let c = ShmArc::<u64>::from_shared(file).unwrap();
+        assert_eq!((c.get(), c.strong_count()), (0, 0));
+    }
+}
@@ -0,0 +1,95 @@
+//! Offset based pointers.
+
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+
+/// A pointer to a `T` in shared memory, stored as the offset from the start of the mapping.
+///
+/// Offset zero is the null pointer; the start of a mapping usually holds a header anyway.
+/// It is `AsBytes` and `FromBytes`, so structures holding it can derive those too and be
+/// stored in shared memory themselves.
+#[repr(transparent)]
+pub struct ShmPtr<T> {
+    offset: u64,
+    _phantom: PhantomData<T>,
+}
+
+// A plain u64 inside; PhantomData takes up no space.
+unsafe impl<T> zerocopy::AsBytes for ShmPtr<T> {
+    fn only_derive_is_allowed_to_implement_this_trait() {}
+}
+
+unsafe impl<T> zerocopy::FromBytes for ShmPtr<T> {
+    fn only_derive_is_allowed_to_implement_this_trait() {}
+}
+
+impl<T> Clone for ShmPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ShmPtr<T> {}
+
+impl<T> PartialEq for ShmPtr<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.offset == other.offset
+    }
+}
+
+impl<T> Eq for ShmPtr<T> {}
+
+impl<T> std::fmt::Debug for ShmPtr<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ShmPtr({:#x})", self.offset)
+    }
+}
+
+impl<T> Default for ShmPtr<T> {
+    fn default() -> Self {
+        Self::null()
+    }
+}
+
+impl<T> ShmPtr<T> {
+    /// The null pointer.
+    pub fn null() -> Self {
+        Self::from_offset(0)
+    }
+
+    /// A pointer to the `T` at this offset into the mapping.
+    pub fn from_offset(offset: u64) -> Self {
+        ShmPtr {
+            offset,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// The offset into the mapping.
+    pub fn offset(self) -> u64 {
+        self.offset
+    }
+
+    /// Returns true for the null pointer.
+    pub fn is_null(self) -> bool {
+        self.offset == 0
+    }
+
+    /// Resolves the pointer against a mapping of `len` bytes at `base`.
+    ///
+    /// Returns `None` for the null pointer, and for pointers that are out of bounds or
+    /// misaligned. Even then, the `T` might be written by others at any time, so only
+    /// access it through raw pointer reads and writes.
+    pub fn resolve(self, base: *mut u8, len: usize) -> Option<*mut T> {
+        let offset = usize::try_from(self.offset).ok()?;
+        let end = offset.checked_add(std::mem::size_of::<T>())?;
+        if self.is_null() || end > len {
+            return None;
+        }
+        let p = base.wrapping_add(offset);
+        if (p as usize) % std::mem::align_of::<T>() != 0 {
+            return None;
+        }
+        Some(p as *mut T)
+    }
+}
@@ -0,0 +1,181 @@
+//! A memfd with an arena allocator.
+
+use super::ShmPtr;
+use crate::mem::{mfd, mmap};
+use crate::Error;
+use std::fs::File;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Room reserved in front of the allocations, for the allocator state.
+const HEADER_SIZE: u64 = 64;
+
+/// A memfd with a shared arena allocator, for structures linked with `ShmPtr`.
+///
+/// Any process that has the segment attached can allocate. Allocations are never freed
+/// individually; the memory is given back when the memfd is gone.
+pub struct Segment {
+    memfd: mfd::Memfd,
+    mmap: mmap::MmapRaw,
+    _charge: Option<crate::quota::Charge>,
+}
+
+/// A `T` allocated in a `Segment`.
+///
+/// It can only be read and written by copying, since other processes can access it too.
+/// Dropping it does not free the memory, see `Segment`.
+pub struct ShmBox<'a, T> {
+    segment: &'a Segment,
+    ptr: ShmPtr<T>,
+}
+
+impl Segment {
+    /// Creates a segment of `size` bytes.
+    pub fn new(size: usize) -> Result<Self, Error> {
+        let charge = crate::quota::Charge::new(None, size as u64)?;
+        let memfd = crate::mem::CreateOptions::default().create("shmem-ipc segment")?;
+        memfd.as_file().set_len(size as u64)?;
+        let mut seg = Self::attach(memfd, size)?;
+        seg._charge = Some(charge);
+        seg.next().store(HEADER_SIZE, Ordering::Release);
+        Ok(seg)
+    }
+
+    /// Attaches to a segment of `size` bytes created by another process.
+    pub fn open(size: usize, memfd: File) -> Result<Self, Error> {
+        Self::attach(crate::mem::memfd_from_file(memfd)?, size)
+    }
+
+    fn attach(memfd: mfd::Memfd, size: usize) -> Result<Self, Error> {
+        if (size as u64) < HEADER_SIZE {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        let mmap = crate::mem::raw_memfd(&memfd, size)?;
+        if (memfd.as_file().metadata()?.len() as usize) < size {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        Ok(Segment {
+            memfd,
+            mmap,
+            _charge: None,
+        })
+    }
+
+    /// The file descriptor to hand to the other processes, together with the size.
+    pub fn memfd(&self) -> &mfd::Memfd {
+        &self.memfd
+    }
+
+    /// Size of the segment in bytes.
+    pub fn len(&self) -> usize {
+        self.mmap.len()
+    }
+
+    /// Returns true if the segment has zero size, which it never has.
+    pub fn is_empty(&self) -> bool {
+        self.mmap.len() == 0
+    }
+
+    fn next(&self) -> &AtomicU64 {
+        unsafe { &*(self.mmap.as_ptr() as *const AtomicU64) }
+    }
+
+    /// Allocates room for a `T` and moves `value` there.
+    ///
+    /// Returns `None` if the segment is full.
+    pub fn alloc<T: Copy + zerocopy::AsBytes>(
+        &self,
+        value: T,
+    ) -> Result<Option<ShmBox<'_, T>>, Error> {
+        let align = std::mem::align_of::<T>() as u64;
+        let size = std::mem::size_of::<T>() as u64;
+        let mut next = self.next().load(Ordering::Acquire);
+        let offset = loop {
+            if !(HEADER_SIZE..=self.len() as u64).contains(&next) {
+                Err(crate::ringbuf::Error::BufCorrupt)?
+            }
+            let offset = next.next_multiple_of(align);
+            if offset + size > self.len() as u64 {
+                return Ok(None);
+            }
+            // Allocations are never empty, so that they have distinct addresses.
+            let end = offset + std::cmp::max(size, 1);
+            match self
+                .next()
+                .compare_exchange(next, end, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => break offset,
+                Err(x) => next = x,
+            }
+        };
+        let b = ShmBox {
+            segment: self,
+            ptr: ShmPtr::from_offset(offset),
+        };
+        b.set(value)?;
+        Ok(Some(b))
+    }
+
+    fn resolve<T>(&self, p: ShmPtr<T>) -> Result<*mut T, Error> {
+        p.resolve(self.mmap.as_mut_ptr(), self.len())
+            .ok_or(Error::OutOfBounds)
+    }
+
+    /// Reads the `T` that a pointer points to.
+    ///
+    /// Fails with `OutOfBounds` for the null pointer, and for pointers that do not point
+    /// into the segment.
+    pub fn read<T: Copy + zerocopy::FromBytes>(&self, p: ShmPtr<T>) -> Result<T, Error> {
+        let p = self.resolve(p)?;
+        Ok(unsafe { std::ptr::read_volatile(p) })
+    }
+
+    /// Writes the `T` that a pointer points to; fails like `read`.
+    pub fn write<T: Copy + zerocopy::AsBytes>(&self, p: ShmPtr<T>, value: T) -> Result<(), Error> {
+        let p = self.resolve(p)?;
+        unsafe { std::ptr::write_volatile(p, value) };
+        Ok(())
+    }
+}
+
+impl<T: Copy> ShmBox<'_, T> {
+    /// The pointer to store in other structures in the segment.
+    pub fn ptr(&self) -> ShmPtr<T> {
+        self.ptr
+    }
+
+    /// Reads the value.
+    pub fn get(&self) -> Result<T, Error>
+    where
+        T: zerocopy::FromBytes,
+    {
+        self.segment.read(self.ptr)
+    }
+
+    /// Writes the value.
+    pub fn set(&self, value: T) -> Result<(), Error>
+    where
+        T: zerocopy::AsBytes,
+    {
+        self.segment.write(self.ptr, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arena() {
+        let seg = Segment::new(128).unwrap();
+        let a = seg.alloc(1u8).unwrap().unwrap();
+        let b = seg.alloc(2u64).unwrap().unwrap();
+        assert_eq!((a.ptr().offset(), b.ptr().offset()), (64, 72));
+        let file = seg.memfd().as_file().try_clone().unwrap();
+        let other = Segment::open(128, file).unwrap();
+        assert_eq!(other.read(b.ptr()).unwrap(), 2);
+        assert!(other.alloc([0u64; 7]).unwrap().is_none());
+        assert!(other.alloc(3u64).unwrap().is_some());
+        assert!(other.read(ShmPtr::<u64>::from_offset(125)).is_err());
+        assert!(other.read(ShmPtr::<u64>::null()).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+//! A hook for security relevant events, e g to feed them to a SIEM.
+//!
+//! Misbehaviour of the peer shows up as an error returned to the caller, who may well just
+//! drop the connection without logging anything. An audit hook set with `set_hook` sees
+//! every such event in the process as it happens, with what context there is. Without a
+//! hook, reporting an event costs one atomic load.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+/// Something an untrusted peer did, or tried to do, see `set_hook`.
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A memfd from the peer lacked seals that are needed for safe access, and they
+    /// could not be added.
+    SealDenied {
+        /// Name of the memfd, if known
+        name: Option<String>,
+        /// The missing seals, as `F_SEAL_*` bits
+        wanted: u32,
+        /// The seals that were present
+        present: u32,
+    },
+    /// The shared header of a ringbuffer, or the directory of a bundle, did not validate.
+    HeaderInvalid {
+        /// What was being validated
+        what: &'static str,
+    },
+    /// A companion socket record belonged to another message than the one expected.
+    SequenceGap {
+        /// Sequence number of the current message
+        expected: u64,
+        /// Sequence number in the record
+        got: u64,
+    },
+    /// Attaching to a channel was refused.
+    AttachRejected {
+        /// Why
+        reason: &'static str,
+    },
+}
+
+type Hook = Box<dyn Fn(&Event) + Send + Sync>;
+
+static HOOK: RwLock<Option<Hook>> = RwLock::new(None);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Calls `hook` for every event from now on, instead of a previously set hook.
+///
+/// The hook is called on the thread that ran into the event, before the error is returned,
+/// so it should be quick; it must not set or clear hooks itself.
+pub fn set_hook<F: Fn(&Event) + Send + Sync + 'static>(hook: F) {
+    *HOOK.write().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(hook));
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Removes the hook.
+pub fn clear_hook() {
+    ENABLED.store(false, Ordering::Release);
+    *HOOK.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// Hands an event to the hook, if there is one; the event is only built if so.
+pub(crate) fn report<F: FnOnce() -> Event>(event: F) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    if let Some(hook) = &*HOOK.read().unwrap_or_else(|e| e.into_inner()) {
+        hook(&event());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn hook() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let e = events.clone();
+        set_hook(move |ev| e.lock().unwrap().push(ev.clone()));
+        let s: crate::sharedring::Sender<u64> = crate::sharedring::Sender::new(10).unwrap();
+        let file = s.memfd().as_file();
+        file.write_all_at(b"garbage!", 0).unwrap();
+        let r = crate::sharedring::Receiver::<u64>::open(
+            10,
+            file.try_clone().unwrap(),
+            s.empty_signal().try_clone().unwrap(),
+            s.full_signal().try_clone().unwrap(),
+        );
+        assert!(r.is_err());
+        clear_hook();
+        report(|| panic!("no hook, so nothing is built"));
+        assert!(events
+            .lock()
+            .unwrap()
+            .contains(&Event::HeaderInvalid { what: "ringbuffer" }));
+    }
+}
@@ -0,0 +1,255 @@
+//! A configuration struct in shared memory, which one process updates and any number of
+//! others read, e g worker processes picking up new settings.
+//!
+//! The value is behind a seqlock: `ConfigCell::set` marks it as being written, writes it and
+//! marks it as done, and readers copy it out and retry if it changed meanwhile. Readers map
+//! it read-only, see `mem::Broadcast`. Every update also pulses an eventfd shared by all
+//! readers, so that they can wait for changes in an event loop without the writer having to
+//! send a message to each of them.
+//!
+//! # Example
+//! ```rust
+//! use shmem_ipc::cell::{ConfigCell, ConfigView};
+//! let mut cell = ConfigCell::new(&[16u32, 4]).unwrap();
+//! let memfd = cell.memfd().as_file().try_clone().unwrap();
+//! let signal = cell.changed_signal().try_clone().unwrap();
+//! let mut view = ConfigView::<[u32; 2]>::open(memfd, signal).unwrap();
+//! assert_eq!(view.get().unwrap(), [16, 4]);
+//! cell.set(&[32, 4]).unwrap();
+//! assert!(view.changed());
+//! assert_eq!(view.get().unwrap(), [32, 4]);
+//! ```
+
+use crate::mem::{mfd, Broadcast, BroadcastView};
+use crate::sync::futex;
+use crate::wire::{AtomicLe32, AtomicLe64};
+use crate::{Error, Op};
+use std::fs::File;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::os::unix::io::FromRawFd;
+use std::sync::atomic::{fence, Ordering};
+use std::time::Duration;
+
+/// The value starts after a cache line with the header.
+const HEADER_SIZE: usize = 64;
+
+/// How often `ConfigView::get` tries before giving up on a writer that does not finish.
+const RETRIES: usize = 1000;
+
+#[repr(C)]
+struct Header {
+    /// Twice the number of updates, plus one while an update is being written.
+    sequence: AtomicLe64,
+    /// The size of the value.
+    size: AtomicLe64,
+    /// Number of updates, for waiting on with a futex.
+    version: AtomicLe32,
+}
+
+/// The writing side, see the module documentation.
+pub struct ConfigCell<T> {
+    data: Broadcast,
+    signal: File,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Copy + zerocopy::AsBytes> ConfigCell<T> {
+    /// Creates a cell holding `value`.
+    pub fn new(value: &T) -> Result<Self, Error> {
+        if std::mem::align_of::<T>() > HEADER_SIZE {
+            Err(Error::Misaligned)?
+        }
+        let size = HEADER_SIZE + std::mem::size_of::<T>();
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            Err(Error::os(Op::Signal, None)(std::io::Error::last_os_error()))?
+        }
+        let cell = ConfigCell {
+            data: crate::mem::broadcast(size, "shmem-ipc config")?,
+            signal: unsafe { File::from_raw_fd(fd) },
+            _phantom: PhantomData,
+        };
+        let h = cell.header();
+        h.size
+            .store(std::mem::size_of::<T>() as u64, Ordering::Relaxed);
+        unsafe { std::ptr::write_volatile(cell.value_ptr(), *value) };
+        h.sequence.store(0, Ordering::Release);
+        Ok(cell)
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*(self.data.as_mut_ptr() as *const Header) }
+    }
+
+    fn value_ptr(&self) -> *mut T {
+        unsafe { self.data.as_mut_ptr().add(HEADER_SIZE) as *mut T }
+    }
+
+    /// Replaces the value, and lets the readers know.
+    pub fn set(&mut self, value: &T) -> Result<(), Error> {
+        let h = self.header();
+        let sequence = h.sequence.load(Ordering::Relaxed);
+        h.sequence.store(sequence + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe { std::ptr::write_volatile(self.value_ptr(), *value) };
+        h.sequence.store(sequence + 2, Ordering::Release);
+        h.version.fetch_add(1, Ordering::Release);
+        futex::wake_all(h.version.raw())?;
+        (&self.signal)
+            .write_all(&1u64.to_ne_bytes())
+            .map_err(Error::os(Op::Signal, None))
+    }
+
+    /// The memfd to hand to the readers.
+    pub fn memfd(&self) -> &mfd::Memfd {
+        self.data.memfd()
+    }
+
+    /// The eventfd to hand to the readers.
+    pub fn changed_signal(&self) -> &File {
+        &self.signal
+    }
+}
+
+/// A reading side, see the module documentation.
+pub struct ConfigView<T> {
+    data: BroadcastView,
+    signal: File,
+    /// The sequence of the value last returned by `get`.
+    seen: u64,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Copy + zerocopy::FromBytes> ConfigView<T> {
+    /// Attaches to a cell, given the file descriptors from `ConfigCell::memfd` and
+    /// `ConfigCell::changed_signal`.
+    pub fn open(memfd: File, signal: File) -> Result<Self, Error> {
+        let data = crate::mem::read_broadcast(&crate::mem::memfd_from_file(memfd)?)?;
+        if data.len() < HEADER_SIZE + std::mem::size_of::<T>() {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        let view = ConfigView {
+            data,
+            signal,
+            seen: u64::MAX,
+            _phantom: PhantomData,
+        };
+        if view.header().size.load(Ordering::Acquire) != std::mem::size_of::<T>() as u64 {
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        Ok(view)
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*(self.data.as_ptr() as *const Header) }
+    }
+
+    /// Copies out the current value.
+    ///
+    /// Fails with `Error::TornRead` if the writer kept changing it, or never finished an
+    /// update, e g because it died.
+    pub fn get(&mut self) -> Result<T, Error> {
+        let h = self.header();
+        for _ in 0..RETRIES {
+            let sequence = h.sequence.load(Ordering::Acquire);
+            if sequence % 2 == 0 {
+                let p = unsafe { self.data.as_ptr().add(HEADER_SIZE) as *const T };
+                let value = unsafe { std::ptr::read_volatile(p) };
+                fence(Ordering::Acquire);
+                if h.sequence.load(Ordering::Relaxed) == sequence {
+                    self.seen = sequence;
+                    return Ok(value);
+                }
+            }
+            std::thread::yield_now();
+        }
+        Err(Error::TornRead)
+    }
+
+    /// True if the value has been updated since `get` last returned it.
+    pub fn changed(&self) -> bool {
+        self.header().sequence.load(Ordering::Acquire) != self.seen
+    }
+
+    /// Number of updates so far.
+    pub fn version(&self) -> u32 {
+        self.header().version.load(Ordering::Acquire)
+    }
+
+    /// For blocking scenarios, waits until the number of updates is no longer `version`,
+    /// or the timeout expires. Returns false on timeout.
+    pub fn wait_for_change(&self, version: u32, timeout: Option<Duration>) -> Result<bool, Error> {
+        let deadline = timeout.map(|t| crate::sim::instant() + t);
+        let word = &self.header().version;
+        while word.load(Ordering::Acquire) == version {
+            let left = futex::remaining(deadline);
+            if left == Some(Duration::from_secs(0))
+                || !futex::wait(word.raw(), version.to_le(), left)?
+            {
+                return Ok(word.load(Ordering::Acquire) != version);
+            }
+        }
+        Ok(true)
+    }
+
+    /// The eventfd that the writer pulses on every update, e g for `Reactor::register`.
+    ///
+    /// It is shared by all readers, so do not read from it, which would take the pulse
+    /// away from the others; wait for it edge-triggered, as `Reactor` does, and check
+    /// `changed`.
+    pub fn changed_signal(&self) -> &File {
+        &self.signal
+    }
+}
+
+impl<T> crate::sync::Pollable for ConfigView<T> {
+    /// Moves on with the next update after the one last returned by `get`.
+    fn poll_source(&self) -> crate::sync::PollSource<'_> {
+        let header = unsafe { &*(self.data.as_ptr() as *const Header) };
+        crate::sync::PollSource {
+            word: header.version.raw(),
+            expected: ((self.seen / 2) as u32).to_le(),
+            signal: &self.signal,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates() {
+        let mut cell = ConfigCell::new(&7u64).unwrap();
+        let memfd = |c: &ConfigCell<u64>| c.memfd().as_file().try_clone().unwrap();
+        let signal = cell.changed_signal().try_clone().unwrap();
+        let mut view = ConfigView::<u64>::open(memfd(&cell), signal).unwrap();
+        assert!(ConfigView::<u32>::open(memfd(&cell), cell.signal.try_clone().unwrap()).is_err());
+        assert!(view.changed());
+        assert_eq!(view.get().unwrap(), 7);
+        assert!(!view.changed());
+        let version = view.version();
+        let t = std::thread::spawn(move || {
+            assert!(view.wait_for_change(version, None).unwrap());
+            view.get().unwrap()
+        });
+        std::thread::sleep(Duration::from_millis(10));
+        cell.set(&8).unwrap();
+        assert_eq!(t.join().unwrap(), 8);
+        let mut pfd = libc::pollfd {
+            fd: std::os::unix::io::AsRawFd::as_raw_fd(&cell.signal),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        assert_eq!(unsafe { libc::poll(&mut pfd, 1, 0) }, 1);
+        let mut view =
+            ConfigView::<u64>::open(memfd(&cell), cell.signal.try_clone().unwrap()).unwrap();
+        assert!(!view
+            .wait_for_change(1, Some(Duration::from_millis(1)))
+            .unwrap());
+        // A writer that died within `set`
+        cell.header().sequence.fetch_add(1, Ordering::Relaxed);
+        assert!(matches!(view.get(), Err(Error::TornRead)));
+    }
+}
@@ -0,0 +1,341 @@
+//! A bidirectional message channel that uses shared memory where it can, and the unix socket
+//! where it cannot.
+//!
+//! `Channel::connect` is called on both ends of a connected `UnixStream`. Each side tries to
+//! set up a `framed` ringbuffer for the messages it sends and offers it to the peer; if both
+//! rings could be created and attached to, messages go through shared memory. Otherwise,
+//! e g in a container whose seccomp profile denies `memfd_create` or `eventfd`, they are sent
+//! over the socket itself, as a little endian `u32` length followed by the data. The API is
+//! the same either way, except that `send` over the socket never returns false, but blocks
+//! until the message is written.
+//!
+//! # Example
+//! ```rust
+//! use shmem_ipc::channel::{Channel, Transport};
+//! use std::os::unix::net::UnixStream;
+//! let (a, b) = UnixStream::pair().unwrap();
+//! let peer = std::thread::spawn(move || Channel::connect(b, 4096));
+//! let mut a = Channel::connect(a, 4096).unwrap();
+//! let mut b = peer.join().unwrap().unwrap();
+//! assert_eq!(a.transport(), Transport::SharedMemory);
+//! assert!(a.send(b"hello").unwrap());
+//! b.block_until_readable().unwrap();
+//! assert_eq!(b.recv().unwrap().unwrap().data(), b"hello");
+//! ```
+
+use crate::framed::{self, Message};
+use crate::unix;
+use crate::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+
+/// "SHMC", the first word of the handshake.
+const MAGIC: u32 = 0x434d_4853;
+/// The sender offers a ringbuffer; its file descriptors come with the hello.
+const FLAG_SHMEM: u32 = 1;
+const HELLO_SIZE: usize = 16;
+/// Largest capacity accepted from the peer, in bytes.
+const MAX_CAPACITY: u64 = 1 << 32;
+/// How much to read from the socket at a time, in the fallback transport.
+const READ_CHUNK: usize = 64 * 1024;
+
+/// How a `Channel` carries messages.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// A pair of `framed` ringbuffers.
+    SharedMemory,
+    /// Length prefixed messages over the unix socket.
+    Socket,
+}
+
+// There is one per channel, so the size difference does not matter.
+#[allow(clippy::large_enum_variant)]
+enum Inner {
+    Shmem {
+        tx: framed::Sender,
+        rx: framed::Receiver,
+    },
+    Socket {
+        /// Largest message the peer accepts.
+        peer_capacity: usize,
+        /// Largest message we accept.
+        capacity: usize,
+        /// Received bytes, not yet returned as messages.
+        buf: Vec<u8>,
+        seq: u64,
+    },
+}
+
+/// A bidirectional message channel, see the module documentation.
+pub struct Channel {
+    socket: UnixStream,
+    inner: Inner,
+}
+
+fn write_hello(
+    socket: &UnixStream,
+    tx: Option<&framed::Sender>,
+    capacity: usize,
+) -> Result<(), Error> {
+    let mut msg = [0u8; HELLO_SIZE];
+    msg[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    msg[8..].copy_from_slice(&(capacity as u64).to_le_bytes());
+    let fds = match tx {
+        Some(tx) => {
+            msg[4..8].copy_from_slice(&FLAG_SHMEM.to_le_bytes());
+            let ring = tx.ring();
+            vec![
+                ring.memfd().as_raw_fd(),
+                ring.empty_signal().as_raw_fd(),
+                ring.full_signal().as_raw_fd(),
+            ]
+        }
+        None => vec![],
+    };
+    let n = unix::send_with_fds(socket, &msg, &fds)?;
+    let mut socket = socket;
+    socket.write_all(&msg[n..])?;
+    Ok(())
+}
+
+/// Reads the peer's hello: its capacity and, if it offered a ringbuffer, its fds.
+fn read_hello(socket: &UnixStream) -> Result<(usize, Option<[File; 3]>), Error> {
+    let mut msg = [0u8; HELLO_SIZE];
+    let mut fds = vec![];
+    let n = unix::recv_with_fds(socket, &mut msg, &mut fds)?;
+    if n == 0 {
+        Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?
+    }
+    let mut s = socket;
+    s.read_exact(&mut msg[n..])?;
+    let mut word = [0u8; 4];
+    word.copy_from_slice(&msg[..4]);
+    let magic = u32::from_le_bytes(word);
+    word.copy_from_slice(&msg[4..8]);
+    let flags = u32::from_le_bytes(word);
+    let mut capacity = [0u8; 8];
+    capacity.copy_from_slice(&msg[8..]);
+    let capacity = u64::from_le_bytes(capacity);
+    let want_fds = if flags & FLAG_SHMEM != 0 { 3 } else { 0 };
+    if magic != MAGIC
+        || flags & !FLAG_SHMEM != 0
+        || fds.len() != want_fds
+        || capacity > MAX_CAPACITY
+    {
+        Err(crate::ringbuf::Error::BufCorrupt)?
+    }
+    let fds = match (fds.pop(), fds.pop(), fds.pop()) {
+        (Some(full), Some(empty), Some(memfd)) => Some([memfd, empty, full]),
+        _ => None,
+    };
+    Ok((capacity as usize, fds))
+}
+
+/// Exchanges one byte with the peer, and returns true if both sent true.
+fn agree(socket: &UnixStream, ok: bool) -> Result<bool, Error> {
+    let mut s = socket;
+    s.write_all(&[ok as u8])?;
+    let mut peer = [0u8];
+    s.read_exact(&mut peer)?;
+    match peer[0] {
+        0 => Ok(false),
+        1 => Ok(ok),
+        _ => Err(crate::ringbuf::Error::BufCorrupt)?,
+    }
+}
+
+impl Channel {
+    /// Sets up a channel with the peer at the other end of `socket`, who must call this too.
+    /// Messages from this side can be up to about `capacity` bytes.
+    ///
+    /// Blocks until the peer has answered. The socket remains in use by the channel.
+    pub fn connect(socket: UnixStream, capacity: usize) -> Result<Self, Error> {
+        // Failing to set up shared memory is not an error, just a reason to fall back.
+        let tx = framed::Sender::new(capacity).ok();
+        write_hello(&socket, tx.as_ref(), capacity)?;
+        let (peer_capacity, fds) = read_hello(&socket)?;
+        let rx = fds.and_then(|[memfd, empty, full]| {
+            framed::Receiver::open(peer_capacity, memfd, empty, full).ok()
+        });
+        let both = tx.is_some() && rx.is_some();
+        let inner = match (tx, rx) {
+            (Some(tx), Some(rx)) if agree(&socket, true)? => Inner::Shmem { tx, rx },
+            _ => {
+                // If we had both rings, the peer could not attach and has said so already.
+                if !both {
+                    agree(&socket, false)?;
+                }
+                Inner::Socket {
+                    peer_capacity,
+                    capacity,
+                    buf: vec![],
+                    seq: 0,
+                }
+            }
+        };
+        Ok(Channel { socket, inner })
+    }
+
+    /// Which transport was negotiated.
+    pub fn transport(&self) -> Transport {
+        match self.inner {
+            Inner::Shmem { .. } => Transport::SharedMemory,
+            Inner::Socket { .. } => Transport::Socket,
+        }
+    }
+
+    /// The underlying socket, e g to check whether the peer has hung up.
+    pub fn socket(&self) -> &UnixStream {
+        &self.socket
+    }
+
+    /// The socket, and the ringbuffers if the channel uses shared memory.
+    #[cfg(feature = "tonic")]
+    pub(crate) fn into_parts(self) -> (UnixStream, Option<(framed::Sender, framed::Receiver)>) {
+        match self.inner {
+            Inner::Shmem { tx, rx } => (self.socket, Some((tx, rx))),
+            Inner::Socket { .. } => (self.socket, None),
+        }
+    }
+
+    /// Sends a message.
+    ///
+    /// Returns false if there is currently not enough room in the ringbuffer; try again
+    /// when the peer has made room. Fails with `MessageTooBig` for messages that can never
+    /// fit.
+    pub fn send(&mut self, data: &[u8]) -> Result<bool, Error> {
+        match &mut self.inner {
+            Inner::Shmem { tx, .. } => tx.send(data),
+            Inner::Socket { peer_capacity, .. } => {
+                if data.len() > *peer_capacity {
+                    Err(Error::MessageTooBig)?
+                }
+                let mut s = &self.socket;
+                s.write_all(&(data.len() as u32).to_le_bytes())?;
+                s.write_all(data)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Receives the next message, if any, without blocking.
+    pub fn recv(&mut self) -> Result<Option<Message>, Error> {
+        let (capacity, buf, seq) = match &mut self.inner {
+            Inner::Shmem { rx, .. } => return rx.recv(),
+            Inner::Socket {
+                capacity, buf, seq, ..
+            } => (*capacity, buf, seq),
+        };
+        loop {
+            if buf.len() >= 4 {
+                let mut word = [0u8; 4];
+                word.copy_from_slice(&buf[..4]);
+                let len = u32::from_le_bytes(word) as usize;
+                if len > capacity {
+                    Err(crate::ringbuf::Error::BufCorrupt)?
+                }
+                if buf.len() >= 4 + len {
+                    let data = buf[4..4 + len].to_vec();
+                    buf.drain(..4 + len);
+                    *seq += 1;
+                    return Ok(Some(Message::from_vec(*seq - 1, data)));
+                }
+            }
+            let old = buf.len();
+            buf.resize(old + READ_CHUNK, 0);
+            let n = unsafe {
+                libc::recv(
+                    self.socket.as_raw_fd(),
+                    buf[old..].as_mut_ptr() as *mut _,
+                    READ_CHUNK,
+                    libc::MSG_DONTWAIT,
+                )
+            };
+            buf.truncate(old + n.max(0) as usize);
+            if n == 0 {
+                Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?
+            }
+            if n < 0 {
+                let e = std::io::Error::last_os_error();
+                match e.kind() {
+                    std::io::ErrorKind::WouldBlock => return Ok(None),
+                    std::io::ErrorKind::Interrupted => {}
+                    _ => Err(e)?,
+                }
+            }
+        }
+    }
+
+    /// For blocking scenarios, blocks until the channel is readable.
+    pub fn block_until_readable(&mut self) -> Result<(), Error> {
+        match &mut self.inner {
+            Inner::Shmem { rx, .. } => rx.block_until_readable(),
+            Inner::Socket { buf, .. } => {
+                if !buf.is_empty() {
+                    // Might be a whole message already; if not, recv reads the rest.
+                    return Ok(());
+                }
+                let mut pfd = libc::pollfd {
+                    fd: self.socket.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                let r = unsafe { libc::poll(&mut pfd, 1, -1) };
+                if r < 0 {
+                    let e = std::io::Error::last_os_error();
+                    if e.kind() != std::io::ErrorKind::Interrupted {
+                        Err(e)?
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn connect_socket(socket: UnixStream, capacity: usize) -> Result<Self, Error> {
+        write_hello(&socket, None, capacity)?;
+        let (peer_capacity, _) = read_hello(&socket)?;
+        agree(&socket, false)?;
+        Ok(Channel {
+            socket,
+            inner: Inner::Socket {
+                peer_capacity,
+                capacity,
+                buf: vec![],
+                seq: 0,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fallback() {
+        let (a, b) = UnixStream::pair().unwrap();
+        // One side without shared memory makes both fall back.
+        let peer = std::thread::spawn(move || Channel::connect_socket(b, 16));
+        let mut a = Channel::connect(a, 4096).unwrap();
+        let mut b = peer.join().unwrap().unwrap();
+        assert_eq!(a.transport(), Transport::Socket);
+        assert_eq!(b.transport(), Transport::Socket);
+        assert!(b.recv().unwrap().is_none());
+        assert!(a.send(b"one").unwrap());
+        assert!(a.send(b"two").unwrap());
+        assert!(matches!(a.send(&[0; 17]), Err(Error::MessageTooBig)));
+        b.block_until_readable().unwrap();
+        let m = b.recv().unwrap().unwrap();
+        assert_eq!((m.seq(), m.data()), (0, &b"one"[..]));
+        assert_eq!(b.recv().unwrap().unwrap().data(), b"two");
+        assert!(b.send(b"back").unwrap());
+        a.block_until_readable().unwrap();
+        assert_eq!(a.recv().unwrap().unwrap().data(), b"back");
+        drop(a);
+        assert!(b.recv().is_err());
+    }
+}
@@ -0,0 +1,13 @@
+//! Data structures living in shared memory, for more than two processes.
+//!
+//! Like the ringbuffers, these are set up by one process and attached to by others through
+//! the memfd file descriptor, and all peers are untrusted: a misbehaving peer can garble the
+//! contents, but not make the others misbehave in any other way than reporting corruption.
+
+mod bitset;
+mod interner;
+mod workqueue;
+
+pub use self::bitset::{ShmBitset, ShmBloom, Snapshot};
+pub use self::interner::ShmInterner;
+pub use self::workqueue::{ShmWorkQueue, Steal, Stealer};
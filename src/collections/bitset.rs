@@ -0,0 +1,228 @@
+//! Sets of bits for signaling between processes, e g which shards are dirty.
+
+use crate::mem::{mfd, mmap};
+use crate::Error;
+use std::fs::File;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+fn words(bits: usize) -> usize {
+    std::cmp::max(bits.div_ceil(64), 1)
+}
+
+/// A fixed size set of bits in shared memory. All operations on single bits are atomic.
+pub struct ShmBitset {
+    memfd: mfd::Memfd,
+    mmap: mmap::MmapRaw,
+    bits: usize,
+    _charge: Option<crate::quota::Charge>,
+}
+
+/// Iterator over the indices of the bits that were set when the snapshot was taken.
+pub struct Snapshot {
+    words: Vec<u64>,
+    index: usize,
+    bits: usize,
+}
+
+impl Iterator for Snapshot {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        while let Some(w) = self.words.get_mut(self.index / 64) {
+            let rest = *w >> (self.index % 64);
+            if rest == 0 {
+                self.index = (self.index / 64 + 1) * 64;
+                continue;
+            }
+            let i = self.index + rest.trailing_zeros() as usize;
+            self.index = i + 1;
+            // A misbehaving peer might have set the bits past the end.
+            return Some(i).filter(|&i| i < self.bits);
+        }
+        None
+    }
+}
+
+impl ShmBitset {
+    /// Creates a set of `bits` bits, all clear.
+    pub fn new(bits: usize) -> Result<Self, Error> {
+        let bytes = words(bits) * 8;
+        let charge = crate::quota::Charge::new(None, bytes as u64)?;
+        let memfd = crate::mem::CreateOptions::default().create("shmem-ipc bitset")?;
+        memfd.as_file().set_len(bytes as u64)?;
+        let mut b = Self::attach(memfd, bits)?;
+        b._charge = Some(charge);
+        Ok(b)
+    }
+
+    /// Attaches to a set created by another process with the same number of bits.
+    pub fn open(bits: usize, memfd: File) -> Result<Self, Error> {
+        Self::attach(crate::mem::memfd_from_file(memfd)?, bits)
+    }
+
+    fn attach(memfd: mfd::Memfd, bits: usize) -> Result<Self, Error> {
+        let bytes = words(bits) * 8;
+        let mmap = crate::mem::raw_memfd(&memfd, bytes)?;
+        if (memfd.as_file().metadata()?.len() as usize) < bytes {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        Ok(ShmBitset {
+            memfd,
+            mmap,
+            bits,
+            _charge: None,
+        })
+    }
+
+    /// The file descriptor to hand to the other processes, together with the size.
+    pub fn memfd(&self) -> &mfd::Memfd {
+        &self.memfd
+    }
+
+    /// Number of bits in the set.
+    pub fn len(&self) -> usize {
+        self.bits
+    }
+
+    /// Returns true if the set has no bits at all.
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+
+    fn word(&self, i: usize) -> &AtomicU64 {
+        unsafe { &*(self.mmap.as_ptr() as *const AtomicU64).add(i) }
+    }
+
+    fn locate(&self, bit: usize) -> Result<(&AtomicU64, u64), Error> {
+        if bit >= self.bits {
+            Err(Error::OutOfBounds)?
+        }
+        Ok((self.word(bit / 64), 1 << (bit % 64)))
+    }
+
+    /// Sets a bit, and returns whether it was set before.
+    pub fn set(&self, bit: usize) -> Result<bool, Error> {
+        let (w, mask) = self.locate(bit)?;
+        Ok(w.fetch_or(mask, Ordering::AcqRel) & mask != 0)
+    }
+
+    /// Clears a bit, and returns whether it was set before.
+    pub fn clear(&self, bit: usize) -> Result<bool, Error> {
+        let (w, mask) = self.locate(bit)?;
+        Ok(w.fetch_and(!mask, Ordering::AcqRel) & mask != 0)
+    }
+
+    /// Returns whether a bit is set.
+    pub fn test(&self, bit: usize) -> Result<bool, Error> {
+        let (w, mask) = self.locate(bit)?;
+        Ok(w.load(Ordering::Acquire) & mask != 0)
+    }
+
+    /// Iterates over the bits set right now.
+    ///
+    /// Each word is read atomically, but not the set as a whole.
+    pub fn snapshot(&self) -> Snapshot {
+        self.collect(|w| w.load(Ordering::Acquire))
+    }
+
+    /// Like `snapshot`, but also clears the bits, so that every bit set is seen exactly once.
+    pub fn take(&self) -> Snapshot {
+        self.collect(|w| w.swap(0, Ordering::AcqRel))
+    }
+
+    fn collect<F: Fn(&AtomicU64) -> u64>(&self, f: F) -> Snapshot {
+        Snapshot {
+            words: (0..words(self.bits)).map(|i| f(self.word(i))).collect(),
+            index: 0,
+            bits: self.bits,
+        }
+    }
+}
+
+/// A bloom filter in shared memory: membership tests without false negatives, but with
+/// some false positives.
+pub struct ShmBloom {
+    bits: ShmBitset,
+    hashes: u32,
+}
+
+impl ShmBloom {
+    /// Creates a filter of `bits` bits, with `hashes` hash functions per key.
+    pub fn new(bits: usize, hashes: u32) -> Result<Self, Error> {
+        if bits == 0 {
+            Err(Error::OutOfBounds)?
+        }
+        Ok(ShmBloom {
+            bits: ShmBitset::new(bits)?,
+            hashes,
+        })
+    }
+
+    /// Attaches to a filter created by another process with the same parameters.
+    pub fn open(bits: usize, hashes: u32, memfd: File) -> Result<Self, Error> {
+        if bits == 0 {
+            Err(Error::OutOfBounds)?
+        }
+        Ok(ShmBloom {
+            bits: ShmBitset::open(bits, memfd)?,
+            hashes,
+        })
+    }
+
+    /// The file descriptor to hand to the other processes, together with the parameters.
+    pub fn memfd(&self) -> &mfd::Memfd {
+        self.bits.memfd()
+    }
+
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        // Two FNV-1a hashes with different bases, combined as in Kirsch-Mitzenmacher.
+        let fnv = |basis: u64| {
+            key.iter()
+                .fold(basis, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+        };
+        let (h1, h2) = (fnv(0xcbf29ce484222325), fnv(0x84222325cbf29ce4) | 1);
+        let m = self.bits.len() as u64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % m) as usize)
+    }
+
+    /// Adds a key.
+    pub fn insert(&self, key: &[u8]) -> Result<(), Error> {
+        for p in self.positions(key) {
+            self.bits.set(p)?;
+        }
+        Ok(())
+    }
+
+    /// Returns false if the key has certainly not been added.
+    pub fn contains(&self, key: &[u8]) -> Result<bool, Error> {
+        for p in self.positions(key) {
+            if !self.bits.test(p)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dirty_shards() {
+        let b = ShmBitset::new(130).unwrap();
+        let other = ShmBitset::open(130, b.memfd().as_file().try_clone().unwrap()).unwrap();
+        assert!(!other.set(3).unwrap());
+        assert!(other.set(3).unwrap());
+        other.set(129).unwrap();
+        assert!(other.set(130).is_err());
+        assert_eq!(b.snapshot().collect::<Vec<_>>(), [3, 129]);
+        assert_eq!(b.take().collect::<Vec<_>>(), [3, 129]);
+        assert_eq!(b.snapshot().count(), 0);
+
+        let f = ShmBloom::new(1024, 4).unwrap();
+        f.insert(b"shard-7").unwrap();
+        assert!(f.contains(b"shard-7").unwrap());
+        assert!(!f.contains(b"shard-8").unwrap());
+    }
+}
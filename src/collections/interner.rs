@@ -0,0 +1,258 @@
+//! Interning strings to small ids, so that long keys need not be sent with every message.
+
+use crate::mem::{mfd, mmap};
+use crate::Error;
+use std::fs::File;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// Room for the counters in front of the tables.
+const HEADER_SIZE: usize = 64;
+
+struct Layout {
+    max_strings: usize,
+    table_len: usize,
+    arena_bytes: usize,
+}
+
+impl Layout {
+    fn new(max_strings: usize, arena_bytes: usize) -> Result<Self, Error> {
+        if max_strings == 0 || max_strings >= u32::MAX as usize || arena_bytes >= u32::MAX as usize
+        {
+            Err(Error::OutOfBounds)?
+        }
+        Ok(Layout {
+            max_strings,
+            // At most half full, so that probing ends quickly.
+            table_len: (max_strings * 2).next_power_of_two(),
+            arena_bytes,
+        })
+    }
+
+    fn entries(&self) -> usize {
+        HEADER_SIZE
+    }
+
+    fn table(&self) -> usize {
+        self.entries() + self.max_strings * 8
+    }
+
+    fn arena(&self) -> usize {
+        self.table() + self.table_len * 4
+    }
+
+    fn bytes(&self) -> usize {
+        self.arena() + self.arena_bytes
+    }
+}
+
+fn hash(s: &[u8]) -> u64 {
+    // FNV-1a
+    s.iter().fold(0xcbf29ce484222325, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// A table of strings and their ids, shared by any number of processes.
+///
+/// Ids are stable: once a string has an id, it keeps it for the lifetime of the table.
+/// Strings are never removed. All processes can intern; a misbehaving peer can garble the
+/// table, which then results in errors or wrong strings, but nothing worse.
+pub struct ShmInterner {
+    memfd: mfd::Memfd,
+    mmap: mmap::MmapRaw,
+    layout: Layout,
+    _charge: Option<crate::quota::Charge>,
+}
+
+impl ShmInterner {
+    /// Creates a table for up to `max_strings` strings of `arena_bytes` bytes in total.
+    pub fn new(max_strings: usize, arena_bytes: usize) -> Result<Self, Error> {
+        let layout = Layout::new(max_strings, arena_bytes)?;
+        let bytes = layout.bytes();
+        let charge = crate::quota::Charge::new(None, bytes as u64)?;
+        let memfd = crate::mem::CreateOptions::default().create("shmem-ipc interner")?;
+        memfd.as_file().set_len(bytes as u64)?;
+        let mut t = Self::attach(memfd, layout)?;
+        t._charge = Some(charge);
+        Ok(t)
+    }
+
+    /// Attaches to a table created by another process with the same sizes.
+    pub fn open(max_strings: usize, arena_bytes: usize, memfd: File) -> Result<Self, Error> {
+        let layout = Layout::new(max_strings, arena_bytes)?;
+        Self::attach(crate::mem::memfd_from_file(memfd)?, layout)
+    }
+
+    fn attach(memfd: mfd::Memfd, layout: Layout) -> Result<Self, Error> {
+        let mmap = crate::mem::raw_memfd(&memfd, layout.bytes())?;
+        if (memfd.as_file().metadata()?.len() as usize) < layout.bytes() {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        Ok(ShmInterner {
+            memfd,
+            mmap,
+            layout,
+            _charge: None,
+        })
+    }
+
+    /// The file descriptor to hand to the other processes, together with the sizes.
+    pub fn memfd(&self) -> &mfd::Memfd {
+        &self.memfd
+    }
+
+    fn at<T>(&self, offset: usize) -> &T {
+        unsafe { &*(self.mmap.as_ptr().add(offset) as *const T) }
+    }
+
+    fn count(&self) -> &AtomicU32 {
+        self.at(0)
+    }
+
+    fn arena_next(&self) -> &AtomicU64 {
+        self.at(8)
+    }
+
+    /// Offset (plus one) and length of a string, or zero if not yet written.
+    fn entry(&self, id: u32) -> &AtomicU64 {
+        self.at(self.layout.entries() + id as usize * 8)
+    }
+
+    /// The id plus one of the string in this slot, or zero if it is free.
+    fn slot(&self, index: usize) -> &AtomicU32 {
+        self.at(self.layout.table() + (index & (self.layout.table_len - 1)) * 4)
+    }
+
+    /// Number of strings in the table.
+    pub fn len(&self) -> usize {
+        std::cmp::min(
+            self.count().load(Ordering::Acquire) as usize,
+            self.layout.max_strings,
+        )
+    }
+
+    /// Returns true if the table is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn bytes_of(&self, id: u32) -> Result<Vec<u8>, Error> {
+        if id as usize >= self.layout.max_strings {
+            Err(Error::OutOfBounds)?
+        }
+        let e = self.entry(id).load(Ordering::Acquire);
+        if e == 0 {
+            Err(Error::OutOfBounds)?
+        }
+        let (offset, len) = (((e >> 32) - 1) as usize, (e & 0xffff_ffff) as usize);
+        if offset + len > self.layout.arena_bytes {
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        let p = unsafe { self.mmap.as_ptr().add(self.layout.arena() + offset) };
+        // Copied out, since a misbehaving peer could change them at any time.
+        let mut v = vec![0u8; len];
+        unsafe { std::ptr::copy_nonoverlapping(p, v.as_mut_ptr(), len) };
+        Ok(v)
+    }
+
+    /// Returns the string with this id.
+    ///
+    /// Fails with `OutOfBounds` for ids not in the table.
+    pub fn resolve(&self, id: u32) -> Result<String, Error> {
+        Ok(String::from_utf8(self.bytes_of(id)?).map_err(|_| crate::ringbuf::Error::BufCorrupt)?)
+    }
+
+    /// Looks for the string, and returns the hash table slot where it is or would go.
+    fn find(&self, s: &str) -> Result<(Option<u32>, usize), Error> {
+        let h = hash(s.as_bytes()) as usize;
+        for i in 0..self.layout.table_len {
+            let id = self.slot(h + i).load(Ordering::Acquire);
+            if id == 0 {
+                return Ok((None, h + i));
+            }
+            if self.bytes_of(id - 1)? == s.as_bytes() {
+                return Ok((Some(id - 1), h + i));
+            }
+        }
+        Err(crate::ringbuf::Error::BufCorrupt)?
+    }
+
+    /// Returns the id of a string, if it is in the table.
+    pub fn get(&self, s: &str) -> Result<Option<u32>, Error> {
+        Ok(self.find(s)?.0)
+    }
+
+    /// Returns the id of a string, adding it to the table if needed.
+    ///
+    /// Returns `None` if the table is full.
+    pub fn intern(&self, s: &str) -> Result<Option<u32>, Error> {
+        let (found, mut index) = self.find(s)?;
+        if found.is_some() {
+            return Ok(found);
+        }
+        let len = s.len() as u64;
+        let arena = self.layout.arena_bytes as u64;
+        let offset =
+            match self
+                .arena_next()
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                    Some(n + len).filter(|&end| end <= arena)
+                }) {
+                Ok(x) => x,
+                Err(_) => return Ok(None),
+            };
+        let max = self.layout.max_strings as u32;
+        let id = match self
+            .count()
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |c| {
+                Some(c + 1).filter(|&c| c <= max)
+            }) {
+            Ok(x) => x,
+            Err(_) => return Ok(None),
+        };
+        unsafe {
+            let p = self
+                .mmap
+                .as_mut_ptr()
+                .add(self.layout.arena() + offset as usize);
+            std::ptr::copy_nonoverlapping(s.as_ptr(), p, s.len());
+        }
+        self.entry(id)
+            .store((offset + 1) << 32 | len, Ordering::Release);
+        // Publish in the hash table. Someone else might be interning the same
+        // string right now; then theirs wins, and our id stays an alias for it.
+        loop {
+            match self
+                .slot(index)
+                .compare_exchange(0, id + 1, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return Ok(Some(id)),
+                Err(other) if self.bytes_of(other - 1)? == s.as_bytes() => {
+                    return Ok(Some(other - 1))
+                }
+                Err(_) => index += 1,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intern() {
+        let t = ShmInterner::new(2, 64).unwrap();
+        let topic = "sensors/lidar/front";
+        let id = t.intern(topic).unwrap().unwrap();
+        assert_eq!(t.intern(topic).unwrap(), Some(id));
+        let file = t.memfd().as_file().try_clone().unwrap();
+        let other = ShmInterner::open(2, 64, file).unwrap();
+        assert_eq!(other.resolve(id).unwrap(), topic);
+        assert_eq!(other.get(topic).unwrap(), Some(id));
+        assert_eq!(other.intern("b").unwrap(), Some(1));
+        assert_eq!(t.intern("c").unwrap(), None);
+        assert!(t.resolve(2).is_err());
+        assert_eq!(t.len(), 2);
+    }
+}
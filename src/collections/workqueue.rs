@@ -0,0 +1,267 @@
+//! A work-stealing deque: the owner pushes and pops jobs at one end, and any number of
+//! workers steal them from the other.
+//!
+//! This is a bounded Chase-Lev deque. It is lock-free, so a worker that dies while
+//! stealing leaves nothing locked behind, and jobs already pushed can still be stolen if
+//! the owner dies.
+
+use crate::mem::{mfd, mmap};
+use crate::Error;
+use std::fs::File;
+use std::marker::PhantomData;
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+
+/// `top` and `bottom`, on cache lines of their own, in front of the slots.
+const HEADER_SIZE: usize = 128;
+
+fn queue_bytes<T>(capacity: usize) -> usize {
+    HEADER_SIZE + capacity * std::mem::size_of::<T>()
+}
+
+fn slots<T>(capacity: usize) -> Result<usize, Error> {
+    if capacity == 0 || std::mem::align_of::<T>() > HEADER_SIZE {
+        Err(crate::ringbuf::Error::BufTooSmall)?
+    }
+    Ok(capacity
+        .checked_next_power_of_two()
+        .ok_or(crate::ringbuf::Error::BufTooBig)?)
+}
+
+struct Shared<T> {
+    memfd: mfd::Memfd,
+    mmap: mmap::MmapRaw,
+    mask: u64,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> Shared<T> {
+    fn attach(memfd: mfd::Memfd, slots: usize) -> Result<Self, Error> {
+        let bytes = queue_bytes::<T>(slots);
+        let mmap = crate::mem::raw_memfd(&memfd, bytes)?;
+        if (memfd.as_file().metadata()?.len() as usize) < bytes {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        Ok(Shared {
+            memfd,
+            mmap,
+            mask: slots as u64 - 1,
+            _phantom: PhantomData,
+        })
+    }
+
+    fn top(&self) -> &AtomicU64 {
+        unsafe { &*(self.mmap.as_ptr() as *const AtomicU64) }
+    }
+
+    fn bottom(&self) -> &AtomicU64 {
+        unsafe { &*(self.mmap.as_ptr().add(64) as *const AtomicU64) }
+    }
+
+    /// Slots are indexed modulo the capacity, so whatever a peer wrote into `top` and
+    /// `bottom`, this stays in bounds.
+    fn slot(&self, index: u64) -> *mut T {
+        unsafe {
+            (self.mmap.as_mut_ptr().add(HEADER_SIZE) as *mut T).add((index & self.mask) as usize)
+        }
+    }
+
+    fn capacity(&self) -> u64 {
+        self.mask + 1
+    }
+}
+
+/// The owner's end of the deque, typically held by the coordinator.
+pub struct ShmWorkQueue<T> {
+    shared: Shared<T>,
+    _charge: crate::quota::Charge,
+    /// Our own copy of `bottom`, since we are the only one writing it.
+    bottom: u64,
+}
+
+/// The result of `Stealer::steal`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Steal<T> {
+    /// The deque was empty.
+    Empty,
+    /// A job was stolen.
+    Success(T),
+    /// Lost a race with the owner or another worker; try again.
+    Retry,
+}
+
+impl<T: Copy + zerocopy::AsBytes + zerocopy::FromBytes> ShmWorkQueue<T> {
+    /// Creates a deque that holds at least `capacity` jobs.
+    pub fn new(capacity: usize) -> Result<Self, Error> {
+        let slots = slots::<T>(capacity)?;
+        let bytes = queue_bytes::<T>(slots);
+        let charge = crate::quota::Charge::new(None, bytes as u64)?;
+        let memfd = crate::mem::CreateOptions::default().create(std::any::type_name::<T>())?;
+        memfd.as_file().set_len(bytes as u64)?;
+        Ok(ShmWorkQueue {
+            shared: Shared::attach(memfd, slots)?,
+            _charge: charge,
+            bottom: 0,
+        })
+    }
+
+    /// The file descriptor to hand to the workers, together with the capacity.
+    pub fn memfd(&self) -> &mfd::Memfd {
+        &self.shared.memfd
+    }
+
+    /// Number of jobs the deque can hold.
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity() as usize
+    }
+
+    /// Number of jobs in the deque, which can be outdated as soon as it is returned.
+    pub fn len(&self) -> Result<usize, Error> {
+        let t = self.shared.top().load(Ordering::Acquire);
+        let len = self.bottom.wrapping_sub(t);
+        if len > self.shared.capacity() {
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        Ok(len as usize)
+    }
+
+    /// Returns true if there are no jobs in the deque.
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Pushes a job. Returns false if the deque is full.
+    pub fn push(&mut self, job: T) -> Result<bool, Error> {
+        if self.len()? == self.capacity() {
+            return Ok(false);
+        }
+        let b = self.bottom;
+        unsafe { std::ptr::write_volatile(self.shared.slot(b), job) };
+        self.bottom = b.wrapping_add(1);
+        self.shared.bottom().store(self.bottom, Ordering::Release);
+        Ok(true)
+    }
+
+    /// Pops the job pushed most recently, unless it has been stolen.
+    pub fn pop(&mut self) -> Result<Option<T>, Error> {
+        let shared = &self.shared;
+        let b = self.bottom.wrapping_sub(1);
+        shared.bottom().store(b, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        let t = shared.top().load(Ordering::Relaxed);
+        let len = b.wrapping_sub(t) as i64;
+        if len < 0 {
+            shared.bottom().store(self.bottom, Ordering::Relaxed);
+            return Ok(None);
+        }
+        if len as u64 >= shared.capacity() {
+            shared.bottom().store(self.bottom, Ordering::Relaxed);
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        let job = unsafe { std::ptr::read_volatile(shared.slot(b)) };
+        if len > 0 {
+            self.bottom = b;
+            return Ok(Some(job));
+        }
+        // The last job, which a worker might be stealing right now.
+        let won = shared
+            .top()
+            .compare_exchange(t, t.wrapping_add(1), Ordering::SeqCst, Ordering::Relaxed)
+            .is_ok();
+        shared.bottom().store(self.bottom, Ordering::Relaxed);
+        Ok(if won { Some(job) } else { None })
+    }
+}
+
+/// A worker's end of the deque.
+///
+/// Several workers, in the same process or not, can steal from the same deque.
+pub struct Stealer<T> {
+    shared: Shared<T>,
+}
+
+unsafe impl<T: Send> Send for Stealer<T> {}
+unsafe impl<T: Send> Sync for Stealer<T> {}
+
+impl<T: Copy + zerocopy::FromBytes> Stealer<T> {
+    /// Attaches to a deque created by `ShmWorkQueue::new` with the same capacity.
+    pub fn open(capacity: usize, memfd: File) -> Result<Self, Error> {
+        let slots = slots::<T>(capacity)?;
+        let memfd = crate::mem::memfd_from_file(memfd)?;
+        Ok(Stealer {
+            shared: Shared::attach(memfd, slots)?,
+        })
+    }
+
+    /// Steals the job that was pushed first.
+    pub fn steal(&self) -> Result<Steal<T>, Error> {
+        let shared = &self.shared;
+        let t = shared.top().load(Ordering::Acquire);
+        fence(Ordering::SeqCst);
+        let b = shared.bottom().load(Ordering::Acquire);
+        let len = b.wrapping_sub(t) as i64;
+        // Negative while the owner is popping the last job.
+        if len <= 0 {
+            return Ok(Steal::Empty);
+        }
+        if len as u64 > shared.capacity() {
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        // T is FromBytes, so reading a slot the owner is overwriting gives a garbled job at
+        // worst, and then the exchange below fails anyway.
+        let job = unsafe { std::ptr::read_volatile(shared.slot(t)) };
+        match shared.top().compare_exchange(
+            t,
+            t.wrapping_add(1),
+            Ordering::SeqCst,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => Ok(Steal::Success(job)),
+            Err(_) => Ok(Steal::Retry),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steal() {
+        let mut q = ShmWorkQueue::<u64>::new(1000).unwrap();
+        assert_eq!(q.capacity(), 1024);
+        let file = q.memfd().as_file().try_clone().unwrap();
+        let s = std::sync::Arc::new(Stealer::<u64>::open(1000, file).unwrap());
+        assert_eq!(s.steal().unwrap(), Steal::Empty);
+        for i in 0..1024 {
+            assert!(q.push(i).unwrap());
+        }
+        assert!(!q.push(0).unwrap());
+        assert_eq!(q.pop().unwrap(), Some(1023));
+        assert_eq!(s.steal().unwrap(), Steal::Success(0));
+
+        let workers: Vec<_> = (0..3)
+            .map(|_| {
+                let s = s.clone();
+                std::thread::spawn(move || {
+                    let mut got = vec![];
+                    loop {
+                        match s.steal().unwrap() {
+                            Steal::Success(x) => got.push(x),
+                            Steal::Retry => {}
+                            Steal::Empty => return got,
+                        }
+                    }
+                })
+            })
+            .collect();
+        let mut all = vec![];
+        while let Some(x) = q.pop().unwrap() {
+            all.push(x);
+        }
+        for w in workers {
+            all.extend(w.join().unwrap());
+        }
+        all.sort_unstable();
+        assert_eq!(all, (1..1023).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,72 @@
+//! Using types with `bytemuck` derives instead of `zerocopy` ones.
+//!
+//! Requires the `bytemuck` feature. Wrap the item type in `Bytemuck`, e g
+//! `sharedring::Sender<Bytemuck<MyMsg>>`, and there is no need to derive the zerocopy
+//! traits for `MyMsg` too.
+
+/// A `bytemuck::Pod` type, usable wherever the crate wants zerocopy's `AsBytes` and
+/// `FromBytes`.
+#[repr(transparent)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Bytemuck<T>(pub T);
+
+// bytemuck::Pod guarantees what both of these need: no padding or other uninitialized
+// bytes, and every bit pattern is valid. The wrapper is transparent, so the same holds for it.
+unsafe impl<T: bytemuck::Pod> zerocopy::AsBytes for Bytemuck<T> {
+    fn only_derive_is_allowed_to_implement_this_trait() {}
+}
+
+unsafe impl<T: bytemuck::Pod> zerocopy::FromBytes for Bytemuck<T> {
+    fn only_derive_is_allowed_to_implement_this_trait() {}
+}
+
+impl<T> From<T> for Bytemuck<T> {
+    fn from(t: T) -> Self {
+        Bytemuck(t)
+    }
+}
+
+impl<T> std::ops::Deref for Bytemuck<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> std::ops::DerefMut for Bytemuck<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sharedring::{Receiver, Sender};
+
+    #[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+    #[repr(C)]
+    struct Point {
+        x: u32,
+        y: u32,
+    }
+
+    #[test]
+    fn pod_items() {
+        let mut s: Sender<Bytemuck<Point>> = Sender::new(10).unwrap();
+        let memfd = s.memfd().as_file().try_clone().unwrap();
+        let e = s.empty_signal().try_clone().unwrap();
+        let f = s.full_signal().try_clone().unwrap();
+        let mut r: Receiver<Bytemuck<Point>> = Receiver::open(10, memfd, e, f).unwrap();
+        s.send_raw(|p, _| {
+            unsafe { std::ptr::write(p, Point { x: 1, y: 2 }.into()) };
+            1
+        })
+        .unwrap();
+        r.receive_raw(|p, n| {
+            assert_eq!(unsafe { std::ptr::read(p) }.y, 2);
+            n
+        })
+        .unwrap();
+    }
+}
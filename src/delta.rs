@@ -0,0 +1,229 @@
+//! Shared areas where readers only fetch what changed since their last sync.
+//!
+//! The writer keeps a log of which pages it modified: every page has a generation number,
+//! which is set to the current generation after each write to that page. Readers remember
+//! the generation they last synced to, and only look at pages with a later one.
+//!
+//! The area is shared like `mem::broadcast`: the writer keeps its writable mapping, and
+//! readers can only map it read only.
+//!
+//! The information to be transferred between processes is:
+//!  * size
+//!  * memfd file descriptor
+
+use super::Error;
+use crate::mem::{self, mfd};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const PAGE: usize = 4096;
+
+#[derive(Copy, Clone, Debug)]
+struct Layout {
+    pages: usize,
+    gens_offset: usize,
+    data_offset: usize,
+    total: usize,
+}
+
+impl Layout {
+    fn new(size: usize) -> Self {
+        let pages = size.div_ceil(PAGE);
+        let gens_offset = PAGE;
+        let data_offset = gens_offset + (pages * 8).div_ceil(PAGE) * PAGE;
+        Layout {
+            pages,
+            gens_offset,
+            data_offset,
+            total: data_offset + pages * PAGE,
+        }
+    }
+
+    /// Pages touched by a range of data.
+    fn pages(&self, offset: usize, len: usize) -> Range<usize> {
+        if len == 0 {
+            return 0..0;
+        }
+        offset / PAGE..(offset + len).div_ceil(PAGE)
+    }
+}
+
+fn check(offset: usize, len: usize, size: usize) -> Result<(), Error> {
+    match offset.checked_add(len) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(Error::OutOfBounds),
+    }
+}
+
+unsafe fn atomic<'a>(p: *const u8) -> &'a AtomicU64 {
+    &*(p as *const AtomicU64)
+}
+
+/// The writing side.
+pub struct Writer {
+    area: mem::Broadcast,
+    size: usize,
+    layout: Layout,
+    generation: u64,
+}
+
+impl Writer {
+    /// Creates an area for `size` bytes of data, initially zeroed.
+    pub fn new(size: usize, name: &str) -> Result<Self, Error> {
+        let layout = Layout::new(size);
+        let area = mem::broadcast(layout.total, name)?;
+        Ok(Writer {
+            area,
+            size,
+            layout,
+            generation: 0,
+        })
+    }
+
+    /// The file descriptor to hand out to readers.
+    pub fn memfd(&self) -> &mfd::Memfd {
+        self.area.memfd()
+    }
+
+    /// Size of the data.
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    /// Returns true if the area has no data.
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// The generation of the last write.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Raw pointer to the data, for modifying it in place.
+    ///
+    /// Call `mark_dirty` afterwards, so that readers pick up the change.
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        unsafe { self.area.as_mut_ptr().add(self.layout.data_offset) }
+    }
+
+    /// Marks a range of data as modified, starting a new generation.
+    pub fn mark_dirty(&mut self, offset: usize, len: usize) -> Result<(), Error> {
+        check(offset, len, self.size)?;
+        self.generation += 1;
+        let base = self.area.as_mut_ptr();
+        for p in self.layout.pages(offset, len) {
+            let g = unsafe { atomic(base.add(self.layout.gens_offset + p * 8)) };
+            g.store(self.generation, Ordering::Release);
+        }
+        unsafe { atomic(base) }.store(self.generation, Ordering::Release);
+        Ok(())
+    }
+
+    /// Copies data into the area, and marks it as modified.
+    pub fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        check(offset, data.len(), self.size)?;
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), self.as_mut_ptr().add(offset), data.len())
+        };
+        self.mark_dirty(offset, data.len())
+    }
+}
+
+/// The reading side.
+pub struct Reader {
+    view: mem::BroadcastView,
+    size: usize,
+    layout: Layout,
+    synced: u64,
+}
+
+impl Reader {
+    /// Attaches to an area of `size` bytes of data, created by the writer.
+    ///
+    /// Nothing has been synced yet, so the first call to `changes` returns everything
+    /// written so far.
+    pub fn open(size: usize, memfd: &mfd::Memfd) -> Result<Self, Error> {
+        let layout = Layout::new(size);
+        let view = mem::read_broadcast(memfd)?;
+        if view.len() < layout.total {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        Ok(Reader {
+            view,
+            size,
+            layout,
+            synced: 0,
+        })
+    }
+
+    /// Size of the data.
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    /// Returns true if the area has no data.
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// The generation this reader has synced to.
+    pub fn synced(&self) -> u64 {
+        self.synced
+    }
+
+    /// Returns the ranges of data modified since the last call, and marks them as synced.
+    ///
+    /// Ranges are page granular and coalesced. Read them with `read` right after; anything
+    /// the writer modifies concurrently is returned again by the next call.
+    pub fn changes(&mut self) -> Vec<Range<usize>> {
+        let base = self.view.as_ptr();
+        // The writer is untrusted, so the generation might go backwards; that just means
+        // we return too much.
+        let current = unsafe { atomic(base) }.load(Ordering::Acquire);
+        let mut v: Vec<Range<usize>> = vec![];
+        for p in 0..self.layout.pages {
+            let g = unsafe { atomic(base.add(self.layout.gens_offset + p * 8)) };
+            if g.load(Ordering::Acquire) <= self.synced {
+                continue;
+            }
+            let r = p * PAGE..std::cmp::min((p + 1) * PAGE, self.size);
+            match v.last_mut() {
+                Some(last) if last.end == r.start => last.end = r.end,
+                _ => v.push(r),
+            }
+        }
+        self.synced = current;
+        v
+    }
+
+    /// Copies data out of the area.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), Error> {
+        check(offset, buf.len(), self.size)?;
+        self.view.read(self.layout.data_offset + offset, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_since_sync() {
+        let mut w = Writer::new(10 * PAGE + 100, "delta").unwrap();
+        let peer = mfd::Memfd::try_from_file(w.memfd().as_file().try_clone().unwrap()).unwrap();
+        let mut r = Reader::open(w.len(), &peer).unwrap();
+        assert!(r.changes().is_empty());
+        w.write(PAGE - 1, &[1, 2]).unwrap();
+        w.write(10 * PAGE + 50, &[3]).unwrap();
+        assert_eq!(r.changes(), vec![0..2 * PAGE, 10 * PAGE..10 * PAGE + 100]);
+        assert_eq!(r.synced(), 2);
+        assert!(r.changes().is_empty());
+        w.write(3 * PAGE, &[4]).unwrap();
+        assert_eq!(r.changes(), vec![3 * PAGE..4 * PAGE]);
+        let mut buf = [0u8; 2];
+        r.read(PAGE - 1, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2]);
+        assert!(w.write(10 * PAGE + 100, &[0]).is_err());
+    }
+}
@@ -0,0 +1,696 @@
+//! One sender, many receivers, each of which gets every item.
+//!
+//! The items are in a `mem::Broadcast` area, which only the sender can write to; every
+//! receiver maps it read-only. Each receiver reports how far it has read in a cursor of its
+//! own, a small memfd shared with the sender only. So a receiver cannot change the items
+//! or the cursors of the others, and the only harm it can do is not reading: the sender
+//! never overwrites an item that a receiver has yet to read, so a stuck receiver stalls
+//! the channel until it is removed, see `FanoutSender::remove_receiver`.
+//!
+//! Receivers added later start with the items sent after they were added. The items stay
+//! in the ringbuffer after they have been read, until the sender needs the slot again, so
+//! a receiver can go back and read them again, see `FanoutReceiver::seek`; e g a late
+//! joiner catching up on recent history. Every item carries the `CLOCK_MONOTONIC` time it
+//! was sent at, so that readers can also go back in time, see `FanoutReceiver::seek_back`,
+//! and the sender can limit how old history may be, see `FanoutSender::set_max_age`.
+//!
+//! With compaction, see `FanoutSender::compact_by`, the sender also keeps the latest item
+//! for each key of those that have left the ringbuffer, so that e g a receiver syncing
+//! state can start from that instead of from the beginning, see `FanoutReceiver::bootstrap`.
+//!
+//! A consumer group, see `FanoutSender::add_group`, counts as one receiver, but has any
+//! number of members, each item going to one of them.
+//!
+//! # Example
+//! ```rust
+//! use shmem_ipc::fanout::{FanoutReceiver, FanoutSender};
+//! let mut s: FanoutSender<u64> = FanoutSender::new(64).unwrap();
+//! let (_, fds) = s.add_receiver().unwrap();
+//! let mut a = FanoutReceiver::open(64, fds).unwrap();
+//! let (_, fds) = s.add_receiver().unwrap();
+//! let mut b = FanoutReceiver::open(64, fds).unwrap();
+//! assert!(s.send(&7).unwrap());
+//! assert_eq!(a.recv().unwrap(), Some(7));
+//! assert_eq!(b.recv().unwrap(), Some(7));
+//! ```
+
+use crate::mem::{mfd, mmap, Broadcast, BroadcastView};
+use crate::wire::AtomicLe64;
+use crate::{Error, Op};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::os::unix::io::FromRawFd;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+mod compact;
+mod group;
+
+pub use self::group::GroupMember;
+
+/// The items start after a cache line with the header.
+const HEADER_SIZE: usize = 64;
+
+#[repr(C)]
+struct Header {
+    /// Number of items sent.
+    head: AtomicLe64,
+    capacity: AtomicLe64,
+    /// In nanoseconds, or zero for no limit.
+    max_age: AtomicLe64,
+}
+
+/// The send times, in `CLOCK_MONOTONIC` nanoseconds, come after the items.
+fn times_offset<T>(capacity: usize) -> Option<usize> {
+    let items = capacity.checked_mul(std::mem::size_of::<T>())?;
+    HEADER_SIZE.checked_add(items.checked_add(7)? & !7)
+}
+
+fn data_size<T>(capacity: usize) -> Option<usize> {
+    times_offset::<T>(capacity)?.checked_add(capacity.checked_mul(8)?)
+}
+
+fn time_of(data: *const u8, times: usize, capacity: usize, seq: u64) -> &'static AtomicLe64 {
+    let i = (seq % capacity as u64) as usize;
+    unsafe { &*(data.add(times + 8 * i) as *const AtomicLe64) }
+}
+
+fn eventfd() -> Result<File, Error> {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+    if fd < 0 {
+        Err(Error::os(Op::Signal, None)(std::io::Error::last_os_error()))?
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn signal(file: &File) -> Result<(), Error> {
+    (&*file)
+        .write_all(&1u64.to_ne_bytes())
+        .map_err(Error::os(Op::Signal, None))
+}
+
+fn wait(file: &File) -> Result<(), Error> {
+    let mut b = [0u8; 8];
+    (&*file)
+        .read_exact(&mut b)
+        .map_err(Error::os(Op::Signal, None))
+}
+
+fn cursor(map: &mmap::MmapRaw) -> &AtomicLe64 {
+    unsafe { &*(map.as_ptr() as *const AtomicLe64) }
+}
+
+fn open_data<T>(capacity: usize, file: File) -> Result<BroadcastView, Error> {
+    let size = data_size::<T>(capacity).ok_or(Error::OutOfBounds)?;
+    let data = crate::mem::read_broadcast(&crate::mem::memfd_from_file(file)?)?;
+    if data.len() < size {
+        Err(crate::ringbuf::Error::BufTooSmall)?
+    }
+    let header = unsafe { &*(data.as_ptr() as *const Header) };
+    if header.capacity.load(Ordering::Acquire) != capacity as u64 {
+        Err(crate::ringbuf::Error::BufCorrupt)?
+    }
+    Ok(data)
+}
+
+/// Maps a cursor memfd of at least `len` bytes.
+fn open_cursor(file: File, len: usize) -> Result<mmap::MmapRaw, Error> {
+    let memfd: mfd::Memfd = crate::mem::memfd_from_file(file)?;
+    if (memfd.as_file().metadata()?.len() as usize) < len {
+        Err(crate::ringbuf::Error::BufTooSmall)?
+    }
+    crate::mem::raw_memfd(&memfd, len)
+}
+
+struct Slot {
+    cursor: mmap::MmapRaw,
+    readable: File,
+    /// Last seen cursor, which the receiver may only move forward.
+    tail: u64,
+    group: bool,
+}
+
+/// The file descriptors for one receiver, to be passed to `FanoutReceiver::open`.
+#[derive(Debug)]
+pub struct ReceiverFds {
+    /// The items, which can only be mapped read-only.
+    pub data: File,
+    /// The cursor of this receiver.
+    pub cursor: File,
+    /// Signaled when there are items to read.
+    pub readable: File,
+    /// Shared by all receivers, for signaling that there is room to send.
+    pub writable: File,
+    /// The compacted items, if the sender compacts.
+    pub compacted: Option<File>,
+}
+
+impl ReceiverFds {
+    /// Duplicates the file descriptors, e g for another member of a consumer group.
+    pub fn try_clone(&self) -> Result<Self, Error> {
+        Ok(ReceiverFds {
+            data: self.data.try_clone()?,
+            cursor: self.cursor.try_clone()?,
+            readable: self.readable.try_clone()?,
+            writable: self.writable.try_clone()?,
+            compacted: self.compacted.as_ref().map(File::try_clone).transpose()?,
+        })
+    }
+}
+
+/// The sending side, see the module documentation.
+pub struct FanoutSender<T> {
+    data: Broadcast,
+    capacity: usize,
+    times: usize,
+    head: u64,
+    receivers: Vec<Option<Slot>>,
+    writable: File,
+    compactor: Option<compact::Compactor<T>>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Copy + zerocopy::AsBytes> FanoutSender<T> {
+    /// Creates a channel with room for `capacity` items, and no receivers yet.
+    pub fn new(capacity: usize) -> Result<Self, Error> {
+        if capacity == 0 {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        if std::mem::align_of::<T>() > HEADER_SIZE {
+            Err(Error::Misaligned)?
+        }
+        let size = data_size::<T>(capacity).ok_or(Error::OutOfBounds)?;
+        let data = crate::mem::broadcast(size, "shmem-ipc fanout")?;
+        let s = FanoutSender {
+            data,
+            capacity,
+            times: times_offset::<T>(capacity).unwrap(),
+            head: 0,
+            receivers: vec![],
+            writable: eventfd()?,
+            compactor: None,
+            _phantom: PhantomData,
+        };
+        s.header()
+            .capacity
+            .store(capacity as u64, Ordering::Release);
+        Ok(s)
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*(self.data.as_mut_ptr() as *const Header) }
+    }
+
+    /// Limits how far back in time receivers can go, see `FanoutReceiver::oldest`. Without
+    /// a limit, only the capacity does.
+    pub fn set_max_age(&mut self, max_age: Option<Duration>) {
+        let ns = max_age.map_or(0, |d| (d.as_nanos() as u64).max(1));
+        self.header().max_age.store(ns, Ordering::Release);
+    }
+
+    /// Compacts the items that leave the ringbuffer by key: the latest item for each of up
+    /// to `keys` different keys is kept in a table that receivers can read, see
+    /// `FanoutReceiver::bootstrap`. The compaction is done by `send`, a little at a time,
+    /// and `send` fails with `Error::OutOfBounds` once there would be more keys than that.
+    ///
+    /// Has to be called before sending, and before adding the receivers that want the
+    /// table.
+    pub fn compact_by<F>(&mut self, keys: usize, key: F) -> Result<(), Error>
+    where
+        F: Fn(&T) -> u64 + Send + 'static,
+    {
+        if self.head != 0 {
+            Err(Error::OutOfBounds)?
+        }
+        self.compactor = Some(compact::Compactor::new(keys, Box::new(key))?);
+        Ok(())
+    }
+
+    /// Adds a receiver, and returns its id and the file descriptors to hand to it.
+    pub fn add_receiver(&mut self) -> Result<(usize, ReceiverFds), Error> {
+        self.add(8, false)
+    }
+
+    /// Adds a consumer group, and returns its id and the file descriptors to hand to each
+    /// of its members, see `GroupMember`.
+    ///
+    /// The members share a cursor, so unlike separate receivers, they can get in each
+    /// other's way; the group as a whole is kept apart from the other receivers like any
+    /// single receiver.
+    pub fn add_group(&mut self) -> Result<(usize, ReceiverFds), Error> {
+        let size = group::control_size(self.capacity).ok_or(Error::OutOfBounds)?;
+        self.add(size, true)
+    }
+
+    fn add(&mut self, size: usize, group: bool) -> Result<(usize, ReceiverFds), Error> {
+        let memfd = crate::mem::CreateOptions::default().create("shmem-ipc fanout cursor")?;
+        memfd.as_file().set_len(size as u64)?;
+        let map = crate::mem::raw_memfd(&memfd, size)?;
+        cursor(&map).store(self.head, Ordering::Release);
+        if group {
+            group::claimed(&map).store(self.head, Ordering::Release);
+        }
+        let slot = Slot {
+            cursor: map,
+            readable: eventfd()?,
+            tail: self.head,
+            group,
+        };
+        let fds = ReceiverFds {
+            data: self.data.memfd().as_file().try_clone()?,
+            cursor: memfd.into_file(),
+            readable: slot.readable.try_clone()?,
+            writable: self.writable.try_clone()?,
+            compacted: self.compactor.as_ref().map(|c| c.file()).transpose()?,
+        };
+        let id = match self.receivers.iter().position(Option::is_none) {
+            Some(i) => i,
+            None => {
+                self.receivers.push(None);
+                self.receivers.len() - 1
+            }
+        };
+        self.receivers[id] = Some(slot);
+        Ok((id, fds))
+    }
+
+    /// Removes a receiver, e g one that has stopped reading. Returns false if there was no
+    /// receiver with that id.
+    pub fn remove_receiver(&mut self, id: usize) -> bool {
+        self.receivers.get_mut(id).and_then(Option::take).is_some()
+    }
+
+    /// Number of receivers.
+    pub fn receivers(&self) -> usize {
+        self.receivers.iter().flatten().count()
+    }
+
+    /// Refreshes the cursors, and returns the number of items the slowest receiver has yet
+    /// to read.
+    fn backlog(&mut self) -> u64 {
+        let head = self.head;
+        let mut backlog = 0;
+        for slot in self.receivers.iter_mut().flatten() {
+            // Untrusted, so keep it between what we saw before and what was sent.
+            let t = cursor(&slot.cursor).load(Ordering::Acquire);
+            slot.tail = t.max(slot.tail).min(head);
+            backlog = backlog.max(head - slot.tail);
+        }
+        backlog
+    }
+
+    /// Sends an item to all receivers. Returns false if the slowest receiver has not made
+    /// room for it yet.
+    pub fn send(&mut self, item: &T) -> Result<bool, Error> {
+        if self.backlog() >= self.capacity as u64 {
+            return Ok(false);
+        }
+        let cap = self.capacity as u64;
+        let p = unsafe { self.data.as_mut_ptr().add(HEADER_SIZE) as *mut T };
+        if let Some(c) = self.compactor.as_mut() {
+            // After this, `oldest` is past the item, as its slot is the next to be reused.
+            if let Some(seq) = (self.head + 1).checked_sub(cap) {
+                let old = match seq == self.head {
+                    true => *item,
+                    false => unsafe { std::ptr::read_volatile(p.add((seq % cap) as usize)) },
+                };
+                c.fold(seq, &old)?;
+            }
+        }
+        unsafe { std::ptr::write_volatile(p.add((self.head % cap) as usize), *item) };
+        let now = crate::sim::monotonic_ns();
+        time_of(self.data.as_mut_ptr(), self.times, self.capacity, self.head)
+            .store(now, Ordering::Relaxed);
+        let head = self.head;
+        self.head += 1;
+        // SeqCst, against the receiver storing its cursor and then checking for items.
+        self.header().head.store(self.head, Ordering::SeqCst);
+        // Receivers that are behind will find the item without being woken up.
+        for slot in self.receivers.iter().flatten() {
+            let next = match slot.group {
+                false => cursor(&slot.cursor),
+                true => group::claimed(&slot.cursor),
+            };
+            if next.load(Ordering::SeqCst) == head {
+                signal(&slot.readable)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// For blocking scenarios, blocks until there is room to send.
+    pub fn block_until_writable(&mut self) -> Result<(), Error> {
+        while self.backlog() >= self.capacity as u64 {
+            wait(&self.writable)?;
+        }
+        Ok(())
+    }
+}
+
+/// A receiving side, see the module documentation.
+pub struct FanoutReceiver<T> {
+    data: BroadcastView,
+    cursor: mmap::MmapRaw,
+    capacity: usize,
+    times: usize,
+    tail: u64,
+    /// How far this receiver has got, as reported to the sender; `tail` is behind it
+    /// while replaying.
+    high: u64,
+    skipped: u64,
+    compacted: Option<compact::Compacted<T>>,
+    readable: File,
+    writable: File,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Copy + zerocopy::FromBytes> FanoutReceiver<T> {
+    /// Attaches to a channel, given the file descriptors from `FanoutSender::add_receiver`.
+    pub fn open(capacity: usize, fds: ReceiverFds) -> Result<Self, Error> {
+        let map = open_cursor(fds.cursor, 8)?;
+        let tail = cursor(&map).load(Ordering::Acquire);
+        Ok(FanoutReceiver {
+            data: open_data::<T>(capacity, fds.data)?,
+            times: times_offset::<T>(capacity).ok_or(Error::OutOfBounds)?,
+            tail,
+            high: tail,
+            skipped: 0,
+            compacted: fds.compacted.map(compact::Compacted::open).transpose()?,
+            cursor: map,
+            capacity,
+            readable: fds.readable,
+            writable: fds.writable,
+            _phantom: PhantomData,
+        })
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*(self.data.as_ptr() as *const Header) }
+    }
+
+    /// Receives the next item, if there is one.
+    ///
+    /// While replaying, the sender does not wait for this receiver, and items it overwrites
+    /// before they are read again are skipped, see `skipped`.
+    pub fn recv(&mut self) -> Result<Option<T>, Error> {
+        let cap = self.capacity as u64;
+        loop {
+            let head = self.header().head.load(Ordering::Acquire);
+            let ahead = head.wrapping_sub(self.high);
+            if ahead > cap {
+                Err(crate::ringbuf::Error::BufCorrupt)?
+            }
+            if head == self.tail {
+                return Ok(None);
+            }
+            if self.tail < self.high && head - self.tail >= cap {
+                self.skip_to(head - cap + 1);
+                continue;
+            }
+            let seq = self.tail;
+            let item = unsafe {
+                let p = self.data.as_ptr().add(HEADER_SIZE) as *const T;
+                std::ptr::read_volatile(p.add((seq % cap) as usize))
+            };
+            if seq < self.high {
+                // The sender may have reused the slot while we were reading it.
+                std::sync::atomic::fence(Ordering::Acquire);
+                let now = self.header().head.load(Ordering::Relaxed);
+                if now.wrapping_sub(seq) >= cap {
+                    continue;
+                }
+            }
+            self.tail += 1;
+            if self.tail > self.high {
+                self.advance();
+                // We might have been the one the sender is waiting for.
+                if ahead == cap {
+                    signal(&self.writable)?;
+                }
+            }
+            return Ok(Some(item));
+        }
+    }
+
+    fn skip_to(&mut self, seq: u64) {
+        self.skipped += seq - self.tail;
+        self.tail = seq;
+    }
+
+    /// Reports `tail` to the sender, as it is ahead of where we were.
+    fn advance(&mut self) {
+        self.high = self.tail;
+        cursor(&self.cursor).store(self.high, Ordering::SeqCst);
+    }
+
+    /// The sequence number of the oldest item still in the ringbuffer, and not older than
+    /// the maximum age if the sender has set one; i e the furthest back `seek` can go.
+    pub fn oldest(&self) -> u64 {
+        let head = self.header().head.load(Ordering::Acquire);
+        let oldest = head.saturating_sub(self.capacity as u64 - 1);
+        match self.header().max_age.load(Ordering::Acquire) {
+            0 => oldest,
+            age => {
+                let cutoff = crate::sim::monotonic_ns().saturating_sub(age);
+                self.first_sent_at(oldest, head, cutoff)
+            }
+        }
+    }
+
+    /// The first item in `start..end` sent at `t` or later, or `end`.
+    ///
+    /// Send times only go forward, so this is a binary search. The sender might be reusing
+    /// the oldest slots meanwhile, which makes the result too early, and `seek` catches that.
+    fn first_sent_at(&self, mut start: u64, mut end: u64, t: u64) -> u64 {
+        while start < end {
+            let mid = start + (end - start) / 2;
+            let time = time_of(self.data.as_ptr(), self.times, self.capacity, mid);
+            if time.load(Ordering::Acquire) < t {
+                start = mid + 1;
+            } else {
+                end = mid;
+            }
+        }
+        start
+    }
+
+    /// The sequence number of the next item `recv` returns.
+    pub fn position(&self) -> u64 {
+        self.tail
+    }
+
+    /// Moves the cursor to the item with sequence number `seq`, between `oldest` and the
+    /// next item to be sent. Going back replays items already read; going forward skips
+    /// items.
+    pub fn seek(&mut self, seq: u64) -> Result<(), Error> {
+        let head = self.header().head.load(Ordering::Acquire);
+        if seq > head || seq < self.oldest() {
+            Err(Error::OutOfBounds)?
+        }
+        self.move_to(head, seq)
+    }
+
+    fn move_to(&mut self, head: u64, seq: u64) -> Result<(), Error> {
+        let ahead = head.wrapping_sub(self.high);
+        self.tail = seq;
+        if seq > self.high {
+            self.advance();
+            if ahead == self.capacity as u64 {
+                signal(&self.writable)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the cursor to the oldest item still in the ringbuffer, and returns its
+    /// sequence number.
+    pub fn seek_oldest(&mut self) -> Result<u64, Error> {
+        let seq = self.oldest();
+        self.seek(seq)?;
+        Ok(seq)
+    }
+
+    /// Moves the cursor to the first item sent at `CLOCK_MONOTONIC` time `t` (in
+    /// nanoseconds), or later, and returns its sequence number. If the items from that time
+    /// are gone, moves to `oldest`.
+    pub fn seek_to_time(&mut self, t: u64) -> Result<u64, Error> {
+        let head = self.header().head.load(Ordering::Acquire);
+        let oldest = self.oldest();
+        let seq = self.first_sent_at(oldest, head, t).max(self.oldest());
+        self.seek(seq)?;
+        Ok(seq)
+    }
+
+    /// Moves the cursor to the first item sent within the last `d`, e g for the last five
+    /// seconds of a flight recorder, and returns its sequence number.
+    pub fn seek_back(&mut self, d: Duration) -> Result<u64, Error> {
+        let t = crate::sim::monotonic_ns().saturating_sub(d.as_nanos() as u64);
+        self.seek_to_time(t)
+    }
+
+    /// Copies out the compacted items, see `FanoutSender::compact_by`, and moves the cursor to
+    /// the first item that is not compacted, so that receiving from there on gives every item
+    /// after them. Fails with `Error::OutOfBounds` if the sender does not compact.
+    ///
+    /// This is not limited by `FanoutSender::set_max_age`, as the compacted items cover all
+    /// the older ones anyway. Like any seek back, the sender does not wait for this receiver
+    /// until it has caught up, so items can still be skipped, see `skipped`.
+    pub fn bootstrap(&mut self) -> Result<Vec<T>, Error> {
+        let table = self.compacted.as_ref().ok_or(Error::OutOfBounds)?;
+        loop {
+            // Tries again if the sender changed the table, or moved on, while we copied it.
+            if let Some((items, horizon)) = table.snapshot() {
+                let head = self.header().head.load(Ordering::Acquire);
+                let oldest = head.saturating_sub(self.capacity as u64 - 1);
+                if (oldest..=head).contains(&horizon) {
+                    self.move_to(head, horizon)?;
+                    return Ok(items);
+                }
+            }
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Number of items skipped while replaying, because the sender had reused their slots.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// For blocking scenarios, blocks until there is an item to read.
+    pub fn block_until_readable(&mut self) -> Result<(), Error> {
+        while self.header().head.load(Ordering::SeqCst) == self.tail {
+            wait(&self.readable)?;
+        }
+        Ok(())
+    }
+
+    /// The file descriptor that becomes readable when there are items, e g for an event
+    /// loop.
+    pub fn readable_signal(&self) -> &File {
+        &self.readable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slowest_receiver() {
+        let mut s: FanoutSender<u32> = FanoutSender::new(4).unwrap();
+        let (_, fds) = s.add_receiver().unwrap();
+        let mut fast = FanoutReceiver::<u32>::open(4, fds).unwrap();
+        let (slow_id, fds) = s.add_receiver().unwrap();
+        // Items are read-only for the receivers.
+        let w = unsafe { mmap::MmapOptions::new().map_mut(&fds.data) };
+        assert_eq!(w.unwrap_err().raw_os_error(), Some(libc::EPERM));
+        let mut slow = FanoutReceiver::<u32>::open(4, fds).unwrap();
+        for i in 0..4 {
+            assert!(s.send(&i).unwrap());
+            assert_eq!(fast.recv().unwrap(), Some(i));
+        }
+        assert!(!s.send(&4).unwrap());
+        assert_eq!(slow.recv().unwrap(), Some(0));
+        s.block_until_writable().unwrap();
+        assert!(s.send(&4).unwrap());
+        assert!(!s.send(&5).unwrap());
+        assert!(s.remove_receiver(slow_id));
+        assert!(s.send(&5).unwrap());
+        assert_eq!(s.receivers(), 1);
+        fast.block_until_readable().unwrap();
+        assert_eq!(fast.recv().unwrap(), Some(4));
+        assert_eq!(fast.recv().unwrap(), Some(5));
+        assert_eq!(fast.recv().unwrap(), None);
+    }
+
+    #[test]
+    fn replay() {
+        let mut s: FanoutSender<u32> = FanoutSender::new(4).unwrap();
+        let (_, fds) = s.add_receiver().unwrap();
+        let mut r1 = FanoutReceiver::<u32>::open(4, fds).unwrap();
+        for i in 0..3 {
+            assert!(s.send(&i).unwrap());
+            assert_eq!(r1.recv().unwrap(), Some(i));
+        }
+        // A late joiner catches up on history.
+        let (_, fds) = s.add_receiver().unwrap();
+        let mut r2 = FanoutReceiver::<u32>::open(4, fds).unwrap();
+        assert_eq!(r2.recv().unwrap(), None);
+        assert_eq!(r2.seek_oldest().unwrap(), 0);
+        for i in 0..3 {
+            assert_eq!(r2.recv().unwrap(), Some(i));
+        }
+        assert_eq!(r2.recv().unwrap(), None);
+        for i in 3..7 {
+            assert!(s.send(&i).unwrap());
+            assert_eq!(r1.recv().unwrap(), Some(i));
+        }
+        assert!(r2.seek(2).is_err());
+        assert_eq!(r2.seek_oldest().unwrap(), 4);
+        assert_eq!(r2.recv().unwrap(), Some(4));
+        // Replaying does not hold up the sender, so item 4 is overwritten meanwhile.
+        r2.seek(4).unwrap();
+        for i in 7..9 {
+            assert!(s.send(&i).unwrap());
+            assert_eq!(r1.recv().unwrap(), Some(i));
+        }
+        assert_eq!(r2.recv().unwrap(), Some(6));
+        assert_eq!(r2.skipped(), 2);
+        assert_eq!(r2.position(), 7);
+    }
+
+    #[test]
+    fn compaction() {
+        let mut s: FanoutSender<[u32; 2]> = FanoutSender::new(4).unwrap();
+        s.compact_by(2, |kv| kv[0] as u64).unwrap();
+        let (_, fds) = s.add_receiver().unwrap();
+        let mut r = FanoutReceiver::<[u32; 2]>::open(4, fds).unwrap();
+        for (i, key) in [1, 2, 1, 1, 2, 1, 2].iter().enumerate() {
+            assert!(s.send(&[*key, i as u32]).unwrap());
+            r.recv().unwrap().unwrap();
+        }
+        // A state-sync consumer joins.
+        let (_, fds) = s.add_receiver().unwrap();
+        let mut late = FanoutReceiver::<[u32; 2]>::open(4, fds).unwrap();
+        assert_eq!(late.bootstrap().unwrap(), vec![[1, 3], [2, 1]]);
+        assert_eq!(late.position(), 4);
+        for i in 4..7 {
+            assert_eq!(late.recv().unwrap().unwrap()[1], i);
+        }
+        assert_eq!(late.recv().unwrap(), None);
+        // One more key than there is room for, once it leaves the ringbuffer
+        for i in 7..10 {
+            assert!(s.send(&[3, i]).unwrap());
+            r.recv().unwrap().unwrap();
+        }
+        assert!(matches!(s.send(&[3, 10]), Err(Error::OutOfBounds)));
+        let mut plain: FanoutSender<u32> = FanoutSender::new(4).unwrap();
+        let (_, fds) = plain.add_receiver().unwrap();
+        let mut r = FanoutReceiver::<u32>::open(4, fds).unwrap();
+        assert!(matches!(r.bootstrap(), Err(Error::OutOfBounds)));
+    }
+
+    #[test]
+    fn seek_back_in_time() {
+        crate::sim::enable();
+        let mut s: FanoutSender<u32> = FanoutSender::new(16).unwrap();
+        let (_, fds) = s.add_receiver().unwrap();
+        let mut r = FanoutReceiver::<u32>::open(16, fds).unwrap();
+        // One item a second
+        for i in 0..10 {
+            assert!(s.send(&i).unwrap());
+            crate::sim::advance(Duration::from_secs(1));
+        }
+        while r.recv().unwrap().is_some() {}
+        assert_eq!(r.seek_back(Duration::from_millis(4500)).unwrap(), 6);
+        assert_eq!(r.recv().unwrap(), Some(6));
+        assert_eq!(r.seek_back(Duration::from_secs(60)).unwrap(), 0);
+        s.set_max_age(Some(Duration::from_secs(3)));
+        assert_eq!(r.oldest(), 7);
+        assert_eq!(r.seek_back(Duration::from_secs(60)).unwrap(), 7);
+        assert!(r.seek(6).is_err());
+        assert_eq!(r.seek_back(Duration::from_secs(0)).unwrap(), 10);
+        crate::sim::disable();
+    }
+}
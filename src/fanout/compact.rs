@@ -0,0 +1,137 @@
+//! Key-based compaction: the latest item for each key, of the items that have left the
+//! ringbuffer, see `FanoutSender::compact_by`.
+
+use super::HEADER_SIZE;
+use crate::mem::{Broadcast, BroadcastView};
+use crate::wire::AtomicLe64;
+use crate::Error;
+use std::collections::HashMap;
+use std::fs::File;
+use std::marker::PhantomData;
+use std::sync::atomic::{fence, Ordering};
+
+// The table memfd starts with a cache line holding a generation, which is odd while the
+// sender updates the table; the horizon, i e the first item not folded into the table;
+// the number of items in it; and the room for items. The items come after that, one per
+// key, in the order the keys were first seen.
+#[repr(C)]
+struct TableHeader {
+    generation: AtomicLe64,
+    horizon: AtomicLe64,
+    len: AtomicLe64,
+    keys: AtomicLe64,
+}
+
+fn table_size<T>(keys: usize) -> Option<usize> {
+    keys.checked_mul(std::mem::size_of::<T>())?
+        .checked_add(HEADER_SIZE)
+}
+
+/// The sending side of the table.
+pub(super) struct Compactor<T> {
+    table: Broadcast,
+    keys: usize,
+    /// Where the item for each key is.
+    index: HashMap<u64, usize>,
+    key: Box<dyn Fn(&T) -> u64 + Send>,
+}
+
+impl<T: Copy> Compactor<T> {
+    pub(super) fn new(keys: usize, key: Box<dyn Fn(&T) -> u64 + Send>) -> Result<Self, Error> {
+        if keys == 0 {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        let size = table_size::<T>(keys).ok_or(Error::OutOfBounds)?;
+        let table = crate::mem::broadcast(size, "shmem-ipc fanout compacted")?;
+        let c = Compactor {
+            table,
+            keys,
+            index: HashMap::new(),
+            key,
+        };
+        c.header().keys.store(keys as u64, Ordering::Release);
+        Ok(c)
+    }
+
+    fn header(&self) -> &TableHeader {
+        unsafe { &*(self.table.as_mut_ptr() as *const TableHeader) }
+    }
+
+    pub(super) fn file(&self) -> Result<File, Error> {
+        Ok(self.table.memfd().as_file().try_clone()?)
+    }
+
+    /// Folds in item `seq`, the oldest one in the ringbuffer, as it is about to leave.
+    /// Fails if it has a new key and there is no room left for it.
+    pub(super) fn fold(&mut self, seq: u64, item: &T) -> Result<(), Error> {
+        let key = (self.key)(item);
+        let len = self.index.len();
+        let i = match self.index.get(&key) {
+            Some(&i) => i,
+            None if len < self.keys => len,
+            None => Err(Error::OutOfBounds)?,
+        };
+        let h = self.header();
+        let generation = h.generation.load(Ordering::Relaxed);
+        h.generation.store(generation + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe {
+            let p = self.table.as_mut_ptr().add(HEADER_SIZE) as *mut T;
+            std::ptr::write_volatile(p.add(i), *item);
+        }
+        h.len.store(len.max(i + 1) as u64, Ordering::Relaxed);
+        h.horizon.store(seq + 1, Ordering::Relaxed);
+        h.generation.store(generation + 2, Ordering::Release);
+        self.index.insert(key, i);
+        Ok(())
+    }
+}
+
+/// The receiving side of the table, mapped read-only.
+pub(super) struct Compacted<T> {
+    table: BroadcastView,
+    keys: usize,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Copy + zerocopy::FromBytes> Compacted<T> {
+    pub(super) fn open(file: File) -> Result<Self, Error> {
+        let table = crate::mem::read_broadcast(&crate::mem::memfd_from_file(file)?)?;
+        if table.len() < HEADER_SIZE {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        let header = unsafe { &*(table.as_ptr() as *const TableHeader) };
+        let keys = header.keys.load(Ordering::Acquire) as usize;
+        let size = table_size::<T>(keys).ok_or(crate::ringbuf::Error::BufCorrupt)?;
+        if table.len() < size {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        Ok(Compacted {
+            table,
+            keys,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Copies out the items and the horizon, or returns `None` if the sender was updating
+    /// the table meanwhile.
+    pub(super) fn snapshot(&self) -> Option<(Vec<T>, u64)> {
+        let h = unsafe { &*(self.table.as_ptr() as *const TableHeader) };
+        let generation = h.generation.load(Ordering::Acquire);
+        if generation % 2 == 1 {
+            return None;
+        }
+        // Untrusted, like everything in the table.
+        let len = (h.len.load(Ordering::Relaxed) as usize).min(self.keys);
+        let p = unsafe { self.table.as_ptr().add(HEADER_SIZE) as *const T };
+        let items = (0..len)
+            .map(|i| unsafe { std::ptr::read_volatile(p.add(i)) })
+            .collect();
+        let horizon = h.horizon.load(Ordering::Relaxed);
+        fence(Ordering::Acquire);
+        if h.generation.load(Ordering::Relaxed) != generation {
+            return None;
+        }
+        Some((items, horizon))
+    }
+}
@@ -0,0 +1,392 @@
+//! Consumer groups: receivers that share a cursor, each item going to one of them.
+
+use super::{cursor, open_cursor, open_data, signal, wait, Header, ReceiverFds, HEADER_SIZE};
+use crate::mem::{mmap, BroadcastView};
+use crate::wire::{AtomicLe32, AtomicLe64};
+use crate::Error;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::marker::PhantomData;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::Ordering;
+
+// The control memfd of a group holds the cursor, i e the oldest item not acknowledged
+// yet, which is what the sender goes by; the next item to claim; the pids of the members;
+// and a claim word per slot, saying who has the item in flight, or that it has been
+// acknowledged.
+const CLAIMED_OFFSET: usize = 8;
+const MEMBERS_OFFSET: usize = 16;
+/// Number of members whose pids are tracked; more can join, but are only checked on by
+/// pid, see `GroupMember::rebalance`.
+const MEMBER_SLOTS: usize = 16;
+const CLAIMS_OFFSET: usize = MEMBERS_OFFSET + 4 * MEMBER_SLOTS;
+
+/// The pid in a claim word, zero once the item is acknowledged. pid_max is at most 2^22.
+const PID_BITS: u32 = 23;
+
+pub(super) fn control_size(capacity: usize) -> Option<usize> {
+    capacity.checked_mul(8)?.checked_add(CLAIMS_OFFSET)
+}
+
+pub(super) fn claimed(map: &mmap::MmapRaw) -> &AtomicLe64 {
+    unsafe { &*(map.as_ptr().add(CLAIMED_OFFSET) as *const AtomicLe64) }
+}
+
+/// Only the low bits of the sequence number fit, which is plenty to tell apart the items
+/// that can share a slot.
+fn in_flight(seq: u64, pid: u32) -> u64 {
+    (seq.wrapping_add(1) << PID_BITS) | pid as u64
+}
+
+fn acked(seq: u64) -> u64 {
+    in_flight(seq, 0)
+}
+
+fn is_for(word: u64, seq: u64) -> bool {
+    word >> PID_BITS == in_flight(seq, 0) >> PID_BITS
+}
+
+fn owner(word: u64) -> u32 {
+    (word & ((1 << PID_BITS) - 1)) as u32
+}
+
+fn has_exited(pidfd: &File) -> bool {
+    let mut pfd = libc::pollfd {
+        fd: pidfd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut pfd, 1, 0) > 0 }
+}
+
+/// A member of a consumer group, see `FanoutSender::add_group`.
+///
+/// Members claim items from the shared cursor, so that each item is delivered to one of
+/// them, and acknowledge them when done. An item stays in the ringbuffer until it is
+/// acknowledged; if its member dies before that, `rebalance` hands it to another one. So
+/// items are delivered at least once, and exactly once unless a member dies while doing
+/// its work. Members are told apart by process id, so all of them have to be in different
+/// processes, and in the same pid namespace.
+///
+/// The members register their pids in the control memfd, and each member holds a pidfd of
+/// every other one, which tells reliably when it exits, even if its pid is reused later.
+///
+/// Members share a wakeup signal, and it only wakes one of them when items arrive, so a
+/// member should receive until there is nothing left before it waits again.
+///
+/// # Example
+/// ```rust
+/// use shmem_ipc::fanout::{FanoutSender, GroupMember};
+/// let mut s: FanoutSender<u64> = FanoutSender::new(64).unwrap();
+/// let (_, fds) = s.add_group().unwrap();
+/// let mut worker: GroupMember<u64> = GroupMember::open(64, fds).unwrap();
+/// s.send(&7).unwrap();
+/// let (seq, item) = worker.recv().unwrap().unwrap();
+/// assert_eq!(item, 7);
+/// assert!(worker.ack(seq).unwrap());
+/// ```
+pub struct GroupMember<T> {
+    data: BroadcastView,
+    control: mmap::MmapRaw,
+    capacity: usize,
+    pid: u32,
+    readable: File,
+    writable: File,
+    /// Items taken over from dead members, to be delivered first.
+    taken_over: VecDeque<u64>,
+    /// Of the other members, taken while they were alive.
+    pidfds: HashMap<u32, File>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> GroupMember<T> {
+    fn members(&self) -> impl Iterator<Item = &AtomicLe32> {
+        let p = unsafe { self.control.as_ptr().add(MEMBERS_OFFSET) as *const AtomicLe32 };
+        (0..MEMBER_SLOTS).map(move |i| unsafe { &*p.add(i) })
+    }
+}
+
+impl<T: Copy + zerocopy::FromBytes> GroupMember<T> {
+    /// Joins a consumer group, given the file descriptors from `FanoutSender::add_group`.
+    pub fn open(capacity: usize, fds: ReceiverFds) -> Result<Self, Error> {
+        let size = control_size(capacity).ok_or(Error::OutOfBounds)?;
+        let mut m = GroupMember {
+            data: open_data::<T>(capacity, fds.data)?,
+            control: open_cursor(fds.cursor, size)?,
+            capacity,
+            pid: std::process::id(),
+            readable: fds.readable,
+            writable: fds.writable,
+            taken_over: VecDeque::new(),
+            pidfds: HashMap::new(),
+            _phantom: PhantomData,
+        };
+        let pid = m.pid;
+        if !m.members().any(|p| p.load(Ordering::Relaxed) == pid) {
+            // If the table is full, the others will check on us by pid only.
+            let _ = m.members().find(|p| {
+                p.compare_exchange(0, pid, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+            });
+        }
+        m.watch_members();
+        Ok(m)
+    }
+
+    /// Takes pidfds of members we do not have one of yet.
+    fn watch_members(&mut self) {
+        let pids: Vec<u32> = self
+            .members()
+            .map(|p| p.load(Ordering::Acquire))
+            .filter(|p| *p != 0 && *p != self.pid && !self.pidfds.contains_key(p))
+            .collect();
+        for pid in pids {
+            // If it is gone already, or there are no pidfds, checking by pid has to do.
+            if let Ok(fd) = crate::unix::pidfd_open(pid) {
+                self.pidfds.insert(pid, fd);
+            }
+        }
+    }
+
+    fn is_dead(&self, pid: u32) -> bool {
+        match self.pidfds.get(&pid) {
+            Some(fd) => has_exited(fd),
+            None => !crate::unix::process_alive(pid),
+        }
+    }
+
+    /// Number of members in the table of the group, including dead ones not yet noticed by
+    /// `rebalance`.
+    pub fn member_count(&self) -> usize {
+        self.members()
+            .filter(|p| p.load(Ordering::Relaxed) != 0)
+            .count()
+    }
+
+    fn head(&self) -> u64 {
+        let header = unsafe { &*(self.data.as_ptr() as *const Header) };
+        header.head.load(Ordering::SeqCst)
+    }
+
+    fn claim_word(&self, seq: u64) -> &AtomicLe64 {
+        let i = (seq % self.capacity as u64) as usize;
+        unsafe { &*(self.control.as_ptr().add(CLAIMS_OFFSET + 8 * i) as *const AtomicLe64) }
+    }
+
+    fn read(&self, seq: u64) -> T {
+        let i = (seq % self.capacity as u64) as usize;
+        unsafe {
+            let p = self.data.as_ptr().add(HEADER_SIZE) as *const T;
+            std::ptr::read_volatile(p.add(i))
+        }
+    }
+
+    /// Claims the next item, if there is one, and returns it with its sequence number,
+    /// for `ack`.
+    pub fn recv(&mut self) -> Result<Option<(u64, T)>, Error> {
+        if let Some(seq) = self.taken_over.pop_front() {
+            return Ok(Some((seq, self.read(seq))));
+        }
+        let claimed = claimed(&self.control);
+        loop {
+            let c = claimed.load(Ordering::SeqCst);
+            let head = self.head();
+            if c == head {
+                return Ok(None);
+            }
+            if head.wrapping_sub(c) > self.capacity as u64 {
+                Err(crate::ringbuf::Error::BufCorrupt)?
+            }
+            // The sender only sent this item once the previous one in its slot was acked.
+            let free = match c.checked_sub(self.capacity as u64) {
+                Some(prev) => acked(prev),
+                None => 0,
+            };
+            match self.claim_word(c).compare_exchange(
+                free,
+                in_flight(c, self.pid),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    let _ = claimed.compare_exchange(c, c + 1, Ordering::SeqCst, Ordering::Relaxed);
+                    return Ok(Some((c, self.read(c))));
+                }
+                // Claimed by another member, which has yet to move on.
+                Err(w) if is_for(w, c) => {
+                    let _ = claimed.compare_exchange(c, c + 1, Ordering::SeqCst, Ordering::Relaxed);
+                }
+                Err(_) if claimed.load(Ordering::SeqCst) == c => {
+                    Err(crate::ringbuf::Error::BufCorrupt)?
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Acknowledges an item, so that its slot can be reused. Returns false if this member
+    /// does not have the item in flight, e g because it was taken over by `rebalance`.
+    pub fn ack(&mut self, seq: u64) -> Result<bool, Error> {
+        let w = self.claim_word(seq);
+        if w.compare_exchange(
+            in_flight(seq, self.pid),
+            acked(seq),
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        )
+        .is_err()
+        {
+            return Ok(false);
+        }
+        // Move the cursor past all acknowledged items, on behalf of whoever acked them.
+        let cursor = cursor(&self.control);
+        let claimed = claimed(&self.control);
+        loop {
+            let k = cursor.load(Ordering::SeqCst);
+            if k >= claimed.load(Ordering::SeqCst)
+                || self.claim_word(k).load(Ordering::Acquire) != acked(k)
+            {
+                return Ok(true);
+            }
+            if cursor
+                .compare_exchange(k, k + 1, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+                && self.head().wrapping_sub(k) == self.capacity as u64
+            {
+                // The sender might be waiting for this slot.
+                signal(&self.writable)?;
+            }
+        }
+    }
+
+    /// Takes over the items in flight with members that have died, and returns how many.
+    /// They are delivered by the next calls to `recv`, before any new items. Dead members
+    /// are removed from the table of the group.
+    ///
+    /// Checking on the other members costs a system call per item in flight, so call this
+    /// now and then, e g when `block_until_readable` has timed out in an event loop; not on
+    /// every receive.
+    pub fn rebalance(&mut self) -> Result<usize, Error> {
+        self.watch_members();
+        let start = cursor(&self.control).load(Ordering::SeqCst);
+        let end = claimed(&self.control).load(Ordering::SeqCst);
+        if end.wrapping_sub(start) > self.capacity as u64 {
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        let mut n = 0;
+        for seq in start..end {
+            let w = self.claim_word(seq).load(Ordering::Acquire);
+            let pid = owner(w);
+            if !is_for(w, seq) || pid == 0 || pid == self.pid || !self.is_dead(pid) {
+                continue;
+            }
+            let mine = in_flight(seq, self.pid);
+            let w = self.claim_word(seq);
+            if w.compare_exchange(
+                in_flight(seq, pid),
+                mine,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+            {
+                self.taken_over.push_back(seq);
+                n += 1;
+            }
+        }
+        for p in self.members() {
+            let pid = p.load(Ordering::Acquire);
+            if pid != 0 && pid != self.pid && self.is_dead(pid) {
+                let _ = p.compare_exchange(pid, 0, Ordering::AcqRel, Ordering::Relaxed);
+            }
+        }
+        let dead: Vec<u32> = self
+            .pidfds
+            .iter()
+            .filter(|(_, fd)| has_exited(fd))
+            .map(|(pid, _)| *pid)
+            .collect();
+        for pid in dead {
+            self.pidfds.remove(&pid);
+        }
+        Ok(n)
+    }
+
+    /// For blocking scenarios, blocks until there is an item to claim.
+    pub fn block_until_readable(&mut self) -> Result<(), Error> {
+        let claimed = claimed(&self.control);
+        while self.taken_over.is_empty() && claimed.load(Ordering::SeqCst) == self.head() {
+            wait(&self.readable)?;
+        }
+        Ok(())
+    }
+
+    /// The file descriptor that becomes readable when there are items, e g for an event
+    /// loop. It is shared by all members.
+    pub fn readable_signal(&self) -> &File {
+        &self.readable
+    }
+}
+
+impl<T> Drop for GroupMember<T> {
+    fn drop(&mut self) {
+        for p in self.members() {
+            let _ = p.compare_exchange(self.pid, 0, Ordering::AcqRel, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fanout::FanoutSender;
+
+    #[test]
+    fn compete_and_rebalance() {
+        let mut s: FanoutSender<u32> = FanoutSender::new(4).unwrap();
+        let (_, fds) = s.add_group().unwrap();
+        let mut a = GroupMember::<u32>::open(4, fds.try_clone().unwrap()).unwrap();
+        let mut b = GroupMember::<u32>::open(4, fds).unwrap();
+        for i in 0..4 {
+            assert!(s.send(&(i * 10)).unwrap());
+        }
+        assert_eq!(a.recv().unwrap(), Some((0, 0)));
+        assert_eq!(b.recv().unwrap(), Some((1, 10)));
+        assert_eq!(a.recv().unwrap(), Some((2, 20)));
+        assert_eq!(b.recv().unwrap(), Some((3, 30)));
+        assert_eq!(a.recv().unwrap(), None);
+        // Nothing acknowledged, so no room.
+        assert!(!s.send(&40).unwrap());
+        assert!(a.ack(1).unwrap());
+        assert!(!s.send(&40).unwrap());
+        assert!(a.ack(0).unwrap());
+        assert!(!a.ack(0).unwrap());
+        assert!(s.send(&40).unwrap());
+
+        // Item 2 was with a member that died after joining.
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let dead = child.id();
+        a.members().nth(5).unwrap().store(dead, Ordering::Release);
+        a.claim_word(2).store(in_flight(2, dead), Ordering::Release);
+        assert_eq!(b.rebalance().unwrap(), 0);
+        assert_eq!(b.member_count(), 2);
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(b.rebalance().unwrap(), 1);
+        assert_eq!(b.member_count(), 1);
+        assert_eq!(a.rebalance().unwrap(), 0);
+        assert_eq!(b.recv().unwrap(), Some((2, 20)));
+        assert_eq!(b.recv().unwrap(), Some((4, 40)));
+        for seq in 2..5 {
+            assert!(b.ack(seq).unwrap());
+        }
+        for i in 5..9 {
+            assert!(s.send(&i).unwrap());
+        }
+        a.block_until_readable().unwrap();
+        assert_eq!(a.recv().unwrap(), Some((5, 5)));
+    }
+}
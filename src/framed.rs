@@ -0,0 +1,1596 @@
+//! Length-prefixed messages over a shared ringbuffer.
+//!
+//! Every message is written as a frame: one header word (length, kind and flags) followed by
+//! the payload, padded to whole words. Frames never wrap around the end of the ringbuffer;
+//! if a frame does not fit before the end, the rest is filled up with a padding frame.
+//!
+//! Messages too large for the ringbuffer can be sent with `Sender::send_large`, which puts the
+//! data in a sealed memfd of its own and passes it over a companion unix socket. The same socket
+//! carries file descriptors attached to individual messages with `Sender::send_with_fds`; both
+//! are correlated with the ringbuffer by message sequence number.
+//!
+//! A sender whose ringbuffer turns out to be too small can switch to a bigger one with
+//! `Sender::hand_off`. The new ringbuffer's file descriptors are passed over the companion
+//! socket, and the receiver switches over when it reaches the handoff frame, so no messages
+//! are lost or reordered.
+//!
+//! With `Sender::require_token`, the receiver has to prove that it was meant to attach to
+//! the channel, by presenting a random token over the companion socket. The token itself is
+//! handed out by other means (e g over D-Bus); only a marker that a token is required is
+//! stored in the header, since everything there is visible to whoever holds the memfd.
+//!
+//! Related messages can be sent as a group with `Sender::transaction`, so that the
+//! receiver never sees only part of it.
+//!
+//! Messages can have a time to live (`Sender::set_ttl`), after which the receiver skips
+//! them rather than handing out stale data.
+//!
+//! With the `lz4_flex` feature, large messages can be compressed on the way through the
+//! ringbuffer (`Sender::set_compression_threshold`).
+//!
+//! With the `chacha20poly1305` feature, `SealedSender` and `SealedReceiver` encrypt and
+//! authenticate messages, for when other processes might get hold of the memfd.
+//!
+//! Messages can carry a W3C trace context (`Sender::send_traced`), so that spans can be
+//! linked across the shared memory hop.
+//!
+//! The creator of the channel can declare a maximum message size, which is stored in the
+//! shared header and read by the other side when it attaches. Both sides enforce it: the
+//! sender rejects larger messages, and the receiver treats them as corruption.
+//!
+//! The information to be transferred between processes is the same as for `sharedring`,
+//! with the capacity given in bytes, plus the companion socket if `send_large` is used.
+
+use super::Error;
+use crate::mem::mmap;
+use crate::sharedring;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+#[cfg(feature = "lz4_flex")]
+mod compress;
+mod copy;
+mod header;
+mod priority;
+mod ratelimit;
+mod scheduler;
+#[cfg(feature = "chacha20poly1305")]
+mod sealed;
+mod tagged;
+mod trace;
+mod transaction;
+pub use self::header::{HeaderReceiver, HeaderSender};
+pub use self::priority::{PriorityReceiver, PrioritySender};
+pub use self::ratelimit::RateLimit;
+pub use self::scheduler::Scheduler;
+#[cfg(feature = "chacha20poly1305")]
+pub use self::sealed::{SealedReceiver, SealedSender, TAG_LEN};
+pub use self::tagged::{decode_pod, Tagged, TaggedReceiver, TaggedSender};
+pub use self::trace::TraceContext;
+use self::trace::TRACE_LEN;
+pub use self::transaction::Transaction;
+
+const WORD: usize = std::mem::size_of::<u64>();
+
+const KIND_DATA: u16 = 1;
+const KIND_PAD: u16 = 2;
+const KIND_LARGE: u16 = 3;
+const KIND_HANDOFF: u16 = 4;
+
+/// Frame flag: file descriptors are attached through the companion socket.
+const FLAG_FDS: u16 = 1;
+/// Frame flag: the payload starts with a trace context.
+const FLAG_TRACE: u16 = 2;
+/// Frame flag: the payload starts with a `HeaderSender` header.
+const FLAG_HEADER: u16 = 4;
+/// Frame flag: the payload starts with a deadline word, in `CLOCK_MONOTONIC` nanoseconds.
+/// It comes before any other prefix.
+const FLAG_DEADLINE: u16 = 8;
+/// Frame flag: the rest of the payload, including any `HeaderSender` header, is LZ4
+/// compressed.
+const FLAG_COMPRESSED: u16 = 16;
+
+/// Companion socket record: a spilled message memfd follows.
+const RECORD_SPILL: u64 = 1;
+/// Companion socket record: file descriptors attached to a message follow.
+const RECORD_FDS: u64 = 2;
+/// Companion socket record: the file descriptors of a new ringbuffer follow.
+const RECORD_HANDOFF: u64 = 3;
+/// Companion socket record from the receiver: the channel token, instead of seq and len.
+const RECORD_TOKEN: u64 = 4;
+
+/// A secret that the receiver must present before the sender starts writing.
+pub type Token = [u8; 16];
+const RECORD_LEN: usize = 3 * WORD;
+
+/// Number of frame lengths kept for `Sender::dump`.
+const RECENT_LENGTHS: usize = 16;
+
+/// Lengths of the last few frames, for bug reports.
+#[derive(Default)]
+struct Recent(std::collections::VecDeque<usize>);
+
+impl Recent {
+    fn push(&mut self, len: usize) {
+        if self.0.len() == RECENT_LENGTHS {
+            self.0.pop_front();
+        }
+        self.0.push_back(len);
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct FrameHeader {
+    len: u32,
+    kind: u16,
+    flags: u16,
+}
+
+impl FrameHeader {
+    fn to_word(self) -> u64 {
+        self.len as u64 | (self.kind as u64) << 32 | (self.flags as u64) << 48
+    }
+
+    fn from_word(w: u64) -> Self {
+        FrameHeader {
+            len: w as u32,
+            kind: (w >> 32) as u16,
+            flags: (w >> 48) as u16,
+        }
+    }
+
+    fn words(&self) -> usize {
+        1 + (self.len as usize).div_ceil(WORD)
+    }
+}
+
+fn words_for(len: usize) -> usize {
+    1 + len.div_ceil(WORD)
+}
+
+/// Current `CLOCK_MONOTONIC` time in nanoseconds, which is the same for all processes.
+fn monotonic_ns() -> u64 {
+    crate::sim::monotonic_ns()
+}
+
+fn ring_limit(ring: &mut sharedring::Sender<u64>, size_limit: usize) -> usize {
+    let words = ring.sender_mut().capacity() - 1;
+    std::cmp::min(words * WORD, u32::MAX as usize).min(size_limit)
+}
+
+fn declared_limit(size: u64) -> usize {
+    match size {
+        0 => usize::MAX,
+        x => x.try_into().unwrap_or(usize::MAX),
+    }
+}
+
+fn encode_record(tag: u64, seq: u64, len: u64) -> [u8; RECORD_LEN] {
+    let mut r = [0u8; RECORD_LEN];
+    r[0..8].copy_from_slice(&tag.to_le_bytes());
+    r[8..16].copy_from_slice(&seq.to_le_bytes());
+    r[16..24].copy_from_slice(&len.to_le_bytes());
+    r
+}
+
+fn decode_record(r: &[u8; RECORD_LEN]) -> (u64, u64, u64) {
+    let w = |i: usize| u64::from_le_bytes(r[i * 8..i * 8 + 8].try_into().unwrap());
+    (w(0), w(1), w(2))
+}
+
+/// The tag, sequence number and length of a companion socket record, and the file
+/// descriptors that came with it.
+type Record = (u64, u64, u64, Vec<File>);
+
+/// A companion socket record, gathered over as many reads as it takes to arrive, so that
+/// a peer that sends only part of one cannot make us block.
+#[derive(Default)]
+struct PartialRecord {
+    data: [u8; RECORD_LEN],
+    len: usize,
+    fds: Vec<File>,
+}
+
+impl PartialRecord {
+    /// Reads what has arrived, and returns the record once all of it has. Fails with
+    /// `UnexpectedEof` if the peer closed the socket before that.
+    fn poll(
+        &mut self,
+        socket: &UnixStream,
+    ) -> std::io::Result<Option<([u8; RECORD_LEN], Vec<File>)>> {
+        while self.len < RECORD_LEN {
+            let buf = &mut self.data[self.len..];
+            match crate::unix::try_recv_with_fds(socket, buf, &mut self.fds) {
+                Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.len += n,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let record = std::mem::take(self);
+        Ok(Some((record.data, record.fds)))
+    }
+}
+
+/// The sending half of a message channel.
+pub struct Sender {
+    ring: sharedring::Sender<u64>,
+    socket: Option<UnixStream>,
+    max_message_size: usize,
+    size_limit: usize,
+    spill_threshold: usize,
+    limiter: Option<ratelimit::Limiter>,
+    zeroize: bool,
+    token: Option<Token>,
+    token_record: PartialRecord,
+    nontemporal: Option<usize>,
+    lossy: bool,
+    /// Occupancy limit in words.
+    occupancy_limit: Option<usize>,
+    ttl: Option<std::time::Duration>,
+    #[cfg(feature = "lz4_flex")]
+    compression_threshold: Option<usize>,
+    seq: u64,
+    recent: Recent,
+}
+
+impl Sender {
+    fn from_ring(mut ring: sharedring::Sender<u64>) -> Self {
+        let size_limit = declared_limit(ring.declared_message_size());
+        let max_message_size = ring_limit(&mut ring, size_limit);
+        Sender {
+            ring,
+            socket: None,
+            max_message_size,
+            size_limit,
+            spill_threshold: max_message_size,
+            limiter: None,
+            zeroize: false,
+            token: None,
+            token_record: PartialRecord::default(),
+            nontemporal: None,
+            lossy: false,
+            occupancy_limit: None,
+            ttl: None,
+            #[cfg(feature = "lz4_flex")]
+            compression_threshold: None,
+            seq: 0,
+            recent: Recent::default(),
+        }
+    }
+
+    /// Sets up a new ringbuffer of at least `capacity` bytes and returns the sender half.
+    pub fn new(capacity: usize) -> Result<Self, Error> {
+        Ok(Self::from_ring(sharedring::Sender::new(
+            capacity.div_ceil(WORD),
+        )?))
+    }
+
+    /// Like `new`, but also declares a maximum message size for the channel.
+    ///
+    /// This also limits messages sent with `send_large`. Zero means no limit.
+    pub fn with_max_message_size(capacity: usize, max_message_size: usize) -> Result<Self, Error> {
+        let ring = sharedring::Sender::new(capacity.div_ceil(WORD))?;
+        ring.declare_message_size(max_message_size as u64);
+        Ok(Self::from_ring(ring))
+    }
+
+    /// Attaches to a ringbuffer set up by the receiving side.
+    ///
+    /// Picks up the maximum message size, if the receiving side declared one.
+    pub fn open(
+        capacity: usize,
+        memfd: File,
+        empty_signal: File,
+        full_signal: File,
+    ) -> Result<Self, Error> {
+        let ring =
+            sharedring::Sender::open(capacity.div_ceil(WORD), memfd, empty_signal, full_signal)?;
+        Ok(Self::from_ring(ring))
+    }
+
+    /// The underlying ringbuffer, e g to get its file descriptors.
+    pub fn ring(&self) -> &sharedring::Sender<u64> {
+        &self.ring
+    }
+
+    /// Wipe the ringbuffer when this side is dropped, see `sharedring::Sender::set_zeroize`.
+    ///
+    /// Does not apply to messages spilled by `send_large`, nor to a ringbuffer given up by
+    /// `hand_off`, since the receiver might still be reading from it.
+    pub fn set_zeroize(&mut self, zeroize: bool) {
+        self.zeroize = zeroize;
+        self.ring.set_zeroize(zeroize);
+    }
+
+    /// Sets the companion unix socket, used by `send_large` and `send_with_fds`.
+    pub fn set_socket(&mut self, socket: UnixStream) {
+        self.socket = Some(socket);
+    }
+
+    /// Largest message that fits into the ringbuffer, and is not above the declared maximum
+    /// message size.
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    /// Messages larger than this are spilled into a memfd of their own by `send_large`.
+    ///
+    /// The threshold defaults to (and is capped at) `max_message_size`.
+    pub fn set_spill_threshold(&mut self, bytes: usize) {
+        self.spill_threshold = std::cmp::min(bytes, self.max_message_size());
+    }
+
+    /// Copies messages of at least this many bytes into the ringbuffer with non-temporal
+    /// (streaming) stores, so that large transfers do not evict the rest of the cache.
+    ///
+    /// Needs the `nontemporal` feature, and only has an effect on x86_64. Defaults to `None`.
+    pub fn set_nontemporal_threshold(&mut self, bytes: Option<usize>) {
+        self.nontemporal = bytes;
+    }
+
+    /// Drops messages that do not fit instead of leaving it to the caller to retry.
+    ///
+    /// `send` still returns false for a dropped message, but the drop is also counted in
+    /// the shared header, so the receiver can report the loss, see `Receiver::dropped`.
+    pub fn set_lossy(&mut self, lossy: bool) {
+        self.lossy = lossy;
+    }
+
+    /// Whether messages that do not fit are dropped, see `set_lossy`.
+    pub fn is_lossy(&self) -> bool {
+        self.lossy
+    }
+
+    /// Limits how many bytes of the ringbuffer messages may take up at a time; a message
+    /// that would go over the limit does not fit. `None`, the default, allows the whole
+    /// ringbuffer.
+    ///
+    /// If the receiver reclaims consumed pages (`Receiver::set_reclaim_consumed`), this
+    /// bounds the memory the channel keeps resident, e g while under memory pressure, see
+    /// the `pressure` module.
+    pub fn set_occupancy_limit(&mut self, bytes: Option<usize>) {
+        self.occupancy_limit = bytes.map(|b| b.div_ceil(WORD));
+    }
+
+    /// Compresses messages of at least this many bytes with LZ4, or stops compressing if
+    /// `None`, which is the default.
+    ///
+    /// Messages are only sent compressed if that makes them smaller, and the receiver
+    /// decompresses them transparently; it needs the `lz4_flex` feature too. Traced messages
+    /// are not compressed. The maximum message size still applies to the uncompressed size.
+    ///
+    /// Enabling compression requires `sharedring::FEATURE_COMPRESSION`, so that a receiver
+    /// without it fails to attach instead of failing on the first compressed message.
+    #[cfg(feature = "lz4_flex")]
+    pub fn set_compression_threshold(&mut self, bytes: Option<usize>) {
+        self.compression_threshold = bytes;
+        self.setup_ring(&self.ring);
+    }
+
+    /// Gives messages a deadline this long after they are sent, or removes it if `None`.
+    ///
+    /// The receiver skips messages whose deadline has passed, and counts them in
+    /// `Receiver::expired`. The deadline takes up eight bytes of the ringbuffer. Applies to
+    /// `send`, `send_traced` and `HeaderSender`, but not to messages with file descriptors,
+    /// spilled messages or transactions.
+    pub fn set_ttl(&mut self, ttl: Option<std::time::Duration>) {
+        self.ttl = ttl;
+    }
+
+    /// Requires the receiver to present a freshly generated token before anything can be sent.
+    ///
+    /// Returns the token, to be handed to the intended receiver by other means. Sending
+    /// fails with `Unauthenticated` until `authenticate` has accepted the token.
+    pub fn require_token(&mut self) -> Result<Token, Error> {
+        let mut token = [0u8; 16];
+        let r = unsafe { libc::getrandom(token.as_mut_ptr() as *mut _, token.len(), 0) };
+        if r != token.len() as isize {
+            Err(std::io::Error::last_os_error())?
+        }
+        self.token = Some(token);
+        self.setup_ring(&self.ring);
+        Ok(token)
+    }
+
+    /// Declares in the header of `ring` what the receiver needs to know about this side: the
+    /// maximum message size, the features that compression requires, and whether a token
+    /// is required. Applied to the current ringbuffer, and to new ones on `hand_off`.
+    fn setup_ring(&self, ring: &sharedring::Sender<u64>) {
+        if self.size_limit != usize::MAX {
+            ring.declare_message_size(self.size_limit as u64);
+        }
+        #[cfg(feature = "lz4_flex")]
+        if self.compression_threshold.is_some() {
+            ring.require_features(sharedring::FEATURE_COMPRESSION);
+        }
+        if self.token.is_some() {
+            ring.set_header_flags(sharedring::HEADER_FLAG_TOKEN);
+        }
+    }
+
+    /// Checks the token presented by the receiver over the companion socket.
+    ///
+    /// Returns false if the token has not fully arrived yet; the socket becomes readable
+    /// when more of it does. Fails with `BadToken` if the receiver presented a wrong one.
+    pub fn authenticate(&mut self) -> Result<bool, Error> {
+        let token = match self.token {
+            None => return Ok(true),
+            Some(t) => t,
+        };
+        let socket = self.socket.as_ref().ok_or(Error::NoSocket)?;
+        let record = match self.token_record.poll(socket) {
+            Ok(Some((record, _))) => record,
+            Ok(None) => return Ok(false),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                crate::audit::report(|| crate::audit::Event::AttachRejected {
+                    reason: "bad token",
+                });
+                Err(Error::BadToken)?
+            }
+            Err(e) => Err(e)?,
+        };
+        let diff = record[WORD..]
+            .iter()
+            .zip(token.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+        if decode_record(&record).0 != RECORD_TOKEN || diff != 0 {
+            crate::audit::report(|| crate::audit::Event::AttachRejected {
+                reason: "bad token",
+            });
+            Err(Error::BadToken)?
+        }
+        self.token = None;
+        Ok(true)
+    }
+
+    /// Limits the rate of sending, or removes the limit if `None`.
+    ///
+    /// The limit applies to all kinds of sending, and a message (or a spilled message) that
+    /// exceeds the limit either fails with `RateLimited` or blocks, depending on the limit.
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.limiter = limit.map(ratelimit::Limiter::new);
+    }
+
+    fn admit(&mut self, len: usize) -> Result<(), Error> {
+        match &mut self.limiter {
+            Some(l) => l.admit(len),
+            None => Ok(()),
+        }
+    }
+
+    fn charge(&mut self, len: usize) {
+        if let Some(l) = &mut self.limiter {
+            l.charge(len)
+        }
+    }
+
+    /// Number of messages sent so far, which is also the sequence number of the next message.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// The state of the channel for bug reports, see `sharedring::Sender::dump`, with the
+    /// lengths of the last frames sent. The `Debug` output is this report too.
+    pub fn dump(&self) -> sharedring::Report {
+        let mut r = self.ring.dump();
+        r.recent_lengths = self.recent.0.iter().copied().collect();
+        r
+    }
+
+    /// Makes sure the next `words` words are contiguous and free, padding up to the end of
+    /// the ringbuffer if necessary.
+    ///
+    /// Returns false if there is currently not enough room.
+    fn reserve(&mut self, words: usize) -> Result<bool, Error> {
+        if self.token.is_some() {
+            Err(Error::Unauthenticated)?
+        }
+        loop {
+            let ring = self.ring.sender_mut();
+            let free = ring.write_count()?;
+            let used = ring.capacity() - free;
+            if free < words || self.occupancy_limit.is_some_and(|l| used + words > l) {
+                return Ok(false);
+            }
+            let mut fits = false;
+            self.ring.send_raw(|p, n| {
+                if n >= words {
+                    fits = true;
+                    return 0;
+                }
+                // Not room enough before the end of the ringbuffer.
+                let pad = FrameHeader {
+                    len: ((n - 1) * WORD) as u32,
+                    kind: KIND_PAD,
+                    flags: 0,
+                };
+                unsafe { std::ptr::write(p, pad.to_word()) };
+                n
+            })?;
+            if fits {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Writes `words` words into the ringbuffer with `f`; there must be room reserved for
+    /// them. The receiver can change the shared indices after `reserve`, so this fails with
+    /// `BufCorrupt`, without writing anything, if the room is gone.
+    pub(super) fn write_words<F: FnOnce(*mut u64)>(
+        &mut self,
+        words: usize,
+        f: F,
+    ) -> Result<(), Error> {
+        let mut written = false;
+        self.ring.send_raw(|p, n| {
+            if n < words {
+                return 0;
+            }
+            f(p);
+            written = true;
+            words
+        })?;
+        if !written {
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        Ok(())
+    }
+
+    /// Writes a frame, with `head` (whole words) in front of the data; there must be room
+    /// reserved for it.
+    fn write_frame(
+        &mut self,
+        kind: u16,
+        flags: u16,
+        head: &[u8],
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let hdr = FrameHeader {
+            len: (head.len() + data.len()) as u32,
+            kind,
+            flags,
+        };
+        let words = hdr.words();
+        let nontemporal = self.nontemporal.is_some_and(|t| data.len() >= t);
+        self.write_words(words, |p| unsafe {
+            std::ptr::write(p, hdr.to_word());
+            // Don't leave stale data in the padding of the last word.
+            std::ptr::write(p.add(words - 1), 0);
+            let p = p.add(1) as *mut u8;
+            std::ptr::copy_nonoverlapping(head.as_ptr(), p, head.len());
+            copy::copy(data.as_ptr(), p.add(head.len()), data.len(), nontemporal);
+        })?;
+        self.seq += 1;
+        self.recent.push(hdr.len as usize);
+        Ok(())
+    }
+
+    /// Sends a message through the ringbuffer.
+    ///
+    /// Returns false if there is currently not enough room; try again when the receiver has
+    /// made room (see `block_until_writable`). Fails with `MessageTooBig` for messages that
+    /// can never fit, and with `RateLimited` if a non-blocking rate limit is exceeded.
+    pub fn send(&mut self, data: &[u8]) -> Result<bool, Error> {
+        self.send_data(&[], 0, data)
+    }
+
+    /// Sends a message with a trace context, which the receiver gets in
+    /// `Message::trace_context`, so that spans can be linked across processes.
+    ///
+    /// The context takes up 32 bytes of the ringbuffer, so the message can be at most
+    /// `max_message_size() - 32` bytes. Otherwise this works like `send`.
+    pub fn send_traced(&mut self, data: &[u8], trace: &TraceContext) -> Result<bool, Error> {
+        self.send_data(&trace.to_bytes(), FLAG_TRACE, data)
+    }
+
+    fn send_data(&mut self, head: &[u8], mut flags: u16, data: &[u8]) -> Result<bool, Error> {
+        let prefix = if self.ttl.is_some() { WORD } else { 0 };
+        if prefix + head.len() + data.len() > self.max_message_size() {
+            Err(Error::MessageTooBig)?
+        }
+        let len = data.len();
+        self.admit(len)?;
+        #[cfg(feature = "lz4_flex")]
+        let compressed;
+        #[cfg(feature = "lz4_flex")]
+        let (head, data) = match self.compression_threshold {
+            // The trace context has to stay readable without decompressing.
+            Some(t) if len >= t && flags & FLAG_TRACE == 0 => {
+                match compress::compress(head, data) {
+                    None => (head, data),
+                    Some(c) => {
+                        compressed = c;
+                        flags |= FLAG_COMPRESSED;
+                        (&[][..], &compressed[..])
+                    }
+                }
+            }
+            _ => (head, data),
+        };
+        let prefixed;
+        let head = match self.ttl {
+            None => head,
+            Some(ttl) => {
+                let deadline = monotonic_ns().saturating_add(ttl.as_nanos() as u64);
+                prefixed = [&deadline.to_le_bytes()[..], head].concat();
+                flags |= FLAG_DEADLINE;
+                &prefixed[..]
+            }
+        };
+        if !self.reserve(words_for(head.len() + data.len()))? {
+            if self.lossy {
+                self.ring.record_dropped(1);
+            }
+            return Ok(false);
+        }
+        self.write_frame(KIND_DATA, flags, head, data)?;
+        self.charge(len);
+        Ok(true)
+    }
+
+    /// Sends a message through the ringbuffer, with file descriptors attached.
+    ///
+    /// The file descriptors are passed over the companion socket, and the receiver gets them
+    /// together with the message. Otherwise this works like `send`.
+    pub fn send_with_fds(&mut self, data: &[u8], fds: &[RawFd]) -> Result<bool, Error> {
+        if data.len() > self.max_message_size() {
+            Err(Error::MessageTooBig)?
+        }
+        if fds.is_empty() {
+            return self.send(data);
+        }
+        if self.socket.is_none() {
+            Err(Error::NoSocket)?
+        }
+        if fds.len() > crate::unix::MAX_FDS {
+            Err(std::io::Error::from_raw_os_error(libc::EINVAL))?
+        }
+        self.admit(data.len())?;
+        // Reserve first, so that we never pass fds for a message we then cannot send.
+        if !self.reserve(words_for(data.len()))? {
+            return Ok(false);
+        }
+        // The frame goes first, so that nothing is passed if writing it fails; the receiver
+        // waits for the record.
+        let record = encode_record(RECORD_FDS, self.seq, fds.len() as u64);
+        self.write_frame(KIND_DATA, FLAG_FDS, &[], data)?;
+        crate::unix::send_with_fds(self.socket.as_ref().unwrap(), &record, fds)?;
+        self.charge(data.len());
+        Ok(true)
+    }
+
+    /// Sends a message of any size.
+    ///
+    /// Messages above the spill threshold are copied into a freshly created, sealed memfd,
+    /// which is passed over the companion socket; only a reference to it goes through the
+    /// ringbuffer. The receiver maps it transparently.
+    pub fn send_large(&mut self, data: &[u8]) -> Result<bool, Error> {
+        if data.len() <= self.spill_threshold || data.is_empty() {
+            return self.send(data);
+        }
+        if data.len() > self.size_limit {
+            Err(Error::MessageTooBig)?
+        }
+        if self.socket.is_none() {
+            Err(Error::NoSocket)?
+        }
+        let len = data.len() as u64;
+        self.admit(data.len())?;
+        // Reserve first, so that we never pass a memfd we then cannot reference.
+        if !self.reserve(words_for(WORD))? {
+            return Ok(false);
+        }
+        let memfd =
+            crate::mem::write_once(len, "shmem-ipc large message", |x| x.copy_from_slice(data))?;
+        let record = encode_record(RECORD_SPILL, self.seq, len);
+        self.write_frame(KIND_LARGE, 0, &[], &len.to_le_bytes())?;
+        crate::unix::send_with_fds(self.socket.as_ref().unwrap(), &record, &[memfd.as_raw_fd()])?;
+        self.charge(data.len());
+        Ok(true)
+    }
+
+    /// Switches over to a new ringbuffer of at least `capacity` bytes.
+    ///
+    /// The file descriptors of the new ringbuffer are passed over the companion socket, and
+    /// a handoff frame in the current ringbuffer tells the receiver to switch to it after
+    /// reading everything sent before. The declared maximum message size, the features
+    /// required for compression and the token requirement carry over.
+    ///
+    /// Returns false if there is currently no room for the handoff frame.
+    pub fn hand_off(&mut self, capacity: usize) -> Result<bool, Error> {
+        if self.socket.is_none() {
+            Err(Error::NoSocket)?
+        }
+        let mut ring = sharedring::Sender::new(capacity.div_ceil(WORD))?;
+        self.setup_ring(&ring);
+        if !self.reserve(1)? {
+            return Ok(false);
+        }
+        let record = encode_record(RECORD_HANDOFF, self.seq, capacity as u64);
+        let fds = [
+            ring.memfd().as_raw_fd(),
+            ring.empty_signal().as_raw_fd(),
+            ring.full_signal().as_raw_fd(),
+        ];
+        let hdr = FrameHeader {
+            len: 0,
+            kind: KIND_HANDOFF,
+            flags: 0,
+        };
+        self.write_words(1, |p| unsafe { std::ptr::write(p, hdr.to_word()) })?;
+        crate::unix::send_with_fds(self.socket.as_ref().unwrap(), &record, &fds)?;
+        self.max_message_size = ring_limit(&mut ring, self.size_limit);
+        self.spill_threshold = std::cmp::min(self.spill_threshold, self.max_message_size);
+        ring.set_zeroize(self.zeroize);
+        self.ring.set_zeroize(false);
+        self.ring = ring;
+        Ok(true)
+    }
+
+    /// For blocking scenarios, blocks until the ringbuffer has at least some room.
+    pub fn block_until_writable(&mut self) -> Result<(), Error> {
+        self.ring.block_until_writable()?;
+        Ok(())
+    }
+}
+
+enum Payload {
+    Inline(Vec<u8>),
+    Mapped(mmap::Mmap, usize),
+}
+
+/// A received message.
+pub struct Message {
+    seq: u64,
+    payload: Payload,
+    fds: Vec<File>,
+    trace: Option<TraceContext>,
+    /// The frame has a `HeaderSender` header.
+    header: bool,
+    /// Bytes in front of the data, i e the header once it has been taken.
+    skip: usize,
+}
+
+impl Message {
+    /// A message that did not come through a ringbuffer, see `channel`.
+    pub(crate) fn from_vec(seq: u64, data: Vec<u8>) -> Self {
+        Message {
+            seq,
+            payload: Payload::Inline(data),
+            fds: vec![],
+            trace: None,
+            header: false,
+            skip: 0,
+        }
+    }
+
+    /// Sequence number of the message, counting from zero.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// The message contents.
+    pub fn data(&self) -> &[u8] {
+        match &self.payload {
+            Payload::Inline(v) => &v[self.skip..],
+            Payload::Mapped(m, len) => &m[..*len],
+        }
+    }
+
+    /// True if the message was spilled into a memfd of its own by `Sender::send_large`.
+    pub fn is_spilled(&self) -> bool {
+        matches!(self.payload, Payload::Mapped(..))
+    }
+
+    /// The trace context sent with `Sender::send_traced`, if any and if valid.
+    pub fn trace_context(&self) -> Option<TraceContext> {
+        self.trace
+    }
+
+    /// File descriptors attached to the message by `Sender::send_with_fds`.
+    pub fn fds(&self) -> &[File] {
+        &self.fds
+    }
+
+    /// Takes ownership of the attached file descriptors.
+    pub fn take_fds(&mut self) -> Vec<File> {
+        std::mem::take(&mut self.fds)
+    }
+}
+
+impl std::fmt::Debug for Sender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.dump().fmt(f)
+    }
+}
+
+impl std::fmt::Debug for Receiver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.dump().fmt(f)
+    }
+}
+
+/// The receiving half of a message channel.
+pub struct Receiver {
+    ring: sharedring::Receiver<u64>,
+    socket: Option<UnixStream>,
+    size_limit: usize,
+    max_handoff_capacity: usize,
+    nontemporal: Option<usize>,
+    expired: u64,
+    seq: u64,
+    recent: Recent,
+    record: PartialRecord,
+    /// A frame taken from the ringbuffer whose companion socket record has not fully
+    /// arrived yet.
+    pending: Option<PendingFrame>,
+}
+
+struct PendingFrame {
+    hdr: FrameHeader,
+    data: Vec<u8>,
+    trace: Option<TraceContext>,
+    deadline: Option<u64>,
+    /// Set once the payload is complete, if the frame also waits for file descriptors.
+    payload: Option<Payload>,
+}
+
+impl Receiver {
+    fn from_ring(ring: sharedring::Receiver<u64>) -> Self {
+        Receiver {
+            size_limit: declared_limit(ring.declared_message_size()),
+            ring,
+            socket: None,
+            max_handoff_capacity: 1 << 30,
+            nontemporal: None,
+            expired: 0,
+            seq: 0,
+            recent: Recent::default(),
+            record: PartialRecord::default(),
+            pending: None,
+        }
+    }
+
+    /// Sets up a new ringbuffer of at least `capacity` bytes and returns the receiver half.
+    pub fn new(capacity: usize) -> Result<Self, Error> {
+        Ok(Self::from_ring(sharedring::Receiver::new(
+            capacity.div_ceil(WORD),
+        )?))
+    }
+
+    /// Like `new`, but also declares a maximum message size for the channel.
+    ///
+    /// Zero means no limit.
+    pub fn with_max_message_size(capacity: usize, max_message_size: usize) -> Result<Self, Error> {
+        let ring = sharedring::Receiver::new(capacity.div_ceil(WORD))?;
+        ring.declare_message_size(max_message_size as u64);
+        Ok(Self::from_ring(ring))
+    }
+
+    /// Attaches to a ringbuffer set up by the sending side.
+    ///
+    /// Picks up the maximum message size, if the sending side declared one. Note that the
+    /// sending side can lie about it; declare it on the receiving side if that matters.
+    pub fn open(
+        capacity: usize,
+        memfd: File,
+        empty_signal: File,
+        full_signal: File,
+    ) -> Result<Self, Error> {
+        let ring =
+            sharedring::Receiver::open(capacity.div_ceil(WORD), memfd, empty_signal, full_signal)?;
+        Ok(Self::from_ring(ring))
+    }
+
+    /// Attaches to a memory area holding `data`, for fuzzing the frame parser, see
+    /// `sharedring::Receiver::fuzz_open`. The capacity is in bytes.
+    #[doc(hidden)]
+    pub fn fuzz_open(capacity: usize, data: &[u8]) -> Result<Self, Error> {
+        let ring = sharedring::Receiver::fuzz_open(capacity.div_ceil(WORD), data)?;
+        Ok(Self::from_ring(ring))
+    }
+
+    /// The underlying ringbuffer, e g to get its file descriptors.
+    pub fn ring(&self) -> &sharedring::Receiver<u64> {
+        &self.ring
+    }
+
+    /// Gives back the pages of received messages to the kernel, see
+    /// `sharedring::Receiver::set_reclaim_consumed`.
+    pub fn set_reclaim_consumed(&mut self, reclaim: bool) {
+        self.ring.set_reclaim_consumed(reclaim);
+    }
+
+    /// Sets the companion unix socket, needed to receive messages sent with `send_large`
+    /// or `send_with_fds`.
+    pub fn set_socket(&mut self, socket: UnixStream) {
+        self.socket = Some(socket);
+    }
+
+    /// Largest ringbuffer, in bytes, the sender may hand off to. Defaults to 1 GB.
+    pub fn set_max_handoff_capacity(&mut self, bytes: usize) {
+        self.max_handoff_capacity = bytes;
+    }
+
+    /// Copies messages of at least this many bytes out of the ringbuffer with non-temporal
+    /// (streaming) stores, see `Sender::set_nontemporal_threshold`.
+    ///
+    /// Only worth it if the received data is not going to be used right away.
+    pub fn set_nontemporal_threshold(&mut self, bytes: Option<usize>) {
+        self.nontemporal = bytes;
+    }
+
+    /// Number of messages the sender reports having dropped, see `Sender::set_lossy`.
+    pub fn dropped(&self) -> u64 {
+        self.ring.dropped()
+    }
+
+    /// Number of messages skipped because their deadline had passed, see `Sender::set_ttl`.
+    pub fn expired(&self) -> u64 {
+        self.expired
+    }
+
+    /// The state of the channel, with the lengths of the last frames received, see
+    /// `Sender::dump`.
+    pub fn dump(&self) -> sharedring::Report {
+        let mut r = self.ring.dump();
+        r.recent_lengths = self.recent.0.iter().copied().collect();
+        r
+    }
+
+    /// Number of times the sender reports having started dropping messages.
+    pub fn overflows(&self) -> u64 {
+        self.ring.overflows()
+    }
+
+    /// Returns true if the sender requires a token, see `Sender::require_token`.
+    pub fn token_required(&self) -> bool {
+        self.ring.header_flags() & sharedring::HEADER_FLAG_TOKEN != 0
+    }
+
+    /// Presents the channel token to the sender, over the companion socket.
+    pub fn present_token(&mut self, token: &Token) -> Result<(), Error> {
+        let socket = self.socket.as_ref().ok_or(Error::NoSocket)?;
+        let mut record = [0u8; RECORD_LEN];
+        record[..WORD].copy_from_slice(&RECORD_TOKEN.to_le_bytes());
+        record[WORD..].copy_from_slice(token);
+        let mut socket = socket;
+        socket.write_all(&record)?;
+        Ok(())
+    }
+
+    /// The companion socket record of the current frame, or `None` if it has not fully
+    /// arrived yet.
+    ///
+    /// A well-behaved sender sends the record before writing the frame, so only a
+    /// misbehaving one makes us wait for it.
+    fn recv_record(&mut self) -> Result<Option<Record>, Error> {
+        let socket = self.socket.as_ref().ok_or(Error::NoSocket)?;
+        match self.record.poll(socket) {
+            Ok(Some((record, fds))) => {
+                let (tag, seq, len) = decode_record(&record);
+                Ok(Some((tag, seq, len, fds)))
+            }
+            Ok(None) => Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                Err(crate::ringbuf::Error::BufCorrupt)?
+            }
+            Err(e) => Err(e)?,
+        }
+    }
+
+    /// Fails unless a companion socket record belongs to the current message.
+    fn check_record_seq(&self, seq: u64) -> Result<(), Error> {
+        if seq != self.seq {
+            crate::audit::report(|| crate::audit::Event::SequenceGap {
+                expected: self.seq,
+                got: seq,
+            });
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        Ok(())
+    }
+
+    fn recv_fds(&mut self) -> Result<Option<Vec<File>>, Error> {
+        let (tag, seq, count, fds) = match self.recv_record()? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        self.check_record_seq(seq)?;
+        if tag != RECORD_FDS || count != fds.len() as u64 {
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        Ok(Some(fds))
+    }
+
+    fn map_spilled(&mut self, len: u64) -> Result<Option<Payload>, Error> {
+        if len > self.size_limit as u64 {
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        let (tag, seq, rlen, mut fds) = match self.recv_record()? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        self.check_record_seq(seq)?;
+        if tag != RECORD_SPILL || rlen != len || fds.len() != 1 {
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        let memfd = memfd::Memfd::try_from_file(fds.pop().unwrap())
+            .map_err(|_| crate::ringbuf::Error::BufCorrupt)?;
+        let m = crate::mem::read_memfd(&memfd)?;
+        if (m.len() as u64) < len {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        Ok(Some(Payload::Mapped(m, len as usize)))
+    }
+
+    /// Switches over to the new ringbuffer. Returns false if its record has not fully
+    /// arrived yet.
+    fn take_handoff(&mut self) -> Result<bool, Error> {
+        let (tag, seq, capacity, mut fds) = match self.recv_record()? {
+            Some(r) => r,
+            None => return Ok(false),
+        };
+        self.check_record_seq(seq)?;
+        if tag != RECORD_HANDOFF || fds.len() != 3 {
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        if capacity > self.max_handoff_capacity as u64 {
+            Err(crate::ringbuf::Error::BufTooBig)?
+        }
+        let (full, empty, memfd) = (fds.pop(), fds.pop(), fds.pop());
+        let words = (capacity as usize).div_ceil(WORD);
+        self.ring =
+            sharedring::Receiver::open(words, memfd.unwrap(), empty.unwrap(), full.unwrap())?;
+        Ok(true)
+    }
+
+    #[cfg(feature = "lz4_flex")]
+    fn decompress(&mut self, v: &[u8]) -> Result<Vec<u8>, Error> {
+        let ring = self.ring.receiver_mut().capacity() * WORD;
+        let limit = std::cmp::min(self.size_limit, ring);
+        Ok(compress::decompress(v, limit).ok_or(crate::ringbuf::Error::BufCorrupt)?)
+    }
+
+    #[cfg(not(feature = "lz4_flex"))]
+    fn decompress(&mut self, _: &[u8]) -> Result<Vec<u8>, Error> {
+        Err(crate::ringbuf::Error::BufCorrupt)?
+    }
+
+    /// Receives the next message, if any.
+    ///
+    /// Frames are validated, so a misbehaving sender results in an error rather than
+    /// undefined behavior.
+    ///
+    /// If the sender has handed off to a new ringbuffer, this switches over to it, which
+    /// means the file descriptors returned by `ring()` change. Check them again after a
+    /// call to `recv`, if you registered them with an event loop.
+    ///
+    /// Also returns `None` while the companion socket record of a message has only partly
+    /// arrived, which a well-behaved sender never lets happen; the socket becomes readable
+    /// when more of it does.
+    pub fn recv(&mut self) -> Result<Option<Message>, Error> {
+        loop {
+            let mut pending = match self.pending.take() {
+                Some(p) => p,
+                None => match self.recv_frame()? {
+                    Some(p) => p,
+                    None => return Ok(None),
+                },
+            };
+            let hdr = pending.hdr;
+            if pending.payload.is_none() {
+                let payload = match hdr.kind {
+                    KIND_PAD => continue,
+                    KIND_HANDOFF if pending.data.is_empty() => {
+                        if !self.take_handoff()? {
+                            self.pending = Some(pending);
+                            return Ok(None);
+                        }
+                        continue;
+                    }
+                    KIND_DATA if hdr.flags & FLAG_COMPRESSED != 0 => {
+                        Payload::Inline(self.decompress(&pending.data)?)
+                    }
+                    KIND_DATA => Payload::Inline(std::mem::take(&mut pending.data)),
+                    KIND_LARGE if pending.data.len() == WORD => {
+                        let len = u64::from_le_bytes(pending.data[..].try_into().unwrap());
+                        match self.map_spilled(len)? {
+                            Some(p) => p,
+                            None => {
+                                self.pending = Some(pending);
+                                return Ok(None);
+                            }
+                        }
+                    }
+                    _ => Err(crate::ringbuf::Error::BufCorrupt)?,
+                };
+                pending.payload = Some(payload);
+            }
+            let fds = if hdr.flags & FLAG_FDS != 0 {
+                match self.recv_fds()? {
+                    Some(fds) => fds,
+                    None => {
+                        self.pending = Some(pending);
+                        return Ok(None);
+                    }
+                }
+            } else {
+                vec![]
+            };
+            let PendingFrame {
+                payload,
+                trace,
+                deadline,
+                ..
+            } = pending;
+            let seq = self.seq;
+            self.seq += 1;
+            self.recent.push(hdr.len as usize);
+            if deadline.is_some_and(|d| d < monotonic_ns()) {
+                self.expired += 1;
+                continue;
+            }
+            return Ok(Some(Message {
+                seq,
+                payload: payload.unwrap(),
+                fds,
+                trace,
+                header: hdr.flags & FLAG_HEADER != 0,
+                skip: 0,
+            }));
+        }
+    }
+
+    /// Takes the next frame out of the ringbuffer, if any.
+    fn recv_frame(&mut self) -> Result<Option<PendingFrame>, Error> {
+        let mut frame = None;
+        let mut corrupt = false;
+        let size_limit = self.size_limit;
+        let threshold = self.nontemporal;
+        self.ring.receive_raw(|p, n| {
+            let hdr = FrameHeader::from_word(unsafe { std::ptr::read(p) });
+            let words = hdr.words();
+            let data = hdr.kind == KIND_DATA;
+            let prefix = if data && hdr.flags & FLAG_DEADLINE != 0 {
+                WORD
+            } else {
+                0
+            };
+            let skip = if data && hdr.flags & FLAG_TRACE != 0 {
+                prefix + TRACE_LEN
+            } else {
+                prefix
+            };
+            let len = hdr.len as usize;
+            if words > n || len < skip || (hdr.kind == KIND_DATA && len - skip > size_limit) {
+                corrupt = true;
+                return 0;
+            }
+            let p = unsafe { p.add(1) as *const u8 };
+            let mut deadline = None;
+            if prefix > 0 {
+                let mut d = [0u8; WORD];
+                unsafe { std::ptr::copy_nonoverlapping(p, d.as_mut_ptr(), WORD) };
+                deadline = Some(u64::from_le_bytes(d));
+            }
+            let mut trace = None;
+            if skip > prefix {
+                let mut t = [0u8; TRACE_LEN];
+                let src = unsafe { p.add(prefix) };
+                unsafe { std::ptr::copy_nonoverlapping(src, t.as_mut_ptr(), TRACE_LEN) };
+                trace = TraceContext::from_bytes(&t);
+            }
+            let mut v = vec![0u8; len - skip];
+            let nontemporal = threshold.is_some_and(|t| v.len() >= t);
+            unsafe { copy::copy(p.add(skip), v.as_mut_ptr(), v.len(), nontemporal) };
+            frame = Some((hdr, v, trace, deadline));
+            words
+        })?;
+        if corrupt {
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        Ok(frame.map(|(hdr, data, trace, deadline)| PendingFrame {
+            hdr,
+            data,
+            trace,
+            deadline,
+            payload: None,
+        }))
+    }
+
+    /// For blocking scenarios, blocks until the channel is readable.
+    pub fn block_until_readable(&mut self) -> Result<(), Error> {
+        self.ring.block_until_readable()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(super) fn pair(capacity: usize) -> (Sender, Receiver) {
+        let s = Sender::new(capacity).unwrap();
+        let ring = s.ring();
+        let r = Receiver::open(
+            capacity,
+            ring.memfd().as_file().try_clone().unwrap(),
+            ring.empty_signal().try_clone().unwrap(),
+            ring.full_signal().try_clone().unwrap(),
+        )
+        .unwrap();
+        (s, r)
+    }
+
+    #[test]
+    fn wraparound() {
+        let (mut s, mut r) = pair(4096);
+        let max = s.max_message_size();
+        let msg = vec![7u8; max / 3];
+        for i in 0..20u64 {
+            assert!(s.send(&msg).unwrap());
+            assert!(s.send(&i.to_le_bytes()[..3]).unwrap());
+            let m = r.recv().unwrap().unwrap();
+            assert_eq!(m.data(), &msg[..]);
+            assert_eq!(m.seq(), i * 2);
+            assert_eq!(r.recv().unwrap().unwrap().data(), &i.to_le_bytes()[..3]);
+        }
+        assert!(r.recv().unwrap().is_none());
+        let big = vec![0u8; max];
+        if !s.send(&big).unwrap() {
+            // Padding was written; consume it and retry
+            assert!(r.recv().unwrap().is_none());
+            assert!(s.send(&big).unwrap());
+        }
+        assert!(!s.send(&[1]).unwrap());
+        assert_eq!(r.recv().unwrap().unwrap().data().len(), max);
+        assert!(matches!(
+            s.send(&vec![0u8; max + 1]),
+            Err(Error::MessageTooBig)
+        ));
+    }
+
+    #[test]
+    fn recent_lengths() {
+        let (mut s, mut r) = pair(4096);
+        for len in 1..=20 {
+            assert!(s.send(&vec![0xab; len]).unwrap());
+            r.recv().unwrap().unwrap();
+        }
+        let expected: Vec<usize> = (5..=20).collect();
+        assert_eq!(s.dump().recent_lengths, expected);
+        assert_eq!(r.dump().recent_lengths, expected);
+        assert!(!format!("{:?}", r).contains("171"));
+    }
+
+    #[test]
+    fn spill_large() {
+        let (mut s, mut r) = pair(4096);
+        let big: Vec<u8> = (0..100000).map(|x| x as u8).collect();
+        assert!(matches!(s.send_large(&big), Err(Error::NoSocket)));
+        let (a, b) = UnixStream::pair().unwrap();
+        s.set_socket(a);
+        r.set_socket(b);
+        s.set_spill_threshold(100);
+        assert!(s.send_large(&big[..50]).unwrap());
+        assert!(s.send_large(&big).unwrap());
+        let m = r.recv().unwrap().unwrap();
+        assert!(!m.is_spilled());
+        assert_eq!(m.data(), &big[..50]);
+        let m = r.recv().unwrap().unwrap();
+        assert!(m.is_spilled());
+        assert_eq!(m.seq(), 1);
+        assert_eq!(m.data(), &big[..]);
+    }
+
+    #[test]
+    fn attached_fds() {
+        let (mut s, mut r) = pair(4096);
+        let (a, b) = UnixStream::pair().unwrap();
+        s.set_socket(a);
+        r.set_socket(b);
+        let memfd = crate::mem::write_once(4096, "attached", |x| x[0] = 42).unwrap();
+        assert!(s.send(b"first").unwrap());
+        assert!(s
+            .send_with_fds(b"second", &[memfd.as_raw_fd(), memfd.as_raw_fd()])
+            .unwrap());
+        assert!(s.send(b"third").unwrap());
+        assert!(r.recv().unwrap().unwrap().fds().is_empty());
+        let mut m = r.recv().unwrap().unwrap();
+        assert_eq!(m.data(), b"second");
+        let fds = m.take_fds();
+        assert_eq!(fds.len(), 2);
+        let mfd = memfd::Memfd::try_from_file(fds.into_iter().next().unwrap()).unwrap();
+        assert_eq!(crate::mem::read_memfd(&mfd).unwrap()[0], 42);
+        assert!(r.recv().unwrap().unwrap().fds().is_empty());
+    }
+
+    #[test]
+    fn rate_limit() {
+        let (mut s, mut r) = pair(4096);
+        s.set_rate_limit(Some(RateLimit {
+            messages_per_sec: 2,
+            ..Default::default()
+        }));
+        assert!(s.send(b"a").unwrap());
+        assert!(s.send(b"b").unwrap());
+        assert!(matches!(s.send(b"c"), Err(Error::RateLimited)));
+        s.set_rate_limit(Some(RateLimit {
+            bytes_per_sec: 10000,
+            burst: std::time::Duration::from_millis(1),
+            block: true,
+            ..Default::default()
+        }));
+        let t = std::time::Instant::now();
+        for _ in 0..3 {
+            assert!(s.send(&[0u8; 50]).unwrap());
+        }
+        // 150 bytes with room for 10 bytes a millisecond
+        assert!(t.elapsed() >= std::time::Duration::from_millis(10));
+        let mut count = 0;
+        while r.recv().unwrap().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 5);
+    }
+
+    #[test]
+    fn lossy() {
+        let (mut s, mut r) = pair(4096);
+        s.set_lossy(true);
+        let msg = [0u8; 1000];
+        while s.send(&msg).unwrap() {}
+        assert!(!s.send(&msg).unwrap());
+        assert_eq!((r.dropped(), r.overflows()), (2, 1));
+        r.recv().unwrap().unwrap();
+        assert!(s.send(&msg).unwrap());
+        assert!(!s.send(&msg).unwrap());
+        assert_eq!((r.dropped(), r.overflows()), (3, 2));
+    }
+
+    #[test]
+    fn transaction() {
+        let (mut s, mut r) = pair(4096);
+        assert!(s.send(&vec![0u8; s.max_message_size() - 500]).unwrap());
+        r.recv().unwrap().unwrap();
+        // Does not fit before the end, so it gets padded and written as a whole.
+        let sent = s.transaction(|t| {
+            t.send(b"first")?;
+            t.send(&[1u8; 900])
+        });
+        assert!(sent.unwrap());
+        assert_eq!(s.seq(), 3);
+        assert_eq!(r.recv().unwrap().unwrap().data(), b"first");
+        assert_eq!(r.recv().unwrap().unwrap().data(), &[1u8; 900][..]);
+        assert!(r.recv().unwrap().is_none());
+        // A failing closure sends nothing.
+        let failed = s.transaction(|t| {
+            t.send(b"first")?;
+            t.send(&vec![0u8; 10000])
+        });
+        assert!(failed.is_err());
+        assert!(r.recv().unwrap().is_none());
+    }
+
+    #[test]
+    fn ttl() {
+        let (mut s, mut r) = pair(4096);
+        s.set_ttl(Some(std::time::Duration::from_millis(1)));
+        assert!(s.send(b"stale").unwrap());
+        s.set_ttl(Some(std::time::Duration::from_secs(60)));
+        let t = TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+        assert!(s.send_traced(b"fresh", &t.unwrap()).unwrap());
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let m = r.recv().unwrap().unwrap();
+        assert_eq!(
+            (m.seq(), m.data(), m.trace_context()),
+            (1, &b"fresh"[..], t)
+        );
+        assert_eq!(r.expired(), 1);
+    }
+
+    #[cfg(feature = "lz4_flex")]
+    #[test]
+    fn compressed() {
+        let (s, r) = pair(4096);
+        let (mut s, mut r) = (HeaderSender::<u32>::new(s), HeaderReceiver::<u32>::new(r));
+        s.inner_mut().set_compression_threshold(Some(100));
+        let msg = vec![7u8; 4000];
+        let free = s.inner_mut().ring.sender_mut().write_count().unwrap();
+        assert!(s.send(&3, &msg).unwrap());
+        assert!(s.inner_mut().ring.sender_mut().write_count().unwrap() > free - 100);
+        let (h, m) = r.recv().unwrap().unwrap();
+        assert_eq!((h, m.data()), (3, &msg[..]));
+    }
+
+    #[test]
+    fn traced() {
+        let (mut s, mut r) = pair(4096);
+        let tp = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let t = TraceContext::parse(tp).unwrap();
+        assert_eq!(t.to_traceparent(), tp);
+        assert!(
+            TraceContext::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01")
+                .is_none()
+        );
+        assert!(s.send_traced(b"hello", &t).unwrap());
+        assert!(s.send(b"world").unwrap());
+        let m = r.recv().unwrap().unwrap();
+        assert_eq!((m.data(), m.trace_context()), (&b"hello"[..], Some(t)));
+        assert_eq!(r.recv().unwrap().unwrap().trace_context(), None);
+    }
+
+    #[test]
+    fn typed_header() {
+        let (s, r) = pair(4096);
+        let (mut s, mut r) = (HeaderSender::<u32>::new(s), HeaderReceiver::<u32>::new(r));
+        assert!(s.send(&7, b"payload").unwrap());
+        let (h, m) = r.recv().unwrap().unwrap();
+        assert_eq!((h, m.data()), (7, &b"payload"[..]));
+        assert!(s.inner_mut().send(b"no header").unwrap());
+        assert!(r.recv().is_err());
+    }
+
+    #[test]
+    fn typed_payload() {
+        let (s, r) = pair(4096);
+        let mut s = HeaderSender::<u32, [u64; 2]>::new(s);
+        let mut r = HeaderReceiver::<u32, [u64; 2]>::new(r);
+        assert!(s.send(&7, &[1, 2]).unwrap());
+        assert_eq!(r.recv_typed().unwrap(), Some((7, [1, 2])));
+        assert!(r.recv_typed().unwrap().is_none());
+        let (s, r) = pair(4096);
+        let mut s = HeaderSender::<u32>::new(s);
+        let mut r = HeaderReceiver::<u32, [u64; 2]>::new(r);
+        assert!(s.send(&7, b"short").unwrap());
+        assert!(r.recv_typed().is_err());
+    }
+
+    #[test]
+    fn declared_max_size() {
+        let mut r = Receiver::with_max_message_size(4096, 100).unwrap();
+        let ring = r.ring();
+        let mut s = Sender::open(
+            4096,
+            ring.memfd().as_file().try_clone().unwrap(),
+            ring.empty_signal().try_clone().unwrap(),
+            ring.full_signal().try_clone().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(s.max_message_size(), 100);
+        assert!(matches!(s.send(&[0; 101]), Err(Error::MessageTooBig)));
+        let (a, b) = UnixStream::pair().unwrap();
+        s.set_socket(a);
+        assert!(matches!(s.send_large(&[0; 101]), Err(Error::MessageTooBig)));
+        drop(b);
+
+        // A sender that ignores the limit
+        s.max_message_size = 200;
+        assert!(s.send(&[0; 100]).unwrap());
+        assert!(s.send(&[0; 101]).unwrap());
+        assert_eq!(r.recv().unwrap().unwrap().data().len(), 100);
+        assert!(matches!(
+            r.recv(),
+            Err(Error::Ringbuf(crate::ringbuf::Error::BufCorrupt))
+        ));
+    }
+
+    #[test]
+    fn hand_off() {
+        let (mut s, mut r) = pair(256);
+        let (a, b) = UnixStream::pair().unwrap();
+        s.set_socket(a);
+        r.set_socket(b);
+        let old_fd = r.ring().empty_signal().as_raw_fd();
+        assert!(s.send(b"before").unwrap());
+        let big = vec![5u8; s.max_message_size() + 1];
+        assert!(matches!(s.send(&big), Err(Error::MessageTooBig)));
+        assert!(s.hand_off(big.len() * 2).unwrap());
+        assert!(s.send(&big).unwrap());
+        assert_eq!(r.recv().unwrap().unwrap().data(), b"before");
+        let m = r.recv().unwrap().unwrap();
+        assert_eq!((m.seq(), m.data()), (1, &big[..]));
+        assert_ne!(r.ring().empty_signal().as_raw_fd(), old_fd);
+        assert!(r.recv().unwrap().is_none());
+
+        r.set_max_handoff_capacity(big.len() * 2);
+        assert!(s.hand_off(big.len() * 4).unwrap());
+        assert!(matches!(
+            r.recv(),
+            Err(Error::Ringbuf(crate::ringbuf::Error::BufTooBig))
+        ));
+    }
+
+    #[cfg(feature = "lz4_flex")]
+    #[test]
+    fn hand_off_compressed() {
+        let (mut s, mut r) = pair(4096);
+        let (a, b) = UnixStream::pair().unwrap();
+        s.set_socket(a);
+        r.set_socket(b);
+        s.set_compression_threshold(Some(100));
+        assert!(s.hand_off(8192).unwrap());
+        let compression = sharedring::FEATURE_COMPRESSION;
+        assert_eq!(s.ring().features() & compression, compression);
+        let msg = vec![7u8; 4000];
+        assert!(s.send(&msg).unwrap());
+        assert_eq!(r.recv().unwrap().unwrap().data(), &msg[..]);
+        assert_eq!(r.ring().features() & compression, compression);
+    }
+
+    #[test]
+    fn token() {
+        let (mut s, mut r) = pair(4096);
+        let (a, b) = UnixStream::pair().unwrap();
+        s.set_socket(a);
+        r.set_socket(b);
+        assert!(!r.token_required());
+        let token = s.require_token().unwrap();
+        assert!(r.token_required());
+        assert!(matches!(s.send(b"x"), Err(Error::Unauthenticated)));
+        assert!(!s.authenticate().unwrap());
+        let mut wrong = token;
+        wrong[15] ^= 1;
+        r.present_token(&wrong).unwrap();
+        assert!(matches!(s.authenticate(), Err(Error::BadToken)));
+        r.present_token(&token).unwrap();
+        assert!(s.authenticate().unwrap());
+        assert!(s.send(b"x").unwrap());
+        assert_eq!(r.recv().unwrap().unwrap().data(), b"x");
+    }
+
+    #[test]
+    fn room_taken_after_reserve() {
+        // As if the receiver had rewritten the shared indices after `reserve`
+        let (mut s, _r) = pair(256);
+        while s.send(&[1; 8]).unwrap() {}
+        let seq = s.seq;
+        assert!(matches!(
+            s.write_frame(KIND_DATA, 0, &[], &[2; 8]),
+            Err(Error::Ringbuf(crate::ringbuf::Error::BufCorrupt))
+        ));
+        assert_eq!(s.seq, seq);
+    }
+
+    #[test]
+    fn partial_records() {
+        // A receiver that sends only part of the token
+        let (mut s, _) = pair(4096);
+        let (a, mut b) = UnixStream::pair().unwrap();
+        s.set_socket(a);
+        let token = s.require_token().unwrap();
+        let mut record = [0u8; RECORD_LEN];
+        record[..WORD].copy_from_slice(&RECORD_TOKEN.to_le_bytes());
+        record[WORD..].copy_from_slice(&token);
+        b.write_all(&record[..5]).unwrap();
+        assert!(!s.authenticate().unwrap());
+        b.write_all(&record[5..]).unwrap();
+        assert!(s.authenticate().unwrap());
+
+        // A sender that sends only part of the record of a spilled message
+        let (mut s, mut r) = pair(4096);
+        let (a, relay_in) = UnixStream::pair().unwrap();
+        let (relay_out, b) = UnixStream::pair().unwrap();
+        s.set_socket(a);
+        r.set_socket(b);
+        s.set_spill_threshold(100);
+        let big = vec![3u8; 1000];
+        assert!(s.send_large(&big).unwrap());
+        let mut fds = vec![];
+        let n = crate::unix::recv_with_fds(&relay_in, &mut record, &mut fds).unwrap();
+        assert_eq!((n, fds.len()), (RECORD_LEN, 1));
+        let fd = fds[0].as_raw_fd();
+        crate::unix::send_with_fds(&relay_out, &record[..5], &[fd]).unwrap();
+        assert!(r.recv().unwrap().is_none());
+        crate::unix::send_with_fds(&relay_out, &record[5..], &[]).unwrap();
+        let m = r.recv().unwrap().unwrap();
+        assert!(m.is_spilled());
+        assert_eq!(m.data(), &big[..]);
+    }
+}
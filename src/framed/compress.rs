@@ -0,0 +1,30 @@
+//! LZ4 compression of message payloads.
+
+/// Compresses `head` and `data` together, prefixed with the uncompressed length; returns
+/// `None` if that does not make it any smaller.
+pub(super) fn compress(head: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    let c = lz4_flex::compress_prepend_size(&[head, data].concat());
+    if c.len() < head.len() + data.len() {
+        Some(c)
+    } else {
+        None
+    }
+}
+
+/// Decompresses a payload from the untrusted sender, which must not decompress to more
+/// than `limit` bytes.
+pub(super) fn decompress(v: &[u8], limit: usize) -> Option<Vec<u8>> {
+    if v.len() < 4 {
+        return None;
+    }
+    let len = u32::from_le_bytes([v[0], v[1], v[2], v[3]]) as usize;
+    if len > limit {
+        return None;
+    }
+    let d = lz4_flex::decompress(&v[4..], len).ok()?;
+    if d.len() == len {
+        Some(d)
+    } else {
+        None
+    }
+}
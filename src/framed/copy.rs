@@ -0,0 +1,62 @@
+//! Copying payloads into and out of the ringbuffer.
+//!
+//! With the `nontemporal` feature on x86_64, large copies use streaming stores, which go
+//! around the cache, so that a big batch does not evict everything else the core is working
+//! on. Otherwise (and for small copies) this is a plain memcpy.
+
+/// Copies `len` bytes, with streaming stores if `nontemporal` is set and supported.
+///
+/// # Safety
+///
+/// Same as `std::ptr::copy_nonoverlapping`.
+pub(super) unsafe fn copy(src: *const u8, dst: *mut u8, len: usize, nontemporal: bool) {
+    #[cfg(all(feature = "nontemporal", target_arch = "x86_64"))]
+    {
+        if nontemporal {
+            return stream(src, dst, len);
+        }
+    }
+    let _ = nontemporal;
+    std::ptr::copy_nonoverlapping(src, dst, len);
+}
+
+#[cfg(all(feature = "nontemporal", target_arch = "x86_64"))]
+unsafe fn stream(src: *const u8, dst: *mut u8, len: usize) {
+    use std::arch::x86_64::{__m128i, _mm_loadu_si128, _mm_sfence, _mm_stream_si128};
+    // Streaming stores need an aligned destination; the unaligned ends are copied normally.
+    let head = std::cmp::min(dst.align_offset(16), len);
+    std::ptr::copy_nonoverlapping(src, dst, head);
+    let mut i = head;
+    while i + 16 <= len {
+        let v = _mm_loadu_si128(src.add(i) as *const __m128i);
+        _mm_stream_si128(dst.add(i) as *mut __m128i, v);
+        i += 16;
+    }
+    std::ptr::copy_nonoverlapping(src.add(i), dst.add(i), len - i);
+    // Streaming stores are weakly ordered; they must be visible before the ringbuffer
+    // publishes the data.
+    _mm_sfence();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unaligned() {
+        let src: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        for (offset, len) in [(0, 1000), (3, 997), (5, 10), (1, 0)] {
+            let mut dst = vec![0u8; 1000];
+            unsafe {
+                copy(
+                    src[offset..].as_ptr(),
+                    dst[offset..].as_mut_ptr(),
+                    len,
+                    true,
+                )
+            };
+            assert_eq!(&dst[offset..offset + len], &src[offset..offset + len]);
+            assert!(dst[offset + len..].iter().all(|x| *x == 0));
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Messages with a typed, fixed size header in front of the payload, and optionally a
+//! typed payload too.
+
+use super::{Message, Receiver, Sender, FLAG_HEADER, WORD};
+use crate::Error;
+use std::marker::PhantomData;
+
+fn head_len<H>() -> usize {
+    std::mem::size_of::<H>().div_ceil(WORD) * WORD
+}
+
+/// Sends messages that all start with a header of type `H`, e g a timestamp, type tag or
+/// routing key, so that it does not have to be encoded into the payload.
+///
+/// The payload is of type `T`, by default plain bytes.
+pub struct HeaderSender<H, T: ?Sized = [u8]> {
+    inner: Sender,
+    _phantom: PhantomData<(H, Box<T>)>,
+}
+
+impl<H: Copy + zerocopy::AsBytes, T: zerocopy::AsBytes + ?Sized> HeaderSender<H, T> {
+    /// Wraps a sender. The receiver has to use a `HeaderReceiver` with the same `H` and `T`.
+    pub fn new(inner: Sender) -> Self {
+        HeaderSender {
+            inner,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// The wrapped sender, e g for its ringbuffer or settings.
+    pub fn inner_mut(&mut self) -> &mut Sender {
+        &mut self.inner
+    }
+
+    /// Largest payload that fits together with the header.
+    pub fn max_message_size(&self) -> usize {
+        self.inner
+            .max_message_size()
+            .saturating_sub(head_len::<H>())
+    }
+
+    /// Sends a header and payload; otherwise this works like `Sender::send`.
+    pub fn send(&mut self, header: &H, payload: &T) -> Result<bool, Error> {
+        let mut head = vec![0u8; head_len::<H>()];
+        head[..std::mem::size_of::<H>()].copy_from_slice(header.as_bytes());
+        self.inner.send_data(&head, FLAG_HEADER, payload.as_bytes())
+    }
+}
+
+/// Receives messages sent by a `HeaderSender`.
+pub struct HeaderReceiver<H, T: ?Sized = [u8]> {
+    inner: Receiver,
+    _phantom: PhantomData<(H, Box<T>)>,
+}
+
+impl<H: Copy + zerocopy::FromBytes, T: ?Sized> HeaderReceiver<H, T> {
+    /// Wraps a receiver.
+    pub fn new(inner: Receiver) -> Self {
+        HeaderReceiver {
+            inner,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// The wrapped receiver, e g for its ringbuffer.
+    pub fn inner_mut(&mut self) -> &mut Receiver {
+        &mut self.inner
+    }
+
+    /// Receives a message, if there is one, together with its header; otherwise this works
+    /// like `Receiver::recv`.
+    ///
+    /// Messages sent without a header of the right size are treated as corruption.
+    pub fn recv(&mut self) -> Result<Option<(H, Message)>, Error> {
+        let mut m = match self.inner.recv()? {
+            None => return Ok(None),
+            Some(m) => m,
+        };
+        let len = head_len::<H>();
+        if !m.header || m.data().len() < len {
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        // H is FromBytes, so any bytes the untrusted sender put there make a valid H.
+        let h = unsafe { std::ptr::read_unaligned(m.data().as_ptr() as *const H) };
+        m.skip = len;
+        Ok(Some((h, m)))
+    }
+}
+
+impl<H: Copy + zerocopy::FromBytes, T: Copy + zerocopy::FromBytes> HeaderReceiver<H, T> {
+    /// Like `recv`, but also reads the payload as a `T`.
+    ///
+    /// Messages whose payload is not the size of a `T` are treated as corruption.
+    pub fn recv_typed(&mut self) -> Result<Option<(H, T)>, Error> {
+        let (h, m) = match self.recv()? {
+            None => return Ok(None),
+            Some(x) => x,
+        };
+        if m.data().len() != std::mem::size_of::<T>() {
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        // T is FromBytes too.
+        let t = unsafe { std::ptr::read_unaligned(m.data().as_ptr() as *const T) };
+        Ok(Some((h, t)))
+    }
+}
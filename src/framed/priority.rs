@@ -0,0 +1,155 @@
+//! Messages with a priority, delivered urgent first within a bounded window.
+
+use super::{HeaderReceiver, HeaderSender, Message, Receiver, Sender};
+use crate::Error;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+
+/// Sends messages with a priority; higher is more urgent.
+pub struct PrioritySender {
+    inner: HeaderSender<u64>,
+}
+
+impl PrioritySender {
+    /// Wraps a sender. The receiver has to use a `PriorityReceiver`.
+    pub fn new(inner: Sender) -> Self {
+        PrioritySender {
+            inner: HeaderSender::new(inner),
+        }
+    }
+
+    /// The wrapped sender, e g for its ringbuffer or settings.
+    pub fn inner_mut(&mut self) -> &mut Sender {
+        self.inner.inner_mut()
+    }
+
+    /// Sends a message; otherwise this works like `Sender::send`.
+    pub fn send(&mut self, priority: u8, data: &[u8]) -> Result<bool, Error> {
+        self.inner.send(&(priority as u64), data)
+    }
+}
+
+struct Pending {
+    priority: u8,
+    message: Message,
+    /// Number of later messages delivered before this one.
+    overtaken: usize,
+}
+
+/// Receives messages sent by a `PrioritySender`, reordering them by priority.
+///
+/// Up to `window` messages are taken from the ringbuffer and held back, and the most
+/// urgent of them is delivered first; among equal priorities, the oldest. No message is
+/// overtaken by more than `window - 1` later ones, so a flood of urgent messages delays
+/// the others, but cannot starve them. A window of 1 delivers in order.
+///
+/// # Example
+/// ```rust
+/// use shmem_ipc::framed::{PriorityReceiver, PrioritySender, Receiver, Sender};
+/// let s = Sender::new(4096).unwrap();
+/// let ring = s.ring();
+/// let r = Receiver::open(4096, ring.memfd().as_file().try_clone().unwrap(),
+///     ring.empty_signal().try_clone().unwrap(), ring.full_signal().try_clone().unwrap())
+///     .unwrap();
+/// let (mut s, mut r) = (PrioritySender::new(s), PriorityReceiver::new(r, 8));
+/// s.send(0, b"routine").unwrap();
+/// s.send(9, b"urgent").unwrap();
+/// assert_eq!(r.recv().unwrap().unwrap().1.data(), b"urgent");
+/// assert_eq!(r.recv().unwrap().unwrap().1.data(), b"routine");
+/// ```
+pub struct PriorityReceiver {
+    inner: HeaderReceiver<u64>,
+    window: usize,
+    /// Oldest first.
+    pending: VecDeque<Pending>,
+}
+
+impl PriorityReceiver {
+    /// Wraps a receiver, with a reordering window of `window` messages, at least one.
+    pub fn new(inner: Receiver, window: usize) -> Self {
+        let window = window.max(1);
+        PriorityReceiver {
+            inner: HeaderReceiver::new(inner),
+            window,
+            pending: VecDeque::with_capacity(window),
+        }
+    }
+
+    /// The wrapped receiver, e g for its ringbuffer.
+    pub fn inner_mut(&mut self) -> &mut Receiver {
+        self.inner.inner_mut()
+    }
+
+    /// Number of messages taken from the ringbuffer but not delivered yet.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Receives the most urgent message in the window, if there is one, together with its
+    /// priority.
+    ///
+    /// Messages sent without a priority are treated as corruption.
+    pub fn recv(&mut self) -> Result<Option<(u8, Message)>, Error> {
+        while self.pending.len() < self.window {
+            let (priority, message) = match self.inner.recv()? {
+                None => break,
+                Some(x) => x,
+            };
+            let priority = u8::try_from(priority).map_err(|_| crate::ringbuf::Error::BufCorrupt)?;
+            self.pending.push_back(Pending {
+                priority,
+                message,
+                overtaken: 0,
+            });
+        }
+        let i = match self.pending.front() {
+            None => return Ok(None),
+            // Overtaken as often as allowed
+            Some(p) if p.overtaken + 1 >= self.window => 0,
+            Some(_) => {
+                let mut best = 0;
+                for (i, p) in self.pending.iter().enumerate() {
+                    if p.priority > self.pending[best].priority {
+                        best = i;
+                    }
+                }
+                best
+            }
+        };
+        for p in self.pending.range_mut(..i) {
+            p.overtaken += 1;
+        }
+        let p = self.pending.remove(i).unwrap();
+        Ok(Some((p.priority, p.message)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_reordering() {
+        let s = Sender::new(4096).unwrap();
+        let ring = s.ring();
+        let r = Receiver::open(
+            4096,
+            ring.memfd().as_file().try_clone().unwrap(),
+            ring.empty_signal().try_clone().unwrap(),
+            ring.full_signal().try_clone().unwrap(),
+        )
+        .unwrap();
+        let (mut s, mut r) = (PrioritySender::new(s), PriorityReceiver::new(r, 3));
+        s.send(0, b"low").unwrap();
+        for _ in 0..4 {
+            s.send(5, b"high").unwrap();
+        }
+        let mut order = vec![];
+        while let Some((p, m)) = r.recv().unwrap() {
+            order.push((p, m.seq()));
+        }
+        // The low priority message gives way to two later messages only.
+        assert_eq!(order, [(5, 1), (5, 2), (0, 0), (5, 3), (5, 4)]);
+        assert_eq!(r.pending(), 0);
+    }
+}
@@ -0,0 +1,119 @@
+//! Token bucket rate limiting for senders.
+
+use crate::Error;
+use std::time::{Duration, Instant};
+
+/// Rate limit for a `Sender`, in messages and bytes per second.
+///
+/// Both are token buckets that fill up at the given rate and hold at most `burst` worth of
+/// tokens, so short bursts are allowed as long as the average rate is kept.
+#[derive(Copy, Clone, Debug)]
+pub struct RateLimit {
+    /// Messages per second, or zero for no limit.
+    pub messages_per_sec: u64,
+    /// Payload bytes per second, or zero for no limit.
+    pub bytes_per_sec: u64,
+    /// Size of the buckets, as time worth of tokens. Defaults to one second.
+    pub burst: Duration,
+    /// If true, sending sleeps until the rate limit allows it, instead of failing with
+    /// `Error::RateLimited`.
+    pub block: bool,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            messages_per_sec: 0,
+            bytes_per_sec: 0,
+            burst: Duration::from_secs(1),
+            block: false,
+        }
+    }
+}
+
+struct Bucket {
+    rate: f64,
+    cap: f64,
+    tokens: f64,
+}
+
+impl Bucket {
+    fn new(rate: u64, burst: Duration) -> Option<Self> {
+        if rate == 0 {
+            return None;
+        }
+        let rate = rate as f64;
+        let cap = f64::max(rate * burst.as_secs_f64(), 1.0);
+        Some(Bucket {
+            rate,
+            cap,
+            tokens: cap,
+        })
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        self.tokens = f64::min(self.tokens + self.rate * elapsed.as_secs_f64(), self.cap);
+    }
+
+    /// How long until `amount` tokens are available. Amounts larger than the bucket are
+    /// allowed through once it is full, and then leave it in debt.
+    fn wait_time(&self, amount: f64) -> Duration {
+        let need = f64::min(amount, self.cap) - self.tokens;
+        if need <= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(need / self.rate)
+        }
+    }
+}
+
+pub(super) struct Limiter {
+    block: bool,
+    messages: Option<Bucket>,
+    bytes: Option<Bucket>,
+    last: Instant,
+}
+
+impl Limiter {
+    pub(super) fn new(limit: RateLimit) -> Self {
+        Limiter {
+            block: limit.block,
+            messages: Bucket::new(limit.messages_per_sec, limit.burst),
+            bytes: Bucket::new(limit.bytes_per_sec, limit.burst),
+            last: crate::sim::instant(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = crate::sim::instant();
+        let elapsed = now - self.last;
+        self.last = now;
+        self.messages.iter_mut().for_each(|b| b.refill(elapsed));
+        self.bytes.iter_mut().for_each(|b| b.refill(elapsed));
+    }
+
+    /// Waits (or fails) until a message of `len` bytes is allowed. Does not use up any tokens.
+    pub(super) fn admit(&mut self, len: usize) -> Result<(), Error> {
+        loop {
+            self.refill();
+            let wait = std::cmp::max(
+                self.messages.as_ref().map(|b| b.wait_time(1.0)),
+                self.bytes.as_ref().map(|b| b.wait_time(len as f64)),
+            )
+            .unwrap_or_default();
+            if wait == Duration::from_secs(0) {
+                return Ok(());
+            }
+            if !self.block {
+                Err(Error::RateLimited)?
+            }
+            crate::sim::sleep(wait);
+        }
+    }
+
+    /// Uses up the tokens for a message of `len` bytes that was sent.
+    pub(super) fn charge(&mut self, len: usize) {
+        self.messages.iter_mut().for_each(|b| b.tokens -= 1.0);
+        self.bytes.iter_mut().for_each(|b| b.tokens -= len as f64);
+    }
+}
@@ -0,0 +1,161 @@
+//! Weighted fair sharing of one sender between several streams of messages.
+
+use super::Sender;
+use crate::Error;
+use std::collections::VecDeque;
+
+struct Stream {
+    queue: VecDeque<Vec<u8>>,
+    weight: usize,
+    /// Bytes this stream may still send in its current turn.
+    deficit: usize,
+}
+
+/// Multiplexes several streams of messages into one `Sender` with deficit round robin, so
+/// that e g a bulk transfer cannot starve interactive messages sharing the ringbuffer.
+///
+/// Messages are queued per stream with `enqueue` and go out in `flush`. On its turn, a
+/// stream may send up to `quantum` times its weight in bytes, and allowance it could not
+/// use because its next message was too big carries over to its next turn. Within a stream
+/// messages keep their order; the receiver gets them as plain messages, so tell the streams
+/// apart in the payload if needed.
+///
+/// # Example
+/// ```rust
+/// use shmem_ipc::framed::{Scheduler, Sender};
+/// let mut s = Scheduler::new(Sender::new(65536).unwrap(), 1024);
+/// let bulk = s.add_stream(1);
+/// let interactive = s.add_stream(4);
+/// s.enqueue(bulk, vec![0; 4000]).unwrap();
+/// s.enqueue(interactive, b"ping".to_vec()).unwrap();
+/// s.flush().unwrap();
+/// ```
+pub struct Scheduler {
+    inner: Sender,
+    quantum: usize,
+    streams: Vec<Stream>,
+    /// The stream whose turn it is, and whether it got its allowance for this turn.
+    current: usize,
+    started: bool,
+}
+
+impl Scheduler {
+    /// Wraps a sender; `quantum` is the number of bytes per unit of weight and turn.
+    pub fn new(inner: Sender, quantum: usize) -> Self {
+        Scheduler {
+            inner,
+            quantum: std::cmp::max(quantum, 1),
+            streams: vec![],
+            current: 0,
+            started: false,
+        }
+    }
+
+    /// The wrapped sender, e g for its ringbuffer or settings.
+    pub fn inner_mut(&mut self) -> &mut Sender {
+        &mut self.inner
+    }
+
+    /// Adds a stream with the given weight, and returns its index.
+    pub fn add_stream(&mut self, weight: usize) -> usize {
+        self.streams.push(Stream {
+            queue: VecDeque::new(),
+            weight: std::cmp::max(weight, 1),
+            deficit: 0,
+        });
+        self.streams.len() - 1
+    }
+
+    /// Queues a message on a stream, to be sent by `flush`.
+    ///
+    /// Fails with `MessageTooBig` for messages that can never fit, and panics if there is
+    /// no such stream.
+    pub fn enqueue(&mut self, stream: usize, data: Vec<u8>) -> Result<(), Error> {
+        if data.len() > self.inner.max_message_size() {
+            Err(Error::MessageTooBig)?
+        }
+        self.streams[stream].queue.push_back(data);
+        Ok(())
+    }
+
+    /// Number of messages waiting on a stream.
+    pub fn queued(&self, stream: usize) -> usize {
+        self.streams[stream].queue.len()
+    }
+
+    /// Sends queued messages in fair order until all are sent or the ringbuffer is full.
+    ///
+    /// Returns the number of messages sent. Call it again when the ringbuffer has room,
+    /// see `Sender::block_until_writable`; the stream whose turn it was goes on from
+    /// where it stopped.
+    pub fn flush(&mut self) -> Result<usize, Error> {
+        let mut sent = 0;
+        // Every stream gets at least one turn with nothing to send before we give up.
+        let mut idle = 0;
+        while idle < self.streams.len() {
+            let i = self.current;
+            let quantum = self.quantum;
+            let st = &mut self.streams[i];
+            if st.queue.is_empty() {
+                st.deficit = 0;
+                self.next_turn();
+                idle += 1;
+                continue;
+            }
+            idle = 0;
+            if !self.started {
+                st.deficit = st.deficit.saturating_add(quantum.saturating_mul(st.weight));
+                self.started = true;
+            }
+            while let Some(m) = st.queue.front() {
+                if m.len() > st.deficit {
+                    break;
+                }
+                if !self.inner.send(m)? {
+                    return Ok(sent);
+                }
+                st.deficit -= m.len();
+                st.queue.pop_front();
+                sent += 1;
+            }
+            if st.queue.is_empty() {
+                st.deficit = 0;
+            }
+            self.next_turn();
+        }
+        Ok(sent)
+    }
+
+    fn next_turn(&mut self) {
+        self.current = (self.current + 1) % self.streams.len();
+        self.started = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighted() {
+        let (s, mut r) = crate::framed::tests::pair(1 << 20);
+        let mut s = Scheduler::new(s, 100);
+        let bulk = s.add_stream(1);
+        let interactive = s.add_stream(3);
+        for _ in 0..10 {
+            s.enqueue(bulk, vec![1; 100]).unwrap();
+            s.enqueue(interactive, vec![2; 100]).unwrap();
+        }
+        assert_eq!(s.flush().unwrap(), 20);
+        let order: Vec<u8> = std::iter::from_fn(|| r.recv().unwrap())
+            .map(|m| m.data()[0])
+            .collect();
+        // One bulk message per three interactive ones, until those run out
+        assert_eq!(&order[..8], &[1, 2, 2, 2, 1, 2, 2, 2]);
+        assert_eq!(order.iter().filter(|&&x| x == 1).count(), 10);
+        assert!(matches!(
+            s.enqueue(bulk, vec![0; 1 << 21]),
+            Err(Error::MessageTooBig)
+        ));
+    }
+}
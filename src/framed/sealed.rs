@@ -0,0 +1,121 @@
+//! Authenticated encryption of messages, for when others might be able to map the memfd.
+
+use super::{Message, Payload, Receiver, Sender};
+use crate::Error;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+/// Size of the authentication tag added to every message.
+pub const TAG_LEN: usize = 16;
+
+/// The nonce is the sequence number of the message, so it is never reused with the same
+/// key, and messages that are replayed, reordered or dropped fail to decrypt.
+fn nonce(seq: u64) -> Nonce {
+    let mut n = [0u8; 12];
+    n[..8].copy_from_slice(&seq.to_le_bytes());
+    n.into()
+}
+
+/// Sends messages encrypted and authenticated with ChaCha20-Poly1305.
+///
+/// The key has to be shared with the receiver by other means, and must not be used for
+/// any other channel, nor for the other direction of this one.
+pub struct SealedSender {
+    inner: Sender,
+    cipher: ChaCha20Poly1305,
+}
+
+impl SealedSender {
+    /// Wraps a sender. The receiver has to use a `SealedReceiver` with the same key.
+    pub fn new(inner: Sender, key: &[u8; 32]) -> Self {
+        SealedSender {
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+
+    /// The wrapped sender, e g for its ringbuffer or settings.
+    pub fn inner_mut(&mut self) -> &mut Sender {
+        &mut self.inner
+    }
+
+    /// Largest message that fits together with the authentication tag.
+    pub fn max_message_size(&self) -> usize {
+        self.inner.max_message_size().saturating_sub(TAG_LEN)
+    }
+
+    /// Encrypts and sends a message; otherwise this works like `Sender::send`.
+    pub fn send(&mut self, data: &[u8]) -> Result<bool, Error> {
+        if data.len() > self.max_message_size() {
+            Err(Error::MessageTooBig)?
+        }
+        // A message that is not sent never reaches the shared memory, so it is fine to use
+        // its nonce again for the next one.
+        let sealed = self
+            .cipher
+            .encrypt(&nonce(self.inner.seq()), data)
+            .map_err(|_| Error::MessageTooBig)?;
+        self.inner.send(&sealed)
+    }
+}
+
+/// Receives messages sent by a `SealedSender`.
+pub struct SealedReceiver {
+    inner: Receiver,
+    cipher: ChaCha20Poly1305,
+}
+
+impl SealedReceiver {
+    /// Wraps a receiver.
+    pub fn new(inner: Receiver, key: &[u8; 32]) -> Self {
+        SealedReceiver {
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+
+    /// The wrapped receiver, e g for its ringbuffer.
+    pub fn inner_mut(&mut self) -> &mut Receiver {
+        &mut self.inner
+    }
+
+    /// Receives and decrypts a message, if there is one; otherwise this works like
+    /// `Receiver::recv`.
+    ///
+    /// Messages that fail authentication, i e that were tampered with, replayed or not
+    /// encrypted with the right key, are treated as corruption.
+    pub fn recv(&mut self) -> Result<Option<Message>, Error> {
+        let mut m = match self.inner.recv()? {
+            None => return Ok(None),
+            Some(m) => m,
+        };
+        let plain = self
+            .cipher
+            .decrypt(&nonce(m.seq), m.data())
+            .map_err(|_| crate::ringbuf::Error::BufCorrupt)?;
+        m.payload = Payload::Inline(plain);
+        m.skip = 0;
+        Ok(Some(m))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed() {
+        let (s, r) = crate::framed::tests::pair(4096);
+        let key = [5u8; 32];
+        let (mut s, mut r) = (SealedSender::new(s, &key), SealedReceiver::new(r, &key));
+        assert!(s.send(b"secret").unwrap());
+        let mut shared = vec![];
+        let mut file = s.inner.ring.memfd().as_file().try_clone().unwrap();
+        std::io::Read::read_to_end(&mut file, &mut shared).unwrap();
+        assert!(!shared.windows(6).any(|w| w == b"secret"));
+        assert_eq!(r.recv().unwrap().unwrap().data(), b"secret");
+        // Not encrypted
+        assert!(s.inner_mut().send(b"plain text message").unwrap());
+        assert!(r.recv().is_err());
+    }
+}
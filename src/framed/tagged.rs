@@ -0,0 +1,142 @@
+//! Channels carrying one of several message types, told apart by a tag.
+
+use super::{HeaderReceiver, HeaderSender, Receiver, Sender};
+use crate::Error;
+use std::convert::TryFrom;
+
+/// A message type for a tagged channel, typically an enum with one Pod type per variant.
+///
+/// Decoding is where validation goes: the bytes come from an untrusted peer, so check
+/// everything the `FromBytes` bound of the variant type does not, e g enum discriminants.
+pub trait Tagged: Sized {
+    /// The tag of this variant.
+    fn tag(&self) -> u8;
+    /// The bytes of this variant, e g `zerocopy::AsBytes::as_bytes` of its contents.
+    fn bytes(&self) -> &[u8];
+    /// Decodes and validates a variant, or returns `None` if the tag is unknown or the
+    /// bytes are invalid.
+    fn decode(tag: u8, bytes: &[u8]) -> Option<Self>;
+}
+
+/// Reads a Pod value from bytes of exactly its size, for use in `Tagged::decode`.
+pub fn decode_pod<T: Copy + zerocopy::FromBytes>(bytes: &[u8]) -> Option<T> {
+    if bytes.len() != std::mem::size_of::<T>() {
+        return None;
+    }
+    // T is FromBytes, so any bytes make a valid T.
+    Some(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+/// Sends messages of a `Tagged` type.
+pub struct TaggedSender<M> {
+    inner: HeaderSender<u64>,
+    _phantom: std::marker::PhantomData<M>,
+}
+
+impl<M: Tagged> TaggedSender<M> {
+    /// Wraps a sender. The receiver has to use a `TaggedReceiver` with the same `M`.
+    pub fn new(inner: Sender) -> Self {
+        TaggedSender {
+            inner: HeaderSender::new(inner),
+            _phantom: std::marker::PhantomData,
+        }
+    }
+
+    /// The wrapped sender, e g for its ringbuffer or settings.
+    pub fn inner_mut(&mut self) -> &mut Sender {
+        self.inner.inner_mut()
+    }
+
+    /// Sends a message; otherwise this works like `Sender::send`.
+    pub fn send(&mut self, msg: &M) -> Result<bool, Error> {
+        self.inner.send(&(msg.tag() as u64), msg.bytes())
+    }
+}
+
+/// Receives messages sent by a `TaggedSender`.
+pub struct TaggedReceiver<M> {
+    inner: HeaderReceiver<u64>,
+    _phantom: std::marker::PhantomData<M>,
+}
+
+impl<M: Tagged> TaggedReceiver<M> {
+    /// Wraps a receiver.
+    pub fn new(inner: Receiver) -> Self {
+        TaggedReceiver {
+            inner: HeaderReceiver::new(inner),
+            _phantom: std::marker::PhantomData,
+        }
+    }
+
+    /// The wrapped receiver, e g for its ringbuffer.
+    pub fn inner_mut(&mut self) -> &mut Receiver {
+        self.inner.inner_mut()
+    }
+
+    /// Receives and decodes a message, if there is one.
+    ///
+    /// Messages with an unknown tag, or that fail validation, are treated as corruption.
+    pub fn recv(&mut self) -> Result<Option<M>, Error> {
+        let (tag, m) = match self.inner.recv()? {
+            None => return Ok(None),
+            Some(x) => x,
+        };
+        let tag = u8::try_from(tag).map_err(|_| crate::ringbuf::Error::BufCorrupt)?;
+        Ok(Some(
+            M::decode(tag, m.data()).ok_or(crate::ringbuf::Error::BufCorrupt)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Msg {
+        Ping(u32),
+        Flag(bool),
+    }
+
+    const FLAG_BYTES: [[u8; 1]; 2] = [[0], [1]];
+
+    impl Tagged for Msg {
+        fn tag(&self) -> u8 {
+            match self {
+                Msg::Ping(_) => 1,
+                Msg::Flag(_) => 2,
+            }
+        }
+        fn bytes(&self) -> &[u8] {
+            use zerocopy::AsBytes;
+            match self {
+                Msg::Ping(x) => x.as_bytes(),
+                Msg::Flag(b) => &FLAG_BYTES[*b as usize],
+            }
+        }
+        fn decode(tag: u8, bytes: &[u8]) -> Option<Self> {
+            match tag {
+                1 => decode_pod(bytes).map(Msg::Ping),
+                2 => match decode_pod::<u8>(bytes)? {
+                    0 => Some(Msg::Flag(false)),
+                    1 => Some(Msg::Flag(true)),
+                    _ => None,
+                },
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn variants() {
+        let (s, r) = crate::framed::tests::pair(4096);
+        let (mut s, mut r) = (TaggedSender::<Msg>::new(s), TaggedReceiver::<Msg>::new(r));
+        assert!(s.send(&Msg::Ping(5)).unwrap());
+        assert!(s.send(&Msg::Flag(true)).unwrap());
+        assert_eq!(r.recv().unwrap(), Some(Msg::Ping(5)));
+        assert_eq!(r.recv().unwrap(), Some(Msg::Flag(true)));
+        // A bool that is neither 0 nor 1
+        assert!(s.inner.send(&2, &[7]).unwrap());
+        assert!(r.recv().is_err());
+    }
+}
@@ -0,0 +1,91 @@
+//! W3C trace context carried along with messages.
+
+/// Size of the trace context in a frame, padded to whole words.
+pub(super) const TRACE_LEN: usize = 32;
+
+/// A trace context, as in the W3C `traceparent` header, see `Sender::send_traced`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub parent_id: [u8; 8],
+    /// Trace flags; bit 0 is "sampled".
+    pub flags: u8,
+}
+
+fn hex(b: &[u8]) -> String {
+    b.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn unhex(s: &str, out: &mut [u8]) -> Option<()> {
+    if s.len() != out.len() * 2 {
+        return None;
+    }
+    for (i, o) in out.iter_mut().enumerate() {
+        *o = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(())
+}
+
+impl TraceContext {
+    /// Parses a `traceparent` header value, e g
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    ///
+    /// Returns `None` if it is malformed, or has an all-zero trace or parent id.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.split('-');
+        if parts.next()? != "00" {
+            return None;
+        }
+        let mut t = TraceContext::default();
+        unhex(parts.next()?, &mut t.trace_id)?;
+        unhex(parts.next()?, &mut t.parent_id)?;
+        let mut flags = [0u8];
+        unhex(parts.next()?, &mut flags)?;
+        t.flags = flags[0];
+        if parts.next().is_some() {
+            return None;
+        }
+        t.validate()
+    }
+
+    /// Formats the context as a `traceparent` header value.
+    pub fn to_traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            hex(&self.trace_id),
+            hex(&self.parent_id),
+            self.flags
+        )
+    }
+
+    fn validate(self) -> Option<Self> {
+        if self.trace_id == [0; 16] || self.parent_id == [0; 8] {
+            None
+        } else {
+            Some(self)
+        }
+    }
+
+    pub(super) fn to_bytes(self) -> [u8; TRACE_LEN] {
+        let mut b = [0u8; TRACE_LEN];
+        // b[0] is the version, always zero
+        b[1..17].copy_from_slice(&self.trace_id);
+        b[17..25].copy_from_slice(&self.parent_id);
+        b[25] = self.flags;
+        b
+    }
+
+    /// Decodes a context written by the untrusted sender; invalid ones are ignored.
+    pub(super) fn from_bytes(b: &[u8; TRACE_LEN]) -> Option<Self> {
+        if b[0] != 0 {
+            return None;
+        }
+        let mut t = TraceContext {
+            flags: b[25],
+            ..Default::default()
+        };
+        t.trace_id.copy_from_slice(&b[1..17]);
+        t.parent_id.copy_from_slice(&b[17..25]);
+        t.validate()
+    }
+}
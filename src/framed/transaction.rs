@@ -0,0 +1,101 @@
+//! Groups of messages that become visible to the receiver all at once.
+
+use super::{words_for, FrameHeader, Sender, KIND_DATA, WORD};
+use crate::Error;
+
+/// Messages staged by `Sender::transaction`.
+///
+/// The frames are encoded here and copied into the ringbuffer in one go, so the receiver
+/// either sees all of them or none.
+pub struct Transaction {
+    words: Vec<u64>,
+    bytes: usize,
+    count: u64,
+    max_message_size: usize,
+}
+
+impl Transaction {
+    /// Stages a message. Fails with `MessageTooBig` if the message, or the whole group
+    /// together with it, cannot fit into the ringbuffer.
+    pub fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        let words = words_for(data.len());
+        if data.len() > self.max_message_size
+            || self.words.len() + words > words_for(self.max_message_size)
+        {
+            Err(Error::MessageTooBig)?
+        }
+        let hdr = FrameHeader {
+            len: data.len() as u32,
+            kind: KIND_DATA,
+            flags: 0,
+        };
+        let start = self.words.len();
+        self.words.resize(start + words, 0);
+        self.words[start] = hdr.to_word();
+        let payload = &mut self.words[start + 1..];
+        for (w, chunk) in payload.iter_mut().zip(data.chunks(WORD)) {
+            let mut b = [0u8; WORD];
+            b[..chunk.len()].copy_from_slice(chunk);
+            *w = u64::from_ne_bytes(b);
+        }
+        self.bytes += data.len();
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Number of messages staged so far.
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    /// Whether no messages have been staged yet.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+impl Sender {
+    /// Sends a group of related messages, staged by the closure, so that the receiver sees
+    /// either all of them or none.
+    ///
+    /// Returns false if there is currently not enough room for the whole group, in which
+    /// case none of it is sent. If the closure fails, nothing is sent and the error is
+    /// returned. The group as a whole can be at most `max_message_size` bytes, minus eight
+    /// bytes for every message but the first.
+    pub fn transaction<F: FnOnce(&mut Transaction) -> Result<(), Error>>(
+        &mut self,
+        f: F,
+    ) -> Result<bool, Error> {
+        let mut txn = Transaction {
+            words: vec![],
+            bytes: 0,
+            count: 0,
+            max_message_size: self.max_message_size(),
+        };
+        f(&mut txn)?;
+        if txn.is_empty() {
+            return Ok(true);
+        }
+        self.admit(txn.bytes)?;
+        let words = txn.words.len();
+        if !self.reserve(words)? {
+            if self.lossy {
+                self.ring.record_dropped(txn.count);
+            }
+            return Ok(false);
+        }
+        let nontemporal = self.nontemporal.is_some_and(|t| txn.bytes >= t);
+        // A single write, and so a single update of the shared index, for the whole group.
+        self.write_words(words, |p| unsafe {
+            super::copy::copy(
+                txn.words.as_ptr() as *const u8,
+                p as *mut u8,
+                words * WORD,
+                nontemporal,
+            )
+        })?;
+        self.seq += txn.count;
+        self.charge(txn.bytes);
+        Ok(true)
+    }
+}
@@ -0,0 +1,392 @@
+//! gRPC over channels with tonic, for local services that want to keep their gRPC
+//! contracts, but not go through the TCP loopback stack.
+//!
+//! A `ChannelStream` is a byte stream over a `channel::Channel` that uses shared memory:
+//! what is written goes through its `framed` ringbuffers as messages, and reads return the
+//! data of those in order. It implements tokio's `AsyncRead` and `AsyncWrite`, waiting for
+//! the signal eventfds of the ringbuffers with tokio's reactor, and tonic's `Connected`, so
+//! that servers can take them from `incoming`. Clients connect through a `Connector`, see
+//! `Endpoint::connect_with_connector`.
+//!
+//! Both ends set up the channel over a unix socket, which afterwards only tells each side
+//! when the other has hung up.
+//!
+//! Needs the `tonic` feature, and a tokio runtime.
+//!
+//! # Example
+//! ```rust,no_run
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! use shmem_ipc::grpc::{incoming, Connector};
+//! let listener = tokio::net::UnixListener::bind("/run/example.sock")?;
+//! let (_, health) = tonic_health::server::health_reporter();
+//! let server = tonic::transport::Server::builder().add_service(health);
+//! tokio::spawn(server.serve_with_incoming(incoming(listener, 1 << 20)));
+//! // The URI is not used, but has to be valid.
+//! let channel = tonic::transport::Endpoint::from_static("http://localhost")
+//!     .connect_with_connector(Connector::new("/run/example.sock", 1 << 20))
+//!     .await?;
+//! # Ok(()) }
+//! ```
+
+use crate::channel::Channel;
+use crate::framed::{self, Message};
+use crate::unix::PeerCred;
+use hyper_util::rt::TokioIo;
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf};
+use tokio::sync::mpsc;
+use tonic::codegen::http::Uri;
+
+/// How long the peer gets to answer while the channel is set up.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn io_error(e: crate::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Resets a signal eventfd that was reported readable. It is blocking, so check again first.
+fn reset(fd: RawFd) {
+    let mut pfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    if unsafe { libc::poll(&mut pfd, 1, 0) } <= 0 {
+        return;
+    }
+    let mut b = [0u8; 8];
+    unsafe { libc::read(fd, b.as_mut_ptr() as *mut _, 8) };
+}
+
+/// A byte stream over a channel, see the module documentation.
+pub struct ChannelStream {
+    tx: framed::Sender,
+    rx: framed::Receiver,
+    socket: AsyncFd<UnixStream>,
+    readable: AsyncFd<File>,
+    writable: AsyncFd<File>,
+    /// The message being read, and how much of it has been read.
+    reading: Option<(Message, usize)>,
+    hung_up: bool,
+    shut_down: bool,
+    peer_cred: Option<PeerCred>,
+}
+
+impl ChannelStream {
+    /// Sets up a channel with the peer at the other end of `socket`, who must call this too,
+    /// as with `Channel::connect`. Messages from this side can be up to about `capacity`
+    /// bytes; larger writes are split.
+    ///
+    /// Fails with `Unsupported` if the channel could not use shared memory, and with
+    /// `WouldBlock` if the peer does not answer within five seconds.
+    pub async fn connect(socket: UnixStream, capacity: usize) -> io::Result<Self> {
+        let peer_cred = crate::unix::peer_cred(&socket).ok();
+        let channel = tokio::task::spawn_blocking(move || {
+            socket.set_nonblocking(false)?;
+            socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+            socket.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+            Channel::connect(socket, capacity).map_err(io_error)
+        })
+        .await??;
+        let (socket, rings) = channel.into_parts();
+        let (tx, rx) = rings.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "no shared memory for the channel",
+            )
+        })?;
+        socket.set_read_timeout(None)?;
+        socket.set_write_timeout(None)?;
+        socket.set_nonblocking(true)?;
+        let signal = |f: &File| AsyncFd::with_interest(f.try_clone()?, Interest::READABLE);
+        Ok(ChannelStream {
+            readable: signal(rx.ring().empty_signal())?,
+            writable: signal(tx.ring().full_signal())?,
+            socket: AsyncFd::with_interest(socket, Interest::READABLE)?,
+            tx,
+            rx,
+            reading: None,
+            hung_up: false,
+            shut_down: false,
+            peer_cred,
+        })
+    }
+
+    /// Credentials of the peer, as taken by the kernel when the socket was connected.
+    pub fn peer_cred(&self) -> Option<PeerCred> {
+        self.peer_cred
+    }
+
+    /// Becomes ready once the peer has hung up, or shut down writing. Nothing else is sent
+    /// over the socket once the channel is set up.
+    fn poll_hung_up(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.socket.poll_read_ready(cx))?;
+            let mut b = [0u8];
+            let fd = self.socket.as_raw_fd();
+            let flags = libc::MSG_PEEK | libc::MSG_DONTWAIT;
+            let n = unsafe { libc::recv(fd, b.as_mut_ptr() as *mut _, 1, flags) };
+            if n == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if n > 0 {
+                let e = io::Error::new(io::ErrorKind::InvalidData, "data on the channel socket");
+                return Poll::Ready(Err(e));
+            }
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::WouldBlock => guard.clear_ready(),
+                io::ErrorKind::Interrupted => {}
+                _ => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+}
+
+impl AsyncRead for ChannelStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if let Some((m, pos)) = &mut this.reading {
+                let n = std::cmp::min(buf.remaining(), m.data().len() - *pos);
+                buf.put_slice(&m.data()[*pos..*pos + n]);
+                *pos += n;
+                if *pos == m.data().len() {
+                    this.reading = None;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(m) = this.rx.recv().map_err(io_error)? {
+                if !m.data().is_empty() {
+                    this.reading = Some((m, 0));
+                }
+                continue;
+            }
+            if this.hung_up {
+                return Poll::Ready(Ok(()));
+            }
+            // Whatever was sent before the peer hung up is in the ringbuffer by now, so
+            // look there once more before returning end of file.
+            if this.poll_hung_up(cx)?.is_ready() {
+                this.hung_up = true;
+                continue;
+            }
+            let mut guard = ready!(this.readable.poll_read_ready(cx))?;
+            reset(this.readable.as_raw_fd());
+            guard.clear_ready();
+        }
+    }
+}
+
+impl AsyncWrite for ChannelStream {
+    /// Writes what fits into one message. Only returns an error once `poll_shutdown` has
+    /// been called; if the peer has gone away, reading tells.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.shut_down {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let n = std::cmp::min(buf.len(), this.tx.max_message_size());
+        if n == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            if this.tx.send(&buf[..n]).map_err(io_error)? {
+                return Poll::Ready(Ok(n));
+            }
+            let mut guard = ready!(this.writable.poll_read_ready(cx))?;
+            reset(this.writable.as_raw_fd());
+            guard.clear_ready();
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Tells the peer that nothing more is coming, by shutting down writing on the socket.
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.shut_down {
+            this.socket.get_ref().shutdown(std::net::Shutdown::Write)?;
+            this.shut_down = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl tonic::transport::server::Connected for ChannelStream {
+    type ConnectInfo = Option<PeerCred>;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.peer_cred
+    }
+}
+
+/// Connects to a server taking channels from `incoming`, for
+/// `Endpoint::connect_with_connector`.
+#[derive(Clone, Debug)]
+pub struct Connector {
+    path: PathBuf,
+    capacity: usize,
+}
+
+impl Connector {
+    /// Connects to the unix socket at `path`, see `ChannelStream::connect` for `capacity`.
+    pub fn new<P: AsRef<Path>>(path: P, capacity: usize) -> Self {
+        Connector {
+            path: path.as_ref().into(),
+            capacity,
+        }
+    }
+}
+
+impl tonic::codegen::Service<Uri> for Connector {
+    type Response = TokioIo<ChannelStream>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// The URI is ignored; the channel goes to the socket given to `new`.
+    fn call(&mut self, _: Uri) -> Self::Future {
+        let (path, capacity) = (self.path.clone(), self.capacity);
+        Box::pin(async move {
+            let socket = tokio::net::UnixStream::connect(path).await?.into_std()?;
+            let stream = ChannelStream::connect(socket, capacity).await?;
+            Ok(TokioIo::new(stream))
+        })
+    }
+}
+
+/// Channels set up with clients connecting to a unix socket, for `serve_with_incoming`.
+///
+/// Created by `incoming`. Stops accepting when dropped.
+pub struct Incoming {
+    streams: mpsc::Receiver<io::Result<ChannelStream>>,
+    accept: tokio::task::JoinHandle<()>,
+}
+
+/// Accepts clients on `listener` and sets up a channel with each, see
+/// `ChannelStream::connect` for `capacity`.
+///
+/// Channels are set up concurrently, so that a client that does not answer holds up no
+/// one but itself. Those that fail come out as errors, which tonic skips.
+pub fn incoming(listener: tokio::net::UnixListener, capacity: usize) -> Incoming {
+    let (tx, streams) = mpsc::channel(16);
+    let accept = tokio::spawn(async move {
+        loop {
+            let socket = match listener.accept().await {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    if tx.send(Err(e)).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let stream = match socket.into_std() {
+                    Ok(socket) => ChannelStream::connect(socket, capacity).await,
+                    Err(e) => Err(e),
+                };
+                let _ = tx.send(stream).await;
+            });
+        }
+    });
+    Incoming { streams, accept }
+}
+
+impl tonic::codegen::tokio_stream::Stream for Incoming {
+    type Item = io::Result<ChannelStream>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.streams.poll_recv(cx)
+    }
+}
+
+impl Drop for Incoming {
+    fn drop(&mut self) {
+        self.accept.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn stream() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let peer = tokio::spawn(ChannelStream::connect(b, 4096));
+        let mut a = ChannelStream::connect(a, 4096).await.unwrap();
+        let mut b = peer.await.unwrap().unwrap();
+        assert_eq!(a.peer_cred().map(|c| c.pid), Some(std::process::id() as _));
+        let data: Vec<u8> = (0..20000u32).map(|i| i as u8).collect();
+        let writer = {
+            let data = data.clone();
+            tokio::spawn(async move {
+                a.write_all(&data).await.unwrap();
+                a.shutdown().await.unwrap();
+                a
+            })
+        };
+        let mut got = vec![];
+        b.read_to_end(&mut got).await.unwrap();
+        assert_eq!(got, data);
+        let mut a = writer.await.unwrap();
+        assert!(a.write(b"x").await.is_err());
+        b.write_all(b"back").await.unwrap();
+        drop(b);
+        let mut got = vec![];
+        a.read_to_end(&mut got).await.unwrap();
+        assert_eq!(got, b"back");
+    }
+
+    #[tokio::test]
+    async fn health_check() {
+        use tonic_health::pb::health_check_response::ServingStatus;
+        use tonic_health::pb::{health_client::HealthClient, HealthCheckRequest};
+        let dir = std::env::temp_dir().join(format!("shmem-ipc-grpc-{}", std::process::id()));
+        let _ = std::fs::remove_file(&dir);
+        let listener = tokio::net::UnixListener::bind(&dir).unwrap();
+        let (reporter, health) = tonic_health::server::health_reporter();
+        reporter
+            .set_service_status("test", tonic_health::ServingStatus::Serving)
+            .await;
+        let server = tonic::transport::Server::builder().add_service(health);
+        tokio::spawn(server.serve_with_incoming(incoming(listener, 1 << 16)));
+        let channel = tonic::transport::Endpoint::from_static("http://localhost")
+            .connect_with_connector(Connector::new(&dir, 1 << 16))
+            .await
+            .unwrap();
+        let mut client = HealthClient::new(channel);
+        let request = HealthCheckRequest {
+            service: "test".into(),
+        };
+        let status = client.check(request).await.unwrap().into_inner().status;
+        assert_eq!(status, ServingStatus::Serving as i32);
+        std::fs::remove_file(&dir).unwrap();
+    }
+}
@@ -0,0 +1,359 @@
+//! Lazily populated read-only areas, backed by userfaultfd.
+//!
+//! For huge areas of which the consumer only reads a small part, copying (or even mapping)
+//! all of it up front is wasteful. Instead, the consumer sets up an empty `Area` and hands
+//! its userfaultfd to the producer, which runs a `Server` that fills in every page the
+//! first time the consumer touches it.
+//!
+//! The information to be transferred from the consumer to the producer is:
+//!  * userfaultfd file descriptor
+//!  * base address and length of the area
+//!
+//! This needs userfaultfd to be available to unprivileged processes: either linux 5.11+
+//! (for user mode faults only), or the `vm.unprivileged_userfaultfd` sysctl.
+
+use super::{Error, Op};
+use crate::mem::{mfd, mmap};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+const UFFD_API: u64 = 0xaa;
+const UFFD_USER_MODE_ONLY: libc::c_int = 1;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+const UFFDIO_API: libc::c_ulong = 0xc018_aa3f;
+const UFFDIO_REGISTER: libc::c_ulong = 0xc020_aa00;
+const UFFDIO_COPY: libc::c_ulong = 0xc028_aa03;
+const UFFDIO_ZEROPAGE: libc::c_ulong = 0xc020_aa04;
+const MSG_SIZE: usize = 32;
+
+#[repr(C)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+#[repr(C)]
+struct UffdioZeropage {
+    range: UffdioRange,
+    mode: u64,
+    zeropage: i64,
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+fn ioctl<T>(fd: &File, req: libc::c_ulong, arg: &mut T) -> Result<(), std::io::Error> {
+    if unsafe { libc::ioctl(fd.as_raw_fd(), req as _, arg as *mut T) } < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn userfaultfd() -> Result<File, std::io::Error> {
+    let flags = libc::O_CLOEXEC | libc::O_NONBLOCK;
+    let mut fd = unsafe { libc::syscall(libc::SYS_userfaultfd, flags | UFFD_USER_MODE_ONLY) };
+    if fd < 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::EINVAL) {
+        // Kernel before 5.11
+        fd = unsafe { libc::syscall(libc::SYS_userfaultfd, flags) };
+    }
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let f = unsafe { File::from_raw_fd(fd as libc::c_int) };
+    let mut api = UffdioApi {
+        api: UFFD_API,
+        features: 0,
+        ioctls: 0,
+    };
+    ioctl(&f, UFFDIO_API, &mut api)?;
+    Ok(f)
+}
+
+/// The consumer side: an area of memory that is filled in by the producer on first access.
+pub struct Area {
+    ptr: *mut u8,
+    len: usize,
+    uffd: File,
+}
+
+impl Area {
+    /// Reserves an area of `len` bytes (rounded up to the page size), and registers it with
+    /// a new userfaultfd.
+    pub fn new(len: usize) -> Result<Self, Error> {
+        let ps = page_size();
+        let len = std::cmp::max(len.div_ceil(ps), 1) * ps;
+        let uffd = userfaultfd().map_err(Error::os(Op::Create, None))?;
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            Err(Error::os(Op::Map, None)(std::io::Error::last_os_error()))?
+        }
+        let area = Area {
+            ptr: ptr as *mut u8,
+            len,
+            uffd,
+        };
+        let mut reg = UffdioRegister {
+            range: UffdioRange {
+                start: ptr as u64,
+                len: len as u64,
+            },
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ioctls: 0,
+        };
+        ioctl(&area.uffd, UFFDIO_REGISTER, &mut reg).map_err(Error::os(Op::Map, None))?;
+        Ok(area)
+    }
+
+    /// The userfaultfd to hand to the producer.
+    pub fn uffd(&self) -> &File {
+        &self.uffd
+    }
+
+    /// The base address of the area, to hand to the producer.
+    pub fn base(&self) -> u64 {
+        self.ptr as u64
+    }
+
+    /// Size of the area.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the area has zero size.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Raw pointer to the area.
+    ///
+    /// Touching a page that the producer has not filled in yet blocks until it does.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    /// Copies data out of the area, waiting for the producer to fill it in where necessary.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), Error> {
+        match offset.checked_add(buf.len()) {
+            Some(end) if end <= self.len => {}
+            _ => Err(Error::OutOfBounds)?,
+        }
+        unsafe { std::ptr::copy_nonoverlapping(self.ptr.add(offset), buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    /// Returns a reference to the area.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that the producer is trusted to fill in pages with the intended
+    /// data; once filled in, pages are private to this process and do not change.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        std::slice::from_raw_parts(self.ptr, self.len)
+    }
+}
+
+impl Drop for Area {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut _, self.len) };
+    }
+}
+
+/// The producer side, which fills in the consumer's `Area` from a sealed memfd.
+pub struct Server {
+    uffd: File,
+    base: u64,
+    len: u64,
+    data: mmap::Mmap,
+}
+
+impl Server {
+    /// Sets up a server for the consumer's userfaultfd and area, serving data from `memfd`.
+    ///
+    /// The memfd is sealed read only, as with `mem::read_memfd`. Pages of the area beyond
+    /// the end of the data are filled with zeroes.
+    pub fn new(uffd: File, base: u64, len: u64, memfd: &mfd::Memfd) -> Result<Self, Error> {
+        let data = crate::mem::read_memfd(memfd)?;
+        Ok(Server {
+            uffd,
+            base,
+            len,
+            data,
+        })
+    }
+
+    /// The userfaultfd, which is readable when there are faults to serve.
+    pub fn uffd(&self) -> &File {
+        &self.uffd
+    }
+
+    fn fill(&self, addr: u64) -> Result<(), std::io::Error> {
+        let ps = page_size() as u64;
+        let page = addr & !(ps - 1);
+        if page < self.base || page >= self.base.saturating_add(self.len) {
+            // Not ours to fill in; the consumer lied about the area.
+            return Ok(());
+        }
+        let offset = (page - self.base) as usize;
+        let avail = self.data.len().saturating_sub(offset);
+        let r = if avail == 0 {
+            let mut z = UffdioZeropage {
+                range: UffdioRange {
+                    start: page,
+                    len: ps,
+                },
+                mode: 0,
+                zeropage: 0,
+            };
+            ioctl(&self.uffd, UFFDIO_ZEROPAGE, &mut z)
+        } else {
+            let mut tail;
+            let src = if avail >= ps as usize {
+                unsafe { self.data.as_ptr().add(offset) }
+            } else {
+                // The last, partial page of data
+                tail = vec![0u8; ps as usize];
+                tail[..avail].copy_from_slice(&self.data[offset..]);
+                tail.as_mut_ptr()
+            };
+            let mut c = UffdioCopy {
+                dst: page,
+                src: src as u64,
+                len: ps,
+                mode: 0,
+                copy: 0,
+            };
+            ioctl(&self.uffd, UFFDIO_COPY, &mut c)
+        };
+        match r {
+            // Already filled in, e g by a concurrent fault on the same page.
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+            x => x,
+        }
+    }
+
+    /// Serves all pending faults without blocking, and returns the number served.
+    ///
+    /// Returns `None` once the consumer has gone away.
+    pub fn serve_pending(&self) -> Result<Option<usize>, Error> {
+        let mut n = 0;
+        loop {
+            let mut msg = [0u8; MSG_SIZE];
+            let r = (&self.uffd).read(&mut msg);
+            match r {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(Some(n)),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Ok(0) => return Ok(None),
+                Ok(MSG_SIZE) => {}
+                x => {
+                    x.map_err(Error::os(Op::Signal, None))?;
+                    Err(crate::ringbuf::Error::BufCorrupt)?
+                }
+            }
+            if msg[0] != UFFD_EVENT_PAGEFAULT {
+                continue;
+            }
+            let addr = u64::from_ne_bytes(msg[16..24].try_into().unwrap());
+            self.fill(addr).map_err(Error::os(Op::Map, None))?;
+            n += 1;
+        }
+    }
+
+    /// Waits until there are faults to serve, or the timeout (in milliseconds, or -1 for
+    /// none) expires.
+    pub fn wait(&self, timeout_ms: i32) -> Result<bool, Error> {
+        let mut pfd = libc::pollfd {
+            fd: self.uffd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let r = unsafe { libc::poll(&mut pfd, 1, timeout_ms) };
+        if r < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            Err(Error::os(Op::Signal, None)(e))?
+        }
+        Ok(r > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn lazy_population() {
+        let area = match Area::new(1 << 20) {
+            Ok(a) => a,
+            // userfaultfd not allowed here
+            Err(_) => return,
+        };
+        let data = crate::mem::write_once(100000, "lazy", |x| {
+            for (i, b) in x.iter_mut().enumerate() {
+                *b = (i % 251) as u8;
+            }
+        })
+        .unwrap();
+        let uffd = area.uffd().try_clone().unwrap();
+        let server = Server::new(uffd, area.base(), area.len() as u64, &data).unwrap();
+        let done = AtomicBool::new(false);
+        let served = std::thread::scope(|s| {
+            let t = s.spawn(|| {
+                let mut served = 0;
+                while !done.load(Ordering::SeqCst) {
+                    if server.wait(10).unwrap() {
+                        served += server.serve_pending().unwrap().unwrap();
+                    }
+                }
+                served
+            });
+            let mut buf = [0u8; 4];
+            area.read(99998, &mut buf).unwrap();
+            assert_eq!(buf, [(99998 % 251) as u8, (99999 % 251) as u8, 0, 0]);
+            area.read(500000, &mut buf).unwrap();
+            assert_eq!(buf, [0; 4]);
+            done.store(true, Ordering::SeqCst);
+            t.join().unwrap()
+        });
+        assert_eq!(served, 2);
+    }
+}
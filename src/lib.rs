@@ -4,7 +4,8 @@
 //! and therefore, it works only on Linux.
 //!
 //! You might want to start in the `sharedring` module, which sets up a lock-free ringbuffer
-//! between untrusted processes. Another useful function is `mem::write_once` for a scenario where
+//! between untrusted processes, or in the `framed` module, which sends variable size messages
+//! over such a ringbuffer. Another useful function is `mem::write_once` for a scenario where
 //! you write data once and make it available for reading afterwards. The `mem` and `ringbuf`
 //! contain building blocks that might be useful in other use cases.
 //!
@@ -13,11 +14,131 @@
 
 pub mod mem;
 
+pub mod affinity;
+
+pub mod alloc;
+
+pub mod audit;
+
+pub mod cell;
+
+pub mod channel;
+
+pub mod collections;
+
+#[cfg(feature = "bytemuck")]
+pub mod compat;
+
+pub mod lazy;
+
+#[cfg(any(feature = "calloop", feature = "glib"))]
+pub mod mainloop;
+
+pub mod delta;
+
+pub mod media;
+
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+pub mod fanout;
+
+pub mod framed;
+
+#[cfg(feature = "tonic")]
+pub mod grpc;
+
+pub mod offsets;
+
+pub mod patterns;
+
+#[cfg(feature = "cgroup")]
+pub mod pressure;
+
+pub mod probe;
+
+pub mod pubsub;
+
+#[cfg(feature = "profiling")]
+pub mod profiling;
+
+pub mod quota;
+
+pub mod reactor;
+
+#[cfg(feature = "io-uring")]
+pub mod uring;
+
 pub mod ringbuf;
 
+pub mod sharded;
+
 pub mod sharedring;
 
+pub mod sgring;
+
+pub mod sim;
+
+pub mod sync;
+
+#[cfg(feature = "proptest")]
+pub mod testing;
+
+pub mod timer;
+
+pub mod unix;
+
+pub mod view;
+
+mod wire;
+
+/// The kind of operation that failed, see `Error::Os`.
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// Creating a memfd or eventfd, or sizing a memfd
+    Create,
+    /// Memory mapping a memfd
+    Map,
+    /// Reading or adding seals
+    Seal,
+    /// Signaling or waiting for the other side
+    Signal,
+    /// Attaching to file descriptors received from the other side
+    Attach,
+    /// Copying data into or out of a memfd
+    Copy,
+}
+
+impl std::fmt::Display for Op {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Op::Create => "Create",
+            Op::Map => "Map",
+            Op::Seal => "Seal",
+            Op::Signal => "Signal",
+            Op::Attach => "Attach",
+            Op::Copy => "Copy",
+        };
+        f.write_str(s)
+    }
+}
+
+fn fmt_name(name: &Option<String>) -> String {
+    name.as_ref()
+        .map(|n| format!(" for memfd {:?}", n))
+        .unwrap_or_default()
+}
+
+fn fmt_group(group: &Option<String>) -> String {
+    group
+        .as_ref()
+        .map(|g| format!(" for group {:?}", g))
+        .unwrap_or_default()
+}
+
 /// Enumeration of errors possible in this library
+#[non_exhaustive]
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Memfd errors {0:?}")]
@@ -26,4 +147,110 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error("Ringbuffer errors {0:?}")]
     Ringbuf(#[from] ringbuf::Error),
+    #[error("Range out of bounds")]
+    OutOfBounds,
+    #[error("Misaligned offset")]
+    Misaligned,
+    #[error("Invalid UTF-8 {0:?}")]
+    Utf8(#[from] std::str::Utf8Error),
+    #[error("Message too big")]
+    MessageTooBig,
+    #[error("No companion socket set up")]
+    NoSocket,
+    #[error("Rate limit exceeded")]
+    RateLimited,
+    #[error("Peer has not presented the channel token")]
+    Unauthenticated,
+    #[error("Peer presented a wrong channel token")]
+    BadToken,
+    #[error("Memory area truncated by peer")]
+    Truncated,
+    #[error("Contents do not match the digest")]
+    DigestMismatch,
+    #[error("Item modified by the writer while being read")]
+    TornRead,
+    #[error("Consumer has not fed the watchdog in time")]
+    ConsumerStuck,
+    #[error("Sender paused")]
+    Paused,
+    #[error("Peer requires unsupported features {unsupported:#x}")]
+    UnsupportedFeatures {
+        /// The required feature bits that are not understood
+        unsupported: u64,
+    },
+    #[error("Memory quota exceeded{}", fmt_group(group))]
+    QuotaExceeded {
+        /// The group whose limit was hit, or `None` for the process wide limit
+        group: Option<String>,
+    },
+    #[error("{op} failed{}: {source}", fmt_name(name))]
+    Os {
+        /// The operation that failed
+        op: Op,
+        /// Name of the memfd involved, if known
+        name: Option<String>,
+        source: std::io::Error,
+    },
+    #[error(
+        "Adding seals ({}) denied{}, present seals: {}{}",
+        mem::seal_names(*wanted),
+        fmt_name(name),
+        mem::seal_names(*present),
+        if *busy { ", blocked by a writable mapping" } else { "" }
+    )]
+    SealDenied {
+        /// Name of the memfd, if known
+        name: Option<String>,
+        /// The `F_SEAL_*` bits that could not be added
+        wanted: u32,
+        /// The `F_SEAL_*` bits present on the memfd
+        present: u32,
+        /// True if an existing writable mapping prevented write sealing
+        busy: bool,
+        source: std::io::Error,
+    },
+}
+
+impl Error {
+    pub(crate) fn os(op: Op, name: Option<String>) -> impl FnOnce(std::io::Error) -> Error {
+        move |source| Error::Os { op, name, source }
+    }
+
+    /// The operation that failed, for errors coming from the OS.
+    pub fn op(&self) -> Option<Op> {
+        match self {
+            Error::Os { op, .. } => Some(*op),
+            Error::SealDenied { .. } => Some(Op::Seal),
+            _ => None,
+        }
+    }
+
+    /// The underlying errno, for errors coming from the OS.
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            Error::Os { source, .. } | Error::SealDenied { source, .. } | Error::Io(source) => {
+                source.raw_os_error()
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns true for errors that might go away if the operation is retried later, e g
+    /// running out of memory or file descriptors, or a rate limit or memory quota.
+    ///
+    /// Everything else, in particular a misbehaving peer, is fatal for the channel.
+    pub fn is_transient(&self) -> bool {
+        if let Error::RateLimited | Error::QuotaExceeded { .. } = self {
+            return true;
+        }
+        matches!(
+            self.errno(),
+            Some(libc::EINTR)
+                | Some(libc::EAGAIN)
+                | Some(libc::ENOMEM)
+                | Some(libc::ENOBUFS)
+                | Some(libc::EMFILE)
+                | Some(libc::ENFILE)
+        )
+    }
 }
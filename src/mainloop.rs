@@ -0,0 +1,176 @@
+//! Waiting for channels in main loops other than `Reactor`, e g those of Wayland and GTK
+//! programs, which are usually built on calloop or glib.
+//!
+//! Both adapters take one of the signal eventfds of a channel, e g `Receiver::empty_signal`
+//! of a `sharedring`, reset it when it becomes readable, and then call back; the callback
+//! should then receive until there is nothing left. With the `calloop` feature, see
+//! `CalloopSignal`; with the `glib` feature, see `attach_glib`.
+
+use std::os::unix::io::RawFd;
+
+/// Resets an eventfd that was reported readable. It may be shared with other waiters, and
+/// be blocking, so check again first.
+fn reset(fd: RawFd) {
+    let mut pfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    if unsafe { libc::poll(&mut pfd, 1, 0) } <= 0 {
+        return;
+    }
+    let mut b = [0u8; 8];
+    unsafe { libc::read(fd, b.as_mut_ptr() as *mut _, 8) };
+}
+
+#[cfg(feature = "calloop")]
+pub use self::calloop_source::CalloopSignal;
+
+#[cfg(feature = "calloop")]
+mod calloop_source {
+    use calloop::generic::Generic;
+    use calloop::{EventSource, Interest, Mode, Poll, PostAction, Readiness, Token, TokenFactory};
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+
+    /// A calloop event source for a signal eventfd, see the module documentation.
+    ///
+    /// # Example
+    /// ```rust
+    /// use shmem_ipc::mainloop::CalloopSignal;
+    /// use shmem_ipc::sharedring::Receiver;
+    /// # let s = shmem_ipc::sharedring::Sender::<u64>::new(16).unwrap();
+    /// # let memfd = s.memfd().as_file().try_clone().unwrap();
+    /// # let (e, f) = (s.empty_signal().try_clone().unwrap(), s.full_signal().try_clone().unwrap());
+    /// let mut r: Receiver<u64> = Receiver::open(16, memfd, e, f).unwrap();
+    /// let mut event_loop = calloop::EventLoop::<Receiver<u64>>::try_new().unwrap();
+    /// let signal = CalloopSignal::new(r.empty_signal().try_clone().unwrap());
+    /// event_loop.handle().insert_source(signal, |(), _, r| {
+    ///     r.receive_raw(|_, count| count).unwrap();
+    /// }).unwrap();
+    /// event_loop.dispatch(Some(std::time::Duration::from_millis(1)), &mut r).unwrap();
+    /// ```
+    pub struct CalloopSignal {
+        inner: Generic<File>,
+    }
+
+    impl CalloopSignal {
+        /// Waits for `signal`, e g a duplicate of `Receiver::empty_signal`.
+        pub fn new(signal: File) -> Self {
+            CalloopSignal {
+                inner: Generic::new(signal, Interest::READ, Mode::Level),
+            }
+        }
+    }
+
+    impl EventSource for CalloopSignal {
+        type Event = ();
+        type Metadata = ();
+        type Ret = ();
+        type Error = std::io::Error;
+
+        fn process_events<F>(
+            &mut self,
+            readiness: Readiness,
+            token: Token,
+            mut callback: F,
+        ) -> Result<PostAction, Self::Error>
+        where
+            F: FnMut((), &mut ()),
+        {
+            self.inner.process_events(readiness, token, |_, file| {
+                super::reset(file.as_raw_fd());
+                callback((), &mut ());
+                Ok(PostAction::Continue)
+            })
+        }
+
+        fn register(&mut self, poll: &mut Poll, factory: &mut TokenFactory) -> calloop::Result<()> {
+            self.inner.register(poll, factory)
+        }
+
+        fn reregister(
+            &mut self,
+            poll: &mut Poll,
+            factory: &mut TokenFactory,
+        ) -> calloop::Result<()> {
+            self.inner.reregister(poll, factory)
+        }
+
+        fn unregister(&mut self, poll: &mut Poll) -> calloop::Result<()> {
+            self.inner.unregister(poll)
+        }
+    }
+}
+
+/// Calls `f` on `context` whenever `signal` is signaled, see the module documentation,
+/// until `f` returns `ControlFlow::Break` or the source is removed.
+///
+/// The source keeps `signal` open for as long as it exists.
+#[cfg(feature = "glib")]
+pub fn attach_glib<F>(
+    context: &glib::MainContext,
+    signal: std::fs::File,
+    mut f: F,
+) -> glib::SourceId
+where
+    F: FnMut() -> glib::ControlFlow + Send + 'static,
+{
+    use std::os::unix::io::AsRawFd;
+    let source = glib::unix_fd_source_new(
+        signal.as_raw_fd(),
+        glib::IOCondition::IN,
+        Some("shmem-ipc signal"),
+        glib::Priority::DEFAULT,
+        move |fd, _| {
+            let _open = &signal;
+            reset(fd);
+            f()
+        },
+    );
+    source.attach(Some(context))
+}
+
+#[cfg(all(test, feature = "calloop"))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn calloop_signal() {
+        let mut s = crate::sharedring::Sender::<u32>::new(16).unwrap();
+        let memfd = s.memfd().as_file().try_clone().unwrap();
+        let e = s.empty_signal().try_clone().unwrap();
+        let f = s.full_signal().try_clone().unwrap();
+        let r = crate::sharedring::Receiver::<u32>::open(16, memfd, e, f).unwrap();
+        let mut event_loop =
+            calloop::EventLoop::<(crate::sharedring::Receiver<u32>, usize)>::try_new().unwrap();
+        let signal = CalloopSignal::new(r.empty_signal().try_clone().unwrap());
+        event_loop
+            .handle()
+            .insert_source(signal, |(), _, (r, got)| {
+                r.receive_raw(|_, count| {
+                    *got += count;
+                    count
+                })
+                .unwrap();
+            })
+            .unwrap();
+        let mut state = (r, 0);
+        event_loop
+            .dispatch(Some(Duration::from_millis(1)), &mut state)
+            .unwrap();
+        assert_eq!(state.1, 0);
+        s.send_raw(|_, _| 3).unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_secs(5)), &mut state)
+            .unwrap();
+        assert_eq!(state.1, 3);
+        // Reset, so that it does not fire again.
+        event_loop
+            .dispatch(Some(Duration::from_millis(1)), &mut state)
+            .unwrap();
+        assert_eq!(state.1, 3);
+        assert_eq!(state.0.received(), 3);
+    }
+}
@@ -0,0 +1,204 @@
+//! Helpers for sharing memory with graphics and media stacks.
+//!
+//! GPU drivers and display servers have their own ideas about which seals a memfd
+//! should (and should not) have, so the helpers here differ slightly from `mem::write_once`.
+
+use super::Error;
+use crate::mem::{mfd, mmap};
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+/// Mirrors `struct udmabuf_create` from `linux/udmabuf.h`.
+#[repr(C)]
+struct UdmabufCreate {
+    memfd: u32,
+    flags: u32,
+    offset: u64,
+    size: u64,
+}
+
+const UDMABUF_FLAGS_CLOEXEC: u32 = 0x01;
+
+/// `_IOW('u', 0x42, struct udmabuf_create)`
+const UDMABUF_CREATE: libc::c_ulong = (1 << 30)
+    | ((std::mem::size_of::<UdmabufCreate>() as libc::c_ulong) << 16)
+    | ((b'u' as libc::c_ulong) << 8)
+    | 0x42;
+
+/// Exports a range of a memfd as a dmabuf, through the `/dev/udmabuf` device.
+///
+/// The kernel requires the memfd to be sealed against shrinking, and to *not* be sealed against
+/// writing. The shrink seal is added if missing. Offset and size must be page aligned.
+///
+/// The returned dmabuf file descriptor can be imported by GPU drivers, display servers etc.
+pub fn udmabuf(memfd: &mfd::Memfd, offset: u64, size: u64) -> Result<File, Error> {
+    crate::mem::verify_seal(memfd, mfd::FileSeal::SealShrink)?;
+    let dev = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/udmabuf")?;
+    let create = UdmabufCreate {
+        memfd: memfd.as_raw_fd() as u32,
+        flags: UDMABUF_FLAGS_CLOEXEC,
+        offset,
+        size,
+    };
+    let fd = unsafe { libc::ioctl(dev.as_raw_fd(), UDMABUF_CREATE as _, &create) };
+    if fd < 0 {
+        Err(std::io::Error::last_os_error())?
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Like `mem::write_once`, but also exports the whole area as a dmabuf.
+///
+/// Because udmabuf refuses memfds sealed against writing, the memfd is sealed against
+/// growing, shrinking and further sealing only. Hand out the returned dmabuf rather than the
+/// memfd to peers that should not be able to modify the data.
+///
+/// Size must be a multiple of the page size.
+pub fn write_once_udmabuf<F: FnOnce(&mut [u8])>(
+    size: u64,
+    name: &str,
+    f: F,
+) -> Result<(mfd::Memfd, File), Error> {
+    let opts = crate::mem::CreateOptions::default();
+    let mut h = mfd::SealsHashSet::new();
+    h.insert(mfd::FileSeal::SealGrow);
+    h.insert(mfd::FileSeal::SealShrink);
+    h.insert(mfd::FileSeal::SealSeal);
+
+    let memfd = crate::mem::write_once_with(size, name, opts, &h, f)?.into_memfd();
+    let dmabuf = udmabuf(&memfd, 0, size)?;
+    Ok((memfd, dmabuf))
+}
+
+/// A memory pool suitable for backing Wayland `wl_shm` buffers.
+///
+/// Created by `wl_shm_pool`.
+pub struct ShmPool {
+    memfd: mfd::Memfd,
+    mmap: mmap::MmapRaw,
+    charge: crate::quota::Charge,
+}
+
+/// Creates a memfd and mapping suitable for a Wayland `wl_shm_pool`.
+///
+/// The memfd is sealed against shrinking, so that the compositor cannot get a SIGBUS, but it
+/// can still grow, as `wl_shm_pool.resize` requires. Allocation is sparse: pages are only
+/// backed by memory once they are written to.
+pub fn wl_shm_pool(size: usize) -> Result<ShmPool, Error> {
+    let charge = crate::quota::Charge::new(None, size as u64)?;
+    let memfd = crate::mem::CreateOptions::default().create("wl_shm")?;
+    memfd.as_file().set_len(size as u64)?;
+    let mut h = mfd::SealsHashSet::new();
+    h.insert(mfd::FileSeal::SealShrink);
+    h.insert(mfd::FileSeal::SealSeal);
+    crate::mem::add_seals(&memfd, crate::mem::seal_set_bits(&h))?;
+    let mmap = crate::mem::raw_memfd(&memfd, size)?;
+    Ok(ShmPool {
+        memfd,
+        mmap,
+        charge,
+    })
+}
+
+impl ShmPool {
+    /// The file descriptor to send to the compositor in `wl_shm.create_pool`.
+    pub fn memfd(&self) -> &mfd::Memfd {
+        &self.memfd
+    }
+
+    /// Current size of the pool, in bytes.
+    pub fn len(&self) -> usize {
+        self.mmap.len()
+    }
+
+    /// Returns true if the pool has zero size.
+    pub fn is_empty(&self) -> bool {
+        self.mmap.len() == 0
+    }
+
+    /// Grows the pool and remaps it. Send `wl_shm_pool.resize` to the compositor afterwards.
+    ///
+    /// Shrinking is refused by the kernel.
+    pub fn resize(&mut self, size: usize) -> Result<(), Error> {
+        self.charge
+            .resize(std::cmp::max(size, self.mmap.len()) as u64)?;
+        self.memfd.as_file().set_len(size as u64)?;
+        self.mmap = crate::mem::raw_memfd(&self.memfd, size)?;
+        Ok(())
+    }
+
+    /// Returns a pointer to a buffer within the pool, after checking that the range is
+    /// within bounds.
+    ///
+    /// The compositor may map the pool writable, so we cannot hand out references to the data.
+    pub fn buffer(&self, offset: usize, len: usize) -> Result<*mut u8, Error> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.mmap.len() => {
+                Ok(unsafe { self.mmap.as_mut_ptr().add(offset) })
+            }
+            _ => Err(Error::OutOfBounds),
+        }
+    }
+
+    /// Copies data into the pool at the given offset.
+    pub fn write(&self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        let p = self.buffer(offset, data.len())?;
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), p, data.len()) };
+        Ok(())
+    }
+
+    /// Returns a mutable slice of a buffer within the pool.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that no one else (including the compositor) reads from or writes
+    /// to the range while the slice is alive, e g by waiting for `wl_buffer.release`.
+    pub unsafe fn buffer_mut(&mut self, offset: usize, len: usize) -> Result<&mut [u8], Error> {
+        let p = self.buffer(offset, len)?;
+        Ok(std::slice::from_raw_parts_mut(p, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_once_udmabuf_test() -> Result<(), Error> {
+        if !std::path::Path::new("/dev/udmabuf").exists() {
+            return Ok(());
+        }
+        let (memfd, dmabuf) = write_once_udmabuf(4096, "udmabuf_test", |x| x[5] = 7)?;
+        assert!(!memfd.seals()?.contains(&mfd::FileSeal::SealWrite));
+        assert_eq!(dmabuf.metadata()?.len(), 4096);
+        Ok(())
+    }
+
+    #[test]
+    fn wl_shm_pool_test() -> Result<(), Error> {
+        let mut pool = wl_shm_pool(4096)?;
+        assert_eq!(pool.len(), 4096);
+        pool.write(4000, &[1, 2, 3])?;
+        assert!(pool.write(4095, &[1, 2]).is_err());
+        assert!(pool.resize(2048).is_err());
+        pool.resize(8192)?;
+        pool.write(8190, &[4, 5])?;
+        let m = unsafe { pool.buffer_mut(4000, 3)? };
+        assert_eq!(m, &[1, 2, 3]);
+        assert!(pool.memfd().seals()?.contains(&mfd::FileSeal::SealShrink));
+        Ok(())
+    }
+
+    #[test]
+    fn udmabuf_rejects_write_sealed() -> Result<(), Error> {
+        if !std::path::Path::new("/dev/udmabuf").exists() {
+            return Ok(());
+        }
+        let memfd = crate::mem::write_once(4096, "udmabuf_sealed", |_| {})?;
+        assert!(udmabuf(&memfd, 0, 4096).is_err());
+        Ok(())
+    }
+}
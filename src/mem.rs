@@ -10,39 +10,348 @@ pub mod mfd {
     pub use memfd::*;
 }
 
-use super::Error;
+use super::{Error, Op};
+use std::os::unix::io::FromRawFd;
 
-fn verify_seal(memfd: &mfd::Memfd, seal: mfd::FileSeal) -> Result<(), Error> {
-    let seals = memfd.seals()?;
-    if seals.contains(&seal) {
+const MFD_CLOEXEC: libc::c_uint = 0x1;
+const MFD_ALLOW_SEALING: libc::c_uint = 0x2;
+const MFD_HUGETLB: libc::c_uint = 0x4;
+const MFD_NOEXEC_SEAL: libc::c_uint = 0x8;
+const MFD_EXEC: libc::c_uint = 0x10;
+const MFD_HUGE_SHIFT: libc::c_uint = 26;
+const MADV_POPULATE_READ: libc::c_int = 22;
+const MADV_POPULATE_WRITE: libc::c_int = 23;
+
+/// Whether a memfd may be executable.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Exec {
+    /// Not executable, and sealed so it can never become executable (`MFD_NOEXEC_SEAL`).
+    NoExecSeal,
+    /// Executable (`MFD_EXEC`).
+    Exec,
+    /// No flag given; the kernel decides, see the `vm.memfd_noexec` sysctl.
+    Unspecified,
+}
+
+/// Options for creating memfds.
+///
+/// Like `mfd::MemfdOptions`, but with an exec policy, and defaults suitable for IPC:
+/// sealing allowed, close-on-exec, and `Exec::NoExecSeal`.
+#[derive(Clone, Debug)]
+pub struct CreateOptions {
+    allow_sealing: bool,
+    close_on_exec: bool,
+    hugetlb: Option<mfd::HugetlbSize>,
+    exec: Exec,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        CreateOptions {
+            allow_sealing: true,
+            close_on_exec: true,
+            hugetlb: None,
+            exec: Exec::NoExecSeal,
+        }
+    }
+}
+
+fn huge_shift(size: mfd::HugetlbSize) -> libc::c_uint {
+    use mfd::HugetlbSize::*;
+    match size {
+        Huge64KB => 16,
+        Huge512KB => 19,
+        Huge1MB => 20,
+        Huge2MB => 21,
+        Huge8MB => 23,
+        Huge16MB => 24,
+        Huge256MB => 28,
+        Huge1GB => 30,
+        Huge2GB => 31,
+        Huge16GB => 34,
+    }
+}
+
+impl CreateOptions {
+    /// Same as `default`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether to allow adding seals to the memfd.
+    pub fn allow_sealing(mut self, value: bool) -> Self {
+        self.allow_sealing = value;
+        self
+    }
+
+    /// Whether to set close-on-exec on the memfd.
+    pub fn close_on_exec(mut self, value: bool) -> Self {
+        self.close_on_exec = value;
+        self
+    }
+
+    /// Optional hugetlb support and page size.
+    pub fn hugetlb(mut self, size: Option<mfd::HugetlbSize>) -> Self {
+        self.hugetlb = size;
+        self
+    }
+
+    /// Whether the memfd may be executable.
+    pub fn exec(mut self, exec: Exec) -> Self {
+        self.exec = exec;
+        self
+    }
+
+    fn flags(&self) -> libc::c_uint {
+        let mut flags = 0;
+        if self.allow_sealing {
+            flags |= MFD_ALLOW_SEALING;
+        }
+        if self.close_on_exec {
+            flags |= MFD_CLOEXEC;
+        }
+        if let Some(size) = self.hugetlb {
+            flags |= MFD_HUGETLB | huge_shift(size) << MFD_HUGE_SHIFT;
+        }
+        flags
+    }
+
+    /// Creates the memfd.
+    ///
+    /// On kernels older than 6.3, which do not know about the exec flags, the memfd is
+    /// created without them.
+    pub fn create(&self, name: &str) -> Result<mfd::Memfd, Error> {
+        let err = |e| Error::os(Op::Create, Some(name.into()))(e);
+        let cname = std::ffi::CString::new(name)
+            .map_err(|e| err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)))?;
+        let flags = self.flags();
+        let exec_flags = match self.exec {
+            Exec::NoExecSeal => MFD_NOEXEC_SEAL,
+            Exec::Exec => MFD_EXEC,
+            Exec::Unspecified => 0,
+        };
+        let create = |flags: libc::c_uint| unsafe {
+            libc::syscall(libc::SYS_memfd_create, cname.as_ptr(), flags) as libc::c_int
+        };
+        let mut fd = create(flags | exec_flags);
+        if fd < 0
+            && exec_flags != 0
+            && std::io::Error::last_os_error().raw_os_error() == Some(libc::EINVAL)
+        {
+            fd = create(flags);
+        }
+        if fd < 0 {
+            Err(err(std::io::Error::last_os_error()))?
+        }
+        let file = unsafe { std::fs::File::from_raw_fd(fd) };
+        Ok(mfd::Memfd::try_from_file(file).expect("memfd_create did not return a memfd"))
+    }
+}
+
+fn seal_bits(seal: mfd::FileSeal) -> u32 {
+    (match seal {
+        mfd::FileSeal::SealSeal => libc::F_SEAL_SEAL,
+        mfd::FileSeal::SealShrink => libc::F_SEAL_SHRINK,
+        mfd::FileSeal::SealGrow => libc::F_SEAL_GROW,
+        mfd::FileSeal::SealWrite => libc::F_SEAL_WRITE,
+    }) as u32
+}
+
+pub(crate) fn seal_set_bits(seals: &mfd::SealsHashSet) -> u32 {
+    seals.iter().fold(0, |acc, &s| acc | seal_bits(s))
+}
+
+/// Formats a set of `F_SEAL_*` bits, as found in `Error::SealDenied`.
+pub fn seal_names(seals: u32) -> String {
+    let names = [
+        (libc::F_SEAL_SEAL, "seal"),
+        (libc::F_SEAL_SHRINK, "shrink"),
+        (libc::F_SEAL_GROW, "grow"),
+        (libc::F_SEAL_WRITE, "write"),
+        (libc::F_SEAL_FUTURE_WRITE, "future_write"),
+        (libc::F_SEAL_EXEC, "exec"),
+    ];
+    let v: Vec<_> = names
+        .iter()
+        .filter(|(bit, _)| seals & *bit as u32 != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    if v.is_empty() {
+        "none".into()
+    } else {
+        v.join("|")
+    }
+}
+
+/// The name a memfd was created with, as seen in `/proc/self/fd`.
+pub(crate) fn memfd_name(memfd: &mfd::Memfd) -> Option<String> {
+    use std::os::unix::io::AsRawFd;
+    let link = std::fs::read_link(format!("/proc/self/fd/{}", memfd.as_raw_fd())).ok()?;
+    let link = link.to_str()?;
+    let name = link.strip_prefix("/memfd:")?;
+    Some(name.strip_suffix(" (deleted)").unwrap_or(name).into())
+}
+
+/// Turns a file received from the other side into a memfd.
+pub(crate) fn memfd_from_file(file: std::fs::File) -> Result<mfd::Memfd, Error> {
+    mfd::Memfd::try_from_file(file)
+        .map_err(|_| Error::os(Op::Attach, None)(std::io::Error::from_raw_os_error(libc::EINVAL)))
+}
+
+fn raw_seals(memfd: &mfd::Memfd) -> Result<u32, Error> {
+    use std::os::unix::io::AsRawFd;
+    let r = unsafe { libc::fcntl(memfd.as_raw_fd(), libc::F_GET_SEALS) };
+    if r < 0 {
+        Err(Error::os(Op::Seal, memfd_name(memfd))(
+            std::io::Error::last_os_error(),
+        ))?
+    }
+    Ok(r as u32)
+}
+
+/// Sets or clears close-on-exec on a file descriptor, e g to let an exec'd child inherit
+/// a memfd on purpose.
+pub fn set_close_on_exec<F: std::os::unix::io::AsRawFd>(fd: &F, value: bool) -> Result<(), Error> {
+    let fd = fd.as_raw_fd();
+    let e = |_| Error::os(Op::Create, None)(std::io::Error::last_os_error());
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 {
+        Err(e(()))?
+    }
+    let flags = if value {
+        flags | libc::FD_CLOEXEC
+    } else {
+        flags & !libc::FD_CLOEXEC
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } < 0 {
+        Err(e(()))?
+    }
+    Ok(())
+}
+
+/// Adds the `F_SEAL_*` bits that are not already present.
+///
+/// On failure, tells which of the seals were denied and why.
+pub(crate) fn add_seals(memfd: &mfd::Memfd, wanted: u32) -> Result<(), Error> {
+    use std::os::unix::io::AsRawFd;
+    let present = raw_seals(memfd)?;
+    let missing = wanted & !present;
+    if missing == 0 {
         return Ok(());
     }
-    // Try to add the seal.
-    memfd.add_seal(seal)?;
+    if unsafe { libc::fcntl(memfd.as_raw_fd(), libc::F_ADD_SEALS, missing as libc::c_int) } < 0 {
+        let source = std::io::Error::last_os_error();
+        Err(Error::SealDenied {
+            name: memfd_name(memfd),
+            wanted: missing,
+            present,
+            busy: source.raw_os_error() == Some(libc::EBUSY),
+            source,
+        })?
+    }
     Ok(())
 }
 
+pub(crate) fn verify_seal(memfd: &mfd::Memfd, seal: mfd::FileSeal) -> Result<(), Error> {
+    let r = add_seals(memfd, seal_bits(seal));
+    if let Err(Error::SealDenied {
+        name,
+        wanted,
+        present,
+        ..
+    }) = &r
+    {
+        crate::audit::report(|| crate::audit::Event::SealDenied {
+            name: name.clone(),
+            wanted: *wanted,
+            present: *present,
+        });
+    }
+    r
+}
+
 /// Creates a memory map of a memfd. The memfd is sealed to be read only.
 pub fn read_memfd(memfd: &mfd::Memfd) -> Result<mmap::Mmap, Error> {
     // The file can be truncated; no safe memory mapping.
-    verify_seal(&memfd, mfd::FileSeal::SealShrink)?;
+    verify_seal(memfd, mfd::FileSeal::SealShrink)?;
     // The file can be written to; no safe references.
-    verify_seal(&memfd, mfd::FileSeal::SealWrite)?;
+    verify_seal(memfd, mfd::FileSeal::SealWrite)?;
 
-    let r = unsafe { mmap::MmapOptions::new().map_copy_read_only(memfd.as_file()) }?;
-    Ok(r)
+    let r = unsafe { mmap::MmapOptions::new().map_copy_read_only(memfd.as_file()) };
+    r.map_err(|e| Error::os(Op::Map, memfd_name(memfd))(e))
 }
 
 /// Creates a raw memory map of a memfd, suitable for IPC. It must be writable.
 pub fn raw_memfd(memfd: &mfd::Memfd, len: usize) -> Result<mmap::MmapRaw, Error> {
     // The file can be truncated; no safe memory mapping.
-    verify_seal(&memfd, mfd::FileSeal::SealShrink)?;
+    verify_seal(memfd, mfd::FileSeal::SealShrink)?;
 
     // If the file has been sealed as read-only, the below will fail.
     // If the file later is trying to be sealed as read-only, that call will fail and
     // our mapping will remain.
 
-    Ok(mmap::MmapOptions::new().len(len).map_raw(memfd.as_file())?)
+    let r = mmap::MmapOptions::new().len(len).map_raw(memfd.as_file());
+    r.map_err(|e| Error::os(Op::Map, memfd_name(memfd))(e))
+}
+
+fn prefault(ptr: *const u8, len: usize, write: bool) -> Result<(), Error> {
+    let advice = if write {
+        MADV_POPULATE_WRITE
+    } else {
+        MADV_POPULATE_READ
+    };
+    if unsafe { libc::madvise(ptr as *mut _, len, advice) } == 0 {
+        return Ok(());
+    }
+    let e = std::io::Error::last_os_error();
+    if e.raw_os_error() != Some(libc::EINVAL) {
+        Err(Error::os(Op::Map, None)(e))?
+    }
+    // Linux before 5.14: touch every page ourselves. Writing has to be an atomic no-op,
+    // since the peer might be writing to the same page concurrently.
+    let ps = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+    for offset in (0..len).step_by(ps) {
+        let p = unsafe { ptr.add(offset) };
+        if write {
+            let a = unsafe { &*(p as *const std::sync::atomic::AtomicU8) };
+            a.fetch_or(0, std::sync::atomic::Ordering::Relaxed);
+        } else {
+            unsafe { std::ptr::read_volatile(p) };
+        }
+    }
+    Ok(())
+}
+
+/// Faults in all pages of a writable mapping, so that the page fault cost is paid now
+/// rather than on first access.
+///
+/// For a memfd, this allocates the backing memory too.
+pub fn touch_all(mmap: &mmap::MmapRaw) -> Result<(), Error> {
+    prefault(mmap.as_ptr(), mmap.len(), true)
+}
+
+/// Faults in all pages of a read-only mapping, e g one returned by `read_memfd`.
+pub fn touch_all_read(map: &[u8]) -> Result<(), Error> {
+    prefault(map.as_ptr(), map.len(), false)
+}
+
+/// Returns how many bytes of a mapping are resident in memory, as opposed to its size.
+///
+/// For a memfd mapping, this counts pages that are allocated, whether this process has
+/// touched them or not; memfds are sparse, so untouched pages cost nothing.
+pub fn resident_bytes(mmap: &mmap::MmapRaw) -> Result<usize, Error> {
+    let ps = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+    let mut v = vec![0u8; mmap.len().div_ceil(ps)];
+    if unsafe { libc::mincore(mmap.as_mut_ptr() as *mut _, mmap.len(), v.as_mut_ptr()) } < 0 {
+        Err(Error::os(Op::Map, None)(std::io::Error::last_os_error()))?
+    }
+    Ok(v.iter().filter(|x| *x & 1 != 0).count() * ps)
+}
+
+/// Returns how many bytes of memory are allocated for a memfd, regardless of mappings.
+pub fn allocated_bytes(memfd: &mfd::Memfd) -> Result<u64, Error> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(memfd.as_file().metadata()?.blocks() * 512)
 }
 
 /// Creates a shared memory area that can be written once and read many times.
@@ -63,43 +372,492 @@ pub fn raw_memfd(memfd: &mfd::Memfd, len: usize) -> Result<mmap::MmapRaw, Error>
 /// // Read the data
 /// for (i, j) in map.iter().enumerate() { assert_eq!(i as u8, *j); }
 /// ```
+///
+/// The memfd is charged against the quota while it is written, but not after it is
+/// returned, see `write_once_with` for one that stays charged.
 pub fn write_once<F: FnOnce(&mut [u8])>(size: u64, name: &str, f: F) -> Result<mfd::Memfd, Error> {
-    let opts = memfd::MemfdOptions::new()
-        .allow_sealing(true)
-        .close_on_exec(true);
+    let opts = CreateOptions::default();
     let mut h = mfd::SealsHashSet::new();
     h.insert(mfd::FileSeal::SealGrow);
     h.insert(mfd::FileSeal::SealShrink);
     h.insert(mfd::FileSeal::SealSeal);
     h.insert(mfd::FileSeal::SealWrite);
 
-    write_once_custom(size, name, opts, &h, f)
+    write_once_with(size, name, opts, &h, f).map(ChargedMemfd::into_memfd)
 }
 
 /// Like "write_once", but allows for customisation of the memfd_options and seals added after writing.
+///
+/// `mfd::MemfdOptions` cannot pass an exec policy, so the kernel decides whether the memfd
+/// is executable; use `write_once_with` to have it sealed against that.
 pub fn write_once_custom<F: FnOnce(&mut [u8])>(
     size: u64,
     name: &str,
-    memfd_options: memfd::MemfdOptions,
+    memfd_options: mfd::MemfdOptions,
     seals: &mfd::SealsHashSet,
     f: F,
 ) -> Result<mfd::Memfd, Error> {
+    let charge = crate::quota::Charge::new(None, size)?;
     let memfd = memfd_options.create(name)?;
+    fill_and_seal(memfd, charge, size, name, seals, f).map(ChargedMemfd::into_memfd)
+}
+
+/// Like "write_once_custom", but with `CreateOptions`, which default to `Exec::NoExecSeal`.
+///
+/// The memfd stays charged against the quota until the returned `ChargedMemfd` is dropped.
+pub fn write_once_with<F: FnOnce(&mut [u8])>(
+    size: u64,
+    name: &str,
+    options: CreateOptions,
+    seals: &mfd::SealsHashSet,
+    f: F,
+) -> Result<ChargedMemfd, Error> {
+    let charge = crate::quota::Charge::new(None, size)?;
+    let memfd = options.create(name)?;
+    fill_and_seal(memfd, charge, size, name, seals, f)
+}
+
+/// A memfd together with its charge against the quota, see the `quota` module.
+///
+/// Created by `write_once_with`.
+#[derive(Debug)]
+pub struct ChargedMemfd {
+    memfd: mfd::Memfd,
+    _charge: crate::quota::Charge,
+}
+
+impl ChargedMemfd {
+    /// The memfd, e g to hand out to peers.
+    pub fn memfd(&self) -> &mfd::Memfd {
+        &self.memfd
+    }
+
+    /// Releases the charge and returns the memfd.
+    pub fn into_memfd(self) -> mfd::Memfd {
+        self.memfd
+    }
+}
+
+fn fill_and_seal<F: FnOnce(&mut [u8])>(
+    memfd: mfd::Memfd,
+    charge: crate::quota::Charge,
+    size: u64,
+    name: &str,
+    seals: &mfd::SealsHashSet,
+    f: F,
+) -> Result<ChargedMemfd, Error> {
     // Sets the memory to zeroes.
-    memfd.as_file().set_len(size)?;
+    memfd
+        .as_file()
+        .set_len(size)
+        .map_err(Error::os(Op::Create, Some(name.into())))?;
     // We're the sole owner of the file descriptor, it's safe to create a mutable reference to the data.
-    let mut m = unsafe { mmap::MmapMut::map_mut(memfd.as_file())? };
+    let mut m = unsafe { mmap::MmapMut::map_mut(memfd.as_file()) }
+        .map_err(Error::os(Op::Map, Some(name.into())))?;
     f(&mut m);
     drop(m);
     if !seals.is_empty() {
-        memfd.add_seals(seals)?;
+        add_seals(&memfd, seal_set_bits(seals))?;
+    }
+    Ok(ChargedMemfd {
+        memfd,
+        _charge: charge,
+    })
+}
+
+/// Size of the chunks that `write_once_hashed` fills and hashes in turn.
+#[cfg(feature = "sha2")]
+pub const HASH_CHUNK: usize = 1 << 20;
+
+/// Like `write_once`, but also returns the SHA-256 digest of the contents, for the receiver
+/// to check with `read_memfd_verified`.
+///
+/// The closure is called for one chunk of `HASH_CHUNK` bytes at a time (the last one may be
+/// shorter), together with the chunk's offset. Each chunk is hashed right after it has been
+/// filled, while it is still in the cache, so there is no second pass over the data.
+///
+/// Requires the `sha2` feature.
+#[cfg(feature = "sha2")]
+pub fn write_once_hashed<F: FnMut(usize, &mut [u8])>(
+    size: u64,
+    name: &str,
+    mut f: F,
+) -> Result<(mfd::Memfd, [u8; 32]), Error> {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    let memfd = write_once(size, name, |data| {
+        for (i, chunk) in data.chunks_mut(HASH_CHUNK).enumerate() {
+            f(i * HASH_CHUNK, chunk);
+            hasher.update(&*chunk);
+        }
+    })?;
+    Ok((memfd, hasher.finalize().into()))
+}
+
+/// Like `read_memfd`, but fails with `DigestMismatch` unless the contents have the given
+/// SHA-256 digest.
+///
+/// The memfd is sealed against writes before it is hashed, so the contents cannot change
+/// after having been checked. Requires the `sha2` feature.
+#[cfg(feature = "sha2")]
+pub fn read_memfd_verified(memfd: &mfd::Memfd, digest: &[u8; 32]) -> Result<mmap::Mmap, Error> {
+    use sha2::Digest;
+    let map = read_memfd(memfd)?;
+    if sha2::Sha256::digest(&map[..]).as_slice() != digest {
+        Err(Error::DigestMismatch)?
+    }
+    Ok(map)
+}
+
+fn would_block(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::WouldBlock
+}
+
+/// Moves up to `len` bytes from `src` into the memfd at `offset`, without copying the data
+/// through userspace.
+///
+/// Uses `splice` if `src` is a pipe, and `copy_file_range` otherwise, reading from the
+/// current position of `src`. If the kernel cannot do that between these two files, falls
+/// back to a plain read and write. The memfd grows as needed, unless it is sealed.
+///
+/// Returns the number of bytes moved, which is less than `len` at end of input, or if `src`
+/// is non-blocking and has no more data right now.
+pub fn copy_into<F: std::os::unix::io::AsRawFd>(
+    memfd: &mfd::Memfd,
+    offset: u64,
+    src: &F,
+    len: usize,
+) -> Result<usize, Error> {
+    use std::os::unix::io::AsRawFd;
+    let err = |e| Error::os(Op::Copy, memfd_name(memfd))(e);
+    let (fd_in, fd_out) = (src.as_raw_fd(), memfd.as_file().as_raw_fd());
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd_in, &mut st) } < 0 {
+        Err(err(std::io::Error::last_os_error()))?
+    }
+    let pipe = st.st_mode & libc::S_IFMT == libc::S_IFIFO;
+    let mut done = 0;
+    let mut fallback = false;
+    while done < len {
+        let mut off = (offset + done as u64) as libc::loff_t;
+        let r = if fallback {
+            let mut buf = vec![0u8; std::cmp::min(len - done, 65536)];
+            let src = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(fd_in) });
+            match std::io::Read::read(&mut &*src, &mut buf) {
+                Ok(n) => {
+                    use std::os::unix::fs::FileExt;
+                    memfd
+                        .as_file()
+                        .write_all_at(&buf[..n], off as u64)
+                        .map_err(err)?;
+                    Ok(n)
+                }
+                Err(e) => Err(e),
+            }
+        } else {
+            let r = unsafe {
+                if pipe {
+                    let flags = libc::SPLICE_F_MOVE;
+                    libc::splice(
+                        fd_in,
+                        std::ptr::null_mut(),
+                        fd_out,
+                        &mut off,
+                        len - done,
+                        flags,
+                    )
+                } else {
+                    libc::copy_file_range(
+                        fd_in,
+                        std::ptr::null_mut(),
+                        fd_out,
+                        &mut off,
+                        len - done,
+                        0,
+                    )
+                }
+            };
+            if r < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(r as usize)
+            }
+        };
+        match r {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) if would_block(&e) => break,
+            Err(e)
+                if !fallback
+                    && [libc::EXDEV, libc::EINVAL, libc::ENOSYS, libc::EOPNOTSUPP]
+                        .contains(&e.raw_os_error().unwrap_or(0)) =>
+            {
+                fallback = true
+            }
+            Err(e) => Err(err(e))?,
+        }
+    }
+    Ok(done)
+}
+
+/// Sends up to `len` bytes of the memfd, starting at `offset`, to a socket (or any other
+/// file) with `sendfile`, without copying the data through userspace.
+///
+/// Returns the number of bytes sent, which is less than `len` at the end of the memfd, or
+/// if `dst` is non-blocking and full.
+pub fn send_from<F: std::os::unix::io::AsRawFd>(
+    memfd: &mfd::Memfd,
+    offset: u64,
+    dst: &F,
+    len: usize,
+) -> Result<usize, Error> {
+    use std::os::unix::io::AsRawFd;
+    let mut off = offset as libc::off_t;
+    let mut done = 0;
+    while done < len {
+        let r = unsafe {
+            libc::sendfile(
+                dst.as_raw_fd(),
+                memfd.as_file().as_raw_fd(),
+                &mut off,
+                len - done,
+            )
+        };
+        if r < 0 {
+            let e = std::io::Error::last_os_error();
+            match e.kind() {
+                std::io::ErrorKind::Interrupted => continue,
+                std::io::ErrorKind::WouldBlock => break,
+                _ => Err(Error::os(Op::Copy, memfd_name(memfd))(e))?,
+            }
+        }
+        if r == 0 {
+            break;
+        }
+        done += r as usize;
+    }
+    Ok(done)
+}
+
+/// A shared memory area that the creator can keep writing to, but that peers can only read.
+///
+/// Created by `broadcast`. The memfd is sealed with `F_SEAL_FUTURE_WRITE`, so the creator's
+/// writable mapping stays, but the memfd cannot be mapped writable anymore, by anyone.
+/// Requires linux version 5.1+.
+pub struct Broadcast {
+    memfd: mfd::Memfd,
+    mmap: mmap::MmapRaw,
+    _charge: crate::quota::Charge,
+}
+
+/// Creates a broadcast area of `size` bytes, initially zeroed.
+pub fn broadcast(size: usize, name: &str) -> Result<Broadcast, Error> {
+    let charge = crate::quota::Charge::new(None, size as u64)?;
+    let memfd = CreateOptions::default().create(name)?;
+    memfd
+        .as_file()
+        .set_len(size as u64)
+        .map_err(Error::os(Op::Create, Some(name.into())))?;
+    let mmap = mmap::MmapOptions::new()
+        .len(size)
+        .map_raw(memfd.as_file())
+        .map_err(Error::os(Op::Map, Some(name.into())))?;
+    let seals =
+        libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_FUTURE_WRITE | libc::F_SEAL_SEAL;
+    add_seals(&memfd, seals as u32)?;
+    Ok(Broadcast {
+        memfd,
+        mmap,
+        _charge: charge,
+    })
+}
+
+impl Broadcast {
+    /// The file descriptor to hand out to peers.
+    pub fn memfd(&self) -> &mfd::Memfd {
+        &self.memfd
+    }
+
+    /// Size of the area.
+    pub fn len(&self) -> usize {
+        self.mmap.len()
+    }
+
+    /// Returns true if the area has zero size.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Raw pointer to the area, for writing.
+    ///
+    /// Peers can read at any time, so it is up to the protocol on top (e g a sequence lock)
+    /// to tell them when the data is consistent.
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.mmap.as_mut_ptr()
+    }
+
+    /// Copies data into the area.
+    pub fn write(&self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        match offset.checked_add(data.len()) {
+            Some(end) if end <= self.len() => {}
+            _ => Err(Error::OutOfBounds)?,
+        }
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), self.as_mut_ptr().add(offset), data.len())
+        };
+        Ok(())
+    }
+}
+
+/// A read-only view of a broadcast area, see `read_broadcast`.
+pub struct BroadcastView {
+    mmap: mmap::Mmap,
+}
+
+/// Maps a broadcast area created by a peer.
+///
+/// The memfd is verified (or sealed, if the peer did not) against shrinking and against
+/// writable mappings. The creator might still write to it, so there are no references to
+/// the data, only raw pointers and copies.
+pub fn read_broadcast(memfd: &mfd::Memfd) -> Result<BroadcastView, Error> {
+    let seals = raw_seals(memfd)?;
+    let mut wanted = libc::F_SEAL_SHRINK as u32;
+    if seals & (libc::F_SEAL_WRITE | libc::F_SEAL_FUTURE_WRITE) as u32 == 0 {
+        wanted |= libc::F_SEAL_FUTURE_WRITE as u32;
+    }
+    add_seals(memfd, wanted)?;
+    let mmap = unsafe { mmap::MmapOptions::new().map(memfd.as_file()) }
+        .map_err(|e| Error::os(Op::Map, memfd_name(memfd))(e))?;
+    Ok(BroadcastView { mmap })
+}
+
+impl BroadcastView {
+    /// Size of the area.
+    pub fn len(&self) -> usize {
+        self.mmap.len()
+    }
+
+    /// Returns true if the area has zero size.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Raw pointer to the area.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.mmap.as_ptr()
+    }
+
+    /// Copies data out of the area.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), Error> {
+        match offset.checked_add(buf.len()) {
+            Some(end) if end <= self.len() => {}
+            _ => Err(Error::OutOfBounds)?,
+        }
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.as_ptr().add(offset), buf.as_mut_ptr(), buf.len())
+        };
+        Ok(())
+    }
+}
+
+/// A read-only mapping of a file that cannot be sealed against shrinking, e g a plain file
+/// or an ashmem region.
+///
+/// If the peer truncates the file, touching the mapping beyond the new end would raise
+/// SIGBUS. Instead of touching the mapping directly, all access goes through
+/// `process_vm_readv` on our own process, which fails with `EFAULT` rather than raising a
+/// signal, and that is reported as `Error::Truncated`.
+pub struct GuardedMap {
+    mmap: mmap::Mmap,
+}
+
+/// Maps `len` bytes of a file for guarded reading.
+pub fn guarded_map(file: &std::fs::File, len: usize) -> Result<GuardedMap, Error> {
+    let mmap =
+        unsafe { mmap::MmapOptions::new().len(len).map(file) }.map_err(Error::os(Op::Map, None))?;
+    Ok(GuardedMap { mmap })
+}
+
+impl GuardedMap {
+    /// Size of the mapping.
+    pub fn len(&self) -> usize {
+        self.mmap.len()
+    }
+
+    /// Returns true if the mapping has zero size.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies data out of the mapping.
+    ///
+    /// Fails with `Truncated` if the file has been truncated below the range read.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), Error> {
+        match offset.checked_add(buf.len()) {
+            Some(end) if end <= self.len() => {}
+            _ => Err(Error::OutOfBounds)?,
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let local = libc::iovec {
+                iov_base: buf[done..].as_mut_ptr() as *mut _,
+                iov_len: buf.len() - done,
+            };
+            let remote = libc::iovec {
+                iov_base: unsafe { self.mmap.as_ptr().add(offset + done) } as *mut _,
+                iov_len: buf.len() - done,
+            };
+            let r = unsafe { libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0) };
+            if r < 0 {
+                let e = std::io::Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::EFAULT) => Err(Error::Truncated)?,
+                    Some(libc::EINTR) => continue,
+                    _ => Err(Error::os(Op::Map, None)(e))?,
+                }
+            }
+            if r == 0 {
+                // A partial read stops at the first page that cannot be read.
+                Err(Error::Truncated)?
+            }
+            done += r as usize;
+        }
+        Ok(())
     }
-    Ok(memfd)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn hashed() {
+        let size = HASH_CHUNK as u64 * 2 + 5;
+        let mut offsets = vec![];
+        let (memfd, digest) = write_once_hashed(size, "test-hashed", |offset, chunk| {
+            offsets.push((offset, chunk.len()));
+            chunk.iter_mut().for_each(|b| *b = offset as u8 + 1);
+        })
+        .unwrap();
+        assert_eq!(
+            offsets,
+            [
+                (0, HASH_CHUNK),
+                (HASH_CHUNK, HASH_CHUNK),
+                (2 * HASH_CHUNK, 5)
+            ]
+        );
+        let map = read_memfd_verified(&memfd, &digest).unwrap();
+        assert_eq!(map[HASH_CHUNK * 2], 1);
+        let mut wrong = digest;
+        wrong[0] ^= 1;
+        assert!(matches!(
+            read_memfd_verified(&memfd, &wrong),
+            Err(Error::DigestMismatch)
+        ));
+    }
+
     #[test]
     fn create_mmap() -> Result<(), Error> {
         let opts = mfd::MemfdOptions::default().allow_sealing(true);
@@ -123,7 +881,130 @@ mod tests {
         let mmap_raw = raw_memfd(&memfd, 16384)?;
         assert_eq!(mmap_raw.len(), 16384);
         // The memfd now has a writable mapping, cannot create a read-only one.
-        assert!(read_memfd(&memfd).is_err());
+        match read_memfd(&memfd) {
+            Err(Error::SealDenied {
+                wanted,
+                present,
+                busy,
+                ..
+            }) => {
+                assert_eq!(wanted, libc::F_SEAL_WRITE as u32);
+                assert_eq!(present, libc::F_SEAL_SHRINK as u32);
+                assert!(busy);
+            }
+            _ => panic!(),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn prefaulting() -> Result<(), Error> {
+        let memfd = CreateOptions::default().create("prefault")?;
+        memfd.as_file().set_len(65536)?;
+        let map = raw_memfd(&memfd, 65536)?;
+        assert_eq!(resident_bytes(&map)?, 0);
+        assert_eq!(allocated_bytes(&memfd)?, 0);
+        touch_all(&map)?;
+        assert_eq!(resident_bytes(&map)?, 65536);
+        assert_eq!(allocated_bytes(&memfd)?, 65536);
+        touch_all_read(&read_memfd(&write_once(8192, "prefault_ro", |_| {})?)?)?;
+        Ok(())
+    }
+
+    #[test]
+    fn error_context() -> Result<(), Error> {
+        let m = write_once(4096, "ctx", |_| {})?;
+        let e = raw_memfd(&m, 4096).unwrap_err();
+        assert_eq!(e.op(), Some(Op::Map));
+        assert_eq!(e.errno(), Some(libc::EPERM));
+        assert!(!e.is_transient());
+        assert!(matches!(&e, Error::Os { name: Some(n), .. } if n == "ctx"));
+        assert!(std::error::Error::source(&e).is_some());
+        let src = write_once(16, "ctx-src", |_| {})?;
+        let e = copy_into(&m, 0, src.as_file(), 16).unwrap_err();
+        assert_eq!(e.op(), Some(Op::Copy));
+        assert!(matches!(&e, Error::Os { name: Some(n), .. } if n == "ctx"));
+        Ok(())
+    }
+
+    #[test]
+    fn exec_policy() -> Result<(), Error> {
+        use std::os::unix::fs::PermissionsExt;
+        use std::os::unix::io::AsRawFd;
+        let m = CreateOptions::default().create("test-noexec")?;
+        let seals = unsafe { libc::fcntl(m.as_raw_fd(), libc::F_GET_SEALS) };
+        if seals & libc::F_SEAL_EXEC == 0 {
+            // Kernel without exec flags (before 6.3)
+            return Ok(());
+        }
+        assert_eq!(m.as_file().metadata()?.permissions().mode() & 0o111, 0);
+        let none = mfd::SealsHashSet::new();
+        let opts = CreateOptions::default();
+        let m = write_once_with(4096, "test-noexec-with", opts, &none, |_| {})?;
+        let mode = m.memfd().as_file().metadata()?.permissions().mode();
+        assert_eq!(mode & 0o111, 0);
+        let m = CreateOptions::default()
+            .exec(Exec::Exec)
+            .create("test-exec")?;
+        assert_ne!(m.as_file().metadata()?.permissions().mode() & 0o111, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn broadcast_updates() -> Result<(), Error> {
+        let b = broadcast(4096, "test-broadcast")?;
+        b.write(10, b"one")?;
+        let peer = mfd::Memfd::try_from_file(b.memfd().as_file().try_clone()?).unwrap();
+        let view = read_broadcast(&peer)?;
+        let mut buf = [0u8; 3];
+        view.read(10, &mut buf)?;
+        assert_eq!(&buf, b"one");
+        b.write(10, b"two")?;
+        view.read(10, &mut buf)?;
+        assert_eq!(&buf, b"two");
+        assert!(raw_memfd(&peer, 4096).is_err());
+        assert!(matches!(view.read(4095, &mut buf), Err(Error::OutOfBounds)));
+        Ok(())
+    }
+
+    #[test]
+    fn guarded_truncation() -> Result<(), Error> {
+        let f = CreateOptions::default().create("test-guarded")?.into_file();
+        f.set_len(8192)?;
+        let m = guarded_map(&f, 8192)?;
+        let mut buf = [1u8; 16];
+        m.read(5000, &mut buf)?;
+        assert_eq!(buf, [0; 16]);
+        f.set_len(4096)?;
+        m.read(4080, &mut buf)?;
+        assert!(matches!(m.read(4090, &mut buf), Err(Error::Truncated)));
+        assert!(matches!(m.read(8190, &mut buf), Err(Error::OutOfBounds)));
+        Ok(())
+    }
+
+    #[test]
+    fn splice_and_sendfile() -> Result<(), Error> {
+        use std::io::{Read, Write};
+        let (mut r, w) = std::os::unix::net::UnixStream::pair()?;
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (rx, mut tx) = unsafe {
+            (
+                std::fs::File::from_raw_fd(fds[0]),
+                std::fs::File::from_raw_fd(fds[1]),
+            )
+        };
+        tx.write_all(b"hello world")?;
+        drop(tx);
+        let memfd = CreateOptions::default().create("splice")?;
+        assert_eq!(copy_into(&memfd, 3, &rx, 100)?, 11);
+        let src = write_once(4096, "splice_src", |x| x[..3].copy_from_slice(b"abc"))?;
+        assert_eq!(copy_into(&memfd, 0, src.as_file(), 3)?, 3);
+        assert_eq!(send_from(&memfd, 0, &w, 100)?, 14);
+        drop(w);
+        let mut buf = vec![];
+        r.read_to_end(&mut buf)?;
+        assert_eq!(buf, b"abchello world");
         Ok(())
     }
 
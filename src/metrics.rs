@@ -0,0 +1,94 @@
+//! Exports ringbuffer statistics in the Prometheus text format.
+//!
+//! Requires the `metrics` feature. There is no registry; collect `sharedring::Stats` from the
+//! channels you care about (e g when the scrape endpoint is hit) and encode them with
+//! `encode`, keyed by channel name.
+
+use crate::sharedring::Stats;
+use std::fmt::Write;
+
+const METRICS: &[(&str, &str, &str)] = &[
+    (
+        "capacity",
+        "gauge",
+        "Number of items the ringbuffer can hold",
+    ),
+    (
+        "occupancy",
+        "gauge",
+        "Number of items currently in the ringbuffer",
+    ),
+    ("items_total", "counter", "Number of items sent or received"),
+    (
+        "dropped_total",
+        "counter",
+        "Number of items dropped by the sender",
+    ),
+    (
+        "overflows_total",
+        "counter",
+        "Number of times the sender started dropping items",
+    ),
+    (
+        "wakeups_total",
+        "counter",
+        "Number of times the other side was woken up",
+    ),
+];
+
+fn value(stats: &Stats, i: usize) -> u64 {
+    match i {
+        0 => stats.capacity as u64,
+        1 => stats.occupancy as u64,
+        2 => stats.items,
+        3 => stats.dropped,
+        4 => stats.overflows,
+        _ => stats.wakeups,
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Encodes statistics for a number of channels, given as (name, stats) pairs.
+///
+/// Metrics are named `shmem_ipc_ring_<stat>`, with a `channel` label.
+pub fn encode(channels: &[(&str, Stats)]) -> String {
+    let mut s = String::new();
+    for (i, (name, kind, help)) in METRICS.iter().enumerate() {
+        let _ = writeln!(s, "# HELP shmem_ipc_ring_{} {}", name, help);
+        let _ = writeln!(s, "# TYPE shmem_ipc_ring_{} {}", name, kind);
+        for (channel, stats) in channels {
+            let _ = writeln!(
+                s,
+                "shmem_ipc_ring_{}{{channel=\"{}\"}} {}",
+                name,
+                escape(channel),
+                value(stats, i)
+            );
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prometheus_text() {
+        let st = Stats {
+            capacity: 100,
+            dropped: 3,
+            ..Default::default()
+        };
+        let s = encode(&[("a\"b", st)]);
+        assert!(s.contains("# TYPE shmem_ipc_ring_dropped_total counter\n"));
+        assert!(s.contains("shmem_ipc_ring_capacity{channel=\"a\\\"b\"} 100\n"));
+        assert!(s.contains("shmem_ipc_ring_dropped_total{channel=\"a\\\"b\"} 3\n"));
+    }
+}
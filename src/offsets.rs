@@ -0,0 +1,242 @@
+//! Checkpoints of read positions, e g of `fanout` receivers, so that consumers can resume
+//! where they left off after a restart.
+//!
+//! An `Offsets` area holds a fixed number of slots, one per position to keep, in shared
+//! memory or in a file. A batch of slots is committed at once: there are two copies of the
+//! slots, and a commit writes the copy not in use and then switches over, so readers, and
+//! consumers restarting after a crash, see either all of a batch or none of it. Commits
+//! are serialized by a lock word holding the pid of the committer; if that process dies
+//! while holding it, the next commit takes over.
+//!
+//! # Example
+//! ```rust
+//! use shmem_ipc::fanout::{FanoutReceiver, FanoutSender};
+//! use shmem_ipc::offsets::Offsets;
+//! let mut s: FanoutSender<u64> = FanoutSender::new(64).unwrap();
+//! let (_, fds) = s.add_receiver().unwrap();
+//! let mut r = FanoutReceiver::<u64>::open(64, fds.try_clone().unwrap()).unwrap();
+//! let offsets = Offsets::new(4).unwrap();
+//! for i in 0..3 {
+//!     s.send(&i).unwrap();
+//! }
+//! r.recv().unwrap();
+//! offsets.commit(&[(0, r.position())]).unwrap();
+//! // After a restart
+//! let mut r = FanoutReceiver::<u64>::open(64, fds).unwrap();
+//! r.seek(offsets.get(0).unwrap()).unwrap();
+//! assert_eq!(r.recv().unwrap(), Some(1));
+//! ```
+
+use crate::mem::mmap;
+use crate::wire::{AtomicLe32, AtomicLe64};
+use crate::Error;
+use std::fs::File;
+use std::sync::atomic::{fence, Ordering};
+
+/// The slots start after a cache line with the header.
+const HEADER_SIZE: usize = 64;
+
+#[repr(C)]
+struct Header {
+    slots: AtomicLe64,
+    /// Twice the number of commits, plus one while a commit is being written. Copy
+    /// `(generation / 2) % 2` is the current one.
+    generation: AtomicLe64,
+    /// Pid of the committing process, or zero.
+    lock: AtomicLe32,
+}
+
+fn area_size(slots: usize) -> Option<usize> {
+    slots.checked_mul(16)?.checked_add(HEADER_SIZE)
+}
+
+/// Saved positions, see the module documentation.
+///
+/// Slots hold a position plus one, so that a slot never committed to reads as `None`. A
+/// peer with the area mapped writable can change the positions of the others, so only
+/// share an area between consumers that trust each other; it cannot make reading them
+/// unsafe, though.
+pub struct Offsets {
+    file: File,
+    map: mmap::MmapRaw,
+    slots: usize,
+}
+
+impl Offsets {
+    /// Creates an area with `slots` empty slots, in a new memfd.
+    pub fn new(slots: usize) -> Result<Self, Error> {
+        let memfd = crate::mem::CreateOptions::default().create("shmem-ipc offsets")?;
+        Self::create(memfd.into_file(), slots)
+    }
+
+    /// Creates an area with `slots` empty slots in `file`, e g on disk, to survive the
+    /// processes that use it. Whatever was in the file before is lost.
+    pub fn create(file: File, slots: usize) -> Result<Self, Error> {
+        let size = area_size(slots).ok_or(Error::OutOfBounds)?;
+        file.set_len(0)?;
+        file.set_len(size as u64)?;
+        let o = Offsets {
+            map: map(&file, size)?,
+            file,
+            slots,
+        };
+        o.header().slots.store(slots as u64, Ordering::Release);
+        Ok(o)
+    }
+
+    /// Opens an area made by `new` or `create`, e g in another process or after a restart.
+    pub fn open(file: File) -> Result<Self, Error> {
+        let len = file.metadata()?.len() as usize;
+        if len < HEADER_SIZE {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        let map = map(&file, HEADER_SIZE)?;
+        let slots = unsafe { &*(map.as_ptr() as *const Header) }
+            .slots
+            .load(Ordering::Acquire) as usize;
+        let size = area_size(slots).ok_or(crate::ringbuf::Error::BufCorrupt)?;
+        if len < size {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        let map = self::map(&file, size)?;
+        Ok(Offsets { file, map, slots })
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*(self.map.as_ptr() as *const Header) }
+    }
+
+    fn slot(&self, copy: u64, i: usize) -> &AtomicLe64 {
+        let offset = HEADER_SIZE + 8 * (copy as usize % 2 * self.slots + i);
+        unsafe { &*(self.map.as_ptr().add(offset) as *const AtomicLe64) }
+    }
+
+    /// The file to hand to other consumers, for `open`.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Number of slots.
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    /// The position last committed to slot `i`, or `None` if there is none.
+    pub fn get(&self, i: usize) -> Option<u64> {
+        if i >= self.slots {
+            return None;
+        }
+        self.read(|o| o.slot_value(o.current(), i))
+    }
+
+    /// All the positions, as of the same commit.
+    pub fn load(&self) -> Vec<Option<u64>> {
+        self.read(|o| {
+            let copy = o.current();
+            (0..o.slots).map(|i| o.slot_value(copy, i)).collect()
+        })
+    }
+
+    fn current(&self) -> u64 {
+        self.header().generation.load(Ordering::Acquire) / 2
+    }
+
+    fn slot_value(&self, copy: u64, i: usize) -> Option<u64> {
+        self.slot(copy, i).load(Ordering::Relaxed).checked_sub(1)
+    }
+
+    /// Runs `f` until it ran without a commit writing to the copy it read.
+    fn read<R, F: Fn(&Self) -> R>(&self, f: F) -> R {
+        loop {
+            let before = self.header().generation.load(Ordering::Acquire);
+            let r = f(self);
+            fence(Ordering::Acquire);
+            // The next commit writes the other copy; the one after that writes this one.
+            if self.header().generation.load(Ordering::Relaxed) < (before & !1) + 3 {
+                return r;
+            }
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Saves a batch of positions, as pairs of slot and position, all at once.
+    pub fn commit(&self, batch: &[(usize, u64)]) -> Result<(), Error> {
+        if batch
+            .iter()
+            .any(|&(i, pos)| i >= self.slots || pos == u64::MAX)
+        {
+            Err(Error::OutOfBounds)?
+        }
+        self.lock();
+        let h = self.header();
+        // Odd if a committer died while writing, in which case its copy is redone.
+        let generation = h.generation.load(Ordering::Relaxed) & !1;
+        h.generation.store(generation + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        let (current, next) = (generation / 2, generation / 2 + 1);
+        for i in 0..self.slots {
+            let v = self.slot(current, i).load(Ordering::Relaxed);
+            self.slot(next, i).store(v, Ordering::Relaxed);
+        }
+        for &(i, pos) in batch {
+            self.slot(next, i).store(pos + 1, Ordering::Relaxed);
+        }
+        h.generation.store(generation + 2, Ordering::Release);
+        h.lock.store(0, Ordering::Release);
+        Ok(())
+    }
+
+    fn lock(&self) {
+        let pid = std::process::id();
+        let lock = &self.header().lock;
+        let mut owner = 0;
+        loop {
+            match lock.compare_exchange(owner, pid, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(o) => owner = o,
+            }
+            // Taken over below if the owner is gone; otherwise wait for it.
+            if owner != 0 && owner != pid && !crate::unix::process_alive(owner) {
+                continue;
+            }
+            owner = 0;
+            std::thread::yield_now();
+        }
+    }
+
+    /// Flushes the area to its file, for areas on disk.
+    pub fn sync(&self) -> Result<(), Error> {
+        Ok(self.map.flush()?)
+    }
+}
+
+fn map(file: &File, len: usize) -> Result<mmap::MmapRaw, Error> {
+    Ok(mmap::MmapOptions::new().len(len).map_raw(file)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches() {
+        let a = Offsets::new(3).unwrap();
+        let b = Offsets::open(a.file().try_clone().unwrap()).unwrap();
+        assert_eq!(b.load(), vec![None, None, None]);
+        a.commit(&[(0, 5), (2, 0)]).unwrap();
+        b.commit(&[(1, 7)]).unwrap();
+        assert_eq!(a.load(), vec![Some(5), Some(7), Some(0)]);
+        assert!(matches!(a.commit(&[(3, 1)]), Err(Error::OutOfBounds)));
+        // A committer that died while writing a batch
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead = child.id();
+        child.wait().unwrap();
+        let h = a.header();
+        h.lock.store(dead, Ordering::Relaxed);
+        h.generation.fetch_add(1, Ordering::Relaxed);
+        a.slot(a.current() + 1, 0).store(99, Ordering::Relaxed);
+        assert_eq!(b.get(0), Some(5));
+        b.commit(&[(1, 8)]).unwrap();
+        assert_eq!(a.load(), vec![Some(5), Some(8), Some(0)]);
+    }
+}
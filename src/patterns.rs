@@ -0,0 +1,536 @@
+//! ZeroMQ style messaging patterns over `framed` channels: PUSH/PULL, PUB/SUB and REQ/REP.
+//!
+//! Peers find each other over unix sockets, e g accepted from a `UnixListener`. The binding
+//! side (`Push`, `Pub`, `Rep`) takes any number of peers with `add_peer`, which sets up the
+//! channels to the peer and passes their file descriptors over the socket; the connecting
+//! side (`Pull`, `Sub`, `Req`) attaches to them in `connect`. The binding side keeps the
+//! socket, and drops the peer once the other end hangs up, so the connecting side must keep
+//! its end open for as long as it uses the channels.
+//!
+//! Unlike in ZeroMQ, sending never blocks: a full channel counts as the high water mark
+//! having been reached.
+//!
+//! # Example
+//! ```rust
+//! use shmem_ipc::patterns::{Push, Pull};
+//! use std::os::unix::net::UnixStream;
+//! let (a, b) = UnixStream::pair().unwrap();
+//! let mut push = Push::new(4096);
+//! let pull = std::thread::spawn(move || Pull::connect(&b).map(|p| (p, b)));
+//! push.add_peer(a).unwrap();
+//! let (mut pull, _socket) = pull.join().unwrap().unwrap();
+//! assert!(push.send(b"work item").unwrap());
+//! assert_eq!(pull.recv().unwrap().unwrap().data(), b"work item");
+//! ```
+
+use crate::framed::{self, Message};
+use crate::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+const KIND_PUSH: u64 = 1;
+const KIND_PUB: u64 = 2;
+const KIND_REP: u64 = 3;
+const KIND_REQ: u64 = 4;
+
+/// Largest channel the connecting side accepts from a peer, in bytes.
+const MAX_CAPACITY: u64 = 1 << 32;
+/// Limits on the subscriptions a `Sub` may send.
+const MAX_TOPICS: usize = 1024;
+const MAX_TOPIC_LEN: usize = 4096;
+
+fn corrupt() -> Error {
+    crate::ringbuf::Error::BufCorrupt.into()
+}
+
+/// Sets up a channel of `capacity` bytes and passes it to the peer.
+fn offer(socket: &UnixStream, kind: u64, capacity: usize) -> Result<framed::Sender, Error> {
+    let s = framed::Sender::new(capacity)?;
+    let ring = s.ring();
+    let mut msg = [0u8; 16];
+    msg[..8].copy_from_slice(&kind.to_le_bytes());
+    msg[8..].copy_from_slice(&(capacity as u64).to_le_bytes());
+    let fds = [
+        ring.memfd().as_raw_fd(),
+        ring.empty_signal().as_raw_fd(),
+        ring.full_signal().as_raw_fd(),
+    ];
+    crate::unix::send_with_fds(socket, &msg, &fds)?;
+    Ok(s)
+}
+
+/// Attaches to a channel passed by the peer with `offer`.
+fn accept(socket: &UnixStream, kind: u64) -> Result<framed::Receiver, Error> {
+    let mut msg = [0u8; 16];
+    let mut fds = vec![];
+    let n = crate::unix::recv_with_fds(socket, &mut msg, &mut fds)?;
+    if n == 0 {
+        Err(corrupt())?
+    }
+    let mut socket = socket;
+    socket.read_exact(&mut msg[n..])?;
+    let word = |i: usize| {
+        u64::from_le_bytes([
+            msg[i],
+            msg[i + 1],
+            msg[i + 2],
+            msg[i + 3],
+            msg[i + 4],
+            msg[i + 5],
+            msg[i + 6],
+            msg[i + 7],
+        ])
+    };
+    let capacity = word(8);
+    if word(0) != kind || fds.len() != 3 || capacity > MAX_CAPACITY {
+        Err(corrupt())?
+    }
+    let (full, empty, memfd): (File, File, File) =
+        (fds.pop().unwrap(), fds.pop().unwrap(), fds.pop().unwrap());
+    framed::Receiver::open(capacity as usize, memfd, empty, full)
+}
+
+/// Returns true if the other end of the socket has gone away.
+fn hung_up(socket: &UnixStream) -> bool {
+    let mut pfd = libc::pollfd {
+        fd: socket.as_raw_fd(),
+        events: libc::POLLRDHUP,
+        revents: 0,
+    };
+    let r = unsafe { libc::poll(&mut pfd, 1, 0) };
+    r > 0 && pfd.revents & (libc::POLLRDHUP | libc::POLLHUP | libc::POLLERR) != 0
+}
+
+/// Waits until one of the receivers might have a message. Returns false on timeout.
+fn wait_any<'a, I: Iterator<Item = &'a framed::Receiver> + Clone>(
+    receivers: I,
+    timeout: Option<Duration>,
+) -> Result<bool, Error> {
+    for r in receivers.clone() {
+        if r.ring().stats()?.occupancy > 0 {
+            return Ok(true);
+        }
+    }
+    let mut pfds: Vec<_> = receivers
+        .map(|r| libc::pollfd {
+            fd: r.ring().empty_signal().as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let ms = timeout
+        .map(|t| t.as_millis().min(i32::MAX as u128) as i32)
+        .unwrap_or(-1);
+    let n = unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, ms) };
+    if n < 0 {
+        let e = std::io::Error::last_os_error();
+        return match e.kind() {
+            std::io::ErrorKind::Interrupted => Ok(true),
+            _ => Err(e)?,
+        };
+    }
+    for p in pfds.iter().filter(|p| p.revents & libc::POLLIN != 0) {
+        // Reset the eventfd; poll told us it is readable, so this does not block.
+        let mut b = [0u8; 8];
+        let _ = unsafe { libc::read(p.fd, b.as_mut_ptr() as *mut _, 8) };
+    }
+    Ok(n > 0)
+}
+
+struct Peer<C> {
+    socket: UnixStream,
+    channel: C,
+}
+
+/// Drops the peers whose socket has hung up.
+fn prune<C>(peers: &mut Vec<Peer<C>>) {
+    peers.retain(|p| !hung_up(&p.socket));
+}
+
+/// Distributes messages round robin over the connected `Pull` peers.
+pub struct Push {
+    capacity: usize,
+    peers: Vec<Peer<framed::Sender>>,
+    next: usize,
+}
+
+impl Push {
+    /// Channels to peers will hold `capacity` bytes.
+    pub fn new(capacity: usize) -> Self {
+        Push {
+            capacity,
+            peers: vec![],
+            next: 0,
+        }
+    }
+
+    /// Sets up a channel to a `Pull` at the other end of `socket`.
+    pub fn add_peer(&mut self, socket: UnixStream) -> Result<(), Error> {
+        let channel = offer(&socket, KIND_PUSH, self.capacity)?;
+        self.peers.push(Peer { socket, channel });
+        Ok(())
+    }
+
+    /// Number of connected peers.
+    pub fn peers(&mut self) -> usize {
+        prune(&mut self.peers);
+        self.peers.len()
+    }
+
+    /// Sends a message to the next peer in turn that has room for it.
+    ///
+    /// Returns false if there are no peers, or all of them are full.
+    pub fn send(&mut self, data: &[u8]) -> Result<bool, Error> {
+        prune(&mut self.peers);
+        let n = self.peers.len();
+        for i in 0..n {
+            let j = (self.next + i) % n;
+            if self.peers[j].channel.send(data)? {
+                self.next = (j + 1) % n;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Receives messages from a `Push`.
+pub struct Pull {
+    channel: framed::Receiver,
+}
+
+impl Pull {
+    /// Attaches to the channel that the `Push` at the other end of `socket` sets up.
+    pub fn connect(socket: &UnixStream) -> Result<Self, Error> {
+        Ok(Pull {
+            channel: accept(socket, KIND_PUSH)?,
+        })
+    }
+
+    /// Receives the next message, if any.
+    pub fn recv(&mut self) -> Result<Option<Message>, Error> {
+        self.channel.recv()
+    }
+
+    /// Blocks until there is a message.
+    pub fn block_until_readable(&mut self) -> Result<(), Error> {
+        self.channel.block_until_readable()
+    }
+}
+
+/// Sends every message to all connected `Sub` peers that subscribed to it.
+pub struct Pub {
+    capacity: usize,
+    peers: Vec<Peer<(framed::Sender, Vec<Vec<u8>>)>>,
+}
+
+impl Pub {
+    /// Channels to peers will hold `capacity` bytes.
+    pub fn new(capacity: usize) -> Self {
+        Pub {
+            capacity,
+            peers: vec![],
+        }
+    }
+
+    /// Reads the subscriptions of the `Sub` at the other end of `socket`, and sets up a
+    /// channel to it.
+    pub fn add_peer(&mut self, socket: UnixStream) -> Result<(), Error> {
+        let topics = read_topics(&socket)?;
+        let sender = offer(&socket, KIND_PUB, self.capacity)?;
+        self.peers.push(Peer {
+            socket,
+            channel: (sender, topics),
+        });
+        Ok(())
+    }
+
+    /// Number of connected peers.
+    pub fn peers(&mut self) -> usize {
+        prune(&mut self.peers);
+        self.peers.len()
+    }
+
+    /// Sends a message to every peer with a subscription that is a prefix of it.
+    ///
+    /// Peers that are full miss the message. Returns the number of peers that got it.
+    pub fn send(&mut self, data: &[u8]) -> Result<usize, Error> {
+        prune(&mut self.peers);
+        let mut sent = 0;
+        for p in &mut self.peers {
+            let (sender, topics) = &mut p.channel;
+            if topics.iter().any(|t| data.starts_with(t)) && sender.send(data)? {
+                sent += 1;
+            }
+        }
+        Ok(sent)
+    }
+}
+
+fn read_topics(socket: &UnixStream) -> Result<Vec<Vec<u8>>, Error> {
+    let mut socket = socket;
+    let mut word = [0u8; 4];
+    socket.read_exact(&mut word)?;
+    let count = u32::from_le_bytes(word) as usize;
+    if count > MAX_TOPICS {
+        Err(corrupt())?
+    }
+    let mut topics = Vec::with_capacity(count);
+    for _ in 0..count {
+        socket.read_exact(&mut word)?;
+        let len = u32::from_le_bytes(word) as usize;
+        if len > MAX_TOPIC_LEN {
+            Err(corrupt())?
+        }
+        let mut t = vec![0u8; len];
+        socket.read_exact(&mut t)?;
+        topics.push(t);
+    }
+    Ok(topics)
+}
+
+/// Receives the messages of a `Pub` that match its subscriptions.
+pub struct Sub {
+    channel: framed::Receiver,
+}
+
+impl Sub {
+    /// Subscribes to messages starting with any of `topics`, an empty topic matching all
+    /// messages, and attaches to the channel that the `Pub` at the other end of `socket`
+    /// sets up.
+    ///
+    /// Fails with `OutOfBounds` for more than 1024 topics, or topics over 4096 bytes.
+    pub fn connect(socket: &UnixStream, topics: &[&[u8]]) -> Result<Self, Error> {
+        if topics.len() > MAX_TOPICS || topics.iter().any(|t| t.len() > MAX_TOPIC_LEN) {
+            Err(Error::OutOfBounds)?
+        }
+        let mut msg = (topics.len() as u32).to_le_bytes().to_vec();
+        for t in topics {
+            msg.extend_from_slice(&(t.len() as u32).to_le_bytes());
+            msg.extend_from_slice(t);
+        }
+        let mut s = socket;
+        s.write_all(&msg)?;
+        Ok(Sub {
+            channel: accept(socket, KIND_PUB)?,
+        })
+    }
+
+    /// Receives the next message, if any.
+    pub fn recv(&mut self) -> Result<Option<Message>, Error> {
+        self.channel.recv()
+    }
+
+    /// Blocks until there is a message.
+    pub fn block_until_readable(&mut self) -> Result<(), Error> {
+        self.channel.block_until_readable()
+    }
+}
+
+/// Answers requests from any number of `Req` peers.
+pub struct Rep {
+    capacity: usize,
+    peers: Vec<Peer<(framed::Sender, framed::Receiver)>>,
+    next: usize,
+    /// The peer whose request was received last, and is waiting for a reply.
+    replying: Option<usize>,
+}
+
+impl Rep {
+    /// Channels for replies will hold `capacity` bytes.
+    pub fn new(capacity: usize) -> Self {
+        Rep {
+            capacity,
+            peers: vec![],
+            next: 0,
+            replying: None,
+        }
+    }
+
+    /// Sets up channels with the `Req` at the other end of `socket`.
+    pub fn add_peer(&mut self, socket: UnixStream) -> Result<(), Error> {
+        let sender = offer(&socket, KIND_REP, self.capacity)?;
+        let receiver = accept(&socket, KIND_REQ)?;
+        self.peers.push(Peer {
+            socket,
+            channel: (sender, receiver),
+        });
+        Ok(())
+    }
+
+    /// Number of connected peers.
+    pub fn peers(&mut self) -> usize {
+        self.prune();
+        self.peers.len()
+    }
+
+    fn prune(&mut self) {
+        let before = self.peers.len();
+        prune(&mut self.peers);
+        if self.peers.len() != before {
+            // Indices have shifted; a pending reply has nowhere to go.
+            self.replying = None;
+            self.next = 0;
+        }
+    }
+
+    /// Waits until a peer might have sent a request. Returns false on timeout.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<bool, Error> {
+        wait_any(self.peers.iter().map(|p| &p.channel.1), timeout)
+    }
+
+    /// Receives the next request, from the peers in turn.
+    ///
+    /// The reply to it goes out with `reply`; until then, this fails with `EINVAL`.
+    pub fn recv(&mut self) -> Result<Option<Message>, Error> {
+        if self.replying.is_some() {
+            Err(std::io::Error::from_raw_os_error(libc::EINVAL))?
+        }
+        self.prune();
+        let n = self.peers.len();
+        for i in 0..n {
+            let j = (self.next + i) % n;
+            if let Some(m) = self.peers[j].channel.1.recv()? {
+                self.next = (j + 1) % n;
+                self.replying = Some(j);
+                return Ok(Some(m));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Replies to the last request received.
+    ///
+    /// Returns false if there is no room for it; try again later. Fails with `EINVAL` if
+    /// there is no request to reply to, e g because its peer has gone away.
+    pub fn reply(&mut self, data: &[u8]) -> Result<bool, Error> {
+        let j = self
+            .replying
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+        let sent = self.peers[j].channel.0.send(data)?;
+        if sent {
+            self.replying = None;
+        }
+        Ok(sent)
+    }
+}
+
+/// Sends requests to a `Rep` and receives its replies, one at a time.
+pub struct Req {
+    sender: framed::Sender,
+    receiver: framed::Receiver,
+    waiting: bool,
+}
+
+impl Req {
+    /// Attaches to the `Rep` at the other end of `socket`; requests can be up to
+    /// `capacity` bytes.
+    pub fn connect(socket: &UnixStream, capacity: usize) -> Result<Self, Error> {
+        let receiver = accept(socket, KIND_REP)?;
+        let sender = offer(socket, KIND_REQ, capacity)?;
+        Ok(Req {
+            sender,
+            receiver,
+            waiting: false,
+        })
+    }
+
+    /// Sends a request. Fails with `EINVAL` while the reply to the previous one is
+    /// outstanding.
+    pub fn send(&mut self, data: &[u8]) -> Result<bool, Error> {
+        if self.waiting {
+            Err(std::io::Error::from_raw_os_error(libc::EINVAL))?
+        }
+        self.waiting = self.sender.send(data)?;
+        Ok(self.waiting)
+    }
+
+    /// Receives the reply, if it has arrived.
+    pub fn recv(&mut self) -> Result<Option<Message>, Error> {
+        let m = self.receiver.recv()?;
+        if m.is_some() {
+            self.waiting = false;
+        }
+        Ok(m)
+    }
+
+    /// Blocks until the reply has arrived.
+    pub fn block_until_readable(&mut self) -> Result<(), Error> {
+        self.receiver.block_until_readable()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs<T: Send + 'static, F: Fn(UnixStream) -> T + Send + Copy + 'static>(
+        n: usize,
+        f: F,
+    ) -> (Vec<UnixStream>, Vec<std::thread::JoinHandle<T>>) {
+        (0..n)
+            .map(|_| {
+                let (a, b) = UnixStream::pair().unwrap();
+                (a, std::thread::spawn(move || f(b)))
+            })
+            .unzip()
+    }
+
+    #[test]
+    fn push_pub() {
+        let mut push = Push::new(4096);
+        let (sockets, pulls) = pairs(2, |b| (Pull::connect(&b).unwrap(), b));
+        for s in sockets {
+            push.add_peer(s).unwrap();
+        }
+        let mut pulls: Vec<_> = pulls.into_iter().map(|h| h.join().unwrap()).collect();
+        for i in 0..4u8 {
+            assert!(push.send(&[i]).unwrap());
+        }
+        assert_eq!(pulls[0].0.recv().unwrap().unwrap().data(), [0]);
+        assert_eq!(pulls[1].0.recv().unwrap().unwrap().data(), [1]);
+        assert_eq!(pulls[0].0.recv().unwrap().unwrap().data(), [2]);
+        // A peer that hangs up is dropped
+        drop(pulls.pop());
+        assert_eq!(push.peers(), 1);
+
+        let mut publ = Pub::new(4096);
+        let (sockets, subs) = pairs(2, |b| {
+            let topics: &[&[u8]] = &[b"weather."];
+            (Sub::connect(&b, topics).unwrap(), b)
+        });
+        for s in sockets {
+            publ.add_peer(s).unwrap();
+        }
+        let mut subs: Vec<_> = subs.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(publ.send(b"news.today").unwrap(), 0);
+        assert_eq!(publ.send(b"weather.rain").unwrap(), 2);
+        for s in &mut subs {
+            assert_eq!(s.0.recv().unwrap().unwrap().data(), b"weather.rain");
+            assert!(s.0.recv().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn req_rep() {
+        let mut rep = Rep::new(4096);
+        let (sockets, reqs) = pairs(2, |b| (Req::connect(&b, 4096).unwrap(), b));
+        for s in sockets {
+            rep.add_peer(s).unwrap();
+        }
+        let mut reqs: Vec<_> = reqs.into_iter().map(|h| h.join().unwrap()).collect();
+        assert!(!rep.wait(Some(Duration::from_millis(0))).unwrap());
+        assert!(reqs[1].0.send(b"ping").unwrap());
+        assert!(reqs[1].0.send(b"again").is_err());
+        assert!(rep.wait(None).unwrap());
+        assert_eq!(rep.recv().unwrap().unwrap().data(), b"ping");
+        assert!(rep.recv().is_err());
+        assert!(rep.reply(b"pong").unwrap());
+        assert!(rep.reply(b"pong").is_err());
+        assert_eq!(reqs[1].0.recv().unwrap().unwrap().data(), b"pong");
+        assert!(reqs[1].0.send(b"again").unwrap());
+    }
+}
@@ -0,0 +1,322 @@
+//! Watching the memory of the cgroup this process runs in.
+//!
+//! Shared memory is charged to the cgroup of whoever touches a page first, but it does not
+//! show up in the heap statistics an application usually watches, so a busy channel can
+//! push the cgroup into reclaim or the OOM killer without warning. A `MemoryMonitor` reads
+//! `memory.current`, `memory.high`, `memory.max`, `memory.events` and the PSI stall
+//! figures in `memory.pressure` of a cgroup v2 directory, and boils them down to a
+//! `Level`. The application can then react, or leave it to `shrink` to limit how much of
+//! their ringbuffers lossy senders use.
+//!
+//! Needs the `cgroup` feature.
+//!
+//! # Example
+//! ```rust,no_run
+//! use shmem_ipc::{framed, pressure};
+//! let mut monitor = pressure::MemoryMonitor::open().unwrap();
+//! let mut s = framed::Sender::new(1 << 20).unwrap();
+//! s.set_lossy(true);
+//! loop {
+//!     monitor.wait(Some(std::time::Duration::from_secs(1))).unwrap();
+//!     let level = monitor.poll().unwrap();
+//!     pressure::shrink(level, std::iter::once(&mut s));
+//!     // ...
+//! }
+//! ```
+
+use crate::framed;
+use crate::Error;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Where cgroup v2 is mounted.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// How close the cgroup is to its memory limit, see `MemoryMonitor::poll`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Nothing to worry about.
+    Normal,
+    /// Usage is above the threshold, the cgroup went over `memory.high`, or tasks stall
+    /// on memory noticeably.
+    Elevated,
+    /// Usage is within 5% of the limit, or the cgroup hit `memory.max` or the OOM killer.
+    Critical,
+}
+
+/// Counters from `memory.events`; each counts how often the cgroup ran into that.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryEvents {
+    /// Reclaimed despite being below `memory.low`.
+    pub low: u64,
+    /// Throttled for going over `memory.high`.
+    pub high: u64,
+    /// About to go over `memory.max`.
+    pub max: u64,
+    /// Ran out of memory.
+    pub oom: u64,
+    /// Had a process OOM killed.
+    pub oom_kill: u64,
+}
+
+/// One reading of a cgroup's memory state, see `MemoryMonitor::sample`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct MemoryState {
+    /// Bytes in use, shared memory included.
+    pub current: u64,
+    /// `memory.high`, if set.
+    pub high: Option<u64>,
+    /// `memory.max`, if set.
+    pub max: Option<u64>,
+    /// Event counters since the cgroup was created.
+    pub events: MemoryEvents,
+    /// Percentage of the last 10 seconds in which some task stalled on memory, if the
+    /// kernel has PSI.
+    pub some_avg10: Option<f64>,
+}
+
+impl MemoryState {
+    /// Usage relative to the lower of `memory.high` and `memory.max`, if either is set.
+    pub fn usage_ratio(&self) -> Option<f64> {
+        let limit = match (self.high, self.max) {
+            (Some(h), Some(m)) => h.min(m),
+            (l, None) | (None, l) => l?,
+        };
+        Some(self.current as f64 / limit.max(1) as f64)
+    }
+}
+
+fn read(dir: &Path, name: &str) -> Result<String, Error> {
+    Ok(std::fs::read_to_string(dir.join(name))?)
+}
+
+fn parse_u64(s: &str) -> Result<u64, Error> {
+    s.trim()
+        .parse()
+        .map_err(|_| std::io::Error::from_raw_os_error(libc::EINVAL).into())
+}
+
+/// Parses a limit file, where "max" means there is none.
+fn parse_limit(s: &str) -> Result<Option<u64>, Error> {
+    match s.trim() {
+        "max" => Ok(None),
+        s => parse_u64(s).map(Some),
+    }
+}
+
+fn parse_events(s: &str) -> MemoryEvents {
+    let mut e = MemoryEvents::default();
+    for line in s.lines() {
+        let mut words = line.split_whitespace();
+        let (key, value) = match (words.next(), words.next().map(str::parse)) {
+            (Some(k), Some(Ok(v))) => (k, v),
+            _ => continue,
+        };
+        match key {
+            "low" => e.low = value,
+            "high" => e.high = value,
+            "max" => e.max = value,
+            "oom" => e.oom = value,
+            "oom_kill" => e.oom_kill = value,
+            _ => {}
+        }
+    }
+    e
+}
+
+/// Gets avg10 from the "some" line of a PSI file.
+fn parse_some_avg10(s: &str) -> Option<f64> {
+    s.lines()
+        .find_map(|l| l.strip_prefix("some "))?
+        .split_whitespace()
+        .find_map(|w| w.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+/// Watches the memory of a cgroup, see the module documentation.
+pub struct MemoryMonitor {
+    dir: PathBuf,
+    threshold: f64,
+    stall_threshold: f64,
+    last: MemoryEvents,
+    trigger: Option<File>,
+}
+
+impl MemoryMonitor {
+    /// Watches the cgroup of this process.
+    ///
+    /// Fails with `ENOENT` if the process is not in a cgroup v2 hierarchy.
+    pub fn open() -> Result<Self, Error> {
+        let cgroups = std::fs::read_to_string("/proc/self/cgroup")?;
+        let path = cgroups
+            .lines()
+            .find_map(|l| l.strip_prefix("0::"))
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::ENOENT))?;
+        let dir = Path::new(CGROUP_ROOT).join(path.trim_start_matches('/'));
+        Self::at(dir)
+    }
+
+    /// Watches the cgroup v2 directory `dir`.
+    pub fn at<P: Into<PathBuf>>(dir: P) -> Result<Self, Error> {
+        let dir = dir.into();
+        let last = parse_events(&read(&dir, "memory.events")?);
+        Ok(MemoryMonitor {
+            dir,
+            threshold: 0.8,
+            stall_threshold: 10.0,
+            last,
+            trigger: None,
+        })
+    }
+
+    /// Usage relative to the limit from which on the level is `Elevated`; defaults to 0.8.
+    pub fn set_threshold(&mut self, ratio: f64) {
+        self.threshold = ratio;
+    }
+
+    /// Percentage of time with tasks stalling on memory from which on the level is
+    /// `Elevated`; defaults to 10.
+    pub fn set_stall_threshold(&mut self, percent: f64) {
+        self.stall_threshold = percent;
+    }
+
+    /// Reads the current state of the cgroup.
+    pub fn sample(&self) -> Result<MemoryState, Error> {
+        let dir = &self.dir;
+        Ok(MemoryState {
+            current: parse_u64(&read(dir, "memory.current")?)?,
+            high: parse_limit(&read(dir, "memory.high")?)?,
+            max: parse_limit(&read(dir, "memory.max")?)?,
+            events: parse_events(&read(dir, "memory.events")?),
+            some_avg10: read(dir, "memory.pressure")
+                .ok()
+                .and_then(|s| parse_some_avg10(&s)),
+        })
+    }
+
+    /// Samples the cgroup and rates it. Events count only if they happened since the
+    /// previous call.
+    pub fn poll(&mut self) -> Result<Level, Error> {
+        let s = self.sample()?;
+        let (last, e) = (self.last, s.events);
+        self.last = e;
+        let ratio = s.usage_ratio().unwrap_or(0.0);
+        if e.max > last.max || e.oom > last.oom || e.oom_kill > last.oom_kill || ratio >= 0.95 {
+            return Ok(Level::Critical);
+        }
+        if e.high > last.high
+            || ratio >= self.threshold
+            || s.some_avg10.is_some_and(|a| a >= self.stall_threshold)
+        {
+            return Ok(Level::Elevated);
+        }
+        Ok(Level::Normal)
+    }
+
+    /// Asks the kernel to wake up `wait` when tasks in the cgroup stall on memory for
+    /// `stall` within any `window`, see the kernel's PSI documentation for the limits.
+    ///
+    /// Without a trigger, `wait` just sleeps.
+    pub fn set_psi_trigger(&mut self, stall: Duration, window: Duration) -> Result<(), Error> {
+        use std::io::Write;
+        let mut f = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.dir.join("memory.pressure"))?;
+        let trigger = format!("some {} {}\0", stall.as_micros(), window.as_micros());
+        f.write_all(trigger.as_bytes())?;
+        self.trigger = Some(f);
+        Ok(())
+    }
+
+    /// Waits for the PSI trigger to fire, or for the timeout. Returns false on timeout.
+    pub fn wait(&self, timeout: Option<Duration>) -> Result<bool, Error> {
+        let f = match &self.trigger {
+            Some(f) => f,
+            None => {
+                crate::sim::sleep(timeout.unwrap_or(Duration::from_secs(1)));
+                return Ok(false);
+            }
+        };
+        let mut pfd = libc::pollfd {
+            fd: f.as_raw_fd(),
+            events: libc::POLLPRI,
+            revents: 0,
+        };
+        let ms = timeout
+            .map(|t| t.as_millis().min(i32::MAX as u128) as i32)
+            .unwrap_or(-1);
+        let r = unsafe { libc::poll(&mut pfd, 1, ms) };
+        if r < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() != std::io::ErrorKind::Interrupted {
+                Err(e)?
+            }
+        }
+        if pfd.revents & libc::POLLERR != 0 {
+            // The cgroup is gone.
+            Err(std::io::Error::from_raw_os_error(libc::ENODEV))?
+        }
+        Ok(r > 0)
+    }
+}
+
+/// What share of its ringbuffer a lossy sender may use at a level: all of it, a quarter,
+/// or a sixteenth. Other senders are left alone, as they would just fail to send.
+pub fn shrink<'a, I: IntoIterator<Item = &'a mut framed::Sender>>(level: Level, senders: I) {
+    for s in senders.into_iter().filter(|s| s.is_lossy()) {
+        // The items of a framed ringbuffer are words.
+        let capacity = s.ring().capacity() * 8;
+        s.set_occupancy_limit(match level {
+            Level::Normal => None,
+            Level::Elevated => Some(capacity / 4),
+            Level::Critical => Some(capacity / 16),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels() {
+        let dir = std::env::temp_dir().join(format!("shmem-ipc-pressure-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, s: &str| std::fs::write(dir.join(name), s).unwrap();
+        write("memory.current", "500\n");
+        write("memory.high", "max\n");
+        write("memory.max", "1000\n");
+        write("memory.events", "low 0\nhigh 0\nmax 0\noom 0\noom_kill 0\n");
+        write(
+            "memory.pressure",
+            "some avg10=1.50 avg60=0.00 avg300=0.00 total=10\nfull avg10=0.00 avg60=0.00 avg300=0.00 total=0\n",
+        );
+        let mut m = MemoryMonitor::at(&dir).unwrap();
+        let s = m.sample().unwrap();
+        assert_eq!((s.max, s.some_avg10), (Some(1000), Some(1.5)));
+        assert_eq!(m.poll().unwrap(), Level::Normal);
+        write("memory.current", "850\n");
+        assert_eq!(m.poll().unwrap(), Level::Elevated);
+        write("memory.current", "100\n");
+        write("memory.events", "low 0\nhigh 0\nmax 1\noom 0\noom_kill 0\n");
+        assert_eq!(m.poll().unwrap(), Level::Critical);
+        assert_eq!(m.poll().unwrap(), Level::Normal);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut s = framed::Sender::new(4096).unwrap();
+        s.set_lossy(true);
+        shrink(Level::Critical, std::iter::once(&mut s));
+        let mut sent = 0;
+        while s.send(&[0; 128]).unwrap() {
+            sent += 1;
+        }
+        // A header word and 128 bytes per message
+        assert!(sent > 0 && sent * 136 <= s.ring().capacity() * 8 / 16);
+        shrink(Level::Normal, std::iter::once(&mut s));
+        assert!(s.send(&[0; 128]).unwrap());
+    }
+}
@@ -0,0 +1,181 @@
+//! Finding out at runtime which kernel features are usable, and agreeing on a transport.
+//!
+//! A kernel can be too old for a feature, or a seccomp profile can deny it, typically with
+//! `EPERM` or `ENOSYS`, so the only reliable test is trying. `capabilities` does, and
+//! `negotiate` exchanges the result with a peer over a unix socket, so both ends can pick
+//! the best transport they have in common.
+//!
+//! # Example
+//! ```rust
+//! use shmem_ipc::{channel::Transport, probe};
+//! use std::os::unix::net::UnixStream;
+//! let (a, b) = UnixStream::pair().unwrap();
+//! let peer = std::thread::spawn(move || probe::negotiate(&b, probe::capabilities()));
+//! let common = probe::negotiate(&a, probe::capabilities()).unwrap();
+//! assert_eq!(common, peer.join().unwrap().unwrap());
+//! if common.best_transport() == Transport::SharedMemory {
+//!     // set up sharedring or framed channels
+//! }
+//! ```
+
+use crate::channel::Transport;
+use crate::mem::{mfd, CreateOptions};
+use crate::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixStream;
+
+/// "SHMP", the first word of the negotiation message.
+const MAGIC: u32 = 0x504d_4853;
+
+const CAP_MEMFD: u64 = 1 << 0;
+const CAP_MEMFD_SEALING: u64 = 1 << 1;
+const CAP_EVENTFD: u64 = 1 << 2;
+const CAP_HUGE_PAGES: u64 = 1 << 3;
+const CAP_MEMFD_SECRET: u64 = 1 << 4;
+const CAP_PIDFD: u64 = 1 << 5;
+
+/// Kernel features available to this process, see `capabilities`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// `memfd_create` works.
+    pub memfd: bool,
+    /// Seals can be added to memfds, which untrusted peers need.
+    pub memfd_sealing: bool,
+    /// `eventfd` works.
+    pub eventfd: bool,
+    /// Hugetlb memfds can be created, and there are free huge pages.
+    pub huge_pages: bool,
+    /// `memfd_secret` works.
+    pub memfd_secret: bool,
+    /// `pidfd_open` works.
+    pub pidfd: bool,
+}
+
+impl Capabilities {
+    fn to_bits(self) -> u64 {
+        let caps = [
+            (self.memfd, CAP_MEMFD),
+            (self.memfd_sealing, CAP_MEMFD_SEALING),
+            (self.eventfd, CAP_EVENTFD),
+            (self.huge_pages, CAP_HUGE_PAGES),
+            (self.memfd_secret, CAP_MEMFD_SECRET),
+            (self.pidfd, CAP_PIDFD),
+        ];
+        caps.iter().filter(|c| c.0).fold(0, |bits, c| bits | c.1)
+    }
+
+    /// Unknown bits, from a newer peer, are ignored.
+    fn from_bits(bits: u64) -> Self {
+        Capabilities {
+            memfd: bits & CAP_MEMFD != 0,
+            memfd_sealing: bits & CAP_MEMFD_SEALING != 0,
+            eventfd: bits & CAP_EVENTFD != 0,
+            huge_pages: bits & CAP_HUGE_PAGES != 0,
+            memfd_secret: bits & CAP_MEMFD_SECRET != 0,
+            pidfd: bits & CAP_PIDFD != 0,
+        }
+    }
+
+    /// The features both `self` and `other` have.
+    pub fn intersection(self, other: Capabilities) -> Self {
+        Self::from_bits(self.to_bits() & other.to_bits())
+    }
+
+    /// The best transport these features allow: shared memory needs sealed memfds and
+    /// eventfds, otherwise there is the unix socket.
+    pub fn best_transport(&self) -> Transport {
+        if self.memfd && self.memfd_sealing && self.eventfd {
+            Transport::SharedMemory
+        } else {
+            Transport::Socket
+        }
+    }
+}
+
+/// Takes ownership of a file descriptor returned by a syscall, if it succeeded.
+fn fd(r: libc::c_long) -> Option<File> {
+    if r < 0 {
+        None
+    } else {
+        Some(unsafe { File::from_raw_fd(r as libc::c_int) })
+    }
+}
+
+fn free_huge_pages() -> bool {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").unwrap_or_default();
+    meminfo
+        .lines()
+        .find_map(|l| l.strip_prefix("HugePages_Free:"))
+        .and_then(|n| n.trim().parse::<u64>().ok())
+        .is_some_and(|n| n > 0)
+}
+
+/// Tries out the kernel features this crate can use, see `Capabilities`.
+///
+/// This creates and closes a few file descriptors, so it is cheap, but not free; call it
+/// once at startup.
+pub fn capabilities() -> Capabilities {
+    let memfd = CreateOptions::new().create("probe").ok();
+    let memfd_sealing = memfd
+        .as_ref()
+        .is_some_and(|m| m.add_seal(mfd::FileSeal::SealShrink).is_ok());
+    let huge_pages = CreateOptions::new()
+        .hugetlb(Some(mfd::HugetlbSize::Huge2MB))
+        .create("probe")
+        .is_ok()
+        && free_huge_pages();
+    let eventfd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+    let secret = unsafe { libc::syscall(libc::SYS_memfd_secret, libc::O_CLOEXEC) };
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, libc::getpid(), 0) };
+    Capabilities {
+        memfd: memfd.is_some(),
+        memfd_sealing,
+        eventfd: fd(eventfd as libc::c_long).is_some(),
+        huge_pages,
+        memfd_secret: fd(secret).is_some(),
+        pidfd: fd(pidfd).is_some(),
+    }
+}
+
+/// Sends our capabilities to the peer at the other end of `socket`, who must call this too,
+/// and returns the capabilities both have.
+pub fn negotiate(socket: &UnixStream, local: Capabilities) -> Result<Capabilities, Error> {
+    let mut msg = [0u8; 16];
+    msg[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    msg[8..].copy_from_slice(&local.to_bits().to_le_bytes());
+    let mut s = socket;
+    s.write_all(&msg)?;
+    s.read_exact(&mut msg)?;
+    if msg[..4] != MAGIC.to_le_bytes() {
+        Err(crate::ringbuf::Error::BufCorrupt)?
+    }
+    let mut bits = [0u8; 8];
+    bits.copy_from_slice(&msg[8..]);
+    Ok(local.intersection(Capabilities::from_bits(u64::from_le_bytes(bits))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_common() {
+        let local = capabilities();
+        // The test suite runs on kernels with shared memory support.
+        assert_eq!(local.best_transport(), Transport::SharedMemory);
+        assert_eq!(Capabilities::from_bits(local.to_bits()), local);
+        let (a, b) = UnixStream::pair().unwrap();
+        // A peer in a sandbox without eventfd.
+        let peer = Capabilities {
+            eventfd: false,
+            ..local
+        };
+        let t = std::thread::spawn(move || negotiate(&b, peer));
+        let common = negotiate(&a, local).unwrap();
+        assert_eq!(common, t.join().unwrap().unwrap());
+        assert_eq!(common, peer);
+        assert_eq!(common.best_transport(), Transport::Socket);
+    }
+}
@@ -0,0 +1,152 @@
+//! Cycle counts of the hot path, to tell whether latency is spent copying, on the atomic
+//! operations of the ringbuffer, or on waking up the other side.
+//!
+//! Requires the `profiling` feature. Every `sharedring::Sender` and `Receiver` then keeps a
+//! `Profile`, see `Sender::profile`. Counts are in ticks of the CPU cycle counter: `rdtsc` on
+//! x86_64, `cntvct_el0` on aarch64 (which ticks at a fixed frequency lower than the CPU
+//! clock), and nanoseconds elsewhere.
+
+/// Number of buckets in a histogram, one per power of two.
+pub const BUCKETS: usize = 64;
+
+/// Reads the cycle counter.
+#[inline]
+pub fn cycles() -> u64 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        unsafe { core::arch::x86_64::_rdtsc() }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        let v: u64;
+        unsafe { std::arch::asm!("mrs {}, cntvct_el0", out(reg) v, options(nomem, nostack)) };
+        v
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+        ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+    }
+}
+
+/// A histogram of cycle counts, with power of two buckets.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: [0; BUCKETS],
+            count: 0,
+            sum: 0,
+            max: 0,
+        }
+    }
+}
+
+impl Histogram {
+    #[inline]
+    pub(crate) fn record(&mut self, cycles: u64) {
+        let i = (63 - cycles.max(1).leading_zeros()) as usize;
+        self.buckets[i] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(cycles);
+        self.max = self.max.max(cycles);
+    }
+
+    /// Number of samples in each bucket; bucket `i` holds counts from `2^i` up to
+    /// `2^(i+1) - 1`, and bucket 0 holds zero too.
+    pub fn buckets(&self) -> &[u64; BUCKETS] {
+        &self.buckets
+    }
+
+    /// Number of samples.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Average cycles per sample, or zero if there are none.
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.count as f64
+        }
+    }
+
+    /// Largest sample.
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile, e g 0.99, or zero if there are no
+    /// samples.
+    pub fn quantile(&self, q: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return ((2u128 << i) - 1).min(self.max as u128) as u64;
+            }
+        }
+        self.max
+    }
+}
+
+/// Where one side of a ringbuffer spends its cycles, one sample per call that moved items.
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    /// In the closure that reads or writes the items, i e copying.
+    pub copy: Histogram,
+    /// In the ringbuffer bookkeeping around it, mostly the atomic operations.
+    pub atomic: Histogram,
+    /// In writing to the eventfd that wakes up the other side.
+    pub signal: Histogram,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram() {
+        let mut h = Histogram::default();
+        assert_eq!(h.quantile(0.5), 0);
+        for x in [0, 1, 3, 100, 1000].iter() {
+            h.record(*x);
+        }
+        assert_eq!((h.count(), h.max()), (5, 1000));
+        assert_eq!(h.buckets()[0], 2);
+        assert_eq!(h.buckets()[6], 1);
+        assert_eq!(h.quantile(0.5), 3);
+        assert_eq!(h.quantile(1.0), 1000);
+    }
+
+    #[test]
+    fn ring_profile() {
+        let mut s: crate::sharedring::Sender<u64> = crate::sharedring::Sender::new(100).unwrap();
+        let before = cycles();
+        s.send_raw(|_, _| 10).unwrap();
+        assert!(cycles() >= before);
+        let p = s.profile();
+        assert_eq!(
+            (p.copy.count(), p.atomic.count(), p.signal.count()),
+            (1, 1, 1)
+        );
+        s.reset_profile();
+        assert_eq!(s.profile().copy.count(), 0);
+    }
+}
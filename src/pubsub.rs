@@ -0,0 +1,391 @@
+//! Zero-copy publish/subscribe with loaned chunks.
+//!
+//! Payloads live in a shared pool of fixed size chunks. A publisher loans a chunk, fills it in
+//! and publishes it; only the chunk index traverses the ringbuffer. The subscriber hands the
+//! chunk back through a second ringbuffer when it is done with it.
+//! This is structurally the same as iceoryx style zero-copy pub/sub.
+//!
+//! The pool is a `mem::Broadcast`: it is sealed with `F_SEAL_FUTURE_WRITE`, so only the
+//! publisher's own mapping is writable, and subscribers can only map it read-only. The
+//! chunk indices live in the ringbuffers, which are small and the only memory a subscriber
+//! can write to; a compromised subscriber can mess up its own indices, but not the payloads
+//! that the publisher and any other process holding the pool read. Requires linux version
+//! 5.1+.
+//!
+//! The information to be transferred between processes, in addition to what the two
+//! `sharedring`s need, is:
+//!  * number of chunks
+//!  * pool memfd file descriptor
+
+use super::Error;
+use crate::mem::mfd;
+use crate::sharedring;
+use std::cell::RefCell;
+use std::fs::File;
+use std::marker::PhantomData;
+
+fn pool_bytes<T>(chunks: usize) -> usize {
+    std::cmp::max(chunks * std::mem::size_of::<T>(), 1)
+}
+
+fn chunk_ptr<T>(pool: *const u8, index: u32) -> *mut T {
+    unsafe { (pool as *mut T).add(index as usize) }
+}
+
+struct PubState {
+    queue: sharedring::Sender<u32>,
+    release: sharedring::Receiver<u32>,
+    free: Vec<u32>,
+    in_flight: Vec<bool>,
+}
+
+impl PubState {
+    fn reclaim(&mut self) -> Result<(), Error> {
+        let PubState {
+            release,
+            free,
+            in_flight,
+            ..
+        } = self;
+        let mut corrupt = false;
+        loop {
+            let status = release.receive_raw(|p, count| {
+                for i in 0..count {
+                    let idx = unsafe { std::ptr::read(p.add(i)) };
+                    match in_flight.get_mut(idx as usize) {
+                        Some(x) if *x => {
+                            *x = false;
+                            free.push(idx);
+                        }
+                        _ => corrupt = true,
+                    }
+                }
+                count
+            })?;
+            if corrupt {
+                Err(crate::ringbuf::Error::BufCorrupt)?
+            }
+            if status.remaining == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The sending side, which owns the pool.
+pub struct Publisher<T> {
+    pool: crate::mem::Broadcast,
+    state: RefCell<PubState>,
+    _phantom: PhantomData<T>,
+}
+
+/// A chunk loaned from the publisher's pool, to be filled in and published.
+///
+/// Dropping it without publishing returns the chunk to the pool.
+pub struct SampleMut<'a, T> {
+    publisher: &'a Publisher<T>,
+    index: u32,
+    published: bool,
+}
+
+impl<T: Copy + zerocopy::AsBytes + zerocopy::FromBytes> Publisher<T> {
+    /// Creates the pool and both ringbuffers.
+    pub fn new(chunks: usize) -> Result<Self, Error> {
+        if chunks == 0 || chunks > u32::MAX as usize {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        let pool = crate::mem::broadcast(pool_bytes::<T>(chunks), std::any::type_name::<T>())?;
+        let state = PubState {
+            queue: sharedring::Sender::new(chunks)?,
+            release: sharedring::Receiver::new(chunks)?,
+            free: (0..chunks as u32).rev().collect(),
+            in_flight: vec![false; chunks],
+        };
+        Ok(Publisher {
+            pool,
+            state: RefCell::new(state),
+            _phantom: PhantomData,
+        })
+    }
+
+    /// The file descriptor of the chunk pool. It can only be mapped read-only.
+    pub fn pool_memfd(&self) -> &mfd::Memfd {
+        self.pool.memfd()
+    }
+
+    /// Runs a closure with the ringbuffer carrying published chunk indices,
+    /// e g to get its file descriptors.
+    pub fn with_queue<R, F: FnOnce(&sharedring::Sender<u32>) -> R>(&self, f: F) -> R {
+        f(&self.state.borrow().queue)
+    }
+
+    /// Runs a closure with the ringbuffer carrying released chunk indices,
+    /// e g to get its file descriptors.
+    pub fn with_release<R, F: FnOnce(&sharedring::Receiver<u32>) -> R>(&self, f: F) -> R {
+        f(&self.state.borrow().release)
+    }
+
+    /// Loans a chunk from the pool.
+    ///
+    /// Returns `None` if all chunks are loaned out or not yet released by the subscriber.
+    pub fn loan(&self) -> Result<Option<SampleMut<'_, T>>, Error> {
+        let mut state = self.state.borrow_mut();
+        if state.free.is_empty() {
+            state.reclaim()?;
+        }
+        Ok(state.free.pop().map(|index| SampleMut {
+            publisher: self,
+            index,
+            published: false,
+        }))
+    }
+
+    /// Makes the sample available to the subscriber.
+    ///
+    /// Fails with `EINVAL` if the sample was loaned from another publisher; it then goes
+    /// back to that publisher's pool.
+    pub fn publish(&self, mut sample: SampleMut<'_, T>) -> Result<(), Error> {
+        if !std::ptr::eq(self, sample.publisher) {
+            Err(std::io::Error::from_raw_os_error(libc::EINVAL))?
+        }
+        let mut state = self.state.borrow_mut();
+        let index = sample.index;
+        let mut written = false;
+        state.queue.send_raw(|p, _| {
+            unsafe { std::ptr::write(p, index) };
+            written = true;
+            1
+        })?;
+        if !written {
+            // The subscriber holds at most all chunks, so the queue can only be full if it
+            // has been tampered with.
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        state.in_flight[index as usize] = true;
+        sample.published = true;
+        Ok(())
+    }
+}
+
+impl<'a, T: Copy> SampleMut<'a, T> {
+    /// Raw pointer to the chunk.
+    ///
+    /// Since the subscriber is untrusted, we cannot create references to the data.
+    pub fn as_mut_ptr(&mut self) -> *mut T {
+        chunk_ptr(self.publisher.pool.as_mut_ptr(), self.index)
+    }
+
+    /// Writes the payload into the chunk.
+    pub fn write(&mut self, value: T) {
+        unsafe { std::ptr::write(self.as_mut_ptr(), value) }
+    }
+
+    /// Returns a mutable reference to the chunk.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that no one else can read or write the pool, except for at most one
+    /// Subscriber set up correctly.
+    pub unsafe fn as_mut(&mut self) -> &mut T {
+        &mut *self.as_mut_ptr()
+    }
+}
+
+impl<'a, T> Drop for SampleMut<'a, T> {
+    fn drop(&mut self) {
+        if !self.published {
+            self.publisher.state.borrow_mut().free.push(self.index);
+        }
+    }
+}
+
+struct SubState {
+    queue: sharedring::Receiver<u32>,
+    release: sharedring::Sender<u32>,
+}
+
+/// The receiving side.
+pub struct Subscriber<T> {
+    memfd: mfd::Memfd,
+    pool: crate::mem::BroadcastView,
+    chunks: usize,
+    state: RefCell<SubState>,
+    _phantom: PhantomData<T>,
+}
+
+/// A published chunk. Dropping it releases the chunk back to the publisher.
+pub struct Sample<'a, T> {
+    subscriber: &'a Subscriber<T>,
+    index: u32,
+}
+
+impl<T: Copy + zerocopy::AsBytes + zerocopy::FromBytes> Subscriber<T> {
+    /// Attaches to a pool set up by the publisher, given the two ringbuffers opened
+    /// from the publisher's file descriptors.
+    ///
+    /// The pool is mapped read-only, and sealed against writable mappings if the publisher
+    /// did not do that already.
+    pub fn open(
+        chunks: usize,
+        pool: File,
+        queue: sharedring::Receiver<u32>,
+        release: sharedring::Sender<u32>,
+    ) -> Result<Self, Error> {
+        let bytes = pool_bytes::<T>(chunks);
+        let memfd = crate::mem::memfd_from_file(pool)?;
+        let pool = crate::mem::read_broadcast(&memfd)?;
+        if pool.len() < bytes {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        Ok(Subscriber {
+            memfd,
+            pool,
+            chunks,
+            state: RefCell::new(SubState { queue, release }),
+            _phantom: PhantomData,
+        })
+    }
+
+    /// The file descriptor of the chunk pool.
+    pub fn pool_memfd(&self) -> &mfd::Memfd {
+        &self.memfd
+    }
+
+    /// Runs a closure with the ringbuffer carrying published chunk indices,
+    /// e g to register its `empty_signal` with an event loop.
+    pub fn with_queue<R, F: FnOnce(&sharedring::Receiver<u32>) -> R>(&self, f: F) -> R {
+        f(&self.state.borrow().queue)
+    }
+
+    /// Takes the next published sample, if any.
+    pub fn receive(&self) -> Result<Option<Sample<'_, T>>, Error> {
+        let mut state = self.state.borrow_mut();
+        let mut index = None;
+        state.queue.receive_raw(|p, _| {
+            index = Some(unsafe { std::ptr::read(p) });
+            1
+        })?;
+        match index {
+            None => Ok(None),
+            Some(i) if (i as usize) < self.chunks => Ok(Some(Sample {
+                subscriber: self,
+                index: i,
+            })),
+            Some(_) => Err(crate::ringbuf::Error::BufCorrupt)?,
+        }
+    }
+
+    /// For blocking scenarios, blocks until a sample is available.
+    pub fn block_until_readable(&self) -> Result<(), Error> {
+        self.state.borrow_mut().queue.block_until_readable()?;
+        Ok(())
+    }
+}
+
+impl<'a, T: Copy> Sample<'a, T> {
+    /// Raw pointer to the chunk.
+    pub fn as_ptr(&self) -> *const T {
+        chunk_ptr(self.subscriber.pool.as_ptr(), self.index)
+    }
+
+    /// Copies the payload out of the chunk.
+    pub fn read(&self) -> T {
+        unsafe { std::ptr::read(self.as_ptr()) }
+    }
+
+    /// Returns a reference to the chunk.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that no one else can write to the pool while the reference is alive.
+    pub unsafe fn as_ref(&self) -> &T {
+        &*self.as_ptr()
+    }
+}
+
+impl<'a, T> Drop for Sample<'a, T> {
+    fn drop(&mut self) {
+        let index = self.index;
+        // The release ring has room for every chunk, so this can only fail if the
+        // publisher has corrupted it, in which case there is nothing we can do.
+        let _ = self.subscriber.state.borrow_mut().release.send_raw(|p, _| {
+            unsafe { std::ptr::write(p, index) };
+            1
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::mmap;
+
+    fn setup(chunks: usize) -> (Publisher<u64>, Subscriber<u64>) {
+        let p = Publisher::<u64>::new(chunks).unwrap();
+        let queue = p.with_queue(|s| {
+            let m = s.memfd().as_file().try_clone().unwrap();
+            let e = s.empty_signal().try_clone().unwrap();
+            let f = s.full_signal().try_clone().unwrap();
+            sharedring::Receiver::open(chunks, m, e, f).unwrap()
+        });
+        let release = p.with_release(|r| {
+            let m = r.memfd().as_file().try_clone().unwrap();
+            let e = r.empty_signal().try_clone().unwrap();
+            let f = r.full_signal().try_clone().unwrap();
+            sharedring::Sender::open(chunks, m, e, f).unwrap()
+        });
+        let pool = p.pool_memfd().as_file().try_clone().unwrap();
+        let s = Subscriber::open(chunks, pool, queue, release).unwrap();
+        (p, s)
+    }
+
+    #[test]
+    fn loan_publish_receive() {
+        let (p, s) = setup(2);
+        assert!(s.receive().unwrap().is_none());
+        let mut a = p.loan().unwrap().unwrap();
+        a.write(5);
+        let mut b = p.loan().unwrap().unwrap();
+        b.write(6);
+        assert!(p.loan().unwrap().is_none());
+        p.publish(a).unwrap();
+        drop(b);
+        let mut c = p.loan().unwrap().unwrap();
+        c.write(7);
+        p.publish(c).unwrap();
+        assert!(p.loan().unwrap().is_none());
+
+        let x = s.receive().unwrap().unwrap();
+        assert_eq!(x.read(), 5);
+        drop(x);
+        let mut d = p.loan().unwrap().unwrap();
+        d.write(8);
+        p.publish(d).unwrap();
+        assert_eq!(s.receive().unwrap().unwrap().read(), 7);
+        assert_eq!(s.receive().unwrap().unwrap().read(), 8);
+        assert!(s.receive().unwrap().is_none());
+    }
+
+    #[test]
+    fn read_only_pool() {
+        let (p, s) = setup(2);
+        for m in &[p.pool_memfd(), s.pool_memfd()] {
+            let w = unsafe { mmap::MmapOptions::new().map_mut(m.as_file()) };
+            assert_eq!(w.unwrap_err().raw_os_error(), Some(libc::EPERM));
+        }
+        let mut a = p.loan().unwrap().unwrap();
+        a.write(5);
+        p.publish(a).unwrap();
+        assert_eq!(s.receive().unwrap().unwrap().read(), 5);
+    }
+    #[test]
+    fn foreign_sample() {
+        let (p, _s) = setup(1);
+        let (q, t) = setup(1);
+        let a = p.loan().unwrap().unwrap();
+        assert_eq!(q.publish(a).unwrap_err().errno(), Some(libc::EINVAL));
+        assert!(t.receive().unwrap().is_none());
+        // Back in its own pool
+        assert!(p.loan().unwrap().is_some());
+    }
+}
@@ -0,0 +1,243 @@
+//! Accounting of the memory in memfds created by this crate.
+//!
+//! Every memfd the crate creates (for ringbuffers, pools, broadcast areas and so on) is
+//! charged against a process wide quota, and optionally against a named group, e g one per
+//! client. Creating a memfd that would go over the limit fails with `Error::QuotaExceeded`.
+//! There are no limits until `set_limit` is called.
+//!
+//! The charge is released when the object owning the memfd is dropped, e g a
+//! `mem::ChargedMemfd`. Memfds handed out as they are, e g by `mem::write_once`, are only
+//! charged while they are written, since there is no telling when they go away.
+//!
+//! A daemon serving many untrusted clients can give each user a group of its own with
+//! `Tenant`, which also checks that file descriptors come from that user.
+
+use crate::unix::PeerCred;
+use crate::Error;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Account {
+    limit: Option<u64>,
+    used: u64,
+}
+
+impl Account {
+    fn admits(&self, bytes: u64) -> bool {
+        self.limit
+            .is_none_or(|l| self.used.saturating_add(bytes) <= l)
+    }
+}
+
+struct State {
+    total: Account,
+    groups: BTreeMap<String, Account>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    total: Account {
+        limit: None,
+        used: 0,
+    },
+    groups: BTreeMap::new(),
+});
+
+fn state() -> std::sync::MutexGuard<'static, State> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Sets the limit, in bytes, for the whole process (if `group` is `None`) or for a group.
+///
+/// `None` removes the limit. Lowering a limit below what is in use does not affect existing
+/// memfds, only new ones.
+pub fn set_limit(group: Option<&str>, bytes: Option<u64>) {
+    let mut s = state();
+    match group {
+        None => s.total.limit = bytes,
+        Some(g) => s.groups.entry(g.into()).or_default().limit = bytes,
+    }
+}
+
+/// Returns the number of bytes currently charged to the whole process, or to a group.
+pub fn used(group: Option<&str>) -> u64 {
+    let s = state();
+    match group {
+        None => s.total.used,
+        Some(g) => s.groups.get(g).map(|a| a.used).unwrap_or(0),
+    }
+}
+
+/// A client of a daemon shared by many, identified by its uid, with a quota group of its own
+/// named after it, e g `tenant-1000` with the prefix `tenant-`.
+///
+/// Clients running as the same user share a tenant, as the kernel cannot tell them apart
+/// from their sockets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tenant {
+    uid: libc::uid_t,
+    group: String,
+}
+
+impl Tenant {
+    /// The tenant for `uid`, whose group is `prefix` followed by the uid.
+    pub fn new(prefix: &str, uid: libc::uid_t) -> Self {
+        Tenant {
+            uid,
+            group: format!("{}{}", prefix, uid),
+        }
+    }
+
+    /// The tenant of the peer at the other end of `socket`, if `policy` accepts the peer.
+    ///
+    /// Fails with `PermissionDenied` otherwise, see `unix::verify_peer`.
+    pub fn of_peer<F: FnOnce(&PeerCred) -> bool>(
+        prefix: &str,
+        socket: &UnixStream,
+        policy: F,
+    ) -> io::Result<Self> {
+        let cred = crate::unix::verify_peer(socket, policy)?;
+        Ok(Self::new(prefix, cred.uid))
+    }
+
+    /// The user the tenant's clients run as.
+    pub fn uid(&self) -> libc::uid_t {
+        self.uid
+    }
+
+    /// The quota group, for `set_limit`, `used` and `SharedRingBuilder::quota_group`.
+    pub fn group(&self) -> &str {
+        &self.group
+    }
+
+    /// Sets the limit of the tenant's group, see `set_limit`.
+    pub fn set_limit(&self, bytes: Option<u64>) {
+        set_limit(Some(&self.group), bytes)
+    }
+
+    /// Bytes currently charged to the tenant's group.
+    pub fn used(&self) -> u64 {
+        used(Some(&self.group))
+    }
+
+    /// A builder for ringbuffers charged to the tenant's group.
+    pub fn ring_builder(&self, capacity: usize) -> crate::sharedring::SharedRingBuilder {
+        crate::sharedring::SharedRingBuilder::new(capacity).quota_group(&self.group)
+    }
+
+    /// Receives data and file descriptors from `socket`, but only if its peer runs as the
+    /// tenant's user, see `unix::recv_with_fds_verified`.
+    ///
+    /// Fails with `PermissionDenied` for other peers, without accepting anything.
+    pub fn recv_with_fds(
+        &self,
+        socket: &UnixStream,
+        data: &mut [u8],
+        fds: &mut Vec<File>,
+    ) -> io::Result<usize> {
+        let policy = |c: &PeerCred| c.uid == self.uid;
+        let (_, n) = crate::unix::recv_with_fds_verified(socket, data, fds, policy)?;
+        Ok(n)
+    }
+}
+
+/// Bytes charged against the quota, released on drop.
+#[derive(Debug)]
+pub(crate) struct Charge {
+    group: Option<String>,
+    bytes: u64,
+}
+
+impl Charge {
+    /// Charges `bytes`, or fails if that would exceed the process or group limit.
+    pub(crate) fn new(group: Option<&str>, bytes: u64) -> Result<Self, Error> {
+        let mut c = Charge {
+            group: group.map(Into::into),
+            bytes: 0,
+        };
+        c.resize(bytes)?;
+        Ok(c)
+    }
+
+    /// Changes the charge to `bytes`, e g when a memfd grows.
+    pub(crate) fn resize(&mut self, bytes: u64) -> Result<(), Error> {
+        let mut s = state();
+        let s = &mut *s;
+        let grow = bytes.saturating_sub(self.bytes);
+        let group = match &self.group {
+            None => None,
+            Some(g) => Some(s.groups.entry(g.clone()).or_default()),
+        };
+        if !s.total.admits(grow) || group.as_ref().is_some_and(|a| !a.admits(grow)) {
+            Err(Error::QuotaExceeded {
+                group: self.group.clone(),
+            })?
+        }
+        s.total.used = s.total.used - self.bytes + bytes;
+        if let Some(a) = group {
+            a.used = a.used - self.bytes + bytes;
+        }
+        self.bytes = bytes;
+        Ok(())
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        let _ = self.resize(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_limit() {
+        let g = Some("quota-test");
+        set_limit(g, Some(100000));
+        let b = crate::sharedring::SharedRingBuilder::new(40000).quota_group("quota-test");
+        let (ring, _) = b.build_sender::<u8>().unwrap();
+        assert!(used(g) >= 40000);
+        assert!(matches!(
+            b.build_receiver::<u16>(),
+            Err(Error::QuotaExceeded { .. })
+        ));
+        drop(ring);
+        assert_eq!(used(g), 0);
+        assert!(b.build_receiver::<u16>().is_ok());
+    }
+
+    #[test]
+    fn tenant() {
+        use std::os::unix::io::AsRawFd;
+        let (a, b) = UnixStream::pair().unwrap();
+        let t = Tenant::of_peer("quota-tenant-", &a, |c| c.is_same_user()).unwrap();
+        let uid = unsafe { libc::geteuid() };
+        assert_eq!(t.group(), format!("quota-tenant-{}", uid));
+        let e = Tenant::of_peer("quota-tenant-", &a, |_| false).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+
+        t.set_limit(Some(50000));
+        let (ring, _) = t.ring_builder(40000).build_sender::<u8>().unwrap();
+        assert!(t.used() >= 40000);
+        assert!(matches!(
+            t.ring_builder(40000).build_receiver::<u8>(),
+            Err(Error::QuotaExceeded { group: Some(g) }) if g == t.group()
+        ));
+        drop(ring);
+
+        let memfd = crate::mem::write_once(4096, "tenant", |_| {}).unwrap();
+        crate::unix::send_with_fds(&b, b"x", &[memfd.as_raw_fd()]).unwrap();
+        let (mut data, mut fds) = ([0u8; 1], vec![]);
+        let other = Tenant::new("quota-tenant-", uid.wrapping_add(1));
+        let e = other.recv_with_fds(&a, &mut data, &mut fds).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert!(fds.is_empty());
+        assert_eq!(t.recv_with_fds(&a, &mut data, &mut fds).unwrap(), 1);
+        assert_eq!(fds.len(), 1);
+    }
+}
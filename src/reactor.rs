@@ -0,0 +1,332 @@
+//! One thread that waits for many channels, instead of one blocked thread per channel.
+//!
+//! A `Reactor` owns an epoll instance and a thread waiting on it. Channels register the
+//! file descriptor their data arrives on, typically `Receiver::empty_signal`, with a
+//! callback; when it becomes readable, the reactor thread calls the callback, which should
+//! then receive everything there is, or hand the work to someone who will: the sender only
+//! signals when the ringbuffer goes from empty to non-empty. For tasks that
+//! want to wait themselves, `register_notify` gives a `Notify` to block on or to poll from
+//! a future.
+//!
+//! Registrations are edge triggered: a callback runs once per signal, however much data
+//! came with it, and not again until the next one.
+//!
+//! # Example
+//! ```rust
+//! use shmem_ipc::{reactor::Reactor, sharedring::{Receiver, Sender}};
+//! use std::sync::{Arc, Mutex};
+//! let reactor = Reactor::global().unwrap();
+//! let mut r: Receiver<u64> = Receiver::new(16).unwrap();
+//! let mut s = Sender::open(16, r.memfd().as_file().try_clone().unwrap(),
+//!     r.empty_signal().try_clone().unwrap(), r.full_signal().try_clone().unwrap()).unwrap();
+//! let fd = r.empty_signal().try_clone().unwrap();
+//! let got = Arc::new(Mutex::new(vec![]));
+//! let g = got.clone();
+//! let reg = reactor.register(&fd, move || {
+//!     r.receive_raw(|p, n| {
+//!         g.lock().unwrap().extend_from_slice(unsafe { std::slice::from_raw_parts(p, n) });
+//!         n
+//!     }).unwrap();
+//! }).unwrap();
+//! s.send_raw(|p, n| { unsafe { *p = 7 }; 1 }).unwrap();
+//! while got.lock().unwrap().is_empty() { std::thread::yield_now() }
+//! drop(reg);
+//! ```
+
+use crate::Error;
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::task::Waker;
+use std::thread::{JoinHandle, ThreadId};
+use std::time::Duration;
+
+/// Epoll token of the eventfd that wakes up the reactor thread itself.
+const WAKE_TOKEN: u64 = 0;
+/// Events handled per `epoll_wait`.
+const MAX_EVENTS: usize = 64;
+
+type Handler = Arc<Mutex<Box<dyn FnMut() + Send>>>;
+
+struct Shared {
+    epoll: File,
+    wake: File,
+    handlers: Mutex<HashMap<u64, Handler>>,
+    next: AtomicU64,
+    stop: AtomicBool,
+    thread: OnceLock<ThreadId>,
+}
+
+fn cvt(r: libc::c_int) -> Result<libc::c_int, std::io::Error> {
+    if r < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(r)
+    }
+}
+
+impl Shared {
+    fn add(&self, fd: libc::c_int, token: u64) -> Result<(), std::io::Error> {
+        let mut ev = libc::epoll_event {
+            events: (libc::EPOLLIN | libc::EPOLLET) as u32,
+            u64: token,
+        };
+        let epoll = self.epoll.as_raw_fd();
+        cvt(unsafe { libc::epoll_ctl(epoll, libc::EPOLL_CTL_ADD, fd, &mut ev) })?;
+        Ok(())
+    }
+
+    fn run(&self) {
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        while !self.stop.load(Ordering::Acquire) {
+            let n = unsafe {
+                libc::epoll_wait(
+                    self.epoll.as_raw_fd(),
+                    events.as_mut_ptr(),
+                    MAX_EVENTS as libc::c_int,
+                    -1,
+                )
+            };
+            // EINTR is the only error epoll_wait can give us here.
+            for ev in events.iter().take(n.max(0) as usize) {
+                let token = ev.u64;
+                if token == WAKE_TOKEN {
+                    continue;
+                }
+                let h = self.handlers.lock().unwrap().get(&token).cloned();
+                if let Some(h) = h {
+                    (*h.lock().unwrap_or_else(|e| e.into_inner()))();
+                }
+            }
+        }
+    }
+}
+
+/// A thread dispatching readiness of file descriptors to callbacks, see the module
+/// documentation.
+pub struct Reactor {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+static GLOBAL: OnceLock<Reactor> = OnceLock::new();
+
+impl Reactor {
+    /// Starts a reactor with a thread of its own.
+    pub fn new() -> Result<Self, Error> {
+        let epoll = cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        let epoll = unsafe { File::from_raw_fd(epoll) };
+        let wake = cvt(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) })?;
+        let wake = unsafe { File::from_raw_fd(wake) };
+        let shared = Arc::new(Shared {
+            epoll,
+            wake,
+            handlers: Mutex::new(HashMap::new()),
+            next: AtomicU64::new(WAKE_TOKEN + 1),
+            stop: AtomicBool::new(false),
+            thread: OnceLock::new(),
+        });
+        shared.add(shared.wake.as_raw_fd(), WAKE_TOKEN)?;
+        let s = shared.clone();
+        let thread = std::thread::Builder::new()
+            .name("shmem-ipc reactor".into())
+            .spawn(move || s.run())?;
+        let _ = shared.thread.set(thread.thread().id());
+        Ok(Reactor {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// The reactor of this process, started on first use.
+    pub fn global() -> Result<&'static Reactor, Error> {
+        if let Some(r) = GLOBAL.get() {
+            return Ok(r);
+        }
+        // If another thread got there first, ours just stops again.
+        let _ = GLOBAL.set(Self::new()?);
+        Ok(GLOBAL.get().unwrap())
+    }
+
+    /// Calls `f` on the reactor thread every time `fd` becomes readable, until the returned
+    /// `Registration` is dropped.
+    ///
+    /// `f` must not block for long, as it holds up all other registrations.
+    pub fn register<A, F>(&self, fd: &A, f: F) -> Result<Registration, Error>
+    where
+        A: AsRawFd,
+        F: FnMut() + Send + 'static,
+    {
+        let token = self.shared.next.fetch_add(1, Ordering::Relaxed);
+        let handler: Handler = Arc::new(Mutex::new(Box::new(f)));
+        self.shared
+            .handlers
+            .lock()
+            .unwrap()
+            .insert(token, handler.clone());
+        // The reactor holds no reference to the fd, so take our own.
+        let fd = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+        let fd = match cvt(fd) {
+            Ok(fd) => unsafe { File::from_raw_fd(fd) },
+            Err(e) => {
+                self.shared.handlers.lock().unwrap().remove(&token);
+                Err(e)?
+            }
+        };
+        if let Err(e) = self.shared.add(fd.as_raw_fd(), token) {
+            self.shared.handlers.lock().unwrap().remove(&token);
+            Err(e)?
+        }
+        Ok(Registration {
+            shared: self.shared.clone(),
+            token,
+            fd,
+            handler,
+        })
+    }
+
+    /// Like `register`, but instead of running a callback, signals the returned `Notify`.
+    pub fn register_notify<A: AsRawFd>(&self, fd: &A) -> Result<Notify, Error> {
+        let state = Arc::new(NotifyState::default());
+        let s = state.clone();
+        let registration = self.register(fd, move || s.signal())?;
+        Ok(Notify {
+            state,
+            _registration: registration,
+        })
+    }
+}
+
+impl Drop for Reactor {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Release);
+        let one = 1u64.to_ne_bytes();
+        let _ = unsafe { libc::write(self.shared.wake.as_raw_fd(), one.as_ptr() as *const _, 8) };
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+/// A file descriptor registered with a `Reactor`; dropping it unregisters.
+pub struct Registration {
+    shared: Arc<Shared>,
+    token: u64,
+    fd: File,
+    handler: Handler,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let epoll = self.shared.epoll.as_raw_fd();
+        let mut ev = libc::epoll_event { events: 0, u64: 0 };
+        unsafe { libc::epoll_ctl(epoll, libc::EPOLL_CTL_DEL, self.fd.as_raw_fd(), &mut ev) };
+        self.shared.handlers.lock().unwrap().remove(&self.token);
+        // Once we have the handler lock, the callback is not running and never will again;
+        // unless this is the callback itself dropping its registration.
+        if self.shared.thread.get() != Some(&std::thread::current().id()) {
+            drop(self.handler.lock());
+        }
+    }
+}
+
+#[derive(Default)]
+struct NotifyState {
+    signaled: Mutex<(bool, Option<Waker>)>,
+    cond: Condvar,
+}
+
+impl NotifyState {
+    fn signal(&self) {
+        let mut s = self.signaled.lock().unwrap();
+        s.0 = true;
+        if let Some(w) = s.1.take() {
+            w.wake();
+        }
+        self.cond.notify_all();
+    }
+}
+
+/// Readiness of a file descriptor registered with `Reactor::register_notify`.
+pub struct Notify {
+    state: Arc<NotifyState>,
+    _registration: Registration,
+}
+
+impl Notify {
+    /// Waits until the file descriptor has become readable since the last wait, or for the
+    /// timeout. Returns false on timeout.
+    pub fn wait(&self, timeout: Option<Duration>) -> bool {
+        let s = self.state.signaled.lock().unwrap();
+        let mut s = match timeout {
+            None => self.state.cond.wait_while(s, |s| !s.0).unwrap(),
+            Some(t) => {
+                self.state
+                    .cond
+                    .wait_timeout_while(s, t, |s| !s.0)
+                    .unwrap()
+                    .0
+            }
+        };
+        std::mem::replace(&mut s.0, false)
+    }
+
+    /// Returns true, and resets, if the file descriptor has become readable since the last
+    /// call. Otherwise, `waker` is woken when it does; for implementing futures.
+    pub fn poll_ready(&self, waker: &Waker) -> bool {
+        let mut s = self.state.signaled.lock().unwrap();
+        if std::mem::replace(&mut s.0, false) {
+            return true;
+        }
+        s.1 = Some(waker.clone());
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sharedring::{Receiver, Sender};
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn dispatch() {
+        let reactor = Reactor::new().unwrap();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let pairs: Vec<(Sender<u64>, Notify, Registration)> = (0..10)
+            .map(|_| {
+                let r: Receiver<u64> = Receiver::new(16).unwrap();
+                let s = Sender::open(
+                    16,
+                    r.memfd().as_file().try_clone().unwrap(),
+                    r.empty_signal().try_clone().unwrap(),
+                    r.full_signal().try_clone().unwrap(),
+                )
+                .unwrap();
+                let notify = reactor.register_notify(r.empty_signal()).unwrap();
+                let h = hits.clone();
+                let reg = reactor
+                    .register(r.empty_signal(), move || {
+                        h.fetch_add(1, Ordering::SeqCst);
+                    })
+                    .unwrap();
+                (s, notify, reg)
+            })
+            .collect();
+        let (mut s, notify, _reg) = pairs.into_iter().nth(3).unwrap();
+        assert!(!notify.wait(Some(Duration::from_millis(10))));
+        s.send_raw(|p, _| {
+            unsafe { *p = 1 };
+            1
+        })
+        .unwrap();
+        assert!(notify.wait(Some(Duration::from_secs(10))));
+        // The other registrations were dropped, so only ours can have run.
+        while hits.load(Ordering::SeqCst) == 0 {
+            std::thread::yield_now();
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+}
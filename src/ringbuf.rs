@@ -2,7 +2,8 @@
 //! There can be one producer and one consumer, but they can be in different threads
 //! i e, they are Send but not Clone.
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::wire::AtomicLe64;
+use std::sync::atomic::Ordering;
 use std::mem::size_of;
 use std::{cmp, ptr};
 
@@ -27,7 +28,7 @@ pub enum Error {
 #[derive(Copy, Clone)]
 struct Buf<T> {
     data: *mut T,
-    count_ptr: *const AtomicUsize,
+    count_ptr: *const AtomicLe64,
     length: usize,
 }
 
@@ -69,12 +70,12 @@ pub fn channel<T: zerocopy::AsBytes + zerocopy::FromBytes + Copy>(buffer: &mut [
 
 impl<T> Buf<T> {
     #[inline]
-    fn count(&self) -> &AtomicUsize { unsafe { &*self.count_ptr }}
+    fn count(&self) -> &AtomicLe64 { unsafe { &*self.count_ptr }}
 
     #[inline]
     fn load_count(&self) -> Result<usize, Error> {
         let x = self.count().load(Ordering::Acquire);
-        if x > self.length { Err(Error::BufCorrupt) } else { Ok(x) }
+        if x > self.length as u64 { Err(Error::BufCorrupt) } else { Ok(x as usize) }
     }
 
     unsafe fn attach(data: *mut u8, length: usize, init: bool) -> Result<Self, Error> {
@@ -82,11 +83,11 @@ impl<T> Buf<T> {
         if length < CACHE_LINE_SIZE + size_of::<T>() { Err(BufTooSmall)? }
         if length >= isize::MAX as usize { Err(BufTooBig)? }
         let r = Self {
-            count_ptr: data as *mut _ as *const AtomicUsize,
-            data: data.offset(CACHE_LINE_SIZE as isize) as _,
+            count_ptr: data as *mut _ as *const AtomicLe64,
+            data: data.add(CACHE_LINE_SIZE) as _,
             length: (length - CACHE_LINE_SIZE) / size_of::<T>(),
         };
-        if (r.count_ptr as usize) % std::mem::align_of::<AtomicUsize>() != 0 { Err(BufUnaligned)? }
+        if (r.count_ptr as usize) % std::mem::align_of::<AtomicLe64>() != 0 { Err(BufUnaligned)? }
         if (r.data as usize) % std::mem::align_of::<T>() != 0 { Err(BufUnaligned)? }
         if init {
             r.count().store(0, Ordering::Release);
@@ -126,7 +127,7 @@ impl<T: zerocopy::AsBytes + Copy> Sender<T> {
 
         let n = {
              let end = self.index + cmp::min(l - self.index, l - cb);
-             let slice_start = unsafe { self.buf.data.offset(self.index as isize) };
+             let slice_start = unsafe { self.buf.data.add(self.index) };
              let slice_len = end - self.index;
 
              let n = if slice_len == 0 { 0 } else { f(slice_start, slice_len) };
@@ -135,7 +136,7 @@ impl<T: zerocopy::AsBytes + Copy> Sender<T> {
              n
         };
 
-        let c = self.buf.count().fetch_add(n, Ordering::AcqRel);
+        let c = self.buf.count().fetch_add(n as u64, Ordering::AcqRel) as usize;
         self.index = (self.index + n) % l;
         // dbg!("Send: cb = {}, c = {}, l = {}, n = {}", cb, c, l, n);
         Ok(Status {
@@ -155,7 +156,7 @@ impl<T: zerocopy::AsBytes + Copy> Sender<T> {
             let status = self.send(|p, c| {
                 let mut j = 0;
                 while j < c && count > 0 {
-                    unsafe { ptr::write(p.offset(j as isize), f()) };
+                    unsafe { ptr::write(p.add(j), f()) };
                     j += 1;
                     count -= 1;
                 };
@@ -167,6 +168,12 @@ impl<T: zerocopy::AsBytes + Copy> Sender<T> {
 
     /// Returns number of items that can be written
     pub fn write_count(&self) -> Result<usize, Error> { Ok(self.buf.length - self.buf.load_count()?) }
+
+    /// Where the next item will be written, for debugging.
+    pub fn index(&self) -> usize { self.index }
+
+    /// Returns the number of items the buffer can hold
+    pub fn capacity(&self) -> usize { self.buf.length }
 }
 
 impl<T: zerocopy::FromBytes + Copy> Receiver<T> {
@@ -181,7 +188,7 @@ impl<T: zerocopy::FromBytes + Copy> Receiver<T> {
         let cb = self.buf.load_count()?;
         let l = self.buf.length;
         let n = {
-            let data_start = unsafe { self.buf.data.offset(self.index as isize) };
+            let data_start = unsafe { self.buf.data.add(self.index) };
             let data_len = cmp::min(self.index + cb, l) - self.index;
 
             let n = if data_len == 0 { 0 } else { f(data_start, data_len) };
@@ -189,10 +196,10 @@ impl<T: zerocopy::FromBytes + Copy> Receiver<T> {
             n
         };
 
-        let c = self.buf.count().fetch_sub(n, Ordering::AcqRel);
+        let c = self.buf.count().fetch_sub(n as u64, Ordering::AcqRel) as usize;
         self.index = (self.index + n) % l;
         // dbg!("Recv: cb = {}, c = {}, l = {}, n = {}", cb, c, l, n);
-        return Ok(Status {
+        Ok(Status {
             remaining: c - n,
             signal: c >= l && n > 0,
         })
@@ -209,7 +216,7 @@ impl<T: zerocopy::FromBytes + Copy> Receiver<T> {
             let status = self.recv(|p, c| {
                 let mut j = 0;
                 while j < c && count > 0 {
-                    f(unsafe { ptr::read(p.offset(j as isize)) });
+                    f(unsafe { ptr::read(p.add(j)) });
                     count -= 1;
                     j += 1;
                 };
@@ -223,6 +230,12 @@ impl<T: zerocopy::FromBytes + Copy> Receiver<T> {
     /// Returns number of items that can be read
     pub fn read_count(&self) -> Result<usize, Error> { self.buf.load_count() }
 
+    /// Where the next item will be read from, for debugging.
+    pub fn index(&self) -> usize { self.index }
+
+    /// Returns the number of items the buffer can hold
+    pub fn capacity(&self) -> usize { self.buf.length }
+
     /// Assume a ringbuf is set up at the location.
     ///
     /// A buffer where the first 64 bytes are zero is okay.
@@ -283,7 +296,7 @@ mod tests {
         }).unwrap();
         let mut called = false;
         s.send_foreach(2, || {
-            assert_eq!(called, false);
+            assert!(!called);
             called = true;
             10
         });
@@ -311,7 +324,7 @@ mod tests {
         }).unwrap();
         let mut called = false;
         r.recv_foreach(56, |d| {
-            assert_eq!(called, false);
+            assert!(!called);
             called = true;
             assert_eq!(d, 1);
         });
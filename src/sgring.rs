@@ -0,0 +1,532 @@
+//! Scatter-gather descriptor rings, similar to virtio.
+//!
+//! Message data lives in a shared data pool, and only descriptors referencing (offset, len)
+//! ranges of the pool traverse the ringbuffer. A message can be split into several descriptors
+//! (a chain), so messages can be much larger than the ringbuffer and the pool can be
+//! fragmented. Completed messages are returned by id through a second ringbuffer, in any order.
+//!
+//! The information to be transferred between processes, in addition to what the two
+//! `sharedring`s need, is:
+//!  * pool size
+//!  * pool memfd file descriptor
+
+use super::Error;
+use crate::mem::{mfd, mmap};
+use crate::sharedring;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+
+/// One entry in the descriptor ring.
+#[derive(Copy, Clone, Debug, Default, zerocopy::AsBytes, zerocopy::FromBytes)]
+#[repr(C)]
+pub struct Descriptor {
+    /// Message id, the same for all descriptors in a chain.
+    pub id: u32,
+    /// `DESC_NEXT` if another descriptor follows in the same chain.
+    pub flags: u32,
+    /// Offset into the data pool.
+    pub offset: u64,
+    /// Length of the range.
+    pub len: u64,
+}
+
+/// Descriptor flag: the chain continues with the next descriptor.
+pub const DESC_NEXT: u32 = 1;
+
+const ALLOC_ALIGN: usize = 64;
+
+/// A first-fit allocator over the data pool, which may split allocations.
+struct Pool {
+    free: BTreeMap<usize, usize>,
+}
+
+impl Pool {
+    fn new(size: usize) -> Self {
+        let mut free = BTreeMap::new();
+        free.insert(0, size);
+        Pool { free }
+    }
+
+    /// Allocates `len` bytes, in one range if possible, otherwise in several.
+    fn alloc(&mut self, len: usize) -> Option<Vec<(usize, usize)>> {
+        let len = std::cmp::max(len, 1);
+        let want = len.div_ceil(ALLOC_ALIGN) * ALLOC_ALIGN;
+        let whole = self.free.iter().find(|(_, &l)| l >= want).map(|(&o, _)| o);
+        let picked: Vec<(usize, usize)> = if let Some(o) = whole {
+            vec![(o, want)]
+        } else {
+            let mut total = 0;
+            let mut v = vec![];
+            for (&o, &l) in self.free.iter() {
+                let take = std::cmp::min(l, want - total);
+                v.push((o, take));
+                total += take;
+                if total == want {
+                    break;
+                }
+            }
+            if total < want {
+                return None;
+            }
+            v
+        };
+        for &(o, l) in &picked {
+            let fl = self.free.remove(&o).unwrap();
+            if fl > l {
+                self.free.insert(o + l, fl - l);
+            }
+        }
+        Some(picked)
+    }
+
+    fn free(&mut self, mut offset: usize, mut len: usize) {
+        if let Some((&o, &l)) = self.free.range(..offset).next_back() {
+            if o + l == offset {
+                self.free.remove(&o);
+                offset = o;
+                len += l;
+            }
+        }
+        if let Some(l) = self.free.remove(&(offset + len)) {
+            len += l;
+        }
+        self.free.insert(offset, len);
+    }
+}
+
+fn pool_memfd(size: usize) -> Result<(mfd::Memfd, mmap::MmapRaw, crate::quota::Charge), Error> {
+    let charge = crate::quota::Charge::new(None, size as u64)?;
+    let memfd = crate::mem::CreateOptions::default().create("sgring")?;
+    memfd.as_file().set_len(size as u64)?;
+    let m = crate::mem::raw_memfd(&memfd, size)?;
+    Ok((memfd, m, charge))
+}
+
+/// The sending side, which owns the data pool.
+pub struct Sender {
+    memfd: mfd::Memfd,
+    mmap: mmap::MmapRaw,
+    _charge: crate::quota::Charge,
+    avail: sharedring::Sender<Descriptor>,
+    used: sharedring::Receiver<u32>,
+    pool: Pool,
+    in_flight: HashMap<u32, Vec<(usize, usize)>>,
+    next_id: u32,
+}
+
+impl Sender {
+    /// Creates a data pool of `pool_size` bytes, and ringbuffers with room for at least
+    /// `ring_capacity` descriptors.
+    pub fn new(pool_size: usize, ring_capacity: usize) -> Result<Self, Error> {
+        let (memfd, mmap, charge) = pool_memfd(pool_size)?;
+        Ok(Sender {
+            memfd,
+            mmap,
+            _charge: charge,
+            avail: sharedring::Sender::new(ring_capacity)?,
+            used: sharedring::Receiver::new(ring_capacity)?,
+            pool: Pool::new(pool_size),
+            in_flight: HashMap::new(),
+            next_id: 0,
+        })
+    }
+
+    /// The file descriptor of the data pool.
+    pub fn pool_memfd(&self) -> &mfd::Memfd {
+        &self.memfd
+    }
+
+    /// The ringbuffer carrying descriptors, e g to get its file descriptors.
+    pub fn avail(&self) -> &sharedring::Sender<Descriptor> {
+        &self.avail
+    }
+
+    /// The ringbuffer carrying completed message ids, e g to get its file descriptors
+    /// or register its `empty_signal` with an event loop.
+    pub fn used(&self) -> &sharedring::Receiver<u32> {
+        &self.used
+    }
+
+    /// Number of messages sent but not yet completed by the receiver.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Frees the pool space of messages the receiver has completed.
+    ///
+    /// Returns the number of messages completed.
+    pub fn reclaim(&mut self) -> Result<usize, Error> {
+        let Sender {
+            used,
+            pool,
+            in_flight,
+            ..
+        } = self;
+        let mut n = 0;
+        let mut corrupt = false;
+        loop {
+            let status = used.receive_raw(|p, count| {
+                for i in 0..count {
+                    let id = unsafe { std::ptr::read(p.add(i)) };
+                    match in_flight.remove(&id) {
+                        Some(v) => v.into_iter().for_each(|(o, l)| pool.free(o, l)),
+                        None => corrupt = true,
+                    }
+                }
+                n += count;
+                count
+            })?;
+            if corrupt {
+                Err(crate::ringbuf::Error::BufCorrupt)?
+            }
+            if status.remaining == 0 {
+                return Ok(n);
+            }
+        }
+    }
+
+    /// Sends a message, copying it into the data pool.
+    ///
+    /// Returns the message id, or `None` if there is currently not enough room in the pool or
+    /// the descriptor ring.
+    pub fn send(&mut self, data: &[u8]) -> Result<Option<u32>, Error> {
+        self.send_vectored(&[data])
+    }
+
+    /// Sends a message consisting of several buffers, copying them into the data pool.
+    pub fn send_vectored(&mut self, bufs: &[&[u8]]) -> Result<Option<u32>, Error> {
+        let total: usize = bufs.iter().map(|b| b.len()).sum();
+        self.reclaim()?;
+        let ranges = match self.pool.alloc(total) {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let room = self.avail.sender_mut().write_count();
+        if !matches!(room, Ok(n) if n >= ranges.len()) {
+            ranges.into_iter().for_each(|(o, l)| self.pool.free(o, l));
+            return Ok(room.map(|_| None)?);
+        }
+
+        // Copy the data into the allocated ranges.
+        let base = self.mmap.as_mut_ptr();
+        let mut descs = Vec::with_capacity(ranges.len());
+        let (mut bi, mut bo) = (0, 0);
+        let mut remaining = total;
+        for &(o, l) in &ranges {
+            let len = std::cmp::min(l, remaining);
+            let mut written = 0;
+            while written < len {
+                let b = bufs[bi];
+                let n = std::cmp::min(b.len() - bo, len - written);
+                unsafe {
+                    std::ptr::copy_nonoverlapping(b[bo..].as_ptr(), base.add(o + written), n)
+                };
+                written += n;
+                bo += n;
+                if bo == b.len() {
+                    bi += 1;
+                    bo = 0;
+                }
+            }
+            remaining -= len;
+            descs.push(Descriptor {
+                id: 0,
+                flags: DESC_NEXT,
+                offset: o as u64,
+                len: len as u64,
+            });
+        }
+
+        let id = self.next_free_id();
+        for d in descs.iter_mut() {
+            d.id = id;
+        }
+        descs.last_mut().unwrap().flags = 0;
+
+        // There was room for all of them above, which takes at most two calls when the
+        // ring wraps around; if there is less now, the receiver has tampered with it.
+        let mut sent = 0;
+        let mut r = Ok(());
+        for _ in 0..2 {
+            if sent == descs.len() {
+                break;
+            }
+            r = self
+                .avail
+                .send_raw(|p, count| {
+                    let n = std::cmp::min(count, descs.len() - sent);
+                    for i in 0..n {
+                        unsafe { std::ptr::write(p.add(i), descs[sent + i]) };
+                    }
+                    sent += n;
+                    n
+                })
+                .map(|_| ());
+            if r.is_err() {
+                break;
+            }
+        }
+        if r.is_ok() && sent < descs.len() {
+            r = Err(crate::ringbuf::Error::BufCorrupt.into());
+        }
+        if sent == 0 {
+            ranges.into_iter().for_each(|(o, l)| self.pool.free(o, l));
+        } else {
+            // Once anything is published, the receiver may complete it, so keep the ranges
+            // until it does.
+            self.in_flight.insert(id, ranges);
+        }
+        r.map(|()| Some(id))
+    }
+
+    fn next_free_id(&mut self) -> u32 {
+        loop {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            if !self.in_flight.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+}
+
+/// A received message: one or more ranges of the data pool.
+#[derive(Debug)]
+pub struct Chain {
+    id: u32,
+    segments: Vec<(usize, usize)>,
+}
+
+impl Chain {
+    /// The message id, as returned by `Sender::send`.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Total length of the message.
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|s| s.1).sum()
+    }
+
+    /// Returns true if the message has zero length.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The (offset, len) ranges of the data pool that make up the message.
+    pub fn segments(&self) -> &[(usize, usize)] {
+        &self.segments
+    }
+}
+
+/// The receiving side.
+pub struct Receiver {
+    memfd: mfd::Memfd,
+    mmap: mmap::MmapRaw,
+    avail: sharedring::Receiver<Descriptor>,
+    used: sharedring::Sender<u32>,
+    pool_size: usize,
+    pending: Vec<Descriptor>,
+}
+
+impl Receiver {
+    /// Attaches to a data pool set up by the sender, given the two ringbuffers opened
+    /// from the sender's file descriptors.
+    pub fn open(
+        pool_size: usize,
+        pool: File,
+        avail: sharedring::Receiver<Descriptor>,
+        used: sharedring::Sender<u32>,
+    ) -> Result<Self, Error> {
+        let memfd = crate::mem::memfd_from_file(pool)?;
+        let mmap = crate::mem::raw_memfd(&memfd, pool_size)?;
+        if (memfd.as_file().metadata()?.len() as usize) < pool_size {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        Ok(Receiver {
+            memfd,
+            mmap,
+            avail,
+            used,
+            pool_size,
+            pending: vec![],
+        })
+    }
+
+    /// The file descriptor of the data pool.
+    pub fn pool_memfd(&self) -> &mfd::Memfd {
+        &self.memfd
+    }
+
+    /// The ringbuffer carrying descriptors, e g to register its `empty_signal`
+    /// with an event loop.
+    pub fn avail(&self) -> &sharedring::Receiver<Descriptor> {
+        &self.avail
+    }
+
+    /// Receives the next complete message, if any.
+    ///
+    /// Descriptors are validated against the pool size and chain length.
+    pub fn recv(&mut self) -> Result<Option<Chain>, Error> {
+        let Receiver {
+            avail,
+            pending,
+            pool_size,
+            ..
+        } = self;
+        let max_chain = avail.receiver_mut().capacity();
+        let mut done = false;
+        let mut corrupt = false;
+        while !done && !corrupt {
+            let status = avail.receive_raw(|p, count| {
+                let mut i = 0;
+                while i < count && !done {
+                    let d = unsafe { std::ptr::read(p.add(i)) };
+                    i += 1;
+                    let end = d.offset.checked_add(d.len);
+                    if end.map(|e| e > *pool_size as u64).unwrap_or(true)
+                        || pending.first().map(|f| f.id != d.id).unwrap_or(false)
+                        || pending.len() >= max_chain
+                    {
+                        corrupt = true;
+                        break;
+                    }
+                    pending.push(d);
+                    done = d.flags & DESC_NEXT == 0;
+                }
+                i
+            })?;
+            if status.remaining == 0 {
+                break;
+            }
+        }
+        if corrupt {
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        if !done {
+            return Ok(None);
+        }
+        let segments = pending
+            .iter()
+            .map(|d| (d.offset as usize, d.len as usize))
+            .collect();
+        let id = pending[0].id;
+        pending.clear();
+        Ok(Some(Chain { id, segments }))
+    }
+
+    /// Raw pointer to a segment of the data pool.
+    ///
+    /// Since the sender is untrusted, we cannot create references to the data.
+    /// Fails with `OutOfBounds` if the segment does not fit in the pool, e g because it is
+    /// from a chain received by another receiver.
+    pub fn segment_ptr(&self, segment: (usize, usize)) -> Result<*const u8, Error> {
+        match segment.0.checked_add(segment.1) {
+            Some(end) if end <= self.pool_size => Ok(unsafe { self.mmap.as_ptr().add(segment.0) }),
+            _ => Err(Error::OutOfBounds),
+        }
+    }
+
+    /// Copies a message out of the data pool.
+    pub fn read(&self, chain: &Chain) -> Result<Vec<u8>, Error> {
+        let mut v: Vec<u8> = Vec::with_capacity(chain.len());
+        for &s in &chain.segments {
+            let p = self.segment_ptr(s)?;
+            unsafe {
+                std::ptr::copy_nonoverlapping(p, v.as_mut_ptr().add(v.len()), s.1);
+                v.set_len(v.len() + s.1);
+            }
+        }
+        Ok(v)
+    }
+
+    /// Hands a message back to the sender, so its pool space can be reused.
+    ///
+    /// Messages can be completed in any order.
+    pub fn complete(&mut self, chain: Chain) -> Result<(), Error> {
+        let mut written = false;
+        self.used.send_raw(|p, _| {
+            unsafe { std::ptr::write(p, chain.id) };
+            written = true;
+            1
+        })?;
+        if !written {
+            // There's room for as many ids as descriptors, so this is a misbehaving sender.
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(pool_size: usize, ring: usize) -> (Sender, Receiver) {
+        let s = Sender::new(pool_size, ring).unwrap();
+        let a = s.avail();
+        let avail = sharedring::Receiver::open(
+            ring,
+            a.memfd().as_file().try_clone().unwrap(),
+            a.empty_signal().try_clone().unwrap(),
+            a.full_signal().try_clone().unwrap(),
+        )
+        .unwrap();
+        let u = s.used();
+        let used = sharedring::Sender::open(
+            ring,
+            u.memfd().as_file().try_clone().unwrap(),
+            u.empty_signal().try_clone().unwrap(),
+            u.full_signal().try_clone().unwrap(),
+        )
+        .unwrap();
+        let pool = s.pool_memfd().as_file().try_clone().unwrap();
+        let r = Receiver::open(pool_size, pool, avail, used).unwrap();
+        (s, r)
+    }
+
+    #[test]
+    fn out_of_order_completion() {
+        let (mut s, mut r) = setup(65536, 4);
+        let big: Vec<u8> = (0..40000).map(|x| x as u8).collect();
+        let a = s.send(&big).unwrap().unwrap();
+        let b = s.send_vectored(&[b"hello ", b"world"]).unwrap().unwrap();
+        // Pool is now too full for another big message.
+        assert!(s.send(&big).unwrap().is_none());
+
+        let ca = r.recv().unwrap().unwrap();
+        let cb = r.recv().unwrap().unwrap();
+        assert!(r.recv().unwrap().is_none());
+        assert_eq!((ca.id(), cb.id()), (a, b));
+        assert_eq!(r.read(&ca).unwrap(), big);
+        assert_eq!(r.read(&cb).unwrap(), b"hello world");
+        r.complete(cb).unwrap();
+        assert_eq!(s.reclaim().unwrap(), 1);
+        r.complete(ca).unwrap();
+        assert_eq!(s.reclaim().unwrap(), 1);
+        assert_eq!(s.in_flight(), 0);
+        assert!(r.segment_ptr((65536, 0)).is_ok());
+        assert!(matches!(r.segment_ptr((65536, 1)), Err(Error::OutOfBounds)));
+        assert!(matches!(
+            r.segment_ptr((1, usize::MAX)),
+            Err(Error::OutOfBounds)
+        ));
+    }
+
+    #[test]
+    fn fragmented_pool() {
+        let mut p = Pool::new(1024);
+        let a = p.alloc(256).unwrap();
+        let b = p.alloc(256).unwrap();
+        let c = p.alloc(256).unwrap();
+        p.free(a[0].0, a[0].1);
+        p.free(c[0].0, c[0].1);
+        // 768 bytes are free, but not contiguous.
+        let d = p.alloc(700).unwrap();
+        assert_eq!(d.len(), 2);
+        assert!(p.alloc(100).is_none());
+        p.free(b[0].0, b[0].1);
+        d.into_iter().for_each(|(o, l)| p.free(o, l));
+        assert_eq!(p.free.len(), 1);
+    }
+}
@@ -0,0 +1,234 @@
+//! Routing items to one of several ringbuffers by key, e g one ringbuffer per consumer
+//! process.
+//!
+//! All items with the same key go through the same ringbuffer, so they arrive in the order
+//! they were sent; items with different keys may be reordered relative to each other.
+//!
+//! # Example
+//! ```rust
+//! use shmem_ipc::sharedring;
+//! use shmem_ipc::sharded;
+//! let mut senders = vec![];
+//! let mut receivers: Vec<sharedring::Receiver<u64>> = vec![];
+//! for _ in 0..4 {
+//!     let (s, fds) = sharedring::SharedRingBuilder::new(100).build_sender::<u64>().unwrap();
+//!     receivers.push(sharedring::Receiver::open(fds.capacity, fds.memfd, fds.empty_signal,
+//!         fds.full_signal).unwrap());
+//!     senders.push(s);
+//! }
+//! let mut s = sharded::Sender::new(senders).unwrap();
+//! assert!(s.send("user 17", 5).unwrap());
+//! let mut r = sharded::Receiver::new(receivers).unwrap();
+//! assert!(r.wait(None).unwrap());
+//! let (shard, _) = r.recv(|_, _, count| count).unwrap().unwrap();
+//! assert_eq!(shard, s.shard_for("user 17"));
+//! ```
+
+use crate::ringbuf::Status;
+use crate::sharedring::{self, Fairness, Mux};
+use crate::Error;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+/// FNV-1a, so that keys map to the same shard regardless of Rust version or process.
+struct Fnv(u64);
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 ^ *b as u64).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+}
+
+/// Jump consistent hash: when the number of shards changes, only the keys that have to
+/// move to or from the changed shards do.
+fn jump(mut key: u64, shards: usize) -> usize {
+    let (mut b, mut j) = (-1i64, 0i64);
+    while j < shards as i64 {
+        b = j;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    b as usize
+}
+
+/// Sends items to one of several ringbuffers, picked by hashing a key.
+pub struct Sender<T> {
+    shards: Vec<sharedring::Sender<T>>,
+}
+
+impl<T: Copy + zerocopy::AsBytes> Sender<T> {
+    /// Routes over the given ringbuffers. Fails with `EINVAL` if there are none.
+    pub fn new(shards: Vec<sharedring::Sender<T>>) -> Result<Self, Error> {
+        if shards.is_empty() {
+            Err(std::io::Error::from_raw_os_error(libc::EINVAL))?
+        }
+        Ok(Sender { shards })
+    }
+
+    /// Number of ringbuffers.
+    pub fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Always false, since there is at least one ringbuffer.
+    pub fn is_empty(&self) -> bool {
+        self.shards.is_empty()
+    }
+
+    /// The index of the ringbuffer that items with this key go to.
+    ///
+    /// This only depends on the key and the number of ringbuffers.
+    pub fn shard_for<K: Hash + ?Sized>(&self, key: &K) -> usize {
+        let mut h = Fnv(0xcbf2_9ce4_8422_2325);
+        key.hash(&mut h);
+        jump(h.finish(), self.shards.len())
+    }
+
+    /// Access to one of the ringbuffers, e g to wait for it to become writable.
+    pub fn shard_mut(&mut self, index: usize) -> Option<&mut sharedring::Sender<T>> {
+        self.shards.get_mut(index)
+    }
+
+    /// Sends an item to the ringbuffer for `key`.
+    ///
+    /// Returns false if that ringbuffer is full; the others are not tried, as that would
+    /// break ordering for the key.
+    pub fn send<K: Hash + ?Sized>(&mut self, key: &K, item: T) -> Result<bool, Error> {
+        let i = self.shard_for(key);
+        let mut sent = false;
+        self.shards[i].send_raw(|p, _| {
+            unsafe { std::ptr::write(p, item) };
+            sent = true;
+            1
+        })?;
+        Ok(sent)
+    }
+
+    /// Gives back the ringbuffers.
+    pub fn into_inner(self) -> Vec<sharedring::Sender<T>> {
+        self.shards
+    }
+}
+
+/// Receives from all the ringbuffers of a `Sender`, e g in a consumer that handles every
+/// shard, taking turns between them.
+pub struct Receiver<T> {
+    mux: Mux<T>,
+}
+
+impl<T: Copy + zerocopy::FromBytes> Receiver<T> {
+    /// Receives from the given ringbuffers, which have to be in the same order as on the
+    /// sending side for the shard indices to match.
+    pub fn new(shards: Vec<sharedring::Receiver<T>>) -> Result<Self, Error> {
+        let mut mux = Mux::new(Fairness::RoundRobin)?;
+        for rx in shards {
+            mux.add(rx, 1)?;
+        }
+        Ok(Receiver { mux })
+    }
+
+    /// Number of ringbuffers.
+    pub fn len(&self) -> usize {
+        self.mux.len()
+    }
+
+    /// Returns true if there are no ringbuffers.
+    pub fn is_empty(&self) -> bool {
+        self.mux.is_empty()
+    }
+
+    /// The epoll file descriptor, see `Mux::epoll_fd`.
+    pub fn epoll_fd(&self) -> std::os::unix::io::RawFd {
+        self.mux.epoll_fd()
+    }
+
+    /// Waits until one of the ringbuffers might have data, see `Mux::wait`.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<bool, Error> {
+        self.mux.wait(timeout)
+    }
+
+    /// Receives data from the next ringbuffer in turn that has data.
+    ///
+    /// The closure gets the shard index along with the items, and works like in
+    /// `sharedring::Receiver::receive_raw`. Returns the shard index and status, or `None`
+    /// if no ringbuffer currently has data.
+    pub fn recv<F: FnOnce(usize, *const T, usize) -> usize>(
+        &mut self,
+        f: F,
+    ) -> Result<Option<(usize, Status)>, Error> {
+        self.mux.recv_any(f)
+    }
+
+    /// Access to one of the ringbuffers.
+    pub fn shard_mut(&mut self, index: usize) -> Option<&mut sharedring::Receiver<T>> {
+        self.mux.get_mut(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jump_is_stable() {
+        // Growing from 4 to 5 shards only moves keys to the new shard.
+        for key in 0..1000u64 {
+            let (a, b) = (jump(key, 4), jump(key, 5));
+            assert!(a < 4 && (a == b || b == 4));
+        }
+        // FNV-1a of the bytes of 1u32, which hash as native endian
+        let mut h = Fnv(0xcbf2_9ce4_8422_2325);
+        h.write(&[1, 0, 0, 0]);
+        assert_eq!(h.finish(), 0xad2a_ca77_4798_5764);
+    }
+
+    #[test]
+    fn per_key_order() {
+        let (mut senders, mut receivers) = (vec![], vec![]);
+        for _ in 0..3 {
+            let (s, fds) = sharedring::SharedRingBuilder::new(1000)
+                .build_sender::<u32>()
+                .unwrap();
+            let r: sharedring::Receiver<u32> = sharedring::Receiver::open(
+                fds.capacity,
+                fds.memfd,
+                fds.empty_signal,
+                fds.full_signal,
+            )
+            .unwrap();
+            senders.push(s);
+            receivers.push(r);
+        }
+        let mut s = Sender::new(senders).unwrap();
+        for i in 0..300u32 {
+            // the key is in the upper bits, the sequence number for that key below
+            assert!(s.send(&(i % 10), ((i % 10) << 16) | (i / 10)).unwrap());
+        }
+        let mut r = Receiver::new(receivers).unwrap();
+        let mut next = [0u32; 10];
+        while r.wait(Some(Duration::from_millis(0))).unwrap() {
+            let got = r
+                .recv(|shard, p, count| {
+                    for j in 0..count {
+                        let x = unsafe { *p.add(j) };
+                        let key = (x >> 16) as usize;
+                        assert_eq!(s.shard_for(&(key as u32)), shard);
+                        assert_eq!(x & 0xffff, next[key]);
+                        next[key] += 1;
+                    }
+                    count
+                })
+                .unwrap();
+            if got.is_none() {
+                break;
+            }
+        }
+        assert_eq!(next, [30; 10]);
+    }
+}
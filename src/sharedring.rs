@@ -5,29 +5,189 @@
 //!  * memfd file descriptor
 //!  * empty signal file descriptor
 //!  * full signal file descriptor
+//!
+//! The memory area starts with a small header, used for the acknowledgement lane, followed by
+//! the ringbuffer itself.
+//!
+//! All shared words are fixed width and little-endian, so that peers on other architectures
+//! can interoperate: the header starts with the `u64` layout version `b"shmring1"`, checked
+//! when attaching, and the ringbuffer after it (at byte 256) starts with its `u64` item count,
+//! followed by the items from the next cache line on.
+
+mod array;
+mod builder;
+mod bundle;
+mod drain;
+mod journal;
+mod mux;
+mod peers;
+mod prefetch;
+mod report;
+mod snapshot;
+mod stamped;
+mod switchover;
+mod validate;
+mod watchdog;
+mod watermark;
+
+pub use self::builder::{RingFds, SharedRingBuilder, Signaling};
+pub use self::bundle::{Bundle, BundleBuilder, BundleFds};
+pub use self::drain::Drain;
+pub use self::journal::Journal;
+pub use self::mux::{Fairness, Mux};
+pub use self::report::Report;
+pub use self::snapshot::{HeaderReport, Snapshot};
+pub use self::stamped::{StampedReceiver, StampedSender};
+pub use self::switchover::{BlueGreenReceiver, BlueGreenSender};
+pub use self::validate::Validate;
+pub use self::watermark::Watermark;
 
-use super::Error;
-use crate::mem::mfd::{HugetlbSize, MemfdOptions};
+use super::{Error, Op};
+use crate::mem::mfd::{FileSeal, HugetlbSize};
 use crate::ringbuf::Status;
+use crate::wire::AtomicLe64;
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io::{Read, Write};
-use std::os::unix::io::FromRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::slice::from_raw_parts;
 use std::slice::from_raw_parts_mut;
+use std::sync::atomic::Ordering;
+
+#[cfg(feature = "profiling")]
+use crate::profiling::cycles;
+#[cfg(not(feature = "profiling"))]
+#[inline(always)]
+fn cycles() -> u64 {
+    0
+}
+
+/// Shared state in front of the ringbuffer. Everything in here is written by an untrusted peer.
+#[repr(C)]
+struct Header {
+    /// `LAYOUT`, written by the creator.
+    layout: AtomicLe64,
+    /// Sequence number up to which the receiver has processed items.
+    acked: AtomicLe64,
+    /// Non-zero if the sender is waiting for `acked` to reach this value.
+    ack_wanted: AtomicLe64,
+    /// Maximum message size declared by the creator of the ringbuffer, or zero if none.
+    /// Used by the `framed` module; only read when attaching.
+    max_message_size: AtomicLe64,
+    /// `HEADER_FLAG_*` bits set by the creator.
+    flags: AtomicLe64,
+    /// Number of items the sender dropped because the ringbuffer was full.
+    dropped: AtomicLe64,
+    /// Number of times the sender started dropping items.
+    overflows: AtomicLe64,
+    /// `FEATURE_*` bits the creator requires peers to understand.
+    features_required: AtomicLe64,
+    /// `FEATURE_*` bits the creator offers, for peers to use if they understand them.
+    features_optional: AtomicLe64,
+    /// The offered bits that the attaching side understood.
+    features_accepted: AtomicLe64,
+    /// For `wait_for_peers`.
+    barrier: crate::sync::BarrierState,
+    /// Processes attached to the ringbuffer.
+    peers: peers::PeerTable,
+    /// `CLOCK_MONOTONIC` time in nanoseconds that the receiver last called `feed` at.
+    watchdog_fed: AtomicLe64,
+    /// Bumped by the sender on `pause` and `resume`, so odd while paused.
+    pause: AtomicLe64,
+    /// The `pause` value for which the receiver has received everything.
+    drained: AtomicLe64,
+}
+
+/// Layout version in the first word of the header, checked when attaching.
+pub(crate) const LAYOUT: u64 = u64::from_le_bytes(*b"shmring1");
+
+/// Header flag: the attaching side has to present a token over the companion socket.
+pub(crate) const HEADER_FLAG_TOKEN: u64 = 1;
+
+/// Feature: messages may be compressed, see `framed::Sender::set_compression_threshold`.
+///
+/// Attaching fails for a peer built without the `lz4_flex` feature.
+pub const FEATURE_COMPRESSION: u64 = 1;
+
+/// Feature bits from this one up are free for applications; those below are defined by
+/// this crate.
+pub const FEATURE_APP_FIRST: u64 = 1 << 32;
+
+/// The crate defined features that this build understands.
+const KNOWN_FEATURES: u64 = if cfg!(feature = "lz4_flex") {
+    FEATURE_COMPRESSION
+} else {
+    0
+};
+
+/// Room reserved for the header, a few cache lines.
+pub(crate) const HEADER_SIZE: usize = 256;
+const _: () = assert!(std::mem::size_of::<Header>() <= HEADER_SIZE);
+
+/// Where `features_required` is in the header, for building ring images in `testing`.
+#[cfg(feature = "proptest")]
+pub(crate) const FEATURES_REQUIRED_OFFSET: usize = std::mem::offset_of!(Header, features_required);
 
 struct Inner {
     mmap: memmap2::MmapRaw,
     memfd: memfd::Memfd,
+    /// Where the mapping starts in the memfd, non-zero for rings in a `Bundle`.
+    offset: u64,
     empty_signal: File,
     full_signal: File,
+    /// Number of items sent or received by this side.
+    seq: u64,
+    /// Wipe the memory area on drop.
+    zeroize: bool,
+    /// Punch holes over items as they are consumed (receiver only).
+    reclaim: bool,
+    /// Number of cache lines to prefetch before handing slots to the closure.
+    prefetch: usize,
+    /// Quota charge, if we created the memfd.
+    _charge: Option<crate::quota::Charge>,
+    watermarks: Option<watermark::Watermarks>,
+    /// Checks on the receiver (sender only).
+    watchdog: Option<watchdog::Watchdog>,
+    /// Our `pause` generation, odd while paused (sender only). The header holds a copy
+    /// for the receiver, which can overwrite it.
+    pause: u64,
+    /// The sender has dropped items since it last sent something.
+    overflowing: bool,
+    /// Number of times this side has woken up the other side.
+    wakeups: u64,
+    /// Journal for acknowledged items, and the sequence number of the first item in the
+    /// ringbuffer (receiver only).
+    journal: Option<(Journal, u64)>,
+    /// Our slot in the peer table, if we got one.
+    peer_slot: Option<usize>,
+    #[cfg(feature = "profiling")]
+    profile: crate::profiling::Profile,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Some(slot) = self.peer_slot {
+            self.header().peers.detach(slot);
+        }
+        if !self.zeroize {
+            return;
+        }
+        // Giving the pages back wipes them too, without first allocating the pages of a
+        // sparse ringbuffer that were never touched. Only write zeroes if that fails.
+        if self.punch_hole(0, self.mmap.len()) {
+            return;
+        }
+        unsafe { std::ptr::write_bytes(self.mmap.as_mut_ptr(), 0, self.mmap.len()) };
+        std::sync::atomic::compiler_fence(Ordering::SeqCst);
+    }
 }
 
 fn page_size() -> usize {
     unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
 }
 
-fn round_to_page_size<T>(capacity: usize) -> usize {
-    let bytes = crate::ringbuf::channel_bufsize::<T>(capacity);
+pub(crate) fn round_to_page_size<T>(capacity: usize) -> usize {
+    let bytes = HEADER_SIZE + crate::ringbuf::channel_bufsize::<T>(capacity);
     let ps = page_size();
     let m = bytes % ps;
     if m == 0 {
@@ -37,8 +197,23 @@ fn round_to_page_size<T>(capacity: usize) -> usize {
     }
 }
 
-fn eventfd() -> Result<File, std::io::Error> {
-    let x = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+/// Largest capacity that the fuzzing entry points accept.
+const FUZZ_MAX_CAPACITY: usize = 1 << 20;
+
+/// A memfd holding `data` with room for `capacity` items, and two eventfds.
+fn fuzz_fds<T>(capacity: usize, data: &[u8]) -> Result<(File, File, File), Error> {
+    use std::os::unix::fs::FileExt;
+    let memfd = crate::mem::CreateOptions::default().create("fuzz")?;
+    let file = memfd.into_file();
+    let len = std::cmp::max(round_to_page_size::<T>(capacity), data.len());
+    file.set_len(len as u64)?;
+    file.write_all_at(data, 0)?;
+    Ok((file, eventfd(true)?, eventfd(true)?))
+}
+
+fn eventfd(close_on_exec: bool) -> Result<File, std::io::Error> {
+    let flags = if close_on_exec { libc::EFD_CLOEXEC } else { 0 };
+    let x = unsafe { libc::eventfd(0, flags) };
     if x == -1 {
         Err(std::io::Error::last_os_error())
     } else {
@@ -47,76 +222,322 @@ fn eventfd() -> Result<File, std::io::Error> {
 }
 
 impl Inner {
-    fn new<T>(capacity: usize, tlbsize: Option<HugetlbSize>) -> Result<Self, Error> {
-        let bytes = round_to_page_size::<T>(capacity);
-        let mut opts = MemfdOptions::default()
-            .allow_sealing(true)
-            .close_on_exec(true);
-        if tlbsize.is_some() {
-            opts = opts.hugetlb(tlbsize);
-        }
+    fn new<T>(b: &SharedRingBuilder) -> Result<Self, Error> {
+        let bytes = round_to_page_size::<T>(b.capacity);
+        let charge = crate::quota::Charge::new(b.quota_group.as_deref(), bytes as u64)?;
+        let opts = crate::mem::CreateOptions::default()
+            .hugetlb(b.hugetlb)
+            .exec(b.exec)
+            .close_on_exec(b.close_on_exec);
 
-        let memfd = opts.create(std::any::type_name::<T>())?;
-        if tlbsize.is_none() {
+        let name = b.name.as_deref().unwrap_or(std::any::type_name::<T>());
+        let memfd = opts.create(name)?;
+        if b.hugetlb.is_none() {
             // hugetlb does not need/allow to set_len
-            memfd.as_file().set_len(bytes as u64)?;
+            memfd
+                .as_file()
+                .set_len(bytes as u64)
+                .map_err(Error::os(Op::Create, Some(name.into())))?;
+        }
+        if b.seals.contains(&FileSeal::SealWrite) {
+            Err(std::io::Error::from_raw_os_error(libc::EINVAL))?
         }
 
-        let empty_signal = eventfd()?;
-        let full_signal = eventfd()?;
+        let (empty_signal, full_signal) = match b.signaling {
+            Signaling::EventFd => {
+                let e = |e| Error::os(Op::Create, None)(e);
+                (
+                    eventfd(b.close_on_exec).map_err(e)?,
+                    eventfd(b.close_on_exec).map_err(e)?,
+                )
+            }
+        };
         let mmap = crate::mem::raw_memfd(&memfd, bytes)?;
-        Ok(Self {
+        if let Some(node) = b.numa_node {
+            builder::mbind(&mmap, node)?;
+        }
+        if !b.seals.is_empty() {
+            crate::mem::add_seals(&memfd, crate::mem::seal_set_bits(&b.seals))?;
+        }
+        let mut inner = Self {
             mmap,
             memfd,
+            offset: 0,
             empty_signal,
             full_signal,
-        })
+            seq: 0,
+            pause: 0,
+            zeroize: b.zeroize,
+            reclaim: false,
+            prefetch: b.prefetch,
+            _charge: Some(charge),
+            watermarks: None,
+            watchdog: None,
+            overflowing: false,
+            wakeups: 0,
+            journal: None,
+            peer_slot: None,
+            #[cfg(feature = "profiling")]
+            profile: Default::default(),
+        };
+        let h = inner.header();
+        h.layout.store(LAYOUT, Ordering::Release);
+        h.features_required.store(b.features.0, Ordering::Release);
+        h.features_optional.store(b.features.1, Ordering::Release);
+        if b.mlock {
+            inner.mlock()?;
+        }
+        if b.prefault {
+            crate::mem::touch_all(&inner.mmap)?;
+        }
+        inner.peer_slot = inner.header().peers.attach();
+        Ok(inner)
+    }
+
+    fn set_close_on_exec(&self, value: bool) -> Result<(), Error> {
+        crate::mem::set_close_on_exec(self.memfd.as_file(), value)?;
+        crate::mem::set_close_on_exec(&self.empty_signal, value)?;
+        crate::mem::set_close_on_exec(&self.full_signal, value)
+    }
+
+    fn after_fork(&mut self) {
+        // The slot we had still belongs to the parent, which may well keep using it.
+        self.peer_slot = self.header().peers.attach();
+    }
+
+    fn punch_hole(&self, offset: usize, len: usize) -> bool {
+        let r = unsafe {
+            libc::fallocate(
+                self.memfd.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                (self.offset + offset as u64) as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        r == 0
+    }
+
+    /// Gives back the pages fully covered by a range of the mapping.
+    fn reclaim(&self, p: *const u8, len: usize) {
+        let ps = page_size();
+        let start = (p as usize - self.mmap.as_ptr() as usize).next_multiple_of(ps);
+        let end = (p as usize + len - self.mmap.as_ptr() as usize) / ps * ps;
+        if end > start {
+            self.punch_hole(start, end - start);
+        }
+    }
+
+    fn set_watermarks<F: FnMut(Watermark, usize) + Send + 'static>(
+        &mut self,
+        high: usize,
+        low: usize,
+        callback: F,
+    ) {
+        self.watermarks = Some(watermark::Watermarks::new(high, low, callback));
+    }
+
+    fn update_watermarks(&mut self, occupancy: usize) {
+        if let Some(w) = &mut self.watermarks {
+            w.update(occupancy);
+        }
+    }
+
+    /// Records a call that moved items in `total` cycles, `copy` of them in the closure.
+    #[inline]
+    fn record_transfer(&mut self, total: u64, copy: u64) {
+        #[cfg(feature = "profiling")]
+        {
+            self.profile.copy.record(copy);
+            self.profile.atomic.record(total.saturating_sub(copy));
+        }
+        #[cfg(not(feature = "profiling"))]
+        let _ = (total, copy);
+    }
+
+    #[inline]
+    fn record_signal(&mut self, cycles: u64) {
+        #[cfg(feature = "profiling")]
+        self.profile.signal.record(cycles);
+        #[cfg(not(feature = "profiling"))]
+        let _ = cycles;
+    }
+
+    fn stats(&self, capacity: usize, occupancy: usize) -> Stats {
+        let h = self.header();
+        Stats {
+            capacity,
+            occupancy,
+            items: self.seq,
+            dropped: h.dropped.load(Ordering::Relaxed),
+            overflows: h.overflows.load(Ordering::Relaxed),
+            wakeups: self.wakeups,
+        }
     }
 
     fn mlock(&mut self) -> Result<(), Error> {
         Ok(self.mmap.lock()?)
     }
 
+    fn header(&self) -> &Header {
+        unsafe { &*(self.mmap.as_ptr() as *const Header) }
+    }
+
+    fn ring_ptr(&self) -> *mut u8 {
+        unsafe { self.mmap.as_mut_ptr().add(HEADER_SIZE) }
+    }
+
+    fn ring_len(&self) -> usize {
+        self.mmap.len() - HEADER_SIZE
+    }
+
+    fn signal(file: &File) -> Result<(), Error> {
+        (&*file)
+            .write_all(&1u64.to_ne_bytes())
+            .map_err(Error::os(Op::Signal, None))
+    }
+
+    fn wait(file: &File) -> Result<(), Error> {
+        if crate::sim::is_enabled() {
+            // Nothing else can make the eventfd readable while this thread waits.
+            let mut pfd = libc::pollfd {
+                fd: file.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            if unsafe { libc::poll(&mut pfd, 1, 0) } <= 0 {
+                crate::sim::would_block(None)?;
+            }
+        }
+        let mut b = [0u8; 8];
+        (&*file)
+            .read_exact(&mut b)
+            .map_err(Error::os(Op::Signal, None))
+    }
+
     fn open<T>(
         capacity: usize,
         file: File,
         empty_signal: File,
         full_signal: File,
+        understood: u64,
     ) -> Result<Self, Error> {
         let bytes = round_to_page_size::<T>(capacity);
-        let memfd =
-            memfd::Memfd::try_from_file(file).map_err(|_| std::io::Error::last_os_error())?;
+        let memfd = crate::mem::memfd_from_file(file)?;
         let mmap = crate::mem::raw_memfd(&memfd, bytes)?;
         if mmap.len() < bytes {
+            crate::audit::report(|| crate::audit::Event::AttachRejected {
+                reason: "memfd too small",
+            });
             Err(crate::ringbuf::Error::BufTooSmall)?
         };
-        Ok(Self {
+        Self::attached(mmap, memfd, 0, empty_signal, full_signal, understood)
+    }
+
+    /// Attaches to a ringbuffer that somebody else set up, mapped at `offset` of the memfd.
+    ///
+    /// `understood` are the feature bits the caller understands, besides those of this crate.
+    fn attached(
+        mmap: memmap2::MmapRaw,
+        memfd: memfd::Memfd,
+        offset: u64,
+        empty_signal: File,
+        full_signal: File,
+        understood: u64,
+    ) -> Result<Self, Error> {
+        let mut inner = Self {
             mmap,
             memfd,
+            offset,
             empty_signal,
             full_signal,
-        })
+            seq: 0,
+            pause: 0,
+            zeroize: false,
+            reclaim: false,
+            prefetch: 0,
+            _charge: None,
+            watermarks: None,
+            watchdog: None,
+            overflowing: false,
+            wakeups: 0,
+            journal: None,
+            peer_slot: None,
+            #[cfg(feature = "profiling")]
+            profile: Default::default(),
+        };
+        if inner.header().layout.load(Ordering::Acquire) != LAYOUT {
+            crate::audit::report(|| crate::audit::Event::HeaderInvalid { what: "ringbuffer" });
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        let h = inner.header();
+        let understood = understood | KNOWN_FEATURES;
+        let unsupported = h.features_required.load(Ordering::Acquire) & !understood;
+        if unsupported != 0 {
+            crate::audit::report(|| crate::audit::Event::AttachRejected {
+                reason: "unsupported features",
+            });
+            Err(Error::UnsupportedFeatures { unsupported })?
+        }
+        let accepted = h.features_optional.load(Ordering::Acquire) & understood;
+        h.features_accepted.store(accepted, Ordering::Release);
+        inner.peer_slot = inner.header().peers.attach();
+        Ok(inner)
     }
+
+    /// Features in effect: the required ones, and the offered ones the peer understood.
+    fn features(&self) -> u64 {
+        let h = self.header();
+        h.features_required.load(Ordering::Acquire)
+            | (h.features_optional.load(Ordering::Acquire)
+                & h.features_accepted.load(Ordering::Acquire))
+    }
+
+    #[cfg(feature = "lz4_flex")]
+    fn require_features(&self, bits: u64) {
+        self.header()
+            .features_required
+            .fetch_or(bits, Ordering::AcqRel);
+    }
+}
+
+/// A snapshot of statistics for one side of a ringbuffer, see `Sender::stats`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of items the ringbuffer can hold.
+    pub capacity: usize,
+    /// Number of items currently in the ringbuffer.
+    pub occupancy: usize,
+    /// Number of items sent or received by this side.
+    pub items: u64,
+    /// Number of items dropped by the sender, see `Sender::record_dropped`.
+    pub dropped: u64,
+    /// Number of times the sender started dropping items.
+    pub overflows: u64,
+    /// Number of times this side woke up the other side.
+    pub wakeups: u64,
 }
 
 pub struct Sender<T>(Inner, crate::ringbuf::Sender<T>);
 
 impl<T: Copy + zerocopy::AsBytes> Sender<T> {
-    /// Sets up a new ringbuffer and returns the sender half.
-    pub fn new(capacity: usize) -> Result<Self, Error> {
-        let inner = Inner::new::<T>(capacity, None)?;
+    fn from_inner(inner: Inner) -> Result<Self, Error> {
         let ringbuf =
-            unsafe { crate::ringbuf::Sender::attach(inner.mmap.as_mut_ptr(), inner.mmap.len())? };
+            unsafe { crate::ringbuf::Sender::attach(inner.ring_ptr(), inner.ring_len())? };
         Ok(Self(inner, ringbuf))
     }
 
+    /// Sets up a new ringbuffer and returns the sender half.
+    ///
+    /// See `SharedRingBuilder` for more options.
+    pub fn new(capacity: usize) -> Result<Self, Error> {
+        Self::from_inner(Inner::new::<T>(&SharedRingBuilder::new(capacity))?)
+    }
+
     /// Create a new ringbuffer with hugetlb support and returns the sender half.
     /// Supports linux version 4.16+ only
     pub fn with_hugetlb(capacity: usize, tlbsize: HugetlbSize) -> Result<Self, Error> {
-        let inner = Inner::new::<T>(capacity, Some(tlbsize))?;
-        let ringbuf =
-            unsafe { crate::ringbuf::Sender::attach(inner.mmap.as_mut_ptr(), inner.mmap.len())? };
-        Ok(Self(inner, ringbuf))
+        let b = SharedRingBuilder::new(capacity).hugetlb(tlbsize);
+        Self::from_inner(Inner::new::<T>(&b)?)
     }
 
     /// mlock the backing memory to avoid it being put into swap
@@ -124,6 +545,118 @@ impl<T: Copy + zerocopy::AsBytes> Sender<T> {
         self.0.mlock()
     }
 
+    /// Faults in all pages of the backing memory, so that the page fault cost is paid now
+    /// rather than in the hot path.
+    pub fn touch_all(&self) -> Result<(), Error> {
+        crate::mem::touch_all(&self.0.mmap)
+    }
+
+    /// Bytes of the ringbuffer actually resident in memory, as opposed to its capacity.
+    ///
+    /// The memory is allocated as it is first written to, so this grows with use, and
+    /// shrinks with `Receiver::set_reclaim_consumed`.
+    pub fn resident_bytes(&self) -> Result<usize, Error> {
+        crate::mem::resident_bytes(&self.0.mmap)
+    }
+
+    /// Calls `callback` when the number of items in the ringbuffer rises to `high` or above,
+    /// and when it falls back to `low` or below, to drive backpressure upstream.
+    ///
+    /// The callback gets the current number of items. Occupancy is only checked when this
+    /// side sends, so it is as seen right after each send.
+    pub fn set_watermarks<F: FnMut(Watermark, usize) + Send + 'static>(
+        &mut self,
+        high: usize,
+        low: usize,
+        callback: F,
+    ) {
+        self.0.set_watermarks(high, low, callback)
+    }
+
+    /// Removes the callback set by `set_watermarks`.
+    pub fn clear_watermarks(&mut self) {
+        self.0.watermarks = None;
+    }
+
+    /// Expects the receiver to call `Receiver::feed` at least every `interval`. If it does
+    /// not, `consumer_stuck` becomes true, and blocking calls waiting for the receiver fail
+    /// with `Error::ConsumerStuck`, e g for a supervisor to restart it; both until it feeds
+    /// again.
+    ///
+    /// The checks run every `interval` on `TimerWheel::global`, so it can take up to twice
+    /// that to notice. The receiver gets one interval from now for its first feed.
+    ///
+    /// Fails with `EINVAL` for a zero `interval`.
+    pub fn set_watchdog(&mut self, interval: std::time::Duration) -> Result<(), Error> {
+        self.0.watchdog = Some(watchdog::Watchdog::new(&self.0, interval)?);
+        Ok(())
+    }
+
+    /// Stops the checks started by `set_watchdog`.
+    pub fn clear_watchdog(&mut self) {
+        self.0.watchdog = None;
+    }
+
+    /// True if the watchdog found that the receiver has not fed it in time, see
+    /// `set_watchdog`.
+    pub fn consumer_stuck(&self) -> bool {
+        self.0.watchdog.as_ref().is_some_and(|w| w.stuck())
+    }
+
+    fn check_watchdog(&self) -> Result<(), Error> {
+        if self.consumer_stuck() {
+            Err(Error::ConsumerStuck)?
+        }
+        Ok(())
+    }
+
+    /// Stops sending, for replacing the receiver without losing items, e g in a rolling
+    /// restart: sending fails with `Error::Paused` until `resume`, and the receiver is told
+    /// to finish up, see `Receiver::drained`. Wait for it with `block_until_drained`.
+    pub fn pause(&mut self) -> Result<(), Error> {
+        if self.is_paused() {
+            return Ok(());
+        }
+        self.0.pause += 1;
+        self.0.header().pause.store(self.0.pause, Ordering::SeqCst);
+        // Wakes up a receiver waiting for items, which would not get any.
+        Inner::signal(self.empty_signal())
+    }
+
+    /// Allows sending again after `pause`, e g once the new receiver is attached.
+    pub fn resume(&mut self) {
+        if self.is_paused() {
+            self.0.pause += 1;
+            self.0.header().pause.store(self.0.pause, Ordering::SeqCst);
+        }
+    }
+
+    /// True between `pause` and `resume`.
+    pub fn is_paused(&self) -> bool {
+        self.0.pause % 2 == 1
+    }
+
+    /// For blocking scenarios, after `pause`, blocks until the receiver has received
+    /// everything and seen `Receiver::drained` return true. Returns at once if not paused.
+    pub fn block_until_drained(&mut self) -> Result<(), Error> {
+        if !self.is_paused() {
+            return Ok(());
+        }
+        let pause = self.0.pause;
+        while self.0.header().drained.load(Ordering::Acquire) != pause {
+            self.check_watchdog()?;
+            Inner::wait(self.full_signal())?;
+        }
+        Ok(())
+    }
+
+    /// Wipe the memory area when this side is dropped, e g because secrets pass through it.
+    ///
+    /// The other side sees the wiped area too, so only do this when it is done with it.
+    pub fn set_zeroize(&mut self, zeroize: bool) {
+        self.0.zeroize = zeroize;
+    }
+
     /// Attaches to a ringbuffer set up by the receiving side.
     pub fn open(
         capacity: usize,
@@ -131,10 +664,28 @@ impl<T: Copy + zerocopy::AsBytes> Sender<T> {
         empty_signal: File,
         full_signal: File,
     ) -> Result<Self, Error> {
-        let inner = Inner::open::<T>(capacity, memfd, empty_signal, full_signal)?;
-        let ringbuf =
-            unsafe { crate::ringbuf::Sender::attach(inner.mmap.as_mut_ptr(), inner.mmap.len())? };
-        Ok(Self(inner, ringbuf))
+        Self::open_with_features(capacity, memfd, empty_signal, full_signal, 0)
+    }
+
+    /// Like `open`, for a ringbuffer whose creator may require application defined
+    /// features; `understood` are the ones this side implements.
+    ///
+    /// Fails with `UnsupportedFeatures` if the creator requires others. Offered features
+    /// that are not understood are ignored, see `features`.
+    pub fn open_with_features(
+        capacity: usize,
+        memfd: File,
+        empty_signal: File,
+        full_signal: File,
+        understood: u64,
+    ) -> Result<Self, Error> {
+        Self::from_inner(Inner::open::<T>(
+            capacity,
+            memfd,
+            empty_signal,
+            full_signal,
+            understood,
+        )?)
     }
 
     /// Low-level access to the ringbuffer.
@@ -145,6 +696,11 @@ impl<T: Copy + zerocopy::AsBytes> Sender<T> {
         &mut self.1
     }
 
+    /// Number of items the ringbuffer can hold.
+    pub fn capacity(&self) -> usize {
+        self.1.capacity()
+    }
+
     /// The file descriptor for the shared memory area
     pub fn memfd(&self) -> &memfd::Memfd {
         &self.0.memfd
@@ -169,9 +725,31 @@ impl<T: Copy + zerocopy::AsBytes> Sender<T> {
     /// If the buffer is full, the closure is not called. If there is more data that could be written
     /// (e g in another part of the ringbuffer), that is indicated in the returned `Status` struct.
     pub fn send_raw<F: FnOnce(*mut T, usize) -> usize>(&mut self, f: F) -> Result<Status, Error> {
-        let status = self.sender_mut().send(f)?;
+        if self.is_paused() {
+            Err(Error::Paused)?
+        }
+        let (mut n, mut copy) = (0, 0);
+        let lines = self.0.prefetch;
+        let start = cycles();
+        let status = self.sender_mut().send(|p, count| {
+            prefetch::range(p, count, lines, true);
+            let t = cycles();
+            n = f(p, count);
+            copy = cycles().saturating_sub(t);
+            n
+        })?;
+        self.0.seq += n as u64;
+        if n > 0 {
+            self.0.overflowing = false;
+            self.0.record_transfer(cycles().saturating_sub(start), copy);
+        }
+        let occupancy = self.1.capacity() - status.remaining;
+        self.0.update_watermarks(occupancy);
         if status.signal {
-            self.empty_signal().write(&1u64.to_ne_bytes())?;
+            self.0.wakeups += 1;
+            let t = cycles();
+            Inner::signal(self.empty_signal())?;
+            self.0.record_signal(cycles().saturating_sub(t));
         }
         Ok(status)
     }
@@ -194,6 +772,29 @@ impl<T: Copy + zerocopy::AsBytes> Sender<T> {
         self.send_raw(|p, count| f(from_raw_parts_mut(p, count)))
     }
 
+    /// Sends one or more items through the ringbuffer, filling them in place.
+    ///
+    /// Works like `send_trusted`, but the closure gets the free slots as `MaybeUninit`, so
+    /// items can be written field by field without first building (or zeroing) a whole
+    /// item. It returns the number of items written, and only those are made visible to
+    /// the receiver; the first that many slots must be fully written.
+    ///
+    /// # Safety
+    ///
+    /// Same as for `send_trusted`. In addition, the first returned-count slots must be
+    /// initialized when the closure returns.
+    pub unsafe fn send_uninit<F: FnOnce(&mut [std::mem::MaybeUninit<T>]) -> usize>(
+        &mut self,
+        f: F,
+    ) -> Result<Status, Error> {
+        self.send_raw(|p, count| {
+            f(from_raw_parts_mut(
+                p as *mut std::mem::MaybeUninit<T>,
+                count,
+            ))
+        })
+    }
+
     /// For blocking scenarios, blocks until the channel is writable.
     pub fn block_until_writable(&mut self) -> Result<Status, Error> {
         loop {
@@ -204,30 +805,197 @@ impl<T: Copy + zerocopy::AsBytes> Sender<T> {
                     signal: false,
                 });
             };
-            let mut b = [0u8; 8];
-            self.full_signal().read(&mut b)?;
+            self.check_watchdog()?;
+            Inner::wait(self.full_signal())?;
         }
     }
+
+    pub(crate) fn declared_message_size(&self) -> u64 {
+        self.0.header().max_message_size.load(Ordering::Acquire)
+    }
+
+    #[cfg(feature = "lz4_flex")]
+    pub(crate) fn require_features(&self, bits: u64) {
+        self.0.require_features(bits)
+    }
+
+    pub(crate) fn set_header_flags(&self, flags: u64) {
+        self.0.header().flags.fetch_or(flags, Ordering::AcqRel);
+    }
+
+    pub(crate) fn declare_message_size(&self, size: u64) {
+        self.0
+            .header()
+            .max_message_size
+            .store(size, Ordering::Release)
+    }
+
+    /// Number of items sent through `send_raw` and `send_trusted`, i e the sequence number of
+    /// the next item.
+    pub fn sent(&self) -> u64 {
+        self.0.seq
+    }
+
+    /// The sequence number up to which the receiver has acknowledged processing items,
+    /// see `Receiver::ack`.
+    ///
+    /// Since the receiver is untrusted, the value is capped to the number of items sent.
+    pub fn acked(&self) -> u64 {
+        std::cmp::min(self.0.header().acked.load(Ordering::Acquire), self.0.seq)
+    }
+
+    /// Records that `count` items were dropped instead of sent, because the ringbuffer was
+    /// full, for senders that would rather lose data than wait.
+    ///
+    /// Both sides can read the counters, see `dropped` and `overflows`. An overflow is counted
+    /// the first time items are dropped after something was sent.
+    pub fn record_dropped(&mut self, count: u64) {
+        let h = self.0.header();
+        h.dropped.fetch_add(count, Ordering::Relaxed);
+        if !self.0.overflowing {
+            h.overflows.fetch_add(1, Ordering::Relaxed);
+            self.0.overflowing = true;
+        }
+    }
+
+    /// Statistics for this side of the ringbuffer.
+    pub fn stats(&self) -> Result<Stats, Error> {
+        let free = self.1.write_count()?;
+        Ok(self.0.stats(self.1.capacity(), self.1.capacity() - free))
+    }
+
+    /// The state of this side of the ringbuffer, for bug reports: indices, counters,
+    /// seals, file descriptors and peers, but none of the data.
+    ///
+    /// The `Debug` output of a `Sender` is this report too.
+    pub fn dump(&self) -> Report {
+        Report::new::<T>(&self.0, "sender", self.1.capacity(), self.1.index())
+    }
+
+    /// Prefetches up to this many cache lines of free slots before `send_raw` hands them to
+    /// the closure, see `SharedRingBuilder::prefetch`.
+    pub fn set_prefetch(&mut self, lines: usize) {
+        self.0.prefetch = lines;
+    }
+
+    /// Cycles spent in `send_raw` and friends since creation or the last `reset_profile`.
+    #[cfg(feature = "profiling")]
+    pub fn profile(&self) -> &crate::profiling::Profile {
+        &self.0.profile
+    }
+
+    /// Starts the profile over.
+    #[cfg(feature = "profiling")]
+    pub fn reset_profile(&mut self) {
+        self.0.profile = Default::default();
+    }
+
+    /// Number of items dropped, see `record_dropped`.
+    pub fn dropped(&self) -> u64 {
+        self.0.header().dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of times the sender started dropping items, see `record_dropped`.
+    pub fn overflows(&self) -> u64 {
+        self.0.header().overflows.load(Ordering::Relaxed)
+    }
+
+    /// Number of live processes that have the ringbuffer attached, this one included.
+    ///
+    /// Counted in the shared header, and reconciled against whether the processes are still
+    /// alive, so that one that crashed does not count. Up to eight processes are counted,
+    /// and a peer can lie about this, so it is only a hint.
+    pub fn peers(&self) -> usize {
+        self.0.header().peers.count()
+    }
+
+    /// `FEATURE_*` bits in effect for the ringbuffer: those its creator requires, and those
+    /// it offers that the attaching side understood.
+    ///
+    /// Like everything in the header, the peer can lie about this.
+    pub fn features(&self) -> u64 {
+        self.0.features()
+    }
+
+    /// Blocks until a receiver has attached to the ringbuffer, so that nothing is sent
+    /// before anybody listens.
+    ///
+    /// Returns false on timeout. This relies on `peers`, so it is only a hint too.
+    pub fn wait_attached(&self, timeout: Option<std::time::Duration>) -> Result<bool, Error> {
+        self.0.header().peers.wait_for(2, timeout)
+    }
+
+    /// Sets or clears close-on-exec on the memfd and both eventfds, e g to hand the
+    /// ringbuffer to a child through `exec`. See also `SharedRingBuilder::close_on_exec`.
+    pub fn set_close_on_exec(&self, value: bool) -> Result<(), Error> {
+        self.0.set_close_on_exec(value)
+    }
+
+    /// Fixes up this half in the child after a `fork`.
+    ///
+    /// The memory area and file descriptors are still shared with the parent after a fork,
+    /// so only one of the two processes may go on using this half. If it is the child, it
+    /// calls this so that it is counted among the peers under its own pid. An exec'd child
+    /// instead opens the ringbuffer again from the inherited file descriptors.
+    pub fn after_fork(&mut self) {
+        self.0.after_fork()
+    }
+
+    /// Waits until `n` processes attached to the ringbuffer (this one included) call
+    /// `wait_for_peers`, so that they can all start together. Usually `n` is two, for the
+    /// sender and the receiver.
+    ///
+    /// Returns false on timeout.
+    pub fn wait_for_peers(
+        &self,
+        n: u32,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<bool, Error> {
+        self.0.header().barrier.wait(n, timeout)
+    }
+
+    /// For blocking scenarios, blocks until the receiver has acknowledged items up to `seq`.
+    pub fn block_until_acked(&mut self, seq: u64) -> Result<(), Error> {
+        let seq = std::cmp::min(seq, self.0.seq);
+        while self.acked() < seq {
+            self.0.header().ack_wanted.store(seq, Ordering::SeqCst);
+            if self.acked() >= seq {
+                break;
+            }
+            self.check_watchdog()?;
+            Inner::wait(self.full_signal())?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Copy + zerocopy::AsBytes> std::fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.dump().fmt(f)
+    }
 }
 
 pub struct Receiver<T>(Inner, crate::ringbuf::Receiver<T>);
 
 impl<T: Copy + zerocopy::FromBytes> Receiver<T> {
-    /// Sets up a new ringbuffer and returns the receiver half.
-    pub fn new(capacity: usize) -> Result<Self, Error> {
-        let inner = Inner::new::<T>(capacity, None)?;
+    fn from_inner(inner: Inner) -> Result<Self, Error> {
         let ringbuf =
-            unsafe { crate::ringbuf::Receiver::attach(inner.mmap.as_mut_ptr(), inner.mmap.len())? };
+            unsafe { crate::ringbuf::Receiver::attach(inner.ring_ptr(), inner.ring_len())? };
         Ok(Self(inner, ringbuf))
     }
 
+    /// Sets up a new ringbuffer and returns the receiver half.
+    ///
+    /// See `SharedRingBuilder` for more options.
+    pub fn new(capacity: usize) -> Result<Self, Error> {
+        Self::from_inner(Inner::new::<T>(&SharedRingBuilder::new(capacity))?)
+    }
+
     /// Create a new ringbuffer with hugetlb support and returns the receiver half.
     /// Supports linux version 4.16+ only
     pub fn with_hugetlb(capacity: usize, tlbsize: HugetlbSize) -> Result<Self, Error> {
-        let inner = Inner::new::<T>(capacity, Some(tlbsize))?;
-        let ringbuf =
-            unsafe { crate::ringbuf::Receiver::attach(inner.mmap.as_mut_ptr(), inner.mmap.len())? };
-        Ok(Self(inner, ringbuf))
+        let b = SharedRingBuilder::new(capacity).hugetlb(tlbsize);
+        Self::from_inner(Inner::new::<T>(&b)?)
     }
 
     /// Attaches to a ringbuffer set up by the sending side.
@@ -237,10 +1005,40 @@ impl<T: Copy + zerocopy::FromBytes> Receiver<T> {
         empty_signal: File,
         full_signal: File,
     ) -> Result<Self, Error> {
-        let inner = Inner::open::<T>(capacity, memfd, empty_signal, full_signal)?;
-        let ringbuf =
-            unsafe { crate::ringbuf::Receiver::attach(inner.mmap.as_mut_ptr(), inner.mmap.len())? };
-        Ok(Self(inner, ringbuf))
+        Self::open_with_features(capacity, memfd, empty_signal, full_signal, 0)
+    }
+
+    /// Like `open`, understanding application defined features, see
+    /// `Sender::open_with_features`.
+    pub fn open_with_features(
+        capacity: usize,
+        memfd: File,
+        empty_signal: File,
+        full_signal: File,
+        understood: u64,
+    ) -> Result<Self, Error> {
+        Self::from_inner(Inner::open::<T>(
+            capacity,
+            memfd,
+            empty_signal,
+            full_signal,
+            understood,
+        )?)
+    }
+
+    /// Attaches to a memory area holding `data`, followed by zeroes, as if it came from
+    /// the sending side. For fuzzing the validation of untrusted rings, without setting up
+    /// file descriptors.
+    ///
+    /// Fails with `EINVAL` for a `capacity` above 2^20 items, to keep memory use bounded.
+    /// The cargo-fuzz targets in the `fuzz` directory are built on this.
+    #[doc(hidden)]
+    pub fn fuzz_open(capacity: usize, data: &[u8]) -> Result<Self, Error> {
+        if capacity > FUZZ_MAX_CAPACITY {
+            Err(std::io::Error::from_raw_os_error(libc::EINVAL))?
+        }
+        let (memfd, e, f) = fuzz_fds::<T>(capacity, data)?;
+        Self::open(capacity, memfd, e, f)
     }
 
     /// mlock the backing memory to avoid it being put into swap
@@ -248,6 +1046,58 @@ impl<T: Copy + zerocopy::FromBytes> Receiver<T> {
         self.0.mlock()
     }
 
+    /// Faults in all pages of the backing memory, so that the page fault cost is paid now
+    /// rather than in the hot path.
+    pub fn touch_all(&self) -> Result<(), Error> {
+        crate::mem::touch_all(&self.0.mmap)
+    }
+
+    /// Bytes of the ringbuffer actually resident in memory, as opposed to its capacity.
+    ///
+    /// The memory is allocated as it is first written to, so this grows with use, and
+    /// shrinks with `Receiver::set_reclaim_consumed`.
+    pub fn resident_bytes(&self) -> Result<usize, Error> {
+        crate::mem::resident_bytes(&self.0.mmap)
+    }
+
+    /// Calls `callback` when the number of items in the ringbuffer rises to `high` or above,
+    /// and when it falls back to `low` or below, to drive backpressure upstream.
+    ///
+    /// The callback gets the current number of items. Occupancy is only checked when this
+    /// side receives, so it is as seen right after each receive.
+    pub fn set_watermarks<F: FnMut(Watermark, usize) + Send + 'static>(
+        &mut self,
+        high: usize,
+        low: usize,
+        callback: F,
+    ) {
+        self.0.set_watermarks(high, low, callback)
+    }
+
+    /// Removes the callback set by `set_watermarks`.
+    pub fn clear_watermarks(&mut self) {
+        self.0.watermarks = None;
+    }
+
+    /// Wipe the memory area when this side is dropped, e g because secrets pass through it.
+    ///
+    /// The other side sees the wiped area too, so only do this when it is done with it.
+    pub fn set_zeroize(&mut self, zeroize: bool) {
+        self.0.zeroize = zeroize;
+    }
+
+    /// Gives back memory to the kernel as it is consumed, by punching holes over the pages
+    /// covered by the items taken in each call to `receive_raw` (and friends). This keeps the
+    /// resident size of a huge, mostly idle ringbuffer down, at the cost of page faults and
+    /// zeroing when the sender writes to those pages again.
+    ///
+    /// Pages are only reclaimed while they are still owned by this side, i e before the items
+    /// are released to the sender, so a page is reclaimed only if a single call consumes it in
+    /// full. If the kernel cannot punch holes in the memfd, nothing is reclaimed.
+    pub fn set_reclaim_consumed(&mut self, reclaim: bool) {
+        self.0.reclaim = reclaim;
+    }
+
     /// Low-level access to the ringbuffer.
     ///
     /// Note that reading directly using these methods will not trigger a signal for the sending side
@@ -282,13 +1132,73 @@ impl<T: Copy + zerocopy::FromBytes> Receiver<T> {
         &mut self,
         f: F,
     ) -> Result<Status, Error> {
-        let status = self.receiver_mut().recv(f)?;
+        let status = self.receive_unsignaled(f)?;
         if status.signal {
-            self.full_signal().write(&1u64.to_ne_bytes())?;
+            self.signal_space()?;
         }
         Ok(status)
     }
 
+    /// Like `receive_raw`, but leaves waking up the sender to the caller.
+    fn receive_unsignaled<F: FnOnce(*const T, usize) -> usize>(
+        &mut self,
+        f: F,
+    ) -> Result<Status, Error> {
+        let (mut n, mut copy) = (0, 0);
+        let inner = &self.0;
+        let start = cycles();
+        let status = self.1.recv(|p, count| {
+            prefetch::range(p, count, inner.prefetch, false);
+            let t = cycles();
+            n = f(p, count);
+            copy = cycles().saturating_sub(t);
+            if inner.reclaim && n <= count {
+                // Only now, before the items are handed back to the sender, can we be sure
+                // that it is not writing new data to these pages.
+                inner.reclaim(p as *const u8, n * std::mem::size_of::<T>());
+            }
+            n
+        })?;
+        if n > 0 {
+            self.0.record_transfer(cycles().saturating_sub(start), copy);
+        }
+        self.0.seq += n as u64;
+        self.0.update_watermarks(status.remaining);
+        Ok(status)
+    }
+
+    fn signal_space(&mut self) -> Result<(), Error> {
+        self.0.wakeups += 1;
+        let t = cycles();
+        Inner::signal(self.full_signal())?;
+        self.0.record_signal(cycles().saturating_sub(t));
+        Ok(())
+    }
+
+    /// Inspects data in the ringbuffer without consuming it.
+    ///
+    /// The closure works like in `receive_raw`, but nothing is dropped from the ringbuffer,
+    /// so the same items are seen again by the next receive. This allows consuming a message
+    /// only after e g a downstream resource has been secured for it.
+    /// Returns `None` if the buffer is empty, in which case the closure is not called.
+    pub fn peek<R, F: FnOnce(*const T, usize) -> R>(&mut self, f: F) -> Result<Option<R>, Error> {
+        let mut r = None;
+        self.1.recv(|p, count| {
+            r = Some(f(p, count));
+            0
+        })?;
+        Ok(r)
+    }
+
+    /// Returns an iterator over the items in the ringbuffer, until it is empty.
+    ///
+    /// Items are taken one at a time, so the sender can reuse their space right away, but it
+    /// is woken up (if it was waiting for space) only once, when the iterator is dropped or
+    /// `Drain::finish` is called.
+    pub fn drain(&mut self) -> Drain<'_, T> {
+        Drain::new(self)
+    }
+
     /// Receives data from the ringbuffer.
     ///
     /// The closure receives a slice of data and returns the number of items that can be dropped
@@ -307,7 +1217,49 @@ impl<T: Copy + zerocopy::FromBytes> Receiver<T> {
         self.receive_raw(|p, count| f(from_raw_parts(p, count)))
     }
 
+    /// Receives data from the ringbuffer, but no more than a budget of items and bytes.
+    ///
+    /// The closure works like in `receive_raw`, but is called repeatedly (e g when the data
+    /// wraps around the end of the ringbuffer) until the budget is spent, the buffer is empty,
+    /// or the closure takes fewer items than it was offered.
+    /// This keeps one busy channel from monopolizing an event loop. Check `remaining` in the
+    /// returned `Status` to see if there is more data left to read.
+    pub fn recv_budgeted<F: FnMut(*const T, usize) -> usize>(
+        &mut self,
+        max_items: usize,
+        max_bytes: usize,
+        mut f: F,
+    ) -> Result<Status, Error> {
+        let size = std::cmp::max(std::mem::size_of::<T>(), 1);
+        let mut budget = std::cmp::min(max_items, max_bytes / size);
+        let mut status = Status {
+            remaining: self.receiver_mut().read_count()?,
+            signal: false,
+        };
+        while budget > 0 && status.remaining > 0 {
+            let (mut offered, mut taken) = (0, 0);
+            status = self.receive_raw(|p, count| {
+                offered = std::cmp::min(count, budget);
+                taken = f(p, offered);
+                if taken > offered {
+                    // Make the ringbuffer reject it.
+                    count + 1
+                } else {
+                    taken
+                }
+            })?;
+            budget -= taken;
+            if taken < offered {
+                break;
+            }
+        }
+        Ok(status)
+    }
+
     /// For blocking scenarios, blocks until the channel is readable.
+    ///
+    /// Fails with `Error::Paused` if the sender has paused and there is nothing left to
+    /// receive, see `drained`.
     pub fn block_until_readable(&mut self) -> Result<Status, Error> {
         loop {
             let s = self.receiver_mut().read_count()?;
@@ -317,19 +1269,758 @@ impl<T: Copy + zerocopy::FromBytes> Receiver<T> {
                     signal: false,
                 });
             };
-            let mut b = [0u8; 8];
-            self.empty_signal().read(&mut b)?;
+            if self.drained()? {
+                Err(Error::Paused)?
+            }
+            Inner::wait(self.empty_signal())?;
+        }
+    }
+
+    pub(crate) fn declared_message_size(&self) -> u64 {
+        self.0.header().max_message_size.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn header_flags(&self) -> u64 {
+        self.0.header().flags.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn declare_message_size(&self, size: u64) {
+        self.0
+            .header()
+            .max_message_size
+            .store(size, Ordering::Release)
+    }
+
+    /// Number of live processes that have the ringbuffer attached, see `Sender::peers`.
+    pub fn peers(&self) -> usize {
+        self.0.header().peers.count()
+    }
+
+    /// `FEATURE_*` bits in effect for the ringbuffer, see `Sender::features`.
+    pub fn features(&self) -> u64 {
+        self.0.features()
+    }
+
+    /// Waits until `n` processes attached to the ringbuffer are ready, see
+    /// `Sender::wait_for_peers`.
+    pub fn wait_for_peers(
+        &self,
+        n: u32,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<bool, Error> {
+        self.0.header().barrier.wait(n, timeout)
+    }
+
+    /// Sets or clears close-on-exec on the file descriptors, see `Sender::set_close_on_exec`.
+    pub fn set_close_on_exec(&self, value: bool) -> Result<(), Error> {
+        self.0.set_close_on_exec(value)
+    }
+
+    /// Fixes up this half in the child after a `fork`, see `Sender::after_fork`.
+    pub fn after_fork(&mut self) {
+        self.0.after_fork()
+    }
+
+    /// True once the sender has paused, see `Sender::pause`, and everything it sent has
+    /// been received, so that this side can go away without losing items. Also lets the
+    /// sender know, for `Sender::block_until_drained`.
+    pub fn drained(&mut self) -> Result<bool, Error> {
+        let pause = self.0.header().pause.load(Ordering::SeqCst);
+        if pause % 2 == 0 || self.receiver_mut().read_count()? > 0 {
+            return Ok(false);
+        }
+        let h = self.0.header();
+        if h.drained.swap(pause, Ordering::AcqRel) != pause {
+            Inner::signal(self.full_signal())?;
+        }
+        Ok(true)
+    }
+
+    /// Tells the sender's watchdog that this side is still making progress, see
+    /// `Sender::set_watchdog`. Call it from the processing loop, not from a thread of its
+    /// own, or it will not notice the loop being stuck.
+    pub fn feed(&self) {
+        let now = crate::sim::monotonic_ns();
+        self.0.header().watchdog_fed.store(now, Ordering::Release);
+    }
+
+    /// Number of items received through `receive_raw` and `receive_trusted`, i e the
+    /// sequence number of the next item.
+    pub fn received(&self) -> u64 {
+        self.0.seq
+    }
+
+    /// Statistics for this side of the ringbuffer, see `Sender::stats`.
+    ///
+    /// The drop counters are what the sender says, and the sender is untrusted.
+    pub fn stats(&self) -> Result<Stats, Error> {
+        Ok(self.0.stats(self.1.capacity(), self.1.read_count()?))
+    }
+
+    /// Prefetches up to this many cache lines of items before `receive_raw` hands them to
+    /// the closure, see `SharedRingBuilder::prefetch`.
+    pub fn set_prefetch(&mut self, lines: usize) {
+        self.0.prefetch = lines;
+    }
+
+    /// Takes a private copy of the whole memory area, and decodes its header.
+    ///
+    /// This is for capturing a wedged channel for offline analysis: nothing in the live
+    /// ringbuffer is touched, and neither side is woken up. The copy is not atomic, so the
+    /// peer may be halfway through an update while it is taken.
+    pub fn debug_snapshot(&self) -> Result<Snapshot, Error> {
+        Snapshot::take(&self.0)
+    }
+
+    /// The state of this side of the ringbuffer, see `Sender::dump`.
+    pub fn dump(&self) -> Report {
+        Report::new::<T>(&self.0, "receiver", self.1.capacity(), self.1.index())
+    }
+
+    /// Cycles spent in `receive_raw` and friends, see `Sender::profile`.
+    #[cfg(feature = "profiling")]
+    pub fn profile(&self) -> &crate::profiling::Profile {
+        &self.0.profile
+    }
+
+    /// Starts the profile over.
+    #[cfg(feature = "profiling")]
+    pub fn reset_profile(&mut self) {
+        self.0.profile = Default::default();
+    }
+
+    /// Number of items the sender reports having dropped, see `Sender::record_dropped`.
+    ///
+    /// This is what the sender says, and the sender is untrusted.
+    pub fn dropped(&self) -> u64 {
+        self.0.header().dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of times the sender reports having started dropping items.
+    pub fn overflows(&self) -> u64 {
+        self.0.header().overflows.load(Ordering::Relaxed)
+    }
+
+    /// Acknowledges that items up to (but not including) `seq` have been processed.
+    ///
+    /// The sender can query this with `Sender::acked`, and is woken up if it is waiting for it.
+    /// If a journal is set, processing is committed to it before acknowledging.
+    pub fn ack(&mut self, seq: u64) -> Result<(), Error> {
+        if seq > self.0.seq {
+            Err(Error::OutOfBounds)?
+        }
+        if let Some((journal, base)) = &self.0.journal {
+            journal.commit(base + seq);
         }
+        let h = self.0.header();
+        h.acked.store(seq, Ordering::SeqCst);
+        let wanted = h.ack_wanted.load(Ordering::SeqCst);
+        if wanted != 0 && wanted <= seq && h.ack_wanted.swap(0, Ordering::SeqCst) != 0 {
+            self.0.wakeups += 1;
+            Inner::signal(self.full_signal())?;
+        }
+        Ok(())
+    }
+
+    /// Keeps track of acknowledged items in a journal, so that a consumer restarting after
+    /// a crash does not process items twice.
+    ///
+    /// `base` is the sequence number of the first item in this ringbuffer, as counted by the
+    /// journal. A sender that resends everything not acknowledged to a new consumer tells it
+    /// where it starts, e g its `Sender::acked` from before. Use `skip_committed` to drop the
+    /// items that were already processed.
+    pub fn set_journal(&mut self, journal: Journal, base: u64) {
+        self.0.journal = Some((journal, base));
+    }
+
+    /// Receives and acknowledges items that the journal says have already been processed.
+    ///
+    /// Returns the number of items skipped. Call this before receiving, and again until
+    /// it returns zero if the items might not all have been sent yet.
+    pub fn skip_committed(&mut self) -> Result<usize, Error> {
+        let stale = match &self.0.journal {
+            None => return Ok(0),
+            Some((journal, base)) => journal.committed().saturating_sub(base + self.0.seq),
+        };
+        let mut skipped = 0;
+        while (skipped as u64) < stale {
+            let left = usize::try_from(stale - skipped as u64).unwrap_or(usize::MAX);
+            let mut n = 0;
+            self.receive_raw(|_, count| {
+                n = std::cmp::min(count, left);
+                n
+            })?;
+            if n == 0 {
+                break;
+            }
+            skipped += n;
+        }
+        if skipped > 0 {
+            self.ack(self.0.seq)?;
+        }
+        Ok(skipped)
     }
 }
 
+impl<T: Copy + zerocopy::FromBytes> std::fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.dump().fmt(f)
+    }
+}
+
+/// A sender and a receiver attached to its ringbuffer.
+#[cfg(test)]
+fn pair<T: Copy + zerocopy::AsBytes + zerocopy::FromBytes>(
+    capacity: usize,
+) -> (Sender<T>, Receiver<T>) {
+    let s = Sender::new(capacity).unwrap();
+    let memfd = s.memfd().as_file().try_clone().unwrap();
+    let e = s.empty_signal().try_clone().unwrap();
+    let f = s.full_signal().try_clone().unwrap();
+    let r = Receiver::open(capacity, memfd, e, f).unwrap();
+    (s, r)
+}
+
 #[test]
 fn simple() {
-    let mut s: Sender<i32> = Sender::new(1000).unwrap();
+    let (mut s, mut r) = pair::<i32>(1000);
     assert!(s.sender_mut().write_count().unwrap() >= 1000);
+    assert_eq!(r.receiver_mut().read_count().unwrap(), 0);
+}
+
+#[test]
+fn budgeted() {
+    let (mut s, mut r) = pair::<u32>(100);
+    s.send_raw(|_, _| 30).unwrap();
+    let mut total = 0;
+    let status = r.recv_budgeted(20, 1000, |_, count| {
+        total += count;
+        count
+    });
+    assert_eq!(total, 20);
+    assert_eq!(status.unwrap().remaining, 10);
+    // 40 bytes is 10 items of u32
+    let status = r.recv_budgeted(20, 40, |_, count| count).unwrap();
+    assert_eq!(status.remaining, 0);
+    assert_eq!(r.received(), 30);
+    assert!(r.recv_budgeted(20, 40, |_, _| panic!()).is_ok());
+}
+
+#[test]
+fn acks() {
+    let (mut s, mut r) = pair::<u32>(100);
+    s.send_raw(|_, _| 10).unwrap();
+    assert_eq!(s.sent(), 10);
+    r.receive_raw(|_, count| {
+        assert_eq!(count, 10);
+        6
+    })
+    .unwrap();
+    assert_eq!(r.received(), 6);
+    assert!(r.ack(7).is_err());
+    r.ack(4).unwrap();
+    assert_eq!(s.acked(), 4);
+    let t = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        r.ack(6).unwrap();
+    });
+    s.block_until_acked(6).unwrap();
+    assert_eq!(s.acked(), 6);
+    t.join().unwrap();
+}
+
+#[test]
+fn builder() {
+    let (mut s, fds) = SharedRingBuilder::new(100)
+        .name("builder-test")
+        .seal(FileSeal::SealGrow)
+        .prefault(true)
+        .build_sender::<u16>()
+        .unwrap();
+    assert!(s.memfd().seals().unwrap().contains(&FileSeal::SealGrow));
+    let mut r: Receiver<u16> =
+        Receiver::open(fds.capacity, fds.memfd, fds.empty_signal, fds.full_signal).unwrap();
+    r.touch_all().unwrap();
+    s.send_raw(|p, _| {
+        unsafe { *p = 7 };
+        1
+    })
+    .unwrap();
+    r.receive_raw(|p, n| {
+        assert_eq!((n, unsafe { *p }), (1, 7));
+        1
+    })
+    .unwrap();
+    assert!(SharedRingBuilder::new(100)
+        .seal(FileSeal::SealWrite)
+        .build_receiver::<u16>()
+        .is_err());
+}
+
+#[test]
+fn zeroize() {
+    use std::os::unix::fs::FileExt;
+    let (mut s, fds) = SharedRingBuilder::new(100)
+        .zeroize(true)
+        .build_sender::<u8>()
+        .unwrap();
+    s.send_raw(|p, _| {
+        unsafe { *p = 0xab };
+        1
+    })
+    .unwrap();
+    let mut buf = vec![0u8; 4096];
+    fds.memfd.read_exact_at(&mut buf, 0).unwrap();
+    assert!(buf.contains(&0xab));
+    drop(s);
+    fds.memfd.read_exact_at(&mut buf, 0).unwrap();
+    assert!(!buf.contains(&0xab));
+}
+
+#[test]
+fn reclaim_consumed() {
+    use std::os::unix::fs::MetadataExt;
+    let (mut r, fds) = SharedRingBuilder::new(4 * 4096)
+        .prefault(true)
+        .build_receiver::<u8>()
+        .unwrap();
+    let mut s: Sender<u8> =
+        Sender::open(fds.capacity, fds.memfd, fds.empty_signal, fds.full_signal).unwrap();
+    let memfd = r.memfd().as_file().try_clone().unwrap();
+    let blocks = || memfd.metadata().unwrap().blocks();
+    let before = blocks();
+    assert_eq!(r.resident_bytes().unwrap(), s.resident_bytes().unwrap());
+    s.send_raw(|_, n| std::cmp::min(n, 3 * 4096)).unwrap();
+    r.set_reclaim_consumed(true);
+    r.receive_raw(|_, n| n).unwrap();
+    // At least two whole pages, with the header and ringbuf index in front
+    assert!(blocks() <= before - 2 * 4096 / 512);
+}
+
+#[test]
+fn watermarks() {
+    use std::sync::{Arc, Mutex};
+    let (mut s, mut r) = pair::<u8>(100);
+    let events = Arc::new(Mutex::new(vec![]));
+    let ev = events.clone();
+    r.set_watermarks(50, 10, move |w, n| ev.lock().unwrap().push((w, n)));
+    s.send_raw(|_, _| 80).unwrap();
+    for _ in 0..4 {
+        r.receive_raw(|_, _| 20).unwrap();
+    }
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![(Watermark::High, 60), (Watermark::Low, 0)]
+    );
+    let st = r.stats().unwrap();
+    assert_eq!((st.items, st.occupancy, st.wakeups), (80, 0, 0));
+    assert_eq!(s.stats().unwrap().wakeups, 1);
+}
+
+#[test]
+fn validated() {
+    #[derive(Debug, PartialEq)]
+    struct Flag(bool);
+    impl Validate for Flag {
+        type Raw = u8;
+        fn to_raw(&self) -> u8 {
+            self.0 as u8
+        }
+        fn validate(raw: u8) -> Option<Self> {
+            match raw {
+                0 | 1 => Some(Flag(raw == 1)),
+                _ => None,
+            }
+        }
+    }
+    let (mut s, mut r) = pair::<u8>(100);
+    assert_eq!(s.send_values(&[Flag(true), Flag(false)]).unwrap(), 2);
+    s.send_raw(|p, _| {
+        unsafe { *p = 2 };
+        1
+    })
+    .unwrap();
+    let mut v = vec![];
+    assert!(r.receive_values(10, |x: Flag| v.push(x)).is_err());
+    assert_eq!(v, vec![Flag(true), Flag(false)]);
+}
+
+#[test]
+fn uninit() {
+    let (mut s, mut r) = pair::<[u32; 4]>(10);
+    unsafe {
+        s.send_uninit(|slots| {
+            let p = slots[0].as_mut_ptr() as *mut u32;
+            for i in 0..4 {
+                p.add(i).write(i as u32);
+            }
+            1
+        })
+    }
+    .unwrap();
+    assert_eq!(r.receiver_mut().read_count().unwrap(), 1);
+    r.receive_raw(|p, _| {
+        assert_eq!(unsafe { std::ptr::read(p) }, [0, 1, 2, 3]);
+        1
+    })
+    .unwrap();
+}
+
+#[test]
+fn drain() {
+    let (mut s, mut r) = pair::<u16>(100);
+    let n = s.sender_mut().capacity();
+    s.sender_mut().send_foreach(n, || 3);
+    let sum: u32 = r.drain().map(u32::from).sum();
+    assert_eq!(sum, 3 * n as u32);
+    // Was full, so exactly one wakeup
+    assert_eq!(r.stats().unwrap().wakeups, 1);
+    let mut b = [0u8; 8];
+    use std::io::Read;
+    s.full_signal().read_exact(&mut b).unwrap();
+    assert_eq!(u64::from_ne_bytes(b), 1);
+}
+
+#[test]
+fn peek() {
+    let (mut s, mut r) = pair::<u32>(16);
+    assert_eq!(r.peek(|_, _| ()).unwrap(), None);
+    s.sender_mut().send_foreach(2, || 7);
+    let first = |p: *const u32, _| unsafe { std::ptr::read(p) };
+    assert_eq!(r.peek(first).unwrap(), Some(7));
+    assert_eq!(r.peek(|_, count| count).unwrap(), Some(2));
+    r.receive_raw(|_, _| 1).unwrap();
+    assert_eq!(r.peek(|_, count| count).unwrap(), Some(1));
+}
+
+#[test]
+fn journal() {
+    let memfd = memfd::MemfdOptions::new().create("journal").unwrap();
+    let journal = Journal::from_file(memfd.into_file()).unwrap();
+    let (mut s, mut r) = pair::<u32>(100);
+    // The ringbuffer starts at item 5, and items before 7 were processed before a restart.
+    journal.commit(7);
+    r.set_journal(journal, 5);
+    s.send_raw(|_, _| 4).unwrap();
+    assert_eq!(r.skip_committed().unwrap(), 2);
+    assert_eq!((r.received(), s.acked()), (2, 2));
+    r.receive_raw(|_, count| count).unwrap();
+    r.ack(4).unwrap();
+    assert_eq!(r.0.journal.as_ref().unwrap().0.committed(), 9);
+    assert_eq!(r.skip_committed().unwrap(), 0);
+}
+
+#[test]
+fn wait_for_peers() {
+    let s: Sender<u8> = Sender::new(100).unwrap();
     let memfd = s.memfd().as_file().try_clone().unwrap();
     let e = s.empty_signal().try_clone().unwrap();
     let f = s.full_signal().try_clone().unwrap();
-    let mut r: Receiver<i32> = Receiver::open(1000, memfd, e, f).unwrap();
-    assert_eq!(r.receiver_mut().read_count().unwrap(), 0);
+    let timeout = Some(std::time::Duration::from_secs(10));
+    let t = std::thread::spawn(move || {
+        let r: Receiver<u8> = Receiver::open(100, memfd, e, f).unwrap();
+        r.wait_for_peers(2, timeout).unwrap()
+    });
+    assert!(s.wait_for_peers(2, timeout).unwrap());
+    assert!(t.join().unwrap());
+}
+
+#[test]
+fn peers() {
+    let s: Sender<u8> = Sender::new(100).unwrap();
+    assert_eq!(s.peers(), 1);
+    let memfd = s.memfd().as_file().try_clone().unwrap();
+    let e = s.empty_signal().try_clone().unwrap();
+    let f = s.full_signal().try_clone().unwrap();
+    let r: Receiver<u8> = Receiver::open(100, memfd, e, f).unwrap();
+    assert_eq!((s.peers(), r.peers()), (2, 2));
+    drop(r);
+    assert_eq!(s.peers(), 1);
+    let ms = std::time::Duration::from_millis;
+    assert!(!s.wait_attached(Some(ms(10))).unwrap());
+    let memfd = s.memfd().as_file().try_clone().unwrap();
+    let e = s.empty_signal().try_clone().unwrap();
+    let f = s.full_signal().try_clone().unwrap();
+    let t = std::thread::spawn(move || {
+        std::thread::sleep(ms(20));
+        Receiver::<u8>::open(100, memfd, e, f).unwrap()
+    });
+    assert!(s.wait_attached(Some(ms(10000))).unwrap());
+    t.join().unwrap();
+}
+
+#[test]
+fn close_on_exec() {
+    let cloexec =
+        |f: &File| unsafe { libc::fcntl(f.as_raw_fd(), libc::F_GETFD) } & libc::FD_CLOEXEC != 0;
+    let (s, fds) = SharedRingBuilder::new(100)
+        .close_on_exec(false)
+        .build_sender::<u8>()
+        .unwrap();
+    assert!(!cloexec(s.memfd().as_file()) && !cloexec(s.empty_signal()));
+    s.set_close_on_exec(true).unwrap();
+    assert!(cloexec(s.memfd().as_file()) && cloexec(s.full_signal()));
+    assert!(!cloexec(&fds.memfd) && !cloexec(&fds.empty_signal));
+}
+
+#[test]
+fn layout_checked() {
+    let s: Sender<u64> = Sender::new(100).unwrap();
+    let open = || {
+        Receiver::<u64>::open(
+            100,
+            s.memfd().as_file().try_clone().unwrap(),
+            s.empty_signal().try_clone().unwrap(),
+            s.full_signal().try_clone().unwrap(),
+        )
+    };
+    assert!(open().is_ok());
+    // A peer with the wrong byte order
+    s.0.header()
+        .layout
+        .store(LAYOUT.swap_bytes(), Ordering::Relaxed);
+    assert!(matches!(
+        open(),
+        Err(Error::Ringbuf(crate::ringbuf::Error::BufCorrupt))
+    ));
+}
+
+#[test]
+fn features() {
+    let app = FEATURE_APP_FIRST;
+    let (s, fds) = SharedRingBuilder::new(100)
+        .features(app, app << 1 | app << 2)
+        .build_sender::<u8>()
+        .unwrap();
+    assert_eq!(s.features(), app);
+    let open = |understood| {
+        Receiver::<u8>::open_with_features(
+            100,
+            fds.memfd.try_clone().unwrap(),
+            fds.empty_signal.try_clone().unwrap(),
+            fds.full_signal.try_clone().unwrap(),
+            understood,
+        )
+    };
+    assert!(matches!(
+        open(0),
+        Err(Error::UnsupportedFeatures { unsupported }) if unsupported == app
+    ));
+    let r = open(app | app << 2).unwrap();
+    assert_eq!(
+        (s.features(), r.features()),
+        (app | app << 2, app | app << 2)
+    );
+}
+
+#[test]
+fn prefetch() {
+    let (mut s, fds) = SharedRingBuilder::new(1000)
+        .prefetch(8)
+        .build_sender::<u64>()
+        .unwrap();
+    let mut r: Receiver<u64> =
+        Receiver::open(fds.capacity, fds.memfd, fds.empty_signal, fds.full_signal).unwrap();
+    r.set_prefetch(usize::MAX);
+    let values: Vec<u64> = (0..500).collect();
+    s.send_raw(|p, count| {
+        assert!(count >= 500);
+        unsafe { std::ptr::copy_nonoverlapping(values.as_ptr(), p, 500) };
+        500
+    })
+    .unwrap();
+    r.receive_raw(|p, count| {
+        assert_eq!(unsafe { std::slice::from_raw_parts(p, count) }, &values[..]);
+        count
+    })
+    .unwrap();
+}
+
+#[test]
+fn debug_snapshot() {
+    let (mut s, fds) = SharedRingBuilder::new(1000).build_sender::<u64>().unwrap();
+    let mut r: Receiver<u64> =
+        Receiver::open(fds.capacity, fds.memfd, fds.empty_signal, fds.full_signal).unwrap();
+    s.send_raw(|p, _| {
+        unsafe { *p = 0x1234 };
+        1
+    })
+    .unwrap();
+    s.record_dropped(2);
+    let snap = r.debug_snapshot().unwrap();
+    r.receive_raw(|_, count| count).unwrap();
+    s.send_raw(|p, _| {
+        unsafe { *p = 0x5678 };
+        1
+    })
+    .unwrap();
+    let h = snap.header();
+    assert!(h.layout_ok);
+    assert_eq!((h.count, h.dropped, h.overflows), (1, 2, 1));
+    assert_eq!(h.peers, [std::process::id(); 2]);
+    assert_eq!(snap.bytes().len(), snap.ring().len() + HEADER_SIZE);
+    let first = &snap.ring()[64..72];
+    assert_eq!(first, &0x1234u64.to_ne_bytes());
+    assert_eq!(r.debug_snapshot().unwrap().header().count, 1);
+}
+
+#[test]
+fn dump() {
+    let (mut s, fds) = SharedRingBuilder::new(100).build_sender::<u32>().unwrap();
+    let r: Receiver<u32> =
+        Receiver::open(fds.capacity, fds.memfd, fds.empty_signal, fds.full_signal).unwrap();
+    s.send_raw(|p, _| {
+        unsafe { *p = 0xdead_beef };
+        3
+    })
+    .unwrap();
+    let d = r.dump();
+    assert_eq!((d.side, d.index, d.count, d.seq), ("receiver", 0, 3, 0));
+    assert_eq!((d.capacity, d.item_size), (s.dump().capacity, 4));
+    assert_eq!(s.dump().index, 3);
+    assert_eq!(d.memfd_fd, r.memfd().as_raw_fd());
+    assert!(d.seals.as_ref().unwrap().contains(&FileSeal::SealShrink));
+    let debug = format!("{:?}", s);
+    assert!(debug.contains("sender") && !debug.contains(&0xdead_beefu32.to_string()));
+    assert!(format!("{}", d).contains("index 0, count 3"));
+}
+
+#[test]
+fn fuzz_open() {
+    assert!(matches!(
+        Receiver::<u64>::fuzz_open(100, &[]),
+        Err(Error::Ringbuf(crate::ringbuf::Error::BufCorrupt))
+    ));
+    let mut data = LAYOUT.to_le_bytes().to_vec();
+    data.resize(HEADER_SIZE, 0);
+    // More items than the ringbuffer holds
+    data.extend_from_slice(&1000u64.to_le_bytes());
+    assert!(matches!(
+        Receiver::<u64>::fuzz_open(100, &data),
+        Err(Error::Ringbuf(crate::ringbuf::Error::BufCorrupt))
+    ));
+    data[HEADER_SIZE..].copy_from_slice(&1u64.to_le_bytes());
+    let mut r = Receiver::<u64>::fuzz_open(100, &data).unwrap();
+    assert_eq!(r.receive_raw(|_, count| count).unwrap().remaining, 0);
+    assert!(Receiver::<u64>::fuzz_open(usize::MAX, &data).is_err());
+}
+
+#[test]
+fn watchdog() {
+    use std::time::Duration;
+    let (mut s, r) = pair::<u32>(1);
+    let zero = s.set_watchdog(Duration::from_secs(0)).unwrap_err();
+    assert_eq!(zero.errno(), Some(libc::EINVAL));
+    s.set_watchdog(Duration::from_millis(20)).unwrap();
+    assert!(!s.consumer_stuck());
+    while s.send_raw(|_, n| n).unwrap().remaining > 0 {}
+    // Woken up, with the ringbuffer still full
+    assert!(matches!(
+        s.block_until_writable(),
+        Err(Error::ConsumerStuck)
+    ));
+    assert!(s.consumer_stuck());
+    for _ in 0..10 {
+        r.feed();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    assert!(!s.consumer_stuck());
+    s.clear_watchdog();
+    assert!(!s.consumer_stuck());
+}
+
+#[test]
+fn pause() {
+    let (mut s, mut r) = pair::<u32>(4);
+    s.send_raw(|_, _| 3).unwrap();
+    assert!(!r.drained().unwrap());
+    // Nothing to wait for
+    s.block_until_drained().unwrap();
+    s.pause().unwrap();
+    assert!(matches!(s.send_raw(|_, _| 1), Err(Error::Paused)));
+    assert!(!r.drained().unwrap());
+    let t = std::thread::spawn(move || {
+        // The receiver's loop, until it may exit
+        loop {
+            match r.block_until_readable() {
+                Ok(_) => r.receive_raw(|_, n| n).unwrap(),
+                Err(Error::Paused) => return r,
+                Err(e) => panic!("{:?}", e),
+            };
+        }
+    });
+    s.block_until_drained().unwrap();
+    let mut r = t.join().unwrap();
+    assert!(r.drained().unwrap());
+    assert_eq!(r.received(), 3);
+    s.resume();
+    assert!(!r.drained().unwrap());
+    s.send_raw(|_, _| 1).unwrap();
+    // The receiver cannot pause us
+    s.0.header().pause.store(5, Ordering::SeqCst);
+    assert!(!s.is_paused());
+    s.send_raw(|_, _| 1).unwrap();
+}
+
+#[test]
+fn blue_green() {
+    let (s, r) = pair::<u32>(4);
+    let (mut s, mut r) = (BlueGreenSender::new(s), BlueGreenReceiver::new(r));
+    let send = |s: &mut BlueGreenSender<u32>, v: u32| {
+        s.active()
+            .send_raw(|p, _| {
+                unsafe { p.write(v) };
+                1
+            })
+            .unwrap()
+    };
+    let recv = |r: &mut BlueGreenReceiver<u32>| {
+        let mut v = None;
+        r.active()
+            .unwrap()
+            .receive_raw(|p, n| {
+                v = Some(unsafe { p.read() }).filter(|_| n > 0);
+                n.min(1)
+            })
+            .unwrap();
+        v
+    };
+    send(&mut s, 1);
+    send(&mut s, 2);
+    // A bigger ringbuffer
+    let (next, next_r) = pair(64);
+    let old = s.switch_to(next).unwrap();
+    assert!(old.is_paused());
+    send(&mut s, 3);
+    r.prepare(next_r);
+    assert_eq!(recv(&mut r), Some(1));
+    assert_eq!(r.generation(), 0);
+    assert_eq!(recv(&mut r), Some(2));
+    assert_eq!(recv(&mut r), Some(3));
+    assert_eq!(r.generation(), 1);
+    assert_eq!((r.received(), s.sent()), (3, 3));
+    // Switching again, before the receiver has the new ringbuffer
+    let (next, next_r) = pair(4);
+    let _old = s.switch_to(next).unwrap();
+    assert!(matches!(r.block_until_readable(), Err(Error::Paused)));
+    r.prepare(next_r);
+    send(&mut s, 4);
+    r.block_until_readable().unwrap();
+    assert_eq!(recv(&mut r), Some(4));
+    assert_eq!(r.generation(), 2);
+}
+
+#[test]
+fn arrays() {
+    let (mut s, mut r) = pair::<u64>(16);
+    assert_eq!(r.recv_array::<3>().unwrap(), None);
+    let cap = s.capacity() as u64;
+    // Three does not divide the capacity, so some arrays wrap around.
+    for i in 0..cap {
+        assert!(s.send_array(&[i, i + 1, i + 2]).unwrap());
+        assert_eq!(r.recv_array().unwrap(), Some([i, i + 1, i + 2]));
+    }
+    while s.send_array(&[7; 3]).unwrap() {}
+    assert!(s.sender_mut().write_count().unwrap() < 3);
+    assert_eq!(r.recv_array::<3>().unwrap(), Some([7; 3]));
 }
@@ -0,0 +1,57 @@
+//! Sending and receiving a fixed number of items at a time, e g one message struct.
+
+use super::{Receiver, Sender};
+use crate::Error;
+use std::mem::MaybeUninit;
+
+impl<T: Copy + zerocopy::AsBytes> Sender<T> {
+    /// Sends all `N` items, or nothing if there is not room for all of them. Returns
+    /// whether they were sent.
+    ///
+    /// As `N` is known at compile time, the copy is inlined; if the items wrap around the
+    /// end of the ringbuffer, they are copied in two parts.
+    #[inline]
+    pub fn send_array<const N: usize>(&mut self, items: &[T; N]) -> Result<bool, Error> {
+        if self.sender_mut().write_count()? < N {
+            return Ok(false);
+        }
+        let mut sent = 0;
+        while sent < N {
+            self.send_raw(|p, count| {
+                let n = std::cmp::min(count, N - sent);
+                unsafe { std::ptr::copy_nonoverlapping(items.as_ptr().add(sent), p, n) };
+                sent += n;
+                n
+            })?;
+        }
+        Ok(true)
+    }
+}
+
+impl<T: Copy + zerocopy::FromBytes> Receiver<T> {
+    /// Receives exactly `N` items, or `None` if fewer than that are in the ringbuffer, in
+    /// which case nothing is received. See `Sender::send_array`.
+    #[inline]
+    pub fn recv_array<const N: usize>(&mut self) -> Result<Option<[T; N]>, Error> {
+        if self.receiver_mut().read_count()? < N {
+            return Ok(None);
+        }
+        let mut out = MaybeUninit::<[T; N]>::uninit();
+        let dst = out.as_mut_ptr() as *mut T;
+        let (mut got, mut signal) = (0, false);
+        while got < N {
+            let status = self.receive_unsignaled(|p, count| {
+                let n = std::cmp::min(count, N - got);
+                unsafe { std::ptr::copy_nonoverlapping(p, dst.add(got), n) };
+                got += n;
+                n
+            })?;
+            signal |= status.signal;
+        }
+        // Wake up the sender once, even if the items came in two parts.
+        if signal {
+            self.signal_space()?;
+        }
+        Ok(Some(unsafe { out.assume_init() }))
+    }
+}
@@ -0,0 +1,232 @@
+//! Builder for setting up new ringbuffers.
+
+use super::{Inner, Receiver, Sender};
+use crate::mem::mfd::{FileSeal, HugetlbSize, SealsHashSet};
+use crate::mem::Exec;
+use crate::Error;
+use std::fs::File;
+
+/// How the two sides wake each other up.
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Signaling {
+    /// A pair of eventfds, one for each direction.
+    EventFd,
+}
+
+/// The file descriptors (and capacity) to transfer to the other side, which can pass them
+/// to `Sender::open` or `Receiver::open`.
+#[derive(Debug)]
+pub struct RingFds {
+    pub capacity: usize,
+    pub memfd: File,
+    pub empty_signal: File,
+    pub full_signal: File,
+}
+
+/// Sets up a new ringbuffer.
+///
+/// # Example
+/// ```rust
+/// use shmem_ipc::sharedring::SharedRingBuilder;
+/// let (sender, fds) = SharedRingBuilder::new(1000)
+///     .name("example")
+///     .build_sender::<u64>()
+///     .unwrap();
+///  /* ... send fds to another process somehow ... */
+/// # drop((sender, fds));
+/// ```
+#[derive(Clone, Debug)]
+pub struct SharedRingBuilder {
+    pub(super) capacity: usize,
+    pub(super) hugetlb: Option<HugetlbSize>,
+    pub(super) signaling: Signaling,
+    pub(super) seals: SealsHashSet,
+    pub(super) mlock: bool,
+    pub(super) prefault: bool,
+    pub(super) numa_node: Option<u32>,
+    pub(super) zeroize: bool,
+    pub(super) name: Option<String>,
+    pub(super) exec: Exec,
+    pub(super) quota_group: Option<String>,
+    pub(super) close_on_exec: bool,
+    pub(super) features: (u64, u64),
+    pub(super) prefetch: usize,
+}
+
+impl SharedRingBuilder {
+    /// Starts building a ringbuffer holding `capacity` items.
+    pub fn new(capacity: usize) -> Self {
+        SharedRingBuilder {
+            capacity,
+            hugetlb: None,
+            signaling: Signaling::EventFd,
+            seals: SealsHashSet::new(),
+            mlock: false,
+            prefault: false,
+            numa_node: None,
+            zeroize: false,
+            name: None,
+            exec: Exec::NoExecSeal,
+            quota_group: None,
+            close_on_exec: true,
+            features: (0, 0),
+            prefetch: 0,
+        }
+    }
+
+    /// Backs the ringbuffer with huge pages. Supports linux version 4.16+ only.
+    pub fn hugetlb(mut self, size: HugetlbSize) -> Self {
+        self.hugetlb = Some(size);
+        self
+    }
+
+    /// Selects how the two sides wake each other up. Defaults to eventfds.
+    pub fn signaling(mut self, signaling: Signaling) -> Self {
+        self.signaling = signaling;
+        self
+    }
+
+    /// Adds a seal to the memfd, after it has been sized.
+    ///
+    /// `SealShrink` is always added. `SealWrite` cannot be added, since both sides need to
+    /// write to the memory area.
+    pub fn seal(mut self, seal: FileSeal) -> Self {
+        self.seals.insert(seal);
+        self
+    }
+
+    /// mlocks the backing memory on this side, to avoid it being put into swap.
+    pub fn mlock(mut self, mlock: bool) -> Self {
+        self.mlock = mlock;
+        self
+    }
+
+    /// Faults in the backing memory on this side up front, see `Sender::touch_all`.
+    pub fn prefault(mut self, prefault: bool) -> Self {
+        self.prefault = prefault;
+        self
+    }
+
+    /// Prefetches up to `lines` cache lines of the slots about to be used, before the closure
+    /// of `Sender::send_raw` or `Receiver::receive_raw` gets them. Defaults to zero, no
+    /// prefetching.
+    ///
+    /// Applies to the returned half; the other half has `set_prefetch`. It is a hint, and
+    /// only does something on x86_64 and aarch64. Whether it pays off depends on the CPU
+    /// and the batch sizes, so measure.
+    pub fn prefetch(mut self, lines: usize) -> Self {
+        self.prefetch = lines;
+        self
+    }
+
+    /// Binds the backing memory to a NUMA node.
+    ///
+    /// Pages are placed on the node when they are first touched, regardless of which side
+    /// touches them.
+    pub fn numa_node(mut self, node: u32) -> Self {
+        self.numa_node = Some(node);
+        self
+    }
+
+    /// Wipe the memory area when the returned half is dropped, see `Sender::set_zeroize`.
+    pub fn zeroize(mut self, zeroize: bool) -> Self {
+        self.zeroize = zeroize;
+        self
+    }
+
+    /// Name of the memfd, as seen in `/proc/<pid>/fd`. Defaults to the element type.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Whether the memfd may be executable. Defaults to `Exec::NoExecSeal`.
+    pub fn exec(mut self, exec: Exec) -> Self {
+        self.exec = exec;
+        self
+    }
+
+    /// Charges the memfd to a group, see `quota::set_limit`, on top of the process wide quota.
+    pub fn quota_group(mut self, group: &str) -> Self {
+        self.quota_group = Some(group.into());
+        self
+    }
+
+    /// Whether to set close-on-exec on the memfd and eventfds. Defaults to true; turn it
+    /// off to have an exec'd child inherit them, the returned `RingFds` included, see
+    /// `Sender::after_fork`.
+    pub fn close_on_exec(mut self, value: bool) -> Self {
+        self.close_on_exec = value;
+        self
+    }
+
+    /// Advertises `FEATURE_*` bits to the attaching side: it fails to attach unless it
+    /// understands all of `required`, and uses those of `optional` that it understands,
+    /// see `Sender::features`.
+    pub fn features(mut self, required: u64, optional: u64) -> Self {
+        self.features = (required, optional);
+        self
+    }
+
+    fn fds(&self, inner: &Inner) -> Result<RingFds, Error> {
+        Ok(RingFds {
+            capacity: self.capacity,
+            memfd: dup(inner.memfd.as_file(), self.close_on_exec)?,
+            empty_signal: dup(&inner.empty_signal, self.close_on_exec)?,
+            full_signal: dup(&inner.full_signal, self.close_on_exec)?,
+        })
+    }
+
+    /// Sets up the ringbuffer and returns the sender half, together with the file
+    /// descriptors for the receiving side.
+    pub fn build_sender<T: Copy + zerocopy::AsBytes>(&self) -> Result<(Sender<T>, RingFds), Error> {
+        let s = Sender::from_inner(Inner::new::<T>(self)?)?;
+        let fds = self.fds(&s.0)?;
+        Ok((s, fds))
+    }
+
+    /// Sets up the ringbuffer and returns the receiver half, together with the file
+    /// descriptors for the sending side.
+    pub fn build_receiver<T: Copy + zerocopy::FromBytes>(
+        &self,
+    ) -> Result<(Receiver<T>, RingFds), Error> {
+        let r = Receiver::from_inner(Inner::new::<T>(self)?)?;
+        let fds = self.fds(&r.0)?;
+        Ok((r, fds))
+    }
+}
+
+/// Duplicates a file descriptor to hand to the other side.
+pub(super) fn dup(f: &File, close_on_exec: bool) -> Result<File, Error> {
+    // try_clone always sets close-on-exec on the duplicate
+    let f = f.try_clone()?;
+    crate::mem::set_close_on_exec(&f, close_on_exec)?;
+    Ok(f)
+}
+
+/// Sets the memory policy of a shared mapping, which applies to the memfd as a whole.
+pub(super) fn mbind(mmap: &memmap2::MmapRaw, node: u32) -> Result<(), Error> {
+    const MPOL_BIND: libc::c_int = 2;
+    let mut mask = [0u64; 16];
+    let bits = mask.len() * 64;
+    if node as usize >= bits {
+        Err(std::io::Error::from_raw_os_error(libc::EINVAL))?
+    }
+    mask[node as usize / 64] |= 1 << (node % 64);
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            mmap.as_mut_ptr(),
+            mmap.len(),
+            MPOL_BIND,
+            mask.as_ptr(),
+            bits + 1,
+            0,
+        )
+    };
+    if r < 0 {
+        Err(std::io::Error::last_os_error())?
+    }
+    Ok(())
+}
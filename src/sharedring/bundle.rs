@@ -0,0 +1,387 @@
+//! Many independent ringbuffers in a single memfd.
+//!
+//! The memfd starts with a directory telling where each ringbuffer is, followed by the
+//! ringbuffers themselves, each page aligned and laid out like a standalone one. All of
+//! them share one pair of eventfds, so a bundle takes three file descriptors to hand over,
+//! however many ringbuffers it has.
+
+use super::builder::dup;
+use super::{eventfd, page_size, round_to_page_size, Inner, Receiver, Sender, LAYOUT};
+use crate::mem::mfd::FileSeal;
+use crate::{Error, Op};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+const MAGIC: u64 = u64::from_le_bytes(*b"shmbndl1");
+/// Limit on the number of ringbuffers, so that a directory from an untrusted peer cannot
+/// make us allocate a lot.
+const MAX_RINGS: usize = 4096;
+/// Magic and count, in front of the entries.
+const DIR_HEAD: usize = 16;
+/// Offset, length, capacity and item size.
+const ENTRY_SIZE: usize = 32;
+
+#[derive(Copy, Clone, Debug)]
+struct Entry {
+    offset: u64,
+    len: u64,
+    capacity: u64,
+    item_size: u64,
+}
+
+fn dir_len(count: usize) -> u64 {
+    (DIR_HEAD + count * ENTRY_SIZE).next_multiple_of(page_size()) as u64
+}
+
+/// Sets up a new bundle of ringbuffers.
+///
+/// # Example
+/// ```rust
+/// use shmem_ipc::sharedring::BundleBuilder;
+/// let (mut bundle, fds) = BundleBuilder::new()
+///     .ring::<u64>(100)
+///     .ring::<u8>(4096)
+///     .build()
+///     .unwrap();
+/// let requests = bundle.receiver::<u64>(0).unwrap();
+/// let replies = bundle.sender::<u8>(1).unwrap();
+///  /* ... send fds to another process, which calls Bundle::open ... */
+/// # drop((requests, replies, fds));
+/// ```
+#[derive(Clone, Debug)]
+pub struct BundleBuilder {
+    rings: Vec<Entry>,
+    name: Option<String>,
+    close_on_exec: bool,
+}
+
+impl Default for BundleBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BundleBuilder {
+    /// Starts building an empty bundle.
+    pub fn new() -> Self {
+        BundleBuilder {
+            rings: vec![],
+            name: None,
+            close_on_exec: true,
+        }
+    }
+
+    /// Adds a ringbuffer holding `capacity` items of type `T`. Ringbuffers are numbered
+    /// from zero, in the order they were added.
+    pub fn ring<T>(mut self, capacity: usize) -> Self {
+        self.rings.push(Entry {
+            offset: 0,
+            len: round_to_page_size::<T>(capacity) as u64,
+            capacity: capacity as u64,
+            item_size: std::mem::size_of::<T>() as u64,
+        });
+        self
+    }
+
+    /// Name of the memfd, as seen in `/proc/<pid>/fd`.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Whether to set close-on-exec on the file descriptors, see
+    /// `SharedRingBuilder::close_on_exec`.
+    pub fn close_on_exec(mut self, value: bool) -> Self {
+        self.close_on_exec = value;
+        self
+    }
+
+    /// Sets up the bundle, and returns it together with the file descriptors for the
+    /// other side.
+    pub fn build(&self) -> Result<(Bundle, BundleFds), Error> {
+        if self.rings.len() > MAX_RINGS {
+            Err(std::io::Error::from_raw_os_error(libc::EINVAL))?
+        }
+        let mut entries = self.rings.clone();
+        let mut total = dir_len(entries.len());
+        for e in entries.iter_mut() {
+            e.offset = total;
+            total += e.len;
+        }
+        let charge = crate::quota::Charge::new(None, total)?;
+        let name = self.name.as_deref().unwrap_or("shmem-ipc bundle");
+        let memfd = crate::mem::CreateOptions::default()
+            .close_on_exec(self.close_on_exec)
+            .create(name)?;
+        memfd
+            .as_file()
+            .set_len(total)
+            .map_err(Error::os(Op::Create, Some(name.into())))?;
+        crate::mem::verify_seal(&memfd, FileSeal::SealShrink)?;
+
+        let mut dir = Vec::with_capacity(DIR_HEAD + entries.len() * ENTRY_SIZE);
+        dir.extend_from_slice(&MAGIC.to_le_bytes());
+        dir.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+        for e in entries.iter() {
+            for x in [e.offset, e.len, e.capacity, e.item_size].iter() {
+                dir.extend_from_slice(&x.to_le_bytes());
+            }
+        }
+        memfd
+            .as_file()
+            .write_all_at(&dir, 0)
+            .map_err(Error::os(Op::Create, Some(name.into())))?;
+        for e in entries.iter() {
+            memfd
+                .as_file()
+                .write_all_at(&LAYOUT.to_le_bytes(), e.offset)
+                .map_err(Error::os(Op::Create, Some(name.into())))?;
+        }
+
+        let e = |e| Error::os(Op::Create, None)(e);
+        let empty_signal = eventfd(self.close_on_exec).map_err(e)?;
+        let full_signal = eventfd(self.close_on_exec).map_err(e)?;
+        let fds = BundleFds {
+            memfd: dup(memfd.as_file(), self.close_on_exec)?,
+            empty_signal: dup(&empty_signal, self.close_on_exec)?,
+            full_signal: dup(&full_signal, self.close_on_exec)?,
+        };
+        let bundle = Bundle {
+            taken: vec![[false; 2]; entries.len()],
+            memfd,
+            empty_signal,
+            full_signal,
+            entries,
+            _charge: Some(charge),
+        };
+        Ok((bundle, fds))
+    }
+}
+
+/// The file descriptors to transfer to the other side, which can pass them to
+/// `Bundle::open`.
+#[derive(Debug)]
+pub struct BundleFds {
+    pub memfd: File,
+    pub empty_signal: File,
+    pub full_signal: File,
+}
+
+/// A memfd holding several ringbuffers, from which the sending and receiving halves are
+/// taken one by one.
+///
+/// Either side of a bundle may take either half of a ringbuffer, so that e g requests
+/// and replies can go through the same bundle. The halves work like standalone ones and
+/// outlive the bundle, except for signaling: the eventfds are shared by all ringbuffers,
+/// so a wakeup only means that something happened on one of them. This suits an event
+/// loop that polls the eventfds and then tries all its ringbuffers; blocking on several
+/// ringbuffers of a bundle from different threads can miss wakeups.
+#[derive(Debug)]
+pub struct Bundle {
+    memfd: memfd::Memfd,
+    empty_signal: File,
+    full_signal: File,
+    entries: Vec<Entry>,
+    /// Whether the sender and receiver of each ringbuffer have been taken on this side.
+    taken: Vec<[bool; 2]>,
+    /// Quota charge, if we created the memfd.
+    _charge: Option<crate::quota::Charge>,
+}
+
+impl Bundle {
+    /// Opens a bundle set up by the other side.
+    ///
+    /// The directory is read and checked once, here; the other side changing it later has
+    /// no effect.
+    pub fn open(memfd: File, empty_signal: File, full_signal: File) -> Result<Self, Error> {
+        let memfd = crate::mem::memfd_from_file(memfd)?;
+        crate::mem::verify_seal(&memfd, FileSeal::SealShrink)?;
+        let file_len = memfd.as_file().metadata()?.len();
+        let too_small = |_| crate::ringbuf::Error::BufTooSmall;
+
+        let mut head = [0u8; DIR_HEAD];
+        memfd
+            .as_file()
+            .read_exact_at(&mut head, 0)
+            .map_err(too_small)?;
+        let word = |b: &[u8], i: usize| {
+            let mut w = [0u8; 8];
+            w.copy_from_slice(&b[i * 8..i * 8 + 8]);
+            u64::from_le_bytes(w)
+        };
+        let count = word(&head, 1);
+        if word(&head, 0) != MAGIC || count > MAX_RINGS as u64 {
+            crate::audit::report(|| crate::audit::Event::HeaderInvalid { what: "bundle" });
+            Err(crate::ringbuf::Error::BufCorrupt)?
+        }
+        let count = count as usize;
+        let mut dir = vec![0u8; count * ENTRY_SIZE];
+        memfd
+            .as_file()
+            .read_exact_at(&mut dir, DIR_HEAD as u64)
+            .map_err(too_small)?;
+
+        let ps = page_size() as u64;
+        let mut entries = Vec::with_capacity(count);
+        for b in dir.chunks_exact(ENTRY_SIZE) {
+            let e = Entry {
+                offset: word(b, 0),
+                len: word(b, 1),
+                capacity: word(b, 2),
+                item_size: word(b, 3),
+            };
+            let end = e.offset.checked_add(e.len);
+            if e.offset % ps != 0 || e.offset < dir_len(count) || end.is_none_or(|x| x > file_len) {
+                crate::audit::report(|| crate::audit::Event::HeaderInvalid { what: "bundle" });
+                Err(crate::ringbuf::Error::BufCorrupt)?
+            }
+            entries.push(e);
+        }
+        Ok(Bundle {
+            memfd,
+            empty_signal,
+            full_signal,
+            taken: vec![[false; 2]; count],
+            entries,
+            _charge: None,
+        })
+    }
+
+    /// Number of ringbuffers in the bundle.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the bundle has no ringbuffers at all.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Capacity in items of ringbuffer `index`, as declared by the creator.
+    pub fn capacity(&self, index: usize) -> Option<usize> {
+        self.entries.get(index).map(|e| e.capacity as usize)
+    }
+
+    /// The file descriptor for the shared memory area
+    pub fn memfd(&self) -> &memfd::Memfd {
+        &self.memfd
+    }
+
+    /// The eventfd written to when a receiving side should wake up
+    pub fn empty_signal(&self) -> &File {
+        &self.empty_signal
+    }
+
+    /// The eventfd written to when a sending side should wake up
+    pub fn full_signal(&self) -> &File {
+        &self.full_signal
+    }
+
+    /// Takes the sending half of ringbuffer `index`.
+    ///
+    /// Fails with `EINVAL` if there is no such ringbuffer or it holds items of a different
+    /// size, and with `EBUSY` if the half was taken before.
+    pub fn sender<T: Copy + zerocopy::AsBytes>(
+        &mut self,
+        index: usize,
+    ) -> Result<Sender<T>, Error> {
+        Sender::from_inner(self.take::<T>(index, 0)?)
+    }
+
+    /// Takes the receiving half of ringbuffer `index`, see `sender`.
+    pub fn receiver<T: Copy + zerocopy::FromBytes>(
+        &mut self,
+        index: usize,
+    ) -> Result<Receiver<T>, Error> {
+        Receiver::from_inner(self.take::<T>(index, 1)?)
+    }
+
+    fn take<T>(&mut self, index: usize, half: usize) -> Result<Inner, Error> {
+        let e = match self.entries.get(index) {
+            Some(e) if e.item_size == std::mem::size_of::<T>() as u64 => *e,
+            _ => Err(std::io::Error::from_raw_os_error(libc::EINVAL))?,
+        };
+        if self.taken[index][half] {
+            Err(std::io::Error::from_raw_os_error(libc::EBUSY))?
+        }
+        // Checked in this order so that a hostile capacity cannot overflow.
+        let item_size = std::cmp::max(e.item_size, 1);
+        if e.capacity > e.len / item_size
+            || (e.len as usize) < round_to_page_size::<T>(e.capacity as usize)
+        {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        let memfd = crate::mem::memfd_from_file(self.memfd.as_file().try_clone()?)?;
+        let mmap = memmap2::MmapOptions::new()
+            .offset(e.offset)
+            .len(e.len as usize)
+            .map_raw(memfd.as_file())
+            .map_err(|x| Error::os(Op::Map, crate::mem::memfd_name(&memfd))(x))?;
+        let inner = Inner::attached(
+            mmap,
+            memfd,
+            e.offset,
+            self.empty_signal.try_clone()?,
+            self.full_signal.try_clone()?,
+            0,
+        )?;
+        self.taken[index][half] = true;
+        Ok(inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_way() {
+        let (mut a, fds) = BundleBuilder::new()
+            .ring::<u32>(100)
+            .ring::<u8>(10)
+            .build()
+            .unwrap();
+        let mut b = Bundle::open(fds.memfd, fds.empty_signal, fds.full_signal).unwrap();
+        assert_eq!(
+            (b.len(), b.capacity(0), b.capacity(2)),
+            (2, Some(100), None)
+        );
+
+        let mut s = a.sender::<u32>(0).unwrap();
+        assert!(a.sender::<u32>(0).is_err());
+        assert!(b.receiver::<u64>(0).is_err());
+        let mut r = b.receiver::<u32>(0).unwrap();
+        let mut s2 = b.sender::<u8>(1).unwrap();
+        let mut r2 = a.receiver::<u8>(1).unwrap();
+
+        s.send_raw(|p, _| {
+            unsafe { *p = 7 };
+            1
+        })
+        .unwrap();
+        s2.send_raw(|p, _| {
+            unsafe { *p = 9 };
+            1
+        })
+        .unwrap();
+        r.receive_raw(|p, count| {
+            assert_eq!((unsafe { *p }, count), (7, 1));
+            count
+        })
+        .unwrap();
+        r2.receive_raw(|p, count| {
+            assert_eq!((unsafe { *p }, count), (9, 1));
+            count
+        })
+        .unwrap();
+        assert_eq!(s.peers(), 2);
+    }
+
+    #[test]
+    fn corrupt_directory() {
+        let (_a, fds) = BundleBuilder::new().ring::<u8>(10).build().unwrap();
+        // The entry's offset points into the directory
+        fds.memfd.write_all_at(&0u64.to_le_bytes(), 16).unwrap();
+        assert!(Bundle::open(fds.memfd, fds.empty_signal, fds.full_signal).is_err());
+    }
+}
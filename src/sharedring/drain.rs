@@ -0,0 +1,64 @@
+//! Iterating over received items.
+
+use super::Receiver;
+use crate::Error;
+
+/// Iterator over the items in a ringbuffer, see `Receiver::drain`.
+///
+/// Iteration ends when the ringbuffer is empty, or if it turns out to be corrupt; call
+/// `finish` to find out which.
+pub struct Drain<'a, T: Copy + zerocopy::FromBytes> {
+    rx: &'a mut Receiver<T>,
+    signal: bool,
+    error: Option<Error>,
+}
+
+impl<'a, T: Copy + zerocopy::FromBytes> Drain<'a, T> {
+    pub(super) fn new(rx: &'a mut Receiver<T>) -> Self {
+        Drain {
+            rx,
+            signal: false,
+            error: None,
+        }
+    }
+
+    /// Wakes up the sender if needed, and returns the error that ended iteration, if any.
+    pub fn finish(mut self) -> Result<(), Error> {
+        let error = self.error.take();
+        self.wake()?;
+        error.map_or(Ok(()), Err)
+    }
+
+    fn wake(&mut self) -> Result<(), Error> {
+        if std::mem::take(&mut self.signal) {
+            self.rx.signal_space()?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Copy + zerocopy::FromBytes> Iterator for Drain<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.error.is_some() {
+            return None;
+        }
+        let mut item = None;
+        let r = self.rx.receive_unsignaled(|p, _| {
+            item = Some(unsafe { std::ptr::read(p) });
+            1
+        });
+        match r {
+            Ok(status) => self.signal |= status.signal,
+            Err(e) => self.error = Some(e),
+        }
+        item
+    }
+}
+
+impl<T: Copy + zerocopy::FromBytes> Drop for Drain<'_, T> {
+    fn drop(&mut self) {
+        let _ = self.wake();
+    }
+}
@@ -0,0 +1,70 @@
+//! Remembering how far a consumer got, across restarts.
+
+use crate::{Error, Op};
+use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The sequence number up to which a consumer has committed its processing, kept in a
+/// small file, see `Receiver::set_journal`.
+///
+/// Use a file on disk to survive reboots, or a memfd held by a supervisor to survive
+/// crashes of the consumer only. The journal is written only by the consumer, so unlike the
+/// ringbuffer it is trusted.
+pub struct Journal {
+    mmap: memmap2::MmapRaw,
+    _file: File,
+}
+
+const JOURNAL_SIZE: u64 = std::mem::size_of::<u64>() as u64;
+
+impl Journal {
+    /// Opens the journal at `path`, creating it if it does not exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let name = path.as_ref().display().to_string();
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(Error::os(Op::Create, Some(name)))?;
+        Self::from_file(file)
+    }
+
+    /// Uses an open file (or memfd) as journal; a new journal starts out empty.
+    pub fn from_file(file: File) -> Result<Self, Error> {
+        let len = file.metadata().map_err(Error::os(Op::Map, None))?.len();
+        if len < JOURNAL_SIZE {
+            file.set_len(JOURNAL_SIZE)
+                .map_err(Error::os(Op::Create, None))?;
+        }
+        let mmap = memmap2::MmapOptions::new()
+            .len(JOURNAL_SIZE as usize)
+            .map_raw(&file)
+            .map_err(Error::os(Op::Map, None))?;
+        Ok(Journal { mmap, _file: file })
+    }
+
+    fn seq(&self) -> &AtomicU64 {
+        // Mappings are page aligned, so this is aligned too.
+        unsafe { &*(self.mmap.as_ptr() as *const AtomicU64) }
+    }
+
+    /// The sequence number up to which processing has been committed.
+    pub fn committed(&self) -> u64 {
+        self.seq().load(Ordering::Acquire)
+    }
+
+    /// Commits processing up to (but not including) `seq`. The journal never moves
+    /// backwards.
+    pub fn commit(&self, seq: u64) {
+        self.seq().fetch_max(seq, Ordering::AcqRel);
+    }
+
+    /// Writes the journal through to the file, for journals that have to survive a reboot.
+    pub fn sync(&self) -> Result<(), Error> {
+        self.mmap.flush().map_err(Error::os(Op::Map, None))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,311 @@
+//! Fair multiplexing of many receivers.
+
+use super::Receiver;
+use crate::ringbuf::Status;
+use crate::Error;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::time::Duration;
+
+/// How `Mux::recv_any` shares out reading between receivers.
+#[derive(Copy, Clone, Debug)]
+pub enum Fairness {
+    /// Every receiver with data gets one `receive_raw` call in turn.
+    RoundRobin,
+    /// Deficit round robin: on its turn, every receiver may read up to `quantum` times its
+    /// weight items, and unused allowance carries over as long as it has data.
+    DeficitWeighted {
+        /// Number of items per unit of weight and turn.
+        quantum: usize,
+    },
+}
+
+struct Entry<T> {
+    rx: Receiver<T>,
+    weight: usize,
+    deficit: usize,
+    ready: bool,
+}
+
+/// Owns many receivers and reads from them fairly, waiting for all of them with a single
+/// epoll instance.
+///
+/// Receivers only get woken up when their ringbuffer goes from empty to non-empty, so the mux
+/// keeps track of which receivers may still have data and only looks at those.
+pub struct Mux<T> {
+    entries: Vec<Option<Entry<T>>>,
+    epoll: File,
+    fairness: Fairness,
+    next: usize,
+}
+
+fn cvt(r: libc::c_int) -> Result<libc::c_int, std::io::Error> {
+    if r < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(r)
+    }
+}
+
+impl<T: Copy + zerocopy::FromBytes> Mux<T> {
+    /// Creates an empty mux.
+    pub fn new(fairness: Fairness) -> Result<Self, Error> {
+        let fd = cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        Ok(Mux {
+            entries: vec![],
+            epoll: unsafe { File::from_raw_fd(fd) },
+            fairness,
+            next: 0,
+        })
+    }
+
+    /// The epoll file descriptor, readable when any of the receivers might have data.
+    ///
+    /// Register it with your favorite event loop, and call `wait` with a zero timeout when
+    /// it becomes readable.
+    pub fn epoll_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+
+    /// Adds a receiver with the given weight, and returns its token.
+    ///
+    /// The weight only matters for `Fairness::DeficitWeighted`.
+    pub fn add(&mut self, rx: Receiver<T>, weight: usize) -> Result<usize, Error> {
+        let token = self
+            .entries
+            .iter()
+            .position(|e| e.is_none())
+            .unwrap_or(self.entries.len());
+        let mut ev = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: token as u64,
+        };
+        let fd = rx.empty_signal().as_raw_fd();
+        cvt(unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), libc::EPOLL_CTL_ADD, fd, &mut ev) })?;
+        let entry = Entry {
+            rx,
+            weight,
+            deficit: 0,
+            ready: true,
+        };
+        if token == self.entries.len() {
+            self.entries.push(Some(entry));
+        } else {
+            self.entries[token] = Some(entry);
+        }
+        Ok(token)
+    }
+
+    /// Removes a receiver and gives it back.
+    pub fn remove(&mut self, token: usize) -> Option<Receiver<T>> {
+        let e = self.entries.get_mut(token)?.take()?;
+        let fd = e.rx.empty_signal().as_raw_fd();
+        unsafe {
+            libc::epoll_ctl(
+                self.epoll.as_raw_fd(),
+                libc::EPOLL_CTL_DEL,
+                fd,
+                std::ptr::null_mut(),
+            )
+        };
+        Some(e.rx)
+    }
+
+    /// Access to one of the receivers.
+    pub fn get_mut(&mut self, token: usize) -> Option<&mut Receiver<T>> {
+        self.entries.get_mut(token)?.as_mut().map(|e| &mut e.rx)
+    }
+
+    /// Number of receivers.
+    pub fn len(&self) -> usize {
+        self.entries.iter().filter(|e| e.is_some()).count()
+    }
+
+    /// Returns true if there are no receivers.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn any_ready(&self) -> bool {
+        self.entries.iter().flatten().any(|e| e.ready)
+    }
+
+    /// Waits until one of the receivers might have data, or the timeout expires.
+    ///
+    /// Returns immediately if there are receivers that might still have data from before.
+    /// Returns false on timeout.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<bool, Error> {
+        if self.any_ready() {
+            return Ok(true);
+        }
+        // In virtual time, only look at what is ready now.
+        let ms = match timeout {
+            _ if crate::sim::is_enabled() => 0,
+            Some(t) => t.as_millis().min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; 32];
+        let n = loop {
+            let r = unsafe {
+                libc::epoll_wait(
+                    self.epoll.as_raw_fd(),
+                    events.as_mut_ptr(),
+                    events.len() as i32,
+                    ms,
+                )
+            };
+            match cvt(r) {
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                x => break x? as usize,
+            }
+        };
+        for ev in &events[..n] {
+            let token = ev.u64 as usize;
+            if let Some(Some(e)) = self.entries.get_mut(token) {
+                // Reset the eventfd; epoll told us it is readable, so this does not block.
+                let mut b = [0u8; 8];
+                e.rx.empty_signal().read_exact(&mut b)?;
+                e.ready = true;
+            }
+        }
+        if n == 0 && crate::sim::is_enabled() {
+            return crate::sim::would_block(timeout);
+        }
+        Ok(n > 0)
+    }
+
+    /// Receives data from the next receiver in turn that has data.
+    ///
+    /// The closure works like in `Receiver::receive_raw`, but also gets the token of the
+    /// receiver. Returns the token and status, or `None` if no receiver currently has data.
+    pub fn recv_any<F: FnOnce(usize, *const T, usize) -> usize>(
+        &mut self,
+        f: F,
+    ) -> Result<Option<(usize, Status)>, Error> {
+        let len = self.entries.len();
+        for i in 0..len {
+            let token = (self.next + i) % len;
+            let e = match &mut self.entries[token] {
+                Some(e) if e.ready => e,
+                _ => continue,
+            };
+            // Clear before checking, so that we don't miss a signal in between.
+            e.ready = false;
+            if e.rx.receiver_mut().read_count()? == 0 {
+                e.deficit = 0;
+                continue;
+            }
+            e.ready = true;
+            let limit = match self.fairness {
+                Fairness::RoundRobin => usize::MAX,
+                Fairness::DeficitWeighted { quantum } => {
+                    if e.deficit == 0 {
+                        e.deficit = std::cmp::max(quantum * e.weight, 1);
+                    }
+                    e.deficit
+                }
+            };
+            let mut n = 0;
+            let status = e.rx.receive_raw(|p, count| {
+                n = f(token, p, std::cmp::min(count, limit));
+                n
+            })?;
+            let done_with_turn = match self.fairness {
+                Fairness::RoundRobin => true,
+                Fairness::DeficitWeighted { .. } => {
+                    e.deficit = e.deficit.saturating_sub(n);
+                    if status.remaining == 0 {
+                        e.deficit = 0;
+                    }
+                    e.deficit == 0
+                }
+            };
+            self.next = if done_with_turn { token + 1 } else { token };
+            return Ok(Some((token, status)));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sharedring::Sender;
+
+    fn pair() -> (Sender<u8>, Receiver<u8>) {
+        let s = Sender::new(100).unwrap();
+        let r = Receiver::open(
+            100,
+            s.memfd().as_file().try_clone().unwrap(),
+            s.empty_signal().try_clone().unwrap(),
+            s.full_signal().try_clone().unwrap(),
+        )
+        .unwrap();
+        (s, r)
+    }
+
+    fn fill(s: &mut Sender<u8>, v: u8, n: usize) {
+        s.send_raw(|p, count| {
+            assert!(count >= n);
+            for i in 0..n {
+                unsafe { *p.add(i) = v };
+            }
+            n
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn deficit_weighted() {
+        let mut mux = Mux::new(Fairness::DeficitWeighted { quantum: 2 }).unwrap();
+        let (mut s1, r1) = pair();
+        let (mut s2, r2) = pair();
+        let t1 = mux.add(r1, 2).unwrap();
+        let t2 = mux.add(r2, 1).unwrap();
+        assert!(mux.recv_any(|_, _, _| 0).unwrap().is_none());
+        assert!(!mux.wait(Some(Duration::from_millis(10))).unwrap());
+        fill(&mut s1, 1, 20);
+        fill(&mut s2, 2, 20);
+        assert!(mux.wait(None).unwrap());
+        let mut got = vec![];
+        for _ in 0..6 {
+            // Read one item at a time, to see the interleaving
+            mux.recv_any(|t, p, _| {
+                got.push((t, unsafe { *p }));
+                1
+            })
+            .unwrap()
+            .unwrap();
+        }
+        let (a, b) = ((t1, 1), (t2, 2));
+        assert_eq!(got, vec![a, a, a, a, b, b]);
+    }
+
+    #[test]
+    fn round_robin_skips_empty() {
+        let mut mux = Mux::new(Fairness::RoundRobin).unwrap();
+        let mut senders = vec![];
+        for _ in 0..3 {
+            let (s, r) = pair();
+            mux.add(r, 1).unwrap();
+            senders.push(s);
+        }
+        fill(&mut senders[0], 0, 3);
+        fill(&mut senders[2], 2, 3);
+        let mut order = vec![];
+        while let Some((t, _)) = mux.recv_any(|_, _, _| 1).unwrap() {
+            order.push(t);
+        }
+        assert_eq!(order, vec![0, 2, 0, 2, 0, 2]);
+        // Consume the wakeups from the first round
+        mux.wait(Some(Duration::from_millis(0))).unwrap();
+        assert!(mux.recv_any(|_, _, _| 1).unwrap().is_none());
+        assert!(!mux.wait(Some(Duration::from_millis(0))).unwrap());
+        fill(&mut senders[1], 1, 1);
+        assert!(mux.wait(Some(Duration::from_millis(0))).unwrap());
+        assert_eq!(mux.recv_any(|_, _, _| 1).unwrap().unwrap().0, 1);
+        assert_eq!(mux.remove(1).map(|_| ()), Some(()));
+        assert_eq!(mux.len(), 2);
+    }
+}
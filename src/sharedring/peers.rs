@@ -0,0 +1,111 @@
+//! Keeping track of the processes attached to a ringbuffer.
+
+use crate::wire::AtomicLe32;
+use std::sync::atomic::Ordering;
+
+/// Number of processes that can be tracked; more can attach, but are not counted.
+pub(super) const PEER_SLOTS: usize = 8;
+
+/// Pids of the attached processes, zero for free slots. Written by untrusted peers, so
+/// this is a hint only.
+#[repr(C)]
+pub(super) struct PeerTable {
+    pids: [AtomicLe32; PEER_SLOTS],
+    /// Bumped on every attach, for waiting on with a futex.
+    attached: AtomicLe32,
+}
+
+impl PeerTable {
+    /// Registers this process, and returns the slot taken, if any was free.
+    pub(super) fn attach(&self) -> Option<usize> {
+        let pid = std::process::id();
+        let slot = (0..PEER_SLOTS).find(|&i| {
+            self.pids[i]
+                .compare_exchange(0, pid, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        });
+        self.attached.fetch_add(1, Ordering::Release);
+        let _ = crate::sync::futex::wake_all(self.attached.raw());
+        slot
+    }
+
+    pub(super) fn detach(&self, slot: usize) {
+        let _ = self.pids[slot].compare_exchange(
+            std::process::id(),
+            0,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// The pids in all slots, zero for free ones.
+    pub(super) fn pids(&self) -> [u32; PEER_SLOTS] {
+        let mut pids = [0; PEER_SLOTS];
+        for (pid, slot) in pids.iter_mut().zip(&self.pids) {
+            *pid = slot.load(Ordering::Acquire);
+        }
+        pids
+    }
+
+    /// Waits until at least `n` processes are attached. Returns false on timeout.
+    pub(super) fn wait_for(
+        &self,
+        n: usize,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<bool, crate::Error> {
+        let deadline = timeout.map(|t| crate::sim::instant() + t);
+        loop {
+            // Read the counter first, so that we don't miss an attach in between.
+            let attached = self.attached.load(Ordering::Acquire);
+            if self.count() >= n {
+                return Ok(true);
+            }
+            let left = crate::sync::futex::remaining(deadline);
+            if left == Some(std::time::Duration::from_secs(0))
+                || !crate::sync::futex::wait(self.attached.raw(), attached.to_le(), left)?
+            {
+                return Ok(self.count() >= n);
+            }
+        }
+    }
+
+    /// Number of attached processes that are still alive. Slots of processes that died
+    /// without detaching are freed.
+    pub(super) fn count(&self) -> usize {
+        let me = std::process::id();
+        self.pids
+            .iter()
+            .filter(|p| {
+                let pid = p.load(Ordering::Acquire);
+                if pid == 0 {
+                    return false;
+                }
+                if pid == me || crate::unix::process_alive(pid) {
+                    return true;
+                }
+                let _ = p.compare_exchange(pid, 0, Ordering::AcqRel, Ordering::Relaxed);
+                false
+            })
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dead_peer() {
+        let table: PeerTable = unsafe { std::mem::zeroed() };
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        // Died without detaching
+        table.pids[3].store(pid, Ordering::Relaxed);
+        let slot = table.attach().unwrap();
+        assert_eq!(table.count(), 1);
+        assert_eq!(table.pids[3].load(Ordering::Relaxed), 0);
+        table.detach(slot);
+        assert_eq!(table.count(), 0);
+    }
+}
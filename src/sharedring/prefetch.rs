@@ -0,0 +1,42 @@
+//! Software prefetching of ringbuffer slots, see `SharedRingBuilder::prefetch`.
+//!
+//! Only does something on x86_64 and aarch64; hints are never needed for correctness.
+
+const CACHE_LINE: usize = 64;
+
+#[inline(always)]
+fn line(p: *const u8, write: bool) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_ET0, _MM_HINT_T0};
+        if write {
+            _mm_prefetch::<_MM_HINT_ET0>(p as *const i8)
+        } else {
+            _mm_prefetch::<_MM_HINT_T0>(p as *const i8)
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        if write {
+            std::arch::asm!("prfm pstl1keep, [{}]", in(reg) p, options(nostack, readonly));
+        } else {
+            std::arch::asm!("prfm pldl1keep, [{}]", in(reg) p, options(nostack, readonly));
+        }
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    let _ = (p, write);
+}
+
+/// Hints that `count` items from `p` on are about to be read (or written), up to `lines`
+/// cache lines ahead.
+///
+/// Prefetches do not fault, so this is fine for any address; the range is inside the
+/// ringbuffer anyway.
+#[inline]
+pub(super) fn range<T>(p: *const T, count: usize, lines: usize, write: bool) {
+    let bytes = count.saturating_mul(std::mem::size_of::<T>());
+    let n = std::cmp::min(bytes.div_ceil(CACHE_LINE), lines);
+    for i in 0..n {
+        line((p as *const u8).wrapping_add(i * CACHE_LINE), write);
+    }
+}
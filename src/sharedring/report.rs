@@ -0,0 +1,123 @@
+//! State of one side of a ringbuffer, for bug reports.
+
+use super::Inner;
+use crate::mem::mfd::FileSeal;
+use std::fmt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::Ordering;
+
+/// Everything known about one side of a ringbuffer except the data in it, see
+/// `Sender::dump`.
+///
+/// The `Display` output is meant for pasting into bug reports. Values read from the shared
+/// header were written by the untrusted peer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    /// "sender" or "receiver".
+    pub side: &'static str,
+    /// This process.
+    pub pid: u32,
+    /// Number of items the ringbuffer can hold.
+    pub capacity: usize,
+    /// Size of an item in bytes.
+    pub item_size: usize,
+    /// Where in the ringbuffer this side reads or writes next, in items.
+    pub index: usize,
+    /// The item count word of the ringbuffer, unchecked.
+    pub count: u64,
+    /// Items sent or received by this side, i e the next sequence number.
+    pub seq: u64,
+    /// Sequence number up to which the receiver has acknowledged items.
+    pub acked: u64,
+    /// Items the sender dropped because the ringbuffer was full.
+    pub dropped: u64,
+    /// Number of times the sender started dropping items.
+    pub overflows: u64,
+    /// Number of times this side has woken up the other side.
+    pub wakeups: u64,
+    /// Features in effect, see `Sender::features`.
+    pub features: u64,
+    /// Seals on the memfd, or `None` if they could not be read.
+    pub seals: Option<Vec<FileSeal>>,
+    /// File descriptor of the memfd, in this process.
+    pub memfd_fd: RawFd,
+    /// File descriptor of the empty signal.
+    pub empty_signal_fd: RawFd,
+    /// File descriptor of the full signal.
+    pub full_signal_fd: RawFd,
+    /// Pids of the other processes in the peer table.
+    pub peers: Vec<u32>,
+    /// Lengths in bytes of the last few messages, oldest first. Only the `framed` module
+    /// keeps track of these.
+    pub recent_lengths: Vec<usize>,
+}
+
+impl Report {
+    pub(super) fn new<T>(inner: &Inner, side: &'static str, capacity: usize, index: usize) -> Self {
+        let h = inner.header();
+        let count = unsafe { &*(inner.ring_ptr() as *const crate::wire::AtomicLe64) };
+        let me = std::process::id();
+        let seals = inner.memfd.seals().ok().map(|s| {
+            let all = [
+                FileSeal::SealShrink,
+                FileSeal::SealGrow,
+                FileSeal::SealWrite,
+                FileSeal::SealSeal,
+            ];
+            all.iter().copied().filter(|x| s.contains(x)).collect()
+        });
+        Report {
+            side,
+            pid: me,
+            capacity,
+            item_size: std::mem::size_of::<T>(),
+            index,
+            count: count.load(Ordering::Acquire),
+            seq: inner.seq,
+            acked: h.acked.load(Ordering::Acquire),
+            dropped: h.dropped.load(Ordering::Relaxed),
+            overflows: h.overflows.load(Ordering::Relaxed),
+            wakeups: inner.wakeups,
+            features: inner.features(),
+            seals,
+            memfd_fd: inner.memfd.as_raw_fd(),
+            empty_signal_fd: inner.empty_signal.as_raw_fd(),
+            full_signal_fd: inner.full_signal.as_raw_fd(),
+            peers: h
+                .peers
+                .pids()
+                .iter()
+                .copied()
+                .filter(|p| *p != 0 && *p != me)
+                .collect(),
+            recent_lengths: vec![],
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "ringbuffer {}, pid {}", self.side, self.pid)?;
+        writeln!(
+            f,
+            "  capacity {} x {} bytes, index {}, count {}",
+            self.capacity, self.item_size, self.index, self.count
+        )?;
+        writeln!(
+            f,
+            "  seq {}, acked {}, dropped {} in {} overflows, wakeups {}",
+            self.seq, self.acked, self.dropped, self.overflows, self.wakeups
+        )?;
+        writeln!(f, "  features {:#x}, seals {:?}", self.features, self.seals)?;
+        writeln!(
+            f,
+            "  fds: memfd {}, empty signal {}, full signal {}",
+            self.memfd_fd, self.empty_signal_fd, self.full_signal_fd
+        )?;
+        write!(f, "  peers {:?}", self.peers)?;
+        if !self.recent_lengths.is_empty() {
+            write!(f, "\n  recent message lengths {:?}", self.recent_lengths)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+//! Private copies of a live ringbuffer, for looking at a wedged channel offline.
+
+use super::{page_size, Header, Inner, HEADER_SIZE, LAYOUT};
+use crate::{Error, Op};
+use std::sync::atomic::Ordering;
+
+/// The shared header of a ringbuffer, decoded, see `Snapshot::header`.
+///
+/// All of this was written by the untrusted peer, so take it with a grain of salt.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeaderReport {
+    /// Whether the header starts with the layout version this crate writes.
+    pub layout_ok: bool,
+    /// Sequence number up to which the receiver has acknowledged items.
+    pub acked: u64,
+    /// Sequence number the sender waits to be acknowledged, or zero.
+    pub ack_wanted: u64,
+    /// Maximum message size for the `framed` module, or zero.
+    pub max_message_size: u64,
+    /// Header flags set by the creator.
+    pub flags: u64,
+    /// Items the sender dropped because the ringbuffer was full.
+    pub dropped: u64,
+    /// Times the sender started dropping items.
+    pub overflows: u64,
+    /// Feature bits the creator requires.
+    pub features_required: u64,
+    /// Feature bits the creator offers.
+    pub features_optional: u64,
+    /// The offered feature bits the attaching side understood.
+    pub features_accepted: u64,
+    /// Pids in the peer table.
+    pub peers: Vec<u32>,
+    /// Number of items in the ringbuffer, as per its count word.
+    pub count: u64,
+}
+
+impl HeaderReport {
+    fn decode(data: &[u8]) -> Self {
+        // The copy is page aligned, private and never written to again.
+        let h = unsafe { &*(data.as_ptr() as *const Header) };
+        let mut count = [0; 8];
+        count.copy_from_slice(&data[HEADER_SIZE..HEADER_SIZE + 8]);
+        HeaderReport {
+            layout_ok: h.layout.load(Ordering::Relaxed) == LAYOUT,
+            acked: h.acked.load(Ordering::Relaxed),
+            ack_wanted: h.ack_wanted.load(Ordering::Relaxed),
+            max_message_size: h.max_message_size.load(Ordering::Relaxed),
+            flags: h.flags.load(Ordering::Relaxed),
+            dropped: h.dropped.load(Ordering::Relaxed),
+            overflows: h.overflows.load(Ordering::Relaxed),
+            features_required: h.features_required.load(Ordering::Relaxed),
+            features_optional: h.features_optional.load(Ordering::Relaxed),
+            features_accepted: h.features_accepted.load(Ordering::Relaxed),
+            peers: h.peers.pids().iter().copied().filter(|p| *p != 0).collect(),
+            count: u64::from_le_bytes(count),
+        }
+    }
+}
+
+/// A private copy of a ringbuffer's memory area, see `Receiver::debug_snapshot`.
+pub struct Snapshot {
+    map: memmap2::Mmap,
+    header: HeaderReport,
+}
+
+impl Snapshot {
+    pub(super) fn take(inner: &Inner) -> Result<Self, Error> {
+        let len = inner.mmap.len();
+        let mut map = unsafe {
+            memmap2::MmapOptions::new()
+                .offset(inner.offset)
+                .len(len)
+                .map_copy(inner.memfd.as_file())
+        }
+        .map_err(Error::os(Op::Map, None))?;
+        // Until written to, the pages of a private mapping are those of the memfd, and
+        // would keep changing with the live ringbuffer. Writing every page gives us a
+        // copy of it as it is now.
+        let p = map.as_mut_ptr();
+        for offset in (0..len).step_by(page_size()) {
+            unsafe {
+                let p = p.add(offset);
+                std::ptr::write_volatile(p, std::ptr::read_volatile(p));
+            }
+        }
+        let map = map.make_read_only().map_err(Error::os(Op::Map, None))?;
+        let header = HeaderReport::decode(&map);
+        Ok(Snapshot { map, header })
+    }
+
+    /// The decoded header, as it was when the snapshot was taken.
+    pub fn header(&self) -> &HeaderReport {
+        &self.header
+    }
+
+    /// The whole memory area: header, then the ringbuffer from byte 256 on, see the
+    /// module documentation for the layout.
+    pub fn bytes(&self) -> &[u8] {
+        &self.map
+    }
+
+    /// Just the ringbuffer, starting with its count word.
+    pub fn ring(&self) -> &[u8] {
+        &self.map[HEADER_SIZE..]
+    }
+}
@@ -0,0 +1,245 @@
+//! Items with begin and end stamps, so that a reader can tell if the writer changed an
+//! item while it was being copied out.
+
+use super::{Receiver, Sender};
+use crate::ringbuf::Status;
+use crate::wire::AtomicLe64;
+use crate::Error;
+use std::fs::File;
+use std::sync::atomic::{fence, Ordering};
+
+/// A slot of a stamped ringbuffer: the begin stamp, the value and the end stamp.
+///
+/// This is only ever used through `StampedSender` and `StampedReceiver`, which check
+/// `NO_PADDING` when they are set up; that is what makes the impls below sound.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct Stamped<T> {
+    begin: u64,
+    value: T,
+    end: u64,
+}
+
+// Two u64 and a T without padding, see NO_PADDING.
+unsafe impl<T: zerocopy::AsBytes> zerocopy::AsBytes for Stamped<T> {
+    fn only_derive_is_allowed_to_implement_this_trait() {}
+}
+
+unsafe impl<T: zerocopy::FromBytes> zerocopy::FromBytes for Stamped<T> {
+    fn only_derive_is_allowed_to_implement_this_trait() {}
+}
+
+impl<T> Stamped<T> {
+    const NO_PADDING: () = assert!(
+        std::mem::size_of::<T>() % 8 == 0 && std::mem::align_of::<T>() <= 8,
+        "Stamped<T> needs T to be a multiple of 8 bytes, aligned to at most 8"
+    );
+
+    unsafe fn stamps<'a>(p: *mut Self) -> (&'a AtomicLe64, &'a AtomicLe64) {
+        let begin = &*(std::ptr::addr_of_mut!((*p).begin) as *const AtomicLe64);
+        let end = &*(std::ptr::addr_of_mut!((*p).end) as *const AtomicLe64);
+        (begin, end)
+    }
+}
+
+/// The sending half of a ringbuffer of stamped items.
+///
+/// The sender writes the begin stamp, then the value, then the end stamp; the receiver
+/// reads them in the opposite order. A writer that goes back and changes a slot the
+/// receiver is reading, whether by mistake or on purpose, has to change the begin stamp
+/// first, so the copy is detected as torn instead of being handed on half old and half
+/// new. A writer can of course still send consistent garbage.
+///
+/// `T` must have a size that is a multiple of 8 bytes and an alignment of at most 8, so
+/// that there is no padding around it; this is checked at compile time when the sender is
+/// set up.
+pub struct StampedSender<T>(Sender<Stamped<T>>);
+
+impl<T: Copy + zerocopy::AsBytes> StampedSender<T> {
+    fn from_sender(sender: Sender<Stamped<T>>) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Stamped::<T>::NO_PADDING;
+        StampedSender(sender)
+    }
+
+    /// Sets up a new ringbuffer of `capacity` items and returns the sender half.
+    pub fn new(capacity: usize) -> Result<Self, Error> {
+        Ok(Self::from_sender(Sender::new(capacity)?))
+    }
+
+    /// Attaches to a stamped ringbuffer set up by the receiving side.
+    pub fn open(
+        capacity: usize,
+        memfd: File,
+        empty_signal: File,
+        full_signal: File,
+    ) -> Result<Self, Error> {
+        Ok(Self::from_sender(Sender::open(
+            capacity,
+            memfd,
+            empty_signal,
+            full_signal,
+        )?))
+    }
+
+    /// The file descriptor for the shared memory area
+    pub fn memfd(&self) -> &memfd::Memfd {
+        self.0.memfd()
+    }
+
+    /// The file descriptor written to when the receiving side should wake up
+    pub fn empty_signal(&self) -> &File {
+        self.0.empty_signal()
+    }
+
+    /// The file descriptor written to by the receiving side when the buffer is no longer
+    /// full.
+    pub fn full_signal(&self) -> &File {
+        self.0.full_signal()
+    }
+
+    /// Number of items sent so far.
+    pub fn sent(&self) -> u64 {
+        self.0.sent()
+    }
+
+    /// Waits until there is room for at least one item, see `Sender::block_until_writable`.
+    pub fn block_until_writable(&mut self) -> Result<Status, Error> {
+        self.0.block_until_writable()
+    }
+
+    /// Sends one value in a stamped slot. Returns false if the ringbuffer is full.
+    pub fn send(&mut self, value: &T) -> Result<bool, Error> {
+        // Starts at one, so that a zeroed slot never looks written.
+        let stamp = self.0.sent() + 1;
+        self.0.send_raw(|p, _| {
+            unsafe {
+                let (begin, end) = Stamped::stamps(p);
+                begin.store(stamp, Ordering::Relaxed);
+                fence(Ordering::Release);
+                std::ptr::write_volatile(std::ptr::addr_of_mut!((*p).value), *value);
+                end.store(stamp, Ordering::Release);
+            }
+            1
+        })?;
+        Ok(self.0.sent() == stamp)
+    }
+}
+
+/// The receiving half of a ringbuffer of stamped items, see `StampedSender`.
+pub struct StampedReceiver<T>(Receiver<Stamped<T>>);
+
+impl<T: Copy + zerocopy::FromBytes> StampedReceiver<T> {
+    fn from_receiver(receiver: Receiver<Stamped<T>>) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Stamped::<T>::NO_PADDING;
+        StampedReceiver(receiver)
+    }
+
+    /// Sets up a new ringbuffer of `capacity` items and returns the receiver half.
+    pub fn new(capacity: usize) -> Result<Self, Error> {
+        Ok(Self::from_receiver(Receiver::new(capacity)?))
+    }
+
+    /// Attaches to a stamped ringbuffer set up by the sending side.
+    pub fn open(
+        capacity: usize,
+        memfd: File,
+        empty_signal: File,
+        full_signal: File,
+    ) -> Result<Self, Error> {
+        Ok(Self::from_receiver(Receiver::open(
+            capacity,
+            memfd,
+            empty_signal,
+            full_signal,
+        )?))
+    }
+
+    /// The file descriptor for the shared memory area
+    pub fn memfd(&self) -> &memfd::Memfd {
+        self.0.memfd()
+    }
+
+    /// The file descriptor written to by the sending side when the buffer is no longer
+    /// empty.
+    pub fn empty_signal(&self) -> &File {
+        self.0.empty_signal()
+    }
+
+    /// The file descriptor written to when the sending side should wake up
+    pub fn full_signal(&self) -> &File {
+        self.0.full_signal()
+    }
+
+    /// Waits until there is at least one item, see `Receiver::block_until_readable`.
+    pub fn block_until_readable(&mut self) -> Result<Status, Error> {
+        self.0.block_until_readable()
+    }
+
+    /// Receives one value from a stamped slot, or `None` if the ringbuffer is empty.
+    ///
+    /// A torn copy is retried up to `retries` times. If it is still torn after that, the
+    /// slot is dropped and this fails with `Error::TornRead`; the channel can go on with
+    /// the next slot. For a writer that is merely slow to finish, rather than malicious,
+    /// a few retries are usually enough.
+    pub fn recv(&mut self, retries: usize) -> Result<Option<T>, Error> {
+        let mut got = None;
+        let mut torn = false;
+        self.0.receive_raw(|p, _| {
+            let p = p as *mut Stamped<T>;
+            for _ in 0..=retries {
+                let v = unsafe {
+                    let (begin, end) = Stamped::stamps(p);
+                    let e = end.load(Ordering::Acquire);
+                    let v = std::ptr::read_volatile(std::ptr::addr_of!((*p).value));
+                    fence(Ordering::Acquire);
+                    Some(v).filter(|_| begin.load(Ordering::Relaxed) == e)
+                };
+                if v.is_some() {
+                    got = v;
+                    return 1;
+                }
+                std::hint::spin_loop();
+            }
+            torn = true;
+            1
+        })?;
+        if torn {
+            Err(Error::TornRead)?
+        }
+        Ok(got)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn torn() {
+        let mut s: StampedSender<u64> = StampedSender::new(16).unwrap();
+        let memfd = s.memfd().as_file().try_clone().unwrap();
+        let e = s.empty_signal().try_clone().unwrap();
+        let f = s.full_signal().try_clone().unwrap();
+        let mut r: StampedReceiver<u64> = StampedReceiver::open(16, memfd, e, f).unwrap();
+        assert!(s.send(&5).unwrap());
+        // As left behind by a writer that was interrupted rewriting the slot
+        s.0.send_raw(|p, _| {
+            unsafe {
+                p.write(Stamped {
+                    begin: 3,
+                    value: 6,
+                    end: 2,
+                })
+            };
+            1
+        })
+        .unwrap();
+        assert!(s.send(&7).unwrap());
+        assert_eq!(r.recv(3).unwrap(), Some(5));
+        assert!(matches!(r.recv(3), Err(Error::TornRead)));
+        assert_eq!(r.recv(3).unwrap(), Some(7));
+        assert_eq!(r.recv(3).unwrap(), None);
+    }
+}
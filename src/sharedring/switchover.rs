@@ -0,0 +1,123 @@
+//! Replacing a ringbuffer with a new one, e g of another capacity, without the receiver
+//! losing items or getting them out of order.
+
+use super::{Receiver, Sender};
+use crate::ringbuf::Status;
+use crate::Error;
+
+/// The sending side of a channel that can move to a new ringbuffer, see `switch_to`.
+pub struct BlueGreenSender<T> {
+    active: Sender<T>,
+    /// Items sent through the ringbuffers before the active one.
+    base: u64,
+    generation: u64,
+}
+
+impl<T: Copy + zerocopy::AsBytes> BlueGreenSender<T> {
+    /// Starts out with `sender` as the first generation.
+    pub fn new(sender: Sender<T>) -> Self {
+        BlueGreenSender {
+            active: sender,
+            base: 0,
+            generation: 0,
+        }
+    }
+
+    /// The ringbuffer to send to.
+    pub fn active(&mut self) -> &mut Sender<T> {
+        &mut self.active
+    }
+
+    /// Sends to `next` from now on. The items sent so far stay in the old ringbuffer, which
+    /// is paused, see `Sender::pause`, and returned, so that the caller can wait for it to
+    /// drain before dropping it; the receiver moves over once it has received them all.
+    ///
+    /// Hand the file descriptors of `next` to the receiver, for
+    /// `BlueGreenReceiver::prepare`.
+    pub fn switch_to(&mut self, next: Sender<T>) -> Result<Sender<T>, Error> {
+        self.active.pause()?;
+        self.base += self.active.sent();
+        self.generation += 1;
+        Ok(std::mem::replace(&mut self.active, next))
+    }
+
+    /// Number of switches so far.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Number of items sent, over all generations.
+    pub fn sent(&self) -> u64 {
+        self.base + self.active.sent()
+    }
+}
+
+/// The receiving side of a `BlueGreenSender`.
+///
+/// Receives from the old ringbuffer until it has been drained, and only then from the new
+/// one, so all items of one generation come before those of the next.
+pub struct BlueGreenReceiver<T> {
+    active: Receiver<T>,
+    next: Option<Receiver<T>>,
+    base: u64,
+    generation: u64,
+}
+
+impl<T: Copy + zerocopy::FromBytes> BlueGreenReceiver<T> {
+    /// Starts out with `receiver` as the first generation.
+    pub fn new(receiver: Receiver<T>) -> Self {
+        BlueGreenReceiver {
+            active: receiver,
+            next: None,
+            base: 0,
+            generation: 0,
+        }
+    }
+
+    /// Sets the ringbuffer to move to once the active one is drained, as opened from the
+    /// file descriptors that the sender passed on after `BlueGreenSender::switch_to`.
+    pub fn prepare(&mut self, next: Receiver<T>) {
+        self.next = Some(next);
+    }
+
+    /// Moves to the prepared ringbuffer if the active one is drained. Returns true if it
+    /// did.
+    fn switch_if_drained(&mut self) -> Result<bool, Error> {
+        if self.next.is_none() || !self.active.drained()? {
+            return Ok(false);
+        }
+        self.base += self.active.received();
+        self.generation += 1;
+        self.active = self.next.take().unwrap();
+        Ok(true)
+    }
+
+    /// The ringbuffer to receive from, after moving over if it is time to.
+    pub fn active(&mut self) -> Result<&mut Receiver<T>, Error> {
+        self.switch_if_drained()?;
+        Ok(&mut self.active)
+    }
+
+    /// For blocking scenarios, blocks until there is something to receive from `active`.
+    ///
+    /// Fails with `Error::Paused` if the active ringbuffer is drained and there is no new
+    /// one prepared yet.
+    pub fn block_until_readable(&mut self) -> Result<Status, Error> {
+        loop {
+            match self.active.block_until_readable() {
+                Err(Error::Paused) if self.switch_if_drained()? => continue,
+                r => return r,
+            }
+        }
+    }
+
+    /// Number of switches so far.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Number of items received, over all generations.
+    pub fn received(&self) -> u64 {
+        self.base + self.active.received()
+    }
+}
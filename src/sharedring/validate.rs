@@ -0,0 +1,87 @@
+//! Receiving types that not every bit pattern is valid for, e g with enums, bools or
+//! `NonZero` integers.
+
+use super::{Receiver, Sender};
+use crate::ringbuf::Status;
+use crate::Error;
+
+/// A type that travels through the ringbuffer as a plain `Raw` representation, and is
+/// validated field by field when received from the untrusted peer.
+///
+/// # Example
+/// ```rust
+/// use shmem_ipc::sharedring::Validate;
+/// #[derive(Copy, Clone, Debug, PartialEq)]
+/// struct Level(bool, std::num::NonZeroU32);
+/// impl Validate for Level {
+///     type Raw = [u32; 2];
+///     fn to_raw(&self) -> [u32; 2] { [self.0 as u32, self.1.get()] }
+///     fn validate(raw: [u32; 2]) -> Option<Self> {
+///         let b = match raw[0] { 0 => false, 1 => true, _ => return None };
+///         Some(Level(b, std::num::NonZeroU32::new(raw[1])?))
+///     }
+/// }
+/// ```
+pub trait Validate: Sized {
+    /// The representation in the ringbuffer.
+    type Raw: Copy + zerocopy::AsBytes + zerocopy::FromBytes;
+    /// Converts to the representation in the ringbuffer.
+    fn to_raw(&self) -> Self::Raw;
+    /// Converts from the representation in the ringbuffer, or returns `None` if it is not a
+    /// valid value.
+    fn validate(raw: Self::Raw) -> Option<Self>;
+}
+
+impl<R: Copy + zerocopy::AsBytes> Sender<R> {
+    /// Sends as many values as there is room for, and returns how many were sent.
+    pub fn send_values<V: Validate<Raw = R>>(&mut self, values: &[V]) -> Result<usize, Error> {
+        let mut sent = 0;
+        while sent < values.len() {
+            let mut n = 0;
+            self.send_raw(|p, count| {
+                n = std::cmp::min(count, values.len() - sent);
+                for (i, v) in values[sent..sent + n].iter().enumerate() {
+                    unsafe { std::ptr::write(p.add(i), v.to_raw()) };
+                }
+                n
+            })?;
+            if n == 0 {
+                break;
+            }
+            sent += n;
+        }
+        Ok(sent)
+    }
+}
+
+impl<R: Copy + zerocopy::FromBytes> Receiver<R> {
+    /// Receives and validates up to `max` values, calling `f` for each of them.
+    ///
+    /// Fails with `BufCorrupt` on the first invalid value, which is left in the ringbuffer;
+    /// the values before it have been received.
+    pub fn receive_values<V: Validate<Raw = R>, F: FnMut(V)>(
+        &mut self,
+        max: usize,
+        mut f: F,
+    ) -> Result<Status, Error> {
+        let mut invalid = false;
+        self.recv_budgeted(max, usize::MAX, |p, count| {
+            for i in 0..count {
+                match V::validate(unsafe { std::ptr::read(p.add(i)) }) {
+                    Some(v) => f(v),
+                    None => {
+                        invalid = true;
+                        return i;
+                    }
+                }
+            }
+            count
+        })
+        .and_then(|status| {
+            if invalid {
+                Err(crate::ringbuf::Error::BufCorrupt)?
+            }
+            Ok(status)
+        })
+    }
+}
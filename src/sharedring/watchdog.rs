@@ -0,0 +1,82 @@
+//! Telling when the receiver has stopped making progress, see `Sender::set_watchdog`.
+
+use super::{Header, Inner, HEADER_SIZE};
+use crate::mem::mmap;
+use crate::timer::TimerWheel;
+use crate::{Error, Op};
+use std::fs::File;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+struct State {
+    /// The header mapped again, as the checks run on the reactor thread and may outlive
+    /// the sender's mapping.
+    header: mmap::MmapRaw,
+    wakeup: File,
+    interval: Duration,
+    stuck: AtomicBool,
+}
+
+/// The sender's side of the watchdog; dropping it stops the checks.
+pub(super) struct Watchdog {
+    state: Arc<State>,
+}
+
+impl State {
+    fn header(&self) -> &Header {
+        unsafe { &*(self.header.as_ptr() as *const Header) }
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        let fed = self.header().watchdog_fed.load(Ordering::Acquire);
+        let since = crate::sim::monotonic_ns().saturating_sub(fed);
+        let stuck = since > self.interval.as_nanos() as u64;
+        if stuck && !self.stuck.swap(true, Ordering::AcqRel) {
+            // So that a sender waiting for room notices.
+            Inner::signal(&self.wakeup)?;
+        } else if !stuck {
+            self.stuck.store(false, Ordering::Release);
+        }
+        Ok(())
+    }
+}
+
+/// Checks every `interval`, for as long as the sender keeps the watchdog.
+fn schedule(wheel: &'static TimerWheel, state: Weak<State>, interval: Duration) {
+    wheel.schedule(interval, move || {
+        if let Some(s) = state.upgrade() {
+            let _ = s.check();
+            schedule(wheel, state, interval);
+        }
+    });
+}
+
+impl Watchdog {
+    pub(super) fn new(inner: &Inner, interval: Duration) -> Result<Self, Error> {
+        if interval == Duration::from_secs(0) {
+            let e = std::io::Error::from_raw_os_error(libc::EINVAL);
+            Err(Error::os(Op::Signal, None)(e))?
+        }
+        let wheel = TimerWheel::global()?;
+        let header = mmap::MmapOptions::new()
+            .offset(inner.offset)
+            .len(HEADER_SIZE)
+            .map_raw(inner.memfd.as_file())?;
+        let state = Arc::new(State {
+            header,
+            wakeup: inner.full_signal.try_clone()?,
+            interval,
+            stuck: AtomicBool::new(false),
+        });
+        // A grace period of one interval to begin with.
+        let now = crate::sim::monotonic_ns();
+        state.header().watchdog_fed.store(now, Ordering::Release);
+        schedule(wheel, Arc::downgrade(&state), interval);
+        Ok(Watchdog { state })
+    }
+
+    pub(super) fn stuck(&self) -> bool {
+        self.state.stuck.load(Ordering::Acquire)
+    }
+}
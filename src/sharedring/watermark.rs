@@ -0,0 +1,43 @@
+//! Callbacks when ringbuffer occupancy crosses thresholds.
+
+/// Which threshold was crossed, see `Sender::set_watermarks`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Watermark {
+    /// Occupancy rose to the high watermark or above.
+    High,
+    /// Occupancy fell to the low watermark or below, after having been high.
+    Low,
+}
+
+pub(super) struct Watermarks {
+    high: usize,
+    low: usize,
+    above: bool,
+    callback: Box<dyn FnMut(Watermark, usize) + Send>,
+}
+
+impl Watermarks {
+    pub(super) fn new<F: FnMut(Watermark, usize) + Send + 'static>(
+        high: usize,
+        low: usize,
+        callback: F,
+    ) -> Self {
+        Watermarks {
+            high,
+            low: std::cmp::min(low, high),
+            above: false,
+            callback: Box::new(callback),
+        }
+    }
+
+    /// Calls the callback if `occupancy` (in items) crossed a threshold since last time.
+    pub(super) fn update(&mut self, occupancy: usize) {
+        if !self.above && occupancy >= self.high {
+            self.above = true;
+            (self.callback)(Watermark::High, occupancy);
+        } else if self.above && occupancy <= self.low {
+            self.above = false;
+            (self.callback)(Watermark::Low, occupancy);
+        }
+    }
+}
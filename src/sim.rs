@@ -0,0 +1,148 @@
+//! Deterministic virtual time, for testing protocols built on this crate without sleeping.
+//!
+//! After `enable`, the clock behind the crate's timeouts, message deadlines
+//! (`framed::Sender::set_ttl`) and rate limits is a virtual one, which only moves when the
+//! test calls `advance`, or when a wait would block. Instead of going to sleep, a wait with
+//! a timeout moves the clock to its deadline and times out, and a wait without a timeout
+//! fails with `EDEADLK`, as nothing could ever wake it up.
+//!
+//! Simulation is per thread: have the test drive both sides of the channel from the thread
+//! that enabled it. Other threads keep using real time.
+//!
+//! # Example
+//! ```rust
+//! use shmem_ipc::{framed, sim};
+//! use std::time::Duration;
+//! sim::enable();
+//! let mut s = framed::Sender::new(4096).unwrap();
+//! let ring = s.ring();
+//! let mut r = framed::Receiver::open(4096, ring.memfd().as_file().try_clone().unwrap(),
+//!     ring.empty_signal().try_clone().unwrap(), ring.full_signal().try_clone().unwrap())
+//!     .unwrap();
+//! s.set_ttl(Some(Duration::from_secs(1)));
+//! s.send(b"stale soon").unwrap();
+//! sim::advance(Duration::from_secs(2));
+//! assert!(r.recv().unwrap().is_none());
+//! assert_eq!(r.expired(), 1);
+//! sim::disable();
+//! ```
+
+use crate::Error;
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+#[derive(Copy, Clone)]
+struct Clock {
+    /// Real time when simulation was enabled, in both forms.
+    start: Instant,
+    start_ns: u64,
+    elapsed: Duration,
+}
+
+thread_local! {
+    static CLOCK: Cell<Option<Clock>> = const { Cell::new(None) };
+}
+
+fn clock() -> Option<Clock> {
+    CLOCK.with(|c| c.get())
+}
+
+fn real_monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Switches this thread to virtual time, starting at the current time. Enabling it again
+/// restarts it.
+pub fn enable() {
+    let clock = Clock {
+        start: Instant::now(),
+        start_ns: real_monotonic_ns(),
+        elapsed: Duration::from_secs(0),
+    };
+    CLOCK.with(|c| c.set(Some(clock)));
+}
+
+/// Switches this thread back to real time.
+pub fn disable() {
+    CLOCK.with(|c| c.set(None));
+}
+
+/// Returns true if this thread uses virtual time.
+pub fn is_enabled() -> bool {
+    clock().is_some()
+}
+
+/// How far virtual time has moved since `enable`, or zero if it is not enabled.
+pub fn elapsed() -> Duration {
+    clock().map(|c| c.elapsed).unwrap_or_default()
+}
+
+/// Moves virtual time forward. Does nothing unless enabled.
+pub fn advance(by: Duration) {
+    CLOCK.with(|c| {
+        if let Some(mut clock) = c.get() {
+            clock.elapsed += by;
+            c.set(Some(clock));
+        }
+    });
+}
+
+/// The current time, virtual if enabled.
+pub(crate) fn instant() -> Instant {
+    match clock() {
+        Some(c) => c.start + c.elapsed,
+        None => Instant::now(),
+    }
+}
+
+/// The current `CLOCK_MONOTONIC` time in nanoseconds, virtual if enabled.
+pub(crate) fn monotonic_ns() -> u64 {
+    match clock() {
+        Some(c) => c.start_ns.saturating_add(c.elapsed.as_nanos() as u64),
+        None => real_monotonic_ns(),
+    }
+}
+
+/// Sleeps, or moves virtual time forward.
+pub(crate) fn sleep(d: Duration) {
+    if is_enabled() {
+        advance(d)
+    } else {
+        std::thread::sleep(d)
+    }
+}
+
+/// What a wait in virtual time does when it would block: it times out right away, or
+/// fails if there is no timeout. Returns false, like the waits do on timeout.
+pub(crate) fn would_block(timeout: Option<Duration>) -> Result<bool, Error> {
+    match timeout {
+        Some(t) => {
+            advance(t);
+            Ok(false)
+        }
+        None => Err(std::io::Error::from_raw_os_error(libc::EDEADLK))?,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_advance() {
+        enable();
+        let s: crate::sharedring::Sender<u64> = crate::sharedring::Sender::new(10).unwrap();
+        assert!(!s.wait_for_peers(2, Some(Duration::from_secs(30))).unwrap());
+        assert_eq!(elapsed(), Duration::from_secs(30));
+        let mut r: crate::sharedring::Receiver<u64> = crate::sharedring::Receiver::new(10).unwrap();
+        let e = r.block_until_readable().unwrap_err();
+        assert!(matches!(e, Error::Io(e) if e.raw_os_error() == Some(libc::EDEADLK)));
+        disable();
+        assert_eq!(elapsed(), Duration::from_secs(0));
+    }
+}
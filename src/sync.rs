@@ -0,0 +1,21 @@
+//! Synchronization primitives in shared memory, for groups of processes.
+//!
+//! Waiting is done with futexes on the shared memory, so no file descriptors other than the
+//! memfd need to be passed around. As everywhere in this crate, peers are untrusted: a
+//! misbehaving peer can make waiting end too early or time out, but nothing worse.
+
+mod barrier;
+mod epoch;
+pub(crate) mod futex;
+mod mutex;
+mod once;
+mod pollset;
+mod semaphore;
+
+pub(crate) use self::barrier::BarrierState;
+pub use self::barrier::ShmBarrier;
+pub use self::epoch::{EpochDomain, EpochGuard};
+pub use self::mutex::{Protocol, ShmMutex, ShmMutexGuard};
+pub use self::once::ShmOnce;
+pub use self::pollset::{PollSet, PollSource, Pollable};
+pub use self::semaphore::{SemaphorePermit, ShmSemaphore};
@@ -0,0 +1,161 @@
+//! A barrier that a fixed number of processes wait on, to start something together.
+
+use super::futex;
+use crate::mem::{mfd, mmap};
+use crate::wire::{AtomicLe32, AtomicLe64};
+use crate::Error;
+use std::fs::File;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// The shared part of a barrier, which can also live in another header, e g that of a
+/// `sharedring`.
+#[repr(C)]
+pub(crate) struct BarrierState {
+    /// Generation in the upper half, number of arrived peers in the lower half.
+    state: AtomicLe64,
+    /// The generation again, for waiting on with a futex.
+    generation: AtomicLe32,
+}
+
+impl BarrierState {
+    /// Waits until `n` peers (including us) have arrived. Returns false on timeout, in
+    /// which case we are no longer counted as arrived.
+    pub(crate) fn wait(&self, n: u32, timeout: Option<Duration>) -> Result<bool, Error> {
+        if n == 0 {
+            Err(Error::OutOfBounds)?
+        }
+        let deadline = timeout.map(|t| crate::sim::instant() + t);
+        let mut s = self.state.load(Ordering::Acquire);
+        let generation = loop {
+            let (g, c) = ((s >> 32) as u32, s as u32);
+            let next = if c.saturating_add(1) >= n {
+                (g.wrapping_add(1) as u64) << 32
+            } else {
+                s + 1
+            };
+            match self
+                .state
+                .compare_exchange(s, next, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) if next as u32 == 0 => {
+                    // We were the last one to arrive.
+                    self.generation.store(g.wrapping_add(1), Ordering::Release);
+                    futex::wake_all(self.generation.raw())?;
+                    return Ok(true);
+                }
+                Ok(_) => break g,
+                Err(x) => s = x,
+            }
+        };
+        loop {
+            let s = self.state.load(Ordering::Acquire);
+            if (s >> 32) as u32 != generation {
+                return Ok(true);
+            }
+            let left = futex::remaining(deadline);
+            if left == Some(Duration::from_secs(0))
+                || !futex::wait(self.generation.raw(), generation.to_le(), left)?
+            {
+                if self.leave(generation) {
+                    return Ok(false);
+                }
+                // Released just now.
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Takes back our arrival, unless the barrier has been released already.
+    fn leave(&self, generation: u32) -> bool {
+        let mut s = self.state.load(Ordering::Acquire);
+        while (s >> 32) as u32 == generation && s as u32 > 0 {
+            match self
+                .state
+                .compare_exchange(s, s - 1, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return true,
+                Err(x) => s = x,
+            }
+        }
+        (s >> 32) as u32 == generation
+    }
+}
+
+/// A barrier for `n` processes, in a memfd of its own.
+///
+/// It can be reused: once all `n` have arrived, they are released and the next round begins.
+pub struct ShmBarrier {
+    memfd: mfd::Memfd,
+    mmap: mmap::MmapRaw,
+    n: u32,
+}
+
+const BARRIER_SIZE: usize = std::mem::size_of::<BarrierState>();
+
+impl ShmBarrier {
+    /// Creates a barrier that releases when `n` processes wait on it.
+    pub fn new(n: u32) -> Result<Self, Error> {
+        let memfd = crate::mem::CreateOptions::default().create("shmem-ipc barrier")?;
+        memfd.as_file().set_len(BARRIER_SIZE as u64)?;
+        Self::attach(n, memfd)
+    }
+
+    /// Attaches to a barrier created by another process, with the same `n`.
+    pub fn open(n: u32, memfd: File) -> Result<Self, Error> {
+        Self::attach(n, crate::mem::memfd_from_file(memfd)?)
+    }
+
+    fn attach(n: u32, memfd: mfd::Memfd) -> Result<Self, Error> {
+        if n == 0 {
+            Err(Error::OutOfBounds)?
+        }
+        let mmap = crate::mem::raw_memfd(&memfd, BARRIER_SIZE)?;
+        if (memfd.as_file().metadata()?.len() as usize) < BARRIER_SIZE {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        Ok(ShmBarrier { memfd, mmap, n })
+    }
+
+    /// The file descriptor to hand to the other processes.
+    pub fn memfd(&self) -> &mfd::Memfd {
+        &self.memfd
+    }
+
+    /// Number of processes the barrier waits for.
+    pub fn n(&self) -> u32 {
+        self.n
+    }
+
+    /// Waits until `n` processes, this one included, are waiting, or the timeout expires.
+    ///
+    /// Returns false on timeout; this process then no longer counts as waiting.
+    pub fn wait(&self, timeout: Option<Duration>) -> Result<bool, Error> {
+        let state = unsafe { &*(self.mmap.as_ptr() as *const BarrierState) };
+        state.wait(self.n, timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rendezvous() {
+        let b = ShmBarrier::new(3).unwrap();
+        assert!(!b.wait(Some(Duration::from_millis(10))).unwrap());
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let file = b.memfd().as_file().try_clone().unwrap();
+                std::thread::spawn(move || {
+                    let b = ShmBarrier::open(3, file).unwrap();
+                    b.wait(Some(Duration::from_secs(10))).unwrap()
+                })
+            })
+            .collect();
+        assert!(b.wait(None).unwrap());
+        for t in threads {
+            assert!(t.join().unwrap());
+        }
+    }
+}
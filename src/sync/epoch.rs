@@ -0,0 +1,224 @@
+//! Epoch based reclamation across processes.
+//!
+//! Readers pin the current epoch while they look at a shared structure. A writer that
+//! unlinks something retires it instead of freeing it right away, and it is freed once
+//! every reader that might still see it has unpinned, i e two epochs later.
+
+use crate::mem::{mfd, mmap};
+use crate::Error;
+use std::cell::Cell;
+use std::fs::File;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+
+/// Number of processes (or handles) that can take part.
+const SLOTS: usize = 64;
+
+#[repr(C)]
+struct Slot {
+    pid: AtomicU32,
+    _pad: u32,
+    /// The epoch pinned, or zero if not reading.
+    epoch: AtomicU64,
+}
+
+#[repr(C)]
+struct Shared {
+    epoch: AtomicU64,
+    _pad: [u64; 7],
+    slots: [Slot; SLOTS],
+}
+
+const SHARED_SIZE: usize = std::mem::size_of::<Shared>();
+
+/// One handle to an epoch domain shared between processes.
+///
+/// Every handle takes a reader slot; a slot left behind by a process that died is freed.
+/// A misbehaving peer can hold up reclamation by never unpinning, but nothing worse.
+pub struct EpochDomain {
+    memfd: mfd::Memfd,
+    mmap: mmap::MmapRaw,
+    slot: usize,
+    pins: Cell<usize>,
+    retired: Vec<(u64, Box<dyn FnOnce()>)>,
+}
+
+/// Keeps the epoch pinned while reading, see `EpochDomain::pin`.
+pub struct EpochGuard<'a> {
+    domain: &'a EpochDomain,
+}
+
+impl EpochDomain {
+    /// Creates a new domain.
+    pub fn new() -> Result<Self, Error> {
+        let memfd = crate::mem::CreateOptions::default().create("shmem-ipc epoch")?;
+        memfd.as_file().set_len(SHARED_SIZE as u64)?;
+        let mmap = crate::mem::raw_memfd(&memfd, SHARED_SIZE)?;
+        let shared = unsafe { &*(mmap.as_ptr() as *const Shared) };
+        shared.epoch.store(1, Ordering::Release);
+        Self::attach(memfd, mmap)
+    }
+
+    /// Attaches to a domain created by another process.
+    pub fn open(memfd: File) -> Result<Self, Error> {
+        let memfd = crate::mem::memfd_from_file(memfd)?;
+        let mmap = crate::mem::raw_memfd(&memfd, SHARED_SIZE)?;
+        if (memfd.as_file().metadata()?.len() as usize) < SHARED_SIZE {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        Self::attach(memfd, mmap)
+    }
+
+    fn attach(memfd: mfd::Memfd, mmap: mmap::MmapRaw) -> Result<Self, Error> {
+        let shared = unsafe { &*(mmap.as_ptr() as *const Shared) };
+        let pid = std::process::id();
+        let claim = |s: &Slot| {
+            s.pid
+                .compare_exchange(0, pid, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        };
+        let mut slot = shared.slots.iter().position(claim);
+        if slot.is_none() {
+            Self::clear_dead(shared);
+            slot = shared.slots.iter().position(claim);
+        }
+        let slot = slot.ok_or_else(|| std::io::Error::from_raw_os_error(libc::EUSERS))?;
+        shared.slots[slot].epoch.store(0, Ordering::Release);
+        Ok(EpochDomain {
+            memfd,
+            mmap,
+            slot,
+            pins: Cell::new(0),
+            retired: vec![],
+        })
+    }
+
+    fn shared(&self) -> &Shared {
+        unsafe { &*(self.mmap.as_ptr() as *const Shared) }
+    }
+
+    fn clear_dead(shared: &Shared) {
+        for s in shared.slots.iter() {
+            let pid = s.pid.load(Ordering::Acquire);
+            if pid != 0 && !crate::unix::process_alive(pid) {
+                s.epoch.store(0, Ordering::Release);
+                let _ = s
+                    .pid
+                    .compare_exchange(pid, 0, Ordering::AcqRel, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// The file descriptor to hand to the other processes.
+    pub fn memfd(&self) -> &mfd::Memfd {
+        &self.memfd
+    }
+
+    /// Pins the current epoch, so that nothing retired from now on is freed until the
+    /// guard is dropped. Pins can be nested.
+    pub fn pin(&self) -> EpochGuard<'_> {
+        if self.pins.get() == 0 {
+            let shared = self.shared();
+            let slot = &shared.slots[self.slot];
+            loop {
+                let e = shared.epoch.load(Ordering::Acquire);
+                slot.epoch.store(e, Ordering::SeqCst);
+                fence(Ordering::SeqCst);
+                if shared.epoch.load(Ordering::Acquire) == e {
+                    break;
+                }
+            }
+        }
+        self.pins.set(self.pins.get() + 1);
+        EpochGuard { domain: self }
+    }
+
+    /// Schedules `free` to be called once no reader can see the retired item any more.
+    ///
+    /// `free` runs in this process, from `collect`. Items still waiting when the handle is
+    /// dropped are leaked.
+    pub fn retire<F: FnOnce() + 'static>(&mut self, free: F) {
+        let e = self.shared().epoch.load(Ordering::Acquire);
+        self.retired.push((e, Box::new(free)));
+    }
+
+    /// Tries to move to the next epoch: possible if every pinned reader has seen the
+    /// current one.
+    fn try_advance(&self) -> u64 {
+        let shared = self.shared();
+        let e = shared.epoch.load(Ordering::Acquire);
+        fence(Ordering::SeqCst);
+        Self::clear_dead(shared);
+        let behind = shared.slots.iter().any(|s| {
+            let pinned = s.epoch.load(Ordering::Acquire);
+            s.pid.load(Ordering::Acquire) != 0 && pinned != 0 && pinned != e
+        });
+        if behind {
+            return e;
+        }
+        match shared
+            .epoch
+            .compare_exchange(e, e + 1, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => e + 1,
+            Err(x) => x,
+        }
+    }
+
+    /// Frees what can be freed, and returns the number of items still waiting.
+    pub fn collect(&mut self) -> usize {
+        let e = self.try_advance();
+        let (ready, waiting) = std::mem::take(&mut self.retired)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(r, _)| e >= r + 2);
+        self.retired = waiting;
+        for (_, free) in ready {
+            free();
+        }
+        self.retired.len()
+    }
+}
+
+impl Drop for EpochGuard<'_> {
+    fn drop(&mut self) {
+        let d = self.domain;
+        d.pins.set(d.pins.get() - 1);
+        if d.pins.get() == 0 {
+            d.shared().slots[d.slot].epoch.store(0, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for EpochDomain {
+    fn drop(&mut self) {
+        // What is still waiting might be in use by readers in other processes, and waiting
+        // for them could take forever, so it is leaked.
+        self.collect();
+        std::mem::forget(std::mem::take(&mut self.retired));
+        let slot = &self.shared().slots[self.slot];
+        let _ =
+            slot.pid
+                .compare_exchange(std::process::id(), 0, Ordering::AcqRel, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn grace_period() {
+        let mut writer = EpochDomain::new().unwrap();
+        let reader = EpochDomain::open(writer.memfd().as_file().try_clone().unwrap()).unwrap();
+        let freed = Rc::new(Cell::new(false));
+        let guard = reader.pin();
+        let f = freed.clone();
+        writer.retire(move || f.set(true));
+        for _ in 0..3 {
+            assert_eq!(writer.collect(), 1);
+        }
+        drop(guard);
+        assert_eq!(writer.collect(), 0);
+        assert!(freed.get());
+    }
+}
@@ -0,0 +1,162 @@
+//! Thin wrappers around the futex system call, on words that can be shared between
+//! processes.
+
+use crate::{Error, Op};
+use std::sync::atomic::AtomicU32;
+use std::time::{Duration, Instant};
+
+fn futex(word: &AtomicU32, op: libc::c_int, val: u32, ts: Option<&libc::timespec>) -> libc::c_long {
+    let ts = ts.map_or(std::ptr::null(), |t| t as *const _);
+    unsafe { libc::syscall(libc::SYS_futex, word.as_ptr(), op, val, ts, 0usize, 0u32) }
+}
+
+/// Time left until `deadline`, or `None` to wait forever.
+pub(crate) fn remaining(deadline: Option<Instant>) -> Option<Duration> {
+    deadline.map(|d| d.saturating_duration_since(crate::sim::instant()))
+}
+
+/// `t` from now on `clock`, for the system calls that take absolute timeouts.
+fn absolute(clock: libc::clockid_t, t: Duration) -> libc::timespec {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(clock, &mut now) };
+    let nanos = now.tv_nsec as u64 + t.subsec_nanos() as u64;
+    let secs = (now.tv_sec as u64)
+        .saturating_add(t.as_secs())
+        .saturating_add(nanos / 1_000_000_000);
+    libc::timespec {
+        tv_sec: secs.min(libc::time_t::MAX as u64) as libc::time_t,
+        tv_nsec: (nanos % 1_000_000_000) as libc::c_long,
+    }
+}
+
+/// Waits until the word is woken up or no longer holds `expected`.
+///
+/// Returns false on timeout. May return early for no reason, so check the condition again.
+pub(crate) fn wait(
+    word: &AtomicU32,
+    expected: u32,
+    timeout: Option<Duration>,
+) -> Result<bool, Error> {
+    if crate::sim::is_enabled() {
+        if word.load(std::sync::atomic::Ordering::Acquire) != expected {
+            return Ok(true);
+        }
+        return crate::sim::would_block(timeout);
+    }
+    let ts = timeout.map(|t| libc::timespec {
+        tv_sec: t.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+        tv_nsec: t.subsec_nanos() as libc::c_long,
+    });
+    if futex(word, libc::FUTEX_WAIT, expected, ts.as_ref()) == 0 {
+        return Ok(true);
+    }
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ETIMEDOUT) => Ok(false),
+        Some(libc::EAGAIN) | Some(libc::EINTR) => Ok(true),
+        _ => Err(Error::os(Op::Signal, None)(e)),
+    }
+}
+
+/// Most words that `futex_waitv` takes at once.
+pub(crate) const WAITV_MAX: usize = 128;
+
+/// The kernel's `struct futex_waitv`.
+#[repr(C)]
+struct FutexWaitv {
+    val: u64,
+    uaddr: u64,
+    flags: u32,
+    reserved: u32,
+}
+
+const FUTEX2_SIZE_U32: u32 = 0x02;
+
+/// Like `wait`, but for any of at most `WAITV_MAX` words, with one `futex_waitv` call.
+///
+/// Returns `None` if the kernel does not have `futex_waitv`, which came in Linux 5.16.
+pub(crate) fn wait_any(
+    words: &[(&AtomicU32, u32)],
+    timeout: Option<Duration>,
+) -> Result<Option<bool>, Error> {
+    if words.len() > WAITV_MAX {
+        Err(Error::OutOfBounds)?
+    }
+    let waiters: Vec<_> = words
+        .iter()
+        .map(|(word, expected)| FutexWaitv {
+            val: *expected as u64,
+            uaddr: word.as_ptr() as u64,
+            flags: FUTEX2_SIZE_U32,
+            reserved: 0,
+        })
+        .collect();
+    let ts = timeout.map(|t| absolute(libc::CLOCK_MONOTONIC, t));
+    let ts = ts.as_ref().map_or(std::ptr::null(), |t| t as *const _);
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_futex_waitv,
+            waiters.as_ptr(),
+            waiters.len() as libc::c_uint,
+            0u32,
+            ts,
+            libc::CLOCK_MONOTONIC,
+        )
+    };
+    if r >= 0 {
+        return Ok(Some(true));
+    }
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ENOSYS) => Ok(None),
+        Some(libc::ETIMEDOUT) => Ok(Some(false)),
+        Some(libc::EAGAIN) | Some(libc::EINTR) => Ok(Some(true)),
+        _ => Err(Error::os(Op::Signal, None)(e)),
+    }
+}
+
+/// Wakes up all waiters on the word.
+pub(crate) fn wake_all(word: &AtomicU32) -> Result<(), Error> {
+    if futex(word, libc::FUTEX_WAKE, i32::MAX as u32, None) < 0 {
+        Err(Error::os(Op::Signal, None)(std::io::Error::last_os_error()))?
+    }
+    Ok(())
+}
+
+/// Wakes up one waiter on the word.
+pub(crate) fn wake_one(word: &AtomicU32) -> Result<(), Error> {
+    if futex(word, libc::FUTEX_WAKE, 1, None) < 0 {
+        Err(Error::os(Op::Signal, None)(std::io::Error::last_os_error()))?
+    }
+    Ok(())
+}
+
+/// Takes a priority inheritance futex lock, which holds the owner's thread id, through the
+/// kernel. Returns false on timeout.
+pub(crate) fn lock_pi(word: &AtomicU32, timeout: Option<Duration>) -> Result<bool, Error> {
+    if crate::sim::is_enabled() {
+        return crate::sim::would_block(timeout);
+    }
+    // FUTEX_LOCK_PI goes by CLOCK_REALTIME.
+    let ts = timeout.map(|t| absolute(libc::CLOCK_REALTIME, t));
+    if futex(word, libc::FUTEX_LOCK_PI, 0, ts.as_ref()) == 0 {
+        return Ok(true);
+    }
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ETIMEDOUT) => Ok(false),
+        _ => Err(Error::os(Op::Signal, None)(e)),
+    }
+}
+
+/// Releases a priority inheritance futex lock that has waiters, handing it to the most
+/// urgent one.
+pub(crate) fn unlock_pi(word: &AtomicU32) -> Result<(), Error> {
+    if futex(word, libc::FUTEX_UNLOCK_PI, 0, None) < 0 {
+        Err(Error::os(Op::Signal, None)(std::io::Error::last_os_error()))?
+    }
+    Ok(())
+}
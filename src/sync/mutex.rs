@@ -0,0 +1,291 @@
+//! A mutex shared between processes, optionally with priority inheritance.
+
+use super::futex;
+use crate::mem::{mfd, mmap};
+use crate::wire::AtomicLe32;
+use crate::Error;
+use std::fs::File;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+/// Bits of the lock word, as the kernel defines them for PI futexes.
+const FUTEX_WAITERS: u32 = 0x8000_0000;
+const FUTEX_OWNER_DIED: u32 = 0x4000_0000;
+const FUTEX_TID_MASK: u32 = 0x3fff_ffff;
+
+const PROTOCOL_PLAIN: u32 = 1;
+const PROTOCOL_PI: u32 = 2;
+
+#[repr(C)]
+struct MutexState {
+    /// Thread id of the owner, or zero, plus `FUTEX_WAITERS` and `FUTEX_OWNER_DIED`. This
+    /// one is in native byte order, as the kernel reads it for PI futexes.
+    word: AtomicU32,
+    protocol: AtomicLe32,
+}
+
+const MUTEX_SIZE: usize = std::mem::size_of::<MutexState>();
+
+/// How a `ShmMutex` waits for the lock.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// Waiters sleep on a futex, and the owner runs at its own priority.
+    Plain,
+    /// The kernel hands out the lock (`FUTEX_LOCK_PI`), and boosts the owner to the
+    /// priority of the most urgent waiter until it unlocks, so that a low priority process
+    /// holding the lock cannot block a real time one indefinitely. Detects owners that died
+    /// holding the lock, see `ShmMutexGuard::owner_died`. The lock holds thread ids, so all
+    /// processes must be in the same pid namespace.
+    PriorityInheritance,
+}
+
+/// A mutex in a memfd of its own, for processes that share data in some other memory.
+///
+/// It is not reentrant: a thread locking it again waits for itself, or, with priority
+/// inheritance, gets `EDEADLK`. A peer that ignores the lock, or writes garbage to it,
+/// defeats mutual exclusion with itself, and can make locking fail.
+pub struct ShmMutex {
+    memfd: mfd::Memfd,
+    mmap: mmap::MmapRaw,
+    protocol: Protocol,
+}
+
+/// Proof of holding a `ShmMutex`; dropping it unlocks.
+///
+/// It cannot be sent to another thread, as the lock belongs to the thread that took it.
+pub struct ShmMutexGuard<'a> {
+    mutex: &'a ShmMutex,
+    owner_died: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+fn gettid() -> u32 {
+    unsafe { libc::syscall(libc::SYS_gettid) as u32 }
+}
+
+impl ShmMutex {
+    /// Creates an unlocked mutex.
+    pub fn new(protocol: Protocol) -> Result<Self, Error> {
+        let memfd = crate::mem::CreateOptions::default().create("shmem-ipc mutex")?;
+        memfd.as_file().set_len(MUTEX_SIZE as u64)?;
+        let mut m = Self::attach(memfd)?;
+        let p = match protocol {
+            Protocol::Plain => PROTOCOL_PLAIN,
+            Protocol::PriorityInheritance => PROTOCOL_PI,
+        };
+        m.state().protocol.store(p, Ordering::Release);
+        m.protocol = protocol;
+        Ok(m)
+    }
+
+    /// Attaches to a mutex created by another process, with the protocol it chose.
+    pub fn open(memfd: File) -> Result<Self, Error> {
+        let mut m = Self::attach(crate::mem::memfd_from_file(memfd)?)?;
+        m.protocol = match m.state().protocol.load(Ordering::Acquire) {
+            PROTOCOL_PLAIN => Protocol::Plain,
+            PROTOCOL_PI => Protocol::PriorityInheritance,
+            _ => Err(crate::ringbuf::Error::BufCorrupt)?,
+        };
+        Ok(m)
+    }
+
+    fn attach(memfd: mfd::Memfd) -> Result<Self, Error> {
+        let mmap = crate::mem::raw_memfd(&memfd, MUTEX_SIZE)?;
+        if (memfd.as_file().metadata()?.len() as usize) < MUTEX_SIZE {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        Ok(ShmMutex {
+            memfd,
+            mmap,
+            protocol: Protocol::Plain,
+        })
+    }
+
+    fn state(&self) -> &MutexState {
+        unsafe { &*(self.mmap.as_ptr() as *const MutexState) }
+    }
+
+    /// The file descriptor to hand to the other processes.
+    pub fn memfd(&self) -> &mfd::Memfd {
+        &self.memfd
+    }
+
+    /// How this mutex waits for the lock.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    fn guard(&self, owner_died: bool) -> ShmMutexGuard<'_> {
+        ShmMutexGuard {
+            mutex: self,
+            owner_died,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Takes the lock if it is free, without waiting.
+    pub fn try_lock(&self) -> Option<ShmMutexGuard<'_>> {
+        let word = &self.state().word;
+        word.compare_exchange(0, gettid(), Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| self.guard(false))
+    }
+
+    /// Takes the lock, waiting for it up to `timeout`. Returns `None` on timeout.
+    pub fn lock(&self, timeout: Option<Duration>) -> Result<Option<ShmMutexGuard<'_>>, Error> {
+        if let Some(g) = self.try_lock() {
+            return Ok(Some(g));
+        }
+        let word = &self.state().word;
+        if self.protocol == Protocol::PriorityInheritance {
+            return self.lock_pi(timeout);
+        }
+        let deadline = timeout.map(|t| crate::sim::instant() + t);
+        let tid = gettid();
+        loop {
+            // Others might be waiting as well, so whoever gets the lock now has to wake one
+            // of them when unlocking.
+            let cur = match word.compare_exchange(
+                0,
+                tid | FUTEX_WAITERS,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(Some(self.guard(false))),
+                Err(cur) => cur,
+            };
+            if cur & FUTEX_WAITERS == 0
+                && word
+                    .compare_exchange(
+                        cur,
+                        cur | FUTEX_WAITERS,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    )
+                    .is_err()
+            {
+                continue;
+            }
+            let left = futex::remaining(deadline);
+            if left == Some(Duration::from_secs(0))
+                || !futex::wait(word, cur | FUTEX_WAITERS, left)?
+            {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+impl ShmMutex {
+    fn lock_pi(&self, timeout: Option<Duration>) -> Result<Option<ShmMutexGuard<'_>>, Error> {
+        let word = &self.state().word;
+        loop {
+            match futex::lock_pi(word, timeout) {
+                Ok(false) => return Ok(None),
+                Ok(true) => {
+                    // Set by the kernel if the owner had the lock on its robust list.
+                    let died = word.fetch_and(!FUTEX_OWNER_DIED, Ordering::AcqRel);
+                    return Ok(Some(self.guard(died & FUTEX_OWNER_DIED != 0)));
+                }
+                Err(Error::Os { source, .. }) if source.raw_os_error() == Some(libc::ESRCH) => {
+                    // The owner is gone without unlocking, so nobody else can be queued
+                    // in the kernel either. Take over, unless another waiter was quicker.
+                    let cur = word.load(Ordering::Relaxed);
+                    if cur & FUTEX_TID_MASK != 0
+                        && word
+                            .compare_exchange(cur, gettid(), Ordering::Acquire, Ordering::Relaxed)
+                            .is_ok()
+                    {
+                        return Ok(Some(self.guard(true)));
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl ShmMutexGuard<'_> {
+    /// True if the previous owner died while holding the lock, so whatever it protects may
+    /// be half updated. Only detected with `Protocol::PriorityInheritance`.
+    pub fn owner_died(&self) -> bool {
+        self.owner_died
+    }
+}
+
+impl Drop for ShmMutexGuard<'_> {
+    fn drop(&mut self) {
+        let word = &self.mutex.state().word;
+        match self.mutex.protocol {
+            Protocol::PriorityInheritance => {
+                let tid = gettid();
+                if word
+                    .compare_exchange(tid, 0, Ordering::Release, Ordering::Relaxed)
+                    .is_err()
+                {
+                    let _ = futex::unlock_pi(word);
+                }
+            }
+            Protocol::Plain => {
+                if word.swap(0, Ordering::Release) & FUTEX_WAITERS != 0 {
+                    let _ = futex::wake_one(word);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+
+    #[test]
+    fn exclusion() {
+        for protocol in [Protocol::Plain, Protocol::PriorityInheritance]
+            .iter()
+            .copied()
+        {
+            let m = ShmMutex::new(protocol).unwrap();
+            let g = m.lock(None).unwrap().unwrap();
+            let file = m.memfd().as_file().try_clone().unwrap();
+            let counter = Arc::new(AtomicU64::new(0));
+            let c = counter.clone();
+            let t = std::thread::spawn(move || {
+                let m = ShmMutex::open(file).unwrap();
+                assert_eq!(m.protocol(), protocol);
+                assert!(m.try_lock().is_none());
+                assert!(m.lock(Some(Duration::from_millis(10))).unwrap().is_none());
+                for _ in 0..1000 {
+                    let _g = m.lock(None).unwrap().unwrap();
+                    // Not atomic as a whole, so only correct under the lock
+                    c.store(c.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+                }
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            drop(g);
+            for _ in 0..1000 {
+                let g = m.lock(None).unwrap().unwrap();
+                assert!(!g.owner_died());
+                counter.store(counter.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+            }
+            t.join().unwrap();
+            assert_eq!(counter.load(Ordering::Relaxed), 2000);
+        }
+    }
+
+    #[test]
+    fn owner_died() {
+        let m = Arc::new(ShmMutex::new(Protocol::PriorityInheritance).unwrap());
+        let m2 = m.clone();
+        std::thread::spawn(move || std::mem::forget(m2.lock(None).unwrap()))
+            .join()
+            .unwrap();
+        let g = m.lock(Some(Duration::from_secs(10))).unwrap().unwrap();
+        assert!(g.owner_died());
+        drop(g);
+        assert!(!m.lock(None).unwrap().unwrap().owner_died());
+    }
+}
@@ -0,0 +1,213 @@
+//! One-time initialization of something shared, by whichever process gets there first.
+
+use super::futex;
+use crate::mem::{mfd, mmap};
+use crate::wire::AtomicLe32;
+use crate::Error;
+use std::fs::File;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// Bits of the state word; the rest is the pid of the process initializing, if any. pid_max
+/// is at most 2^22.
+const DONE: u32 = 0x8000_0000;
+/// An initializer died, or failed, before finishing.
+const POISONED: u32 = 0x4000_0000;
+const PID_MASK: u32 = 0x3fff_ffff;
+
+/// How often waiters check that the initializer is still alive.
+const LIVENESS_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The shared part of a once cell, which can also live in another header.
+#[repr(C)]
+pub(crate) struct OnceState {
+    state: AtomicLe32,
+}
+
+/// Puts the state back if the initializer panics.
+struct Reset<'a> {
+    state: &'a AtomicLe32,
+    armed: bool,
+}
+
+impl Drop for Reset<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            self.state.store(POISONED, Ordering::Release);
+            let _ = futex::wake_all(self.state.raw());
+        }
+    }
+}
+
+impl OnceState {
+    pub(crate) fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) & DONE != 0
+    }
+
+    /// See `ShmOnce::call_once`.
+    pub(crate) fn call_once<F>(&self, f: F) -> Result<bool, Error>
+    where
+        F: FnOnce(bool) -> Result<(), Error>,
+    {
+        let pid = std::process::id();
+        let mut s = self.state.load(Ordering::Acquire);
+        loop {
+            if s & DONE != 0 {
+                return Ok(false);
+            }
+            let owner = s & PID_MASK;
+            if owner == 0 {
+                let mine = pid | (s & POISONED);
+                match self
+                    .state
+                    .compare_exchange(s, mine, Ordering::Acquire, Ordering::Acquire)
+                {
+                    Ok(_) => break,
+                    Err(x) => s = x,
+                }
+                continue;
+            }
+            if owner != pid && !crate::unix::process_alive(owner) {
+                // Died while initializing; whoever is next has to pick up the pieces.
+                match self
+                    .state
+                    .compare_exchange(s, POISONED, Ordering::AcqRel, Ordering::Acquire)
+                {
+                    Ok(_) => {
+                        futex::wake_all(self.state.raw())?;
+                        s = POISONED;
+                    }
+                    Err(x) => s = x,
+                }
+                continue;
+            }
+            futex::wait(self.state.raw(), s.to_le(), Some(LIVENESS_INTERVAL))?;
+            s = self.state.load(Ordering::Acquire);
+        }
+        let mut reset = Reset {
+            state: &self.state,
+            armed: true,
+        };
+        let r = f(s & POISONED != 0);
+        reset.armed = false;
+        let next = match r {
+            Ok(()) => DONE,
+            Err(_) => POISONED,
+        };
+        self.state.store(next, Ordering::Release);
+        futex::wake_all(self.state.raw())?;
+        r.map(|()| true)
+    }
+}
+
+/// A once cell in a memfd of its own, so that exactly one of several processes initializes
+/// some shared region, and the others wait for it to be done.
+///
+/// If the initializing process dies before finishing, one of the waiting processes takes
+/// over, and is told that it is recovering, so that it can throw away what was left half
+/// done. A peer that writes garbage to the cell can make the others initialize again, or
+/// skip initializing.
+pub struct ShmOnce {
+    memfd: mfd::Memfd,
+    mmap: mmap::MmapRaw,
+}
+
+const ONCE_SIZE: usize = std::mem::size_of::<OnceState>();
+
+impl ShmOnce {
+    /// Creates a cell that has not run yet.
+    pub fn new() -> Result<Self, Error> {
+        let memfd = crate::mem::CreateOptions::default().create("shmem-ipc once")?;
+        memfd.as_file().set_len(ONCE_SIZE as u64)?;
+        Self::attach(memfd)
+    }
+
+    /// Attaches to a cell created by another process.
+    pub fn open(memfd: File) -> Result<Self, Error> {
+        Self::attach(crate::mem::memfd_from_file(memfd)?)
+    }
+
+    fn attach(memfd: mfd::Memfd) -> Result<Self, Error> {
+        let mmap = crate::mem::raw_memfd(&memfd, ONCE_SIZE)?;
+        if (memfd.as_file().metadata()?.len() as usize) < ONCE_SIZE {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        Ok(ShmOnce { memfd, mmap })
+    }
+
+    fn state(&self) -> &OnceState {
+        unsafe { &*(self.mmap.as_ptr() as *const OnceState) }
+    }
+
+    /// The file descriptor to hand to the other processes.
+    pub fn memfd(&self) -> &mfd::Memfd {
+        &self.memfd
+    }
+
+    /// Runs `f` unless it has already run to completion, in this process or another one,
+    /// and waits while it runs elsewhere. Returns true if `f` ran here and succeeded.
+    ///
+    /// `f` is told whether an earlier attempt died, failed or panicked halfway. If `f`
+    /// fails, the error is returned and the next caller tries again.
+    pub fn call_once<F>(&self, f: F) -> Result<bool, Error>
+    where
+        F: FnOnce(bool) -> Result<(), Error>,
+    {
+        self.state().call_once(f)
+    }
+
+    /// True once initialization has completed.
+    pub fn is_completed(&self) -> bool {
+        self.state().is_completed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn once() {
+        let once = ShmOnce::new().unwrap();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let file = once.memfd().as_file().try_clone().unwrap();
+                std::thread::spawn(move || {
+                    let once = ShmOnce::open(file).unwrap();
+                    let ran = once.call_once(|_| {
+                        std::thread::sleep(Duration::from_millis(10));
+                        Ok(())
+                    });
+                    (ran.unwrap(), once.is_completed())
+                })
+            })
+            .collect();
+        let results: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(results.iter().filter(|r| r.0).count(), 1);
+        assert!(results.iter().all(|r| r.1));
+        assert!(!once.call_once(|_| panic!()).unwrap());
+    }
+
+    #[test]
+    fn recovery() {
+        let once = ShmOnce::new().unwrap();
+        let r = once.call_once(|recovering| {
+            assert!(!recovering);
+            Err(Error::OutOfBounds)
+        });
+        assert!(matches!(r, Err(Error::OutOfBounds)));
+        assert!(!once.is_completed());
+        // An initializer that died halfway
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead = child.id();
+        child.wait().unwrap();
+        once.state().state.store(dead, Ordering::Release);
+        let mut recovered = None;
+        let ran = once.call_once(|recovering| {
+            recovered = Some(recovering);
+            Ok(())
+        });
+        assert!(ran.unwrap());
+        assert_eq!(recovered, Some(true));
+    }
+}
@@ -0,0 +1,204 @@
+//! Waiting for any of many futex words with one system call.
+
+use super::futex;
+use crate::{Error, Op};
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
+
+/// What to wait for on something `Pollable`: a futex word that has moved on once it no
+/// longer holds the expected value, and an eventfd that is signaled along with it.
+pub struct PollSource<'a> {
+    pub(crate) word: &'a AtomicU32,
+    pub(crate) expected: u32,
+    pub(crate) signal: &'a File,
+}
+
+/// Something a `PollSet` can wait for, e g a `cell::ConfigView`.
+pub trait Pollable {
+    /// The futex word to wait on, and the eventfd for kernels without `futex_waitv`.
+    fn poll_source(&self) -> PollSource<'_>;
+}
+
+/// Set once we know that the kernel does not have `futex_waitv`.
+static NO_WAITV: AtomicBool = AtomicBool::new(false);
+
+/// Waits for any of a set of `Pollable`s with a single system call.
+///
+/// Uses `futex_waitv` where the kernel has it, i e Linux 5.16 and later, and otherwise, or
+/// for more than 128 at once, waits for their eventfds with epoll.
+///
+/// # Example
+/// ```rust
+/// use shmem_ipc::cell::{ConfigCell, ConfigView};
+/// use shmem_ipc::sync::PollSet;
+/// let mut cells: Vec<_> = (0..3).map(|i| ConfigCell::new(&i).unwrap()).collect();
+/// let mut views: Vec<_> = cells.iter().map(|c| {
+///     let memfd = c.memfd().as_file().try_clone().unwrap();
+///     ConfigView::<u32>::open(memfd, c.changed_signal().try_clone().unwrap()).unwrap()
+/// }).collect();
+/// for v in &mut views { v.get().unwrap(); }
+/// cells[2].set(&5).unwrap();
+/// let mut set = PollSet::new();
+/// let members: Vec<_> = views.iter().map(|v| v as _).collect();
+/// assert_eq!(set.wait(&members, None).unwrap(), Some(2));
+/// ```
+pub struct PollSet {
+    /// Cleared to test the fallback.
+    waitv: bool,
+}
+
+impl Default for PollSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PollSet {
+    /// The members are passed to each `wait`, so that they can be used in between.
+    pub fn new() -> Self {
+        PollSet { waitv: true }
+    }
+
+    /// Waits until the futex word of any of `members` has moved on, or for the timeout.
+    /// Returns the index of the first one that has, or `None` on timeout.
+    pub fn wait(
+        &mut self,
+        members: &[&dyn Pollable],
+        timeout: Option<Duration>,
+    ) -> Result<Option<usize>, Error> {
+        let deadline = timeout.map(|t| crate::sim::instant() + t);
+        let sources: Vec<_> = members.iter().map(|m| m.poll_source()).collect();
+        let mut epoll = None;
+        loop {
+            let ready = sources
+                .iter()
+                .position(|s| s.word.load(Ordering::Acquire) != s.expected);
+            if ready.is_some() {
+                return Ok(ready);
+            }
+            let left = futex::remaining(deadline);
+            if left == Some(Duration::from_secs(0)) {
+                return Ok(None);
+            }
+            if crate::sim::is_enabled() {
+                crate::sim::would_block(left)?;
+                continue;
+            }
+            let waitv = self.waitv
+                && sources.len() <= futex::WAITV_MAX
+                && !NO_WAITV.load(Ordering::Relaxed);
+            if waitv {
+                let words: Vec<_> = sources.iter().map(|s| (s.word, s.expected)).collect();
+                if futex::wait_any(&words, left)?.is_none() {
+                    NO_WAITV.store(true, Ordering::Relaxed);
+                }
+                continue;
+            }
+            let epoll = match &epoll {
+                Some(e) => e,
+                None => {
+                    // Signals from before this are missed, so check the words again first.
+                    epoll = Some(epoll_over(&sources)?);
+                    continue;
+                }
+            };
+            let ms = left.map_or(-1, |t| {
+                // Round up, so as not to spin when less than a millisecond is left.
+                let ms = t.as_nanos().div_ceil(1_000_000);
+                ms.min(i32::MAX as u128) as libc::c_int
+            });
+            let mut events = [libc::epoll_event { events: 0, u64: 0 }; 16];
+            let n = unsafe { libc::epoll_wait(epoll.as_raw_fd(), events.as_mut_ptr(), 16, ms) };
+            if n < 0 {
+                let e = std::io::Error::last_os_error();
+                if e.raw_os_error() != Some(libc::EINTR) {
+                    Err(Error::os(Op::Signal, None)(e))?
+                }
+            }
+        }
+    }
+}
+
+/// An epoll instance waiting for the eventfd of any of `sources`, edge triggered, as they
+/// can be shared with others and must not be read from.
+fn epoll_over(sources: &[PollSource]) -> Result<File, Error> {
+    let os = |e| Error::os(Op::Signal, None)(e);
+    let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+    if epoll < 0 {
+        Err(os(std::io::Error::last_os_error()))?
+    }
+    let epoll = unsafe { File::from_raw_fd(epoll) };
+    for (i, s) in sources.iter().enumerate() {
+        let mut ev = libc::epoll_event {
+            events: (libc::EPOLLIN | libc::EPOLLET) as u32,
+            u64: i as u64,
+        };
+        let r = unsafe {
+            libc::epoll_ctl(
+                epoll.as_raw_fd(),
+                libc::EPOLL_CTL_ADD,
+                s.signal.as_raw_fd(),
+                &mut ev,
+            )
+        };
+        if r < 0 {
+            let e = std::io::Error::last_os_error();
+            // The same eventfd twice
+            if e.raw_os_error() != Some(libc::EEXIST) {
+                Err(os(e))?
+            }
+        }
+    }
+    Ok(epoll)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::{ConfigCell, ConfigView};
+
+    fn wait_for_set(waitv: bool) {
+        let cells: Vec<_> = (0..4u64).map(|i| ConfigCell::new(&i).unwrap()).collect();
+        let mut views: Vec<_> = cells
+            .iter()
+            .map(|c| {
+                let memfd = c.memfd().as_file().try_clone().unwrap();
+                let signal = c.changed_signal().try_clone().unwrap();
+                ConfigView::<u64>::open(memfd, signal).unwrap()
+            })
+            .collect();
+        let mut set = PollSet::new();
+        set.waitv = waitv;
+        let members: Vec<&dyn Pollable> = views.iter().map(|v| v as _).collect();
+        // Not read yet
+        assert_eq!(set.wait(&members, None).unwrap(), Some(0));
+        drop(members);
+        for v in &mut views {
+            v.get().unwrap();
+        }
+        let members: Vec<&dyn Pollable> = views.iter().map(|v| v as _).collect();
+        let short = Some(Duration::from_millis(10));
+        assert_eq!(set.wait(&members, short).unwrap(), None);
+        let mut cell = cells.into_iter().nth(3).unwrap();
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            cell.set(&7).unwrap();
+            cell
+        });
+        let got = set.wait(&members, Some(Duration::from_secs(10))).unwrap();
+        assert_eq!(got, Some(3));
+        drop(t.join().unwrap());
+    }
+
+    #[test]
+    fn futex_waitv() {
+        wait_for_set(true);
+    }
+
+    #[test]
+    fn epoll_fallback() {
+        wait_for_set(false);
+    }
+}
@@ -0,0 +1,325 @@
+//! A counting semaphore shared between processes, e g to limit how many of them do
+//! something expensive at the same time.
+
+use super::futex;
+use crate::mem::{mfd, mmap};
+use crate::wire::AtomicLe32;
+use crate::Error;
+use std::fs::File;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+#[repr(C)]
+struct SemaphoreState {
+    /// Permits available.
+    count: AtomicLe32,
+    /// Number of processes waiting, so that `release` only wakes when there are any.
+    waiters: AtomicLe32,
+}
+
+const SEMAPHORE_SIZE: usize = std::mem::size_of::<SemaphoreState>();
+
+enum Backend {
+    Futex {
+        memfd: mfd::Memfd,
+        mmap: mmap::MmapRaw,
+    },
+    #[cfg(feature = "posix-sem")]
+    Named(*mut libc::sem_t),
+}
+
+/// A semaphore in a memfd of its own, or, with the `posix-sem` feature, a POSIX named
+/// semaphore, for sharing with programs that use `sem_open`.
+///
+/// Permits are not given back when a process holding them dies, just as with POSIX
+/// semaphores. A peer that writes garbage to the memfd can hand out too many permits or
+/// none.
+///
+/// # Example
+/// ```rust
+/// use shmem_ipc::sync::ShmSemaphore;
+/// let sem = ShmSemaphore::new(2).unwrap();
+/// let a = sem.permit(None).unwrap().unwrap();
+/// let _b = sem.permit(None).unwrap().unwrap();
+/// assert!(!sem.try_acquire().unwrap());
+/// drop(a);
+/// assert_eq!(sem.available().unwrap(), 1);
+/// ```
+pub struct ShmSemaphore {
+    backend: Backend,
+}
+
+// A `sem_t` is made to be used from any thread.
+#[cfg(feature = "posix-sem")]
+unsafe impl Send for ShmSemaphore {}
+#[cfg(feature = "posix-sem")]
+unsafe impl Sync for ShmSemaphore {}
+
+/// A permit taken from a `ShmSemaphore`; dropping it gives it back.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a ShmSemaphore,
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        let _ = self.semaphore.release();
+    }
+}
+
+#[cfg(feature = "posix-sem")]
+fn sem_name(name: &str) -> Result<std::ffi::CString, Error> {
+    std::ffi::CString::new(name).map_err(|_| Error::OutOfBounds)
+}
+
+#[cfg(feature = "posix-sem")]
+fn check(r: libc::c_int) -> Result<(), std::io::Error> {
+    match r {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+impl ShmSemaphore {
+    /// Creates a semaphore with `permits` permits.
+    pub fn new(permits: u32) -> Result<Self, Error> {
+        let memfd = crate::mem::CreateOptions::default().create("shmem-ipc semaphore")?;
+        memfd.as_file().set_len(SEMAPHORE_SIZE as u64)?;
+        let s = Self::attach(memfd)?;
+        s.state().unwrap().count.store(permits, Ordering::Release);
+        Ok(s)
+    }
+
+    /// Attaches to a semaphore created by another process.
+    pub fn open(memfd: File) -> Result<Self, Error> {
+        Self::attach(crate::mem::memfd_from_file(memfd)?)
+    }
+
+    fn attach(memfd: mfd::Memfd) -> Result<Self, Error> {
+        let mmap = crate::mem::raw_memfd(&memfd, SEMAPHORE_SIZE)?;
+        if (memfd.as_file().metadata()?.len() as usize) < SEMAPHORE_SIZE {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        Ok(ShmSemaphore {
+            backend: Backend::Futex { memfd, mmap },
+        })
+    }
+
+    /// Opens the POSIX named semaphore `name`, e g `/my-dataset`, like `sem_open`. If
+    /// `permits` is given, it is created with that many permits unless it exists already.
+    #[cfg(feature = "posix-sem")]
+    pub fn open_named(name: &str, permits: Option<u32>) -> Result<Self, Error> {
+        let cname = sem_name(name)?;
+        let (flags, mode) = match permits {
+            Some(_) => (libc::O_CREAT, 0o600 as libc::c_uint),
+            None => (0, 0),
+        };
+        let value = permits.unwrap_or(0) as libc::c_uint;
+        let sem = unsafe { libc::sem_open(cname.as_ptr(), flags, mode, value) };
+        if sem == libc::SEM_FAILED {
+            Err(std::io::Error::last_os_error())?
+        }
+        Ok(ShmSemaphore {
+            backend: Backend::Named(sem),
+        })
+    }
+
+    /// Removes the POSIX named semaphore `name`, like `sem_unlink`; those who have it open
+    /// can go on using it.
+    #[cfg(feature = "posix-sem")]
+    pub fn unlink_named(name: &str) -> Result<(), Error> {
+        let cname = sem_name(name)?;
+        Ok(check(unsafe { libc::sem_unlink(cname.as_ptr()) })?)
+    }
+
+    fn state(&self) -> Option<&SemaphoreState> {
+        match &self.backend {
+            Backend::Futex { mmap, .. } => {
+                Some(unsafe { &*(mmap.as_ptr() as *const SemaphoreState) })
+            }
+            #[cfg(feature = "posix-sem")]
+            Backend::Named(_) => None,
+        }
+    }
+
+    /// The file descriptor to hand to the other processes, or `None` for a named
+    /// semaphore.
+    pub fn memfd(&self) -> Option<&mfd::Memfd> {
+        match &self.backend {
+            Backend::Futex { memfd, .. } => Some(memfd),
+            #[cfg(feature = "posix-sem")]
+            Backend::Named(_) => None,
+        }
+    }
+
+    /// Takes a permit if one is available right away. Returns true if it did.
+    pub fn try_acquire(&self) -> Result<bool, Error> {
+        match &self.backend {
+            Backend::Futex { .. } => Ok(self.take()),
+            #[cfg(feature = "posix-sem")]
+            Backend::Named(sem) => match check(unsafe { libc::sem_trywait(*sem) }) {
+                Ok(()) => Ok(true),
+                Err(e) if e.raw_os_error() == Some(libc::EAGAIN) => Ok(false),
+                Err(e) => Err(e)?,
+            },
+        }
+    }
+
+    fn take(&self) -> bool {
+        let count = &self.state().unwrap().count;
+        let mut c = count.load(Ordering::Acquire);
+        while c > 0 {
+            match count.compare_exchange(c, c - 1, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return true,
+                Err(x) => c = x,
+            }
+        }
+        false
+    }
+
+    /// Takes a permit, waiting for one if need be. Returns false on timeout.
+    pub fn acquire(&self, timeout: Option<Duration>) -> Result<bool, Error> {
+        let state = match &self.backend {
+            Backend::Futex { .. } => self.state().unwrap(),
+            #[cfg(feature = "posix-sem")]
+            Backend::Named(sem) => return self.acquire_named(*sem, timeout),
+        };
+        let deadline = timeout.map(|t| crate::sim::instant() + t);
+        loop {
+            if self.take() {
+                return Ok(true);
+            }
+            let left = futex::remaining(deadline);
+            if left == Some(Duration::from_secs(0)) {
+                return Ok(false);
+            }
+            state.waiters.fetch_add(1, Ordering::SeqCst);
+            let r = futex::wait(state.count.raw(), 0, left);
+            state.waiters.fetch_sub(1, Ordering::SeqCst);
+            if !r? {
+                return Ok(self.take());
+            }
+        }
+    }
+
+    #[cfg(feature = "posix-sem")]
+    fn acquire_named(
+        &self,
+        sem: *mut libc::sem_t,
+        timeout: Option<Duration>,
+    ) -> Result<bool, Error> {
+        let mut deadline = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        if let Some(t) = timeout {
+            // sem_timedwait takes the realtime clock.
+            unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut deadline) };
+            let ns = deadline.tv_nsec as u64 + t.subsec_nanos() as u64;
+            deadline.tv_sec += (t.as_secs() + ns / 1_000_000_000) as libc::time_t;
+            deadline.tv_nsec = (ns % 1_000_000_000) as libc::c_long;
+        }
+        loop {
+            let r = match timeout {
+                Some(_) => unsafe { libc::sem_timedwait(sem, &deadline) },
+                None => unsafe { libc::sem_wait(sem) },
+            };
+            match check(r) {
+                Ok(()) => return Ok(true),
+                Err(e) if e.raw_os_error() == Some(libc::EINTR) => continue,
+                Err(e) if e.raw_os_error() == Some(libc::ETIMEDOUT) => return Ok(false),
+                Err(e) => Err(e)?,
+            }
+        }
+    }
+
+    /// Like `acquire`, but returns a permit that gives itself back when dropped, or `None`
+    /// on timeout.
+    pub fn permit(&self, timeout: Option<Duration>) -> Result<Option<SemaphorePermit<'_>>, Error> {
+        Ok(match self.acquire(timeout)? {
+            true => Some(SemaphorePermit { semaphore: self }),
+            false => None,
+        })
+    }
+
+    /// Gives back a permit.
+    pub fn release(&self) -> Result<(), Error> {
+        let state = match &self.backend {
+            Backend::Futex { .. } => self.state().unwrap(),
+            #[cfg(feature = "posix-sem")]
+            Backend::Named(sem) => return Ok(check(unsafe { libc::sem_post(*sem) })?),
+        };
+        let mut c = state.count.load(Ordering::Acquire);
+        loop {
+            let next = c.checked_add(1).ok_or(Error::OutOfBounds)?;
+            match state
+                .count
+                .compare_exchange(c, next, Ordering::SeqCst, Ordering::Acquire)
+            {
+                Ok(_) => break,
+                Err(x) => c = x,
+            }
+        }
+        if state.waiters.load(Ordering::SeqCst) > 0 {
+            futex::wake_one(state.count.raw())?;
+        }
+        Ok(())
+    }
+
+    /// Number of permits available right now.
+    pub fn available(&self) -> Result<u32, Error> {
+        match &self.backend {
+            Backend::Futex { .. } => Ok(self.state().unwrap().count.load(Ordering::Acquire)),
+            #[cfg(feature = "posix-sem")]
+            Backend::Named(sem) => {
+                let mut v = 0;
+                check(unsafe { libc::sem_getvalue(*sem, &mut v) })?;
+                Ok(v.max(0) as u32)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "posix-sem")]
+impl Drop for ShmSemaphore {
+    fn drop(&mut self) {
+        if let Backend::Named(sem) = self.backend {
+            unsafe { libc::sem_close(sem) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permits() {
+        let sem = ShmSemaphore::new(1).unwrap();
+        assert!(sem.acquire(None).unwrap());
+        assert!(!sem.acquire(Some(Duration::from_millis(1))).unwrap());
+        let file = sem.memfd().unwrap().as_file().try_clone().unwrap();
+        let t = std::thread::spawn(move || {
+            let sem = ShmSemaphore::open(file).unwrap();
+            let permit = sem.permit(Some(Duration::from_secs(10))).unwrap();
+            permit.is_some()
+        });
+        std::thread::sleep(Duration::from_millis(10));
+        sem.release().unwrap();
+        assert!(t.join().unwrap());
+        assert_eq!(sem.available().unwrap(), 1);
+    }
+
+    #[cfg(feature = "posix-sem")]
+    #[test]
+    fn named() {
+        let name = format!("/shmem-ipc-test-{}", std::process::id());
+        let a = ShmSemaphore::open_named(&name, Some(1)).unwrap();
+        let b = ShmSemaphore::open_named(&name, None).unwrap();
+        ShmSemaphore::unlink_named(&name).unwrap();
+        assert!(a.try_acquire().unwrap());
+        assert!(!b.acquire(Some(Duration::from_millis(1))).unwrap());
+        a.release().unwrap();
+        assert_eq!(b.available().unwrap(), 1);
+        assert!(b.memfd().is_none());
+    }
+}
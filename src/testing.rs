@@ -0,0 +1,5 @@
+//! Helpers for testing code built on this crate.
+//!
+//! Requires the `proptest` feature.
+
+pub mod strategies;
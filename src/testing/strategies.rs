@@ -0,0 +1,147 @@
+//! `proptest` strategies for the memory area of a ringbuffer, as an untrusted sending
+//! side might have left it.
+//!
+//! `valid_ring` images hold the invariants that `sharedring::Receiver` checks, so opening
+//! them succeeds and yields the generated items; `invalid_ring` images break exactly one
+//! of them. Either can be opened with `RingImage::open`, to test how consumer code copes.
+//!
+//! # Example
+//! ```rust
+//! use proptest::prelude::*;
+//! use shmem_ipc::testing::strategies::valid_ring;
+//!
+//! proptest!(|(image in valid_ring::<u32>(1..100))| {
+//!     let mut r = image.open().unwrap();
+//!     let mut got = vec![];
+//!     r.receive_raw(|p, count| {
+//!         got.extend_from_slice(unsafe { std::slice::from_raw_parts(p, count) });
+//!         count
+//!     }).unwrap();
+//!     prop_assert_eq!(got, image.items);
+//! });
+//! ```
+
+use crate::sharedring::{self, FEATURE_APP_FIRST};
+use crate::Error;
+use proptest::prelude::*;
+use std::ops::Range;
+
+/// Where the items start, relative to the ringbuffer: one cache line after its count word.
+const ITEMS_OFFSET: usize = 64;
+
+/// The memory area of a ringbuffer, see `valid_ring`.
+#[derive(Clone, Debug)]
+pub struct RingImage<T> {
+    /// Capacity in items, as passed out of band to the receiving side.
+    pub capacity: usize,
+    /// The contents of the memory area.
+    pub bytes: Vec<u8>,
+    /// The items in the ringbuffer, in order, if the image is valid.
+    pub items: Vec<T>,
+}
+
+impl<T: Copy + zerocopy::AsBytes + zerocopy::FromBytes> RingImage<T> {
+    /// Attaches a receiver to the image, see `sharedring::Receiver::fuzz_open`.
+    pub fn open(&self) -> Result<sharedring::Receiver<T>, Error> {
+        sharedring::Receiver::fuzz_open(self.capacity, &self.bytes)
+    }
+
+    fn new(capacity: usize, items: Vec<T>) -> Self {
+        let mut bytes = vec![0; sharedring::round_to_page_size::<T>(capacity)];
+        bytes[..8].copy_from_slice(&sharedring::LAYOUT.to_le_bytes());
+        let ring = sharedring::HEADER_SIZE;
+        bytes[ring..ring + 8].copy_from_slice(&(items.len() as u64).to_le_bytes());
+        for (i, item) in items.iter().enumerate() {
+            let at = ring + ITEMS_OFFSET + i * std::mem::size_of::<T>();
+            bytes[at..at + std::mem::size_of::<T>()].copy_from_slice(item.as_bytes());
+        }
+        RingImage {
+            capacity,
+            bytes,
+            items,
+        }
+    }
+
+    /// Number of items the ringbuffer really holds, which is at least `capacity`.
+    fn slots(&self) -> usize {
+        (self.bytes.len() - sharedring::HEADER_SIZE - ITEMS_OFFSET) / std::mem::size_of::<T>()
+    }
+}
+
+/// What is wrong with an image from `invalid_ring`.
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Defect {
+    /// The header does not start with the layout version.
+    Layout,
+    /// The header requires an application feature that nobody understands.
+    UnknownFeature,
+    /// The ringbuffer claims to hold more items than fit.
+    CountTooBig,
+}
+
+/// Images of ringbuffers with a capacity in `capacity` (in items, and above zero), holding
+/// up to `capacity` arbitrary items from the start on.
+pub fn valid_ring<T>(capacity: Range<usize>) -> impl Strategy<Value = RingImage<T>>
+where
+    T: Arbitrary + Copy + zerocopy::AsBytes + zerocopy::FromBytes,
+{
+    let capacity = std::cmp::max(capacity.start, 1)..std::cmp::max(capacity.end, 2);
+    capacity.prop_flat_map(|cap| {
+        proptest::collection::vec(any::<T>(), 0..=cap)
+            .prop_map(move |items| RingImage::new(cap, items))
+    })
+}
+
+/// Images like those from `valid_ring`, with one defect, which makes attaching fail.
+pub fn invalid_ring<T>(capacity: Range<usize>) -> impl Strategy<Value = (RingImage<T>, Defect)>
+where
+    T: Arbitrary + Copy + zerocopy::AsBytes + zerocopy::FromBytes,
+{
+    let defect = prop_oneof![
+        Just(Defect::Layout),
+        Just(Defect::UnknownFeature),
+        Just(Defect::CountTooBig),
+    ];
+    (valid_ring(capacity), defect, any::<u64>()).prop_map(|(mut image, defect, x)| {
+        match defect {
+            Defect::Layout => {
+                let bad = if x == sharedring::LAYOUT { !x } else { x };
+                image.bytes[..8].copy_from_slice(&bad.to_le_bytes());
+            }
+            Defect::UnknownFeature => {
+                let bit = FEATURE_APP_FIRST << (x % 32);
+                let at = sharedring::FEATURES_REQUIRED_OFFSET;
+                image.bytes[at..at + 8].copy_from_slice(&bit.to_le_bytes());
+            }
+            Defect::CountTooBig => {
+                let count = image.slots() as u64 + 1 + x % 1000;
+                let ring = sharedring::HEADER_SIZE;
+                image.bytes[ring..ring + 8].copy_from_slice(&count.to_le_bytes());
+            }
+        }
+        image.items.clear();
+        (image, defect)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn invalid_rings_are_rejected((image, defect) in invalid_ring::<u64>(1..600)) {
+            use crate::ringbuf::Error::BufCorrupt;
+            let rejected = matches!(
+                (image.open(), defect),
+                (Err(Error::UnsupportedFeatures { .. }), Defect::UnknownFeature)
+                    | (Err(Error::Ringbuf(BufCorrupt)), Defect::Layout)
+                    | (Err(Error::Ringbuf(BufCorrupt)), Defect::CountTooBig)
+            );
+            prop_assert!(rejected, "{:?} was not rejected", defect);
+        }
+    }
+}
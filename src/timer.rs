@@ -0,0 +1,273 @@
+//! Timeouts for many channels from one timerfd, e g for heartbeats and request deadlines.
+//!
+//! A `TimerWheel` keeps pending timers in a hashed wheel of slots, one slot per tick, and
+//! runs them on the thread of a `Reactor`, woken by a single timerfd that ticks only while
+//! timers are pending. Scheduling and cancelling are O(1) on average; timers fire up to a
+//! tick late, never early.
+//!
+//! # Example
+//! ```rust
+//! use shmem_ipc::timer::TimerWheel;
+//! use std::time::Duration;
+//! let wheel = TimerWheel::global().unwrap();
+//! let (tx, rx) = std::sync::mpsc::channel();
+//! wheel.schedule(Duration::from_millis(5), move || tx.send("peer timed out").unwrap());
+//! assert_eq!(rx.recv().unwrap(), "peer timed out");
+//! ```
+
+use crate::reactor::{Reactor, Registration};
+use crate::Error;
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant};
+
+/// Number of slots; timers further out than this many ticks wait for their round.
+const SLOTS: usize = 256;
+
+struct Entry {
+    id: u64,
+    tick: u64,
+    f: Box<dyn FnOnce() + Send>,
+}
+
+struct Wheel {
+    slots: Vec<Vec<Entry>>,
+    /// Slot of each pending timer, by id.
+    index: HashMap<u64, usize>,
+    start: Instant,
+    tick: Duration,
+    /// The last tick that has been processed.
+    current: u64,
+    next_id: u64,
+}
+
+impl Wheel {
+    fn tick_at(&self, t: Instant) -> u64 {
+        let nanos = t.saturating_duration_since(self.start).as_nanos();
+        (nanos / self.tick.as_nanos()) as u64
+    }
+
+    /// Takes out the timers that are due.
+    fn expire(&mut self, now: Instant) -> Vec<Box<dyn FnOnce() + Send>> {
+        let now = self.tick_at(now);
+        let mut due = vec![];
+        let ticks = now.saturating_sub(self.current).min(SLOTS as u64);
+        for t in self.current + 1..=self.current + ticks {
+            let slot = &mut self.slots[t as usize % SLOTS];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].tick <= now {
+                    let e = slot.swap_remove(i);
+                    self.index.remove(&e.id);
+                    due.push(e.f);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.current = self.current.max(now);
+        due
+    }
+}
+
+struct Shared {
+    wheel: Mutex<Wheel>,
+    timerfd: File,
+}
+
+impl Shared {
+    /// Ticks the timerfd every `tick`, or stops it. Called with the wheel locked, so that
+    /// this cannot race with timers being added or running out.
+    fn arm(&self, tick: Option<Duration>) {
+        let tick = tick.unwrap_or_default();
+        let ts = libc::timespec {
+            tv_sec: tick.as_secs() as libc::time_t,
+            tv_nsec: tick.subsec_nanos() as libc::c_long,
+        };
+        let spec = libc::itimerspec {
+            it_interval: ts,
+            it_value: ts,
+        };
+        // Only fails for invalid arguments, which these are not.
+        let fd = self.timerfd.as_raw_fd();
+        unsafe { libc::timerfd_settime(fd, 0, &spec, std::ptr::null_mut()) };
+    }
+
+    fn fire(&self) {
+        let mut expirations = [0u8; 8];
+        // Non-blocking, so this cannot hang the reactor if there is nothing to read.
+        let _ = unsafe {
+            libc::read(
+                self.timerfd.as_raw_fd(),
+                expirations.as_mut_ptr() as *mut _,
+                8,
+            )
+        };
+        let due = {
+            let mut w = self.wheel.lock().unwrap();
+            let due = w.expire(Instant::now());
+            if w.index.is_empty() {
+                self.arm(None);
+            }
+            due
+        };
+        // Without the lock, so that timers can schedule timers.
+        for f in due {
+            f();
+        }
+    }
+}
+
+/// Pending timers, run on a reactor thread, see the module documentation.
+pub struct TimerWheel {
+    shared: Arc<Shared>,
+    _registration: Registration,
+}
+
+/// A scheduled timer, see `TimerWheel::schedule`. Dropping it does not cancel it.
+pub struct Timer {
+    id: u64,
+    shared: Weak<Shared>,
+}
+
+static GLOBAL: OnceLock<TimerWheel> = OnceLock::new();
+
+impl TimerWheel {
+    /// Creates a timer wheel with a resolution of `tick`, whose timers run on `reactor`.
+    pub fn new(reactor: &Reactor, tick: Duration) -> Result<Self, Error> {
+        if tick == Duration::from_secs(0) {
+            Err(Error::OutOfBounds)?
+        }
+        let flags = libc::TFD_CLOEXEC | libc::TFD_NONBLOCK;
+        let fd = unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, flags) };
+        if fd < 0 {
+            Err(std::io::Error::last_os_error())?
+        }
+        let wheel = Wheel {
+            slots: (0..SLOTS).map(|_| vec![]).collect(),
+            index: HashMap::new(),
+            start: Instant::now(),
+            tick,
+            current: 0,
+            next_id: 0,
+        };
+        let shared = Arc::new(Shared {
+            wheel: Mutex::new(wheel),
+            timerfd: unsafe { File::from_raw_fd(fd) },
+        });
+        let s = Arc::downgrade(&shared);
+        let registration = reactor.register(&shared.timerfd, move || {
+            if let Some(s) = s.upgrade() {
+                s.fire()
+            }
+        })?;
+        Ok(TimerWheel {
+            shared,
+            _registration: registration,
+        })
+    }
+
+    /// The timer wheel of this process, with a tick of a millisecond, running on
+    /// `Reactor::global`.
+    pub fn global() -> Result<&'static TimerWheel, Error> {
+        if let Some(w) = GLOBAL.get() {
+            return Ok(w);
+        }
+        let _ = GLOBAL.set(Self::new(Reactor::global()?, Duration::from_millis(1))?);
+        Ok(GLOBAL.get().unwrap())
+    }
+
+    /// Runs `f` on the reactor thread once `after` has passed, unless cancelled before.
+    ///
+    /// Like reactor callbacks, `f` must not block for long.
+    pub fn schedule<F: FnOnce() + Send + 'static>(&self, after: Duration, f: F) -> Timer {
+        let id = {
+            let mut w = self.shared.wheel.lock().unwrap();
+            // Round up, so that the timer never fires early.
+            let tick = w.tick_at(Instant::now() + after + w.tick - Duration::from_nanos(1));
+            let tick = tick.max(w.current + 1);
+            let id = w.next_id;
+            w.next_id += 1;
+            let slot = tick as usize % SLOTS;
+            w.slots[slot].push(Entry {
+                id,
+                tick,
+                f: Box::new(f),
+            });
+            w.index.insert(id, slot);
+            if w.index.len() == 1 {
+                self.shared.arm(Some(w.tick));
+            }
+            id
+        };
+        Timer {
+            id,
+            shared: Arc::downgrade(&self.shared),
+        }
+    }
+
+    /// Number of pending timers.
+    pub fn len(&self) -> usize {
+        self.shared.wheel.lock().unwrap().index.len()
+    }
+
+    /// True if there are no pending timers.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Timer {
+    /// Cancels the timer. Returns false if it had already fired, or is firing right now.
+    pub fn cancel(&self) -> bool {
+        let shared = match self.shared.upgrade() {
+            Some(s) => s,
+            None => return false,
+        };
+        let mut w = shared.wheel.lock().unwrap();
+        let slot = match w.index.remove(&self.id) {
+            Some(s) => s,
+            None => return false,
+        };
+        let entries = &mut w.slots[slot];
+        if let Some(i) = entries.iter().position(|e| e.id == self.id) {
+            entries.swap_remove(i);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fire_and_cancel() {
+        let reactor = Reactor::new().unwrap();
+        let wheel = TimerWheel::new(&reactor, Duration::from_millis(1)).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let start = Instant::now();
+        let t = |ms: u64, name: &'static str| {
+            let tx = tx.clone();
+            wheel.schedule(Duration::from_millis(ms), move || {
+                tx.send((name, start.elapsed())).unwrap()
+            })
+        };
+        // Further out than the wheel has slots, so it takes a round.
+        let late = t(300, "late");
+        let cancelled = t(10, "cancelled");
+        let _early = t(5, "early");
+        assert!(cancelled.cancel());
+        assert_eq!(wheel.len(), 2);
+        let (name, at) = rx.recv().unwrap();
+        assert_eq!(name, "early");
+        assert!(at >= Duration::from_millis(5));
+        let (name, at) = rx.recv().unwrap();
+        assert_eq!(name, "late");
+        assert!(at >= Duration::from_millis(300));
+        assert!(!late.cancel());
+        assert!(wheel.is_empty());
+    }
+}
@@ -0,0 +1,262 @@
+//! Helpers for passing file descriptors over unix sockets.
+//!
+//! Memfds and eventfds need to be transferred to the other process somehow; over a unix socket
+//! this is done by attaching them as `SCM_RIGHTS` ancillary data.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+/// Maximum number of file descriptors the kernel accepts in one message.
+pub const MAX_FDS: usize = 253;
+
+fn cmsg_space(fds: usize) -> usize {
+    unsafe { libc::CMSG_SPACE((fds * std::mem::size_of::<RawFd>()) as u32) as usize }
+}
+
+/// Sends data together with file descriptors.
+///
+/// At least one byte of data must be sent for the file descriptors to arrive.
+pub fn send_with_fds(socket: &UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+    if fds.len() > MAX_FDS {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut _,
+        iov_len: data.len(),
+    };
+    // u64 to get the alignment cmsghdr needs
+    let mut cmsg_buf = vec![0u64; cmsg_space(fds.len()).div_ceil(8)];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut _;
+        msg.msg_controllen = cmsg_space(fds.len()) as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of_val(fds) as u32) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr(),
+                libc::CMSG_DATA(cmsg) as *mut RawFd,
+                fds.len(),
+            );
+        }
+    }
+    let r = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+    if r < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(r as usize)
+    }
+}
+
+fn recv_inner(
+    socket: &UnixStream,
+    data: &mut [u8],
+    fds: &mut Vec<File>,
+    flags: libc::c_int,
+) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut _,
+        iov_len: data.len(),
+    };
+    let mut cmsg_buf = vec![0u64; cmsg_space(MAX_FDS).div_ceil(8)];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut _;
+    msg.msg_controllen = cmsg_space(MAX_FDS) as _;
+    let r = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, flags | libc::MSG_CMSG_CLOEXEC) };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let bytes = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let p = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..bytes / std::mem::size_of::<RawFd>() {
+                    fds.push(File::from_raw_fd(std::ptr::read_unaligned(p.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::from_raw_os_error(libc::EMSGSIZE));
+    }
+    Ok(r as usize)
+}
+
+/// Receives data together with file descriptors, which are appended to `fds`.
+///
+/// Received file descriptors have close-on-exec set.
+pub fn recv_with_fds(
+    socket: &UnixStream,
+    data: &mut [u8],
+    fds: &mut Vec<File>,
+) -> io::Result<usize> {
+    recv_inner(socket, data, fds, 0)
+}
+
+/// Like `recv_with_fds`, but fails with `WouldBlock` instead of waiting for data.
+pub fn try_recv_with_fds(
+    socket: &UnixStream,
+    data: &mut [u8],
+    fds: &mut Vec<File>,
+) -> io::Result<usize> {
+    recv_inner(socket, data, fds, libc::MSG_DONTWAIT)
+}
+
+/// Credentials of the process at the other end of a unix socket.
+///
+/// These are taken by the kernel when the socket was connected (or created with
+/// `UnixStream::pair`), so they cannot be faked by the peer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PeerCred {
+    pub pid: libc::pid_t,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+impl PeerCred {
+    /// Returns true if the peer runs as the same (effective) user as this process.
+    pub fn is_same_user(&self) -> bool {
+        self.uid == unsafe { libc::geteuid() }
+    }
+}
+
+/// Gets the credentials of the peer, with `SO_PEERCRED`.
+pub fn peer_cred(socket: &UnixStream) -> io::Result<PeerCred> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let r = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCred {
+        pid: cred.pid,
+        uid: cred.uid,
+        gid: cred.gid,
+    })
+}
+
+/// Checks the credentials of the peer against a policy, and returns them if accepted.
+///
+/// Fails with `PermissionDenied` if the policy rejects the peer.
+pub fn verify_peer<F: FnOnce(&PeerCred) -> bool>(
+    socket: &UnixStream,
+    policy: F,
+) -> io::Result<PeerCred> {
+    let cred = peer_cred(socket)?;
+    if policy(&cred) {
+        Ok(cred)
+    } else {
+        Err(io::Error::from_raw_os_error(libc::EPERM))
+    }
+}
+
+/// Like `recv_with_fds`, but verifies the peer against a policy first, so that no file
+/// descriptors are accepted from peers the policy rejects.
+///
+/// Returns the peer credentials together with the number of bytes received.
+pub fn recv_with_fds_verified<F: FnOnce(&PeerCred) -> bool>(
+    socket: &UnixStream,
+    data: &mut [u8],
+    fds: &mut Vec<File>,
+    policy: F,
+) -> io::Result<(PeerCred, usize)> {
+    let cred = verify_peer(socket, policy)?;
+    Ok((cred, recv_with_fds(socket, data, fds)?))
+}
+
+/// Opens a pidfd, which becomes readable when the process exits. Fails with `ESRCH` if
+/// there is no such process, and with `ENOSYS` before linux version 5.3.
+pub(crate) fn pidfd_open(pid: u32) -> io::Result<File> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd as libc::c_int) })
+}
+
+/// Whether the process with this pid exists; pid reuse is not detected.
+pub(crate) fn process_alive(pid: u32) -> bool {
+    let e = match pidfd_open(pid) {
+        Ok(_) => return true,
+        Err(e) => e,
+    };
+    match e.raw_os_error() {
+        Some(libc::ESRCH) => false,
+        // No pidfd support in this kernel; a signal of zero only checks for existence.
+        Some(libc::ENOSYS) => {
+            let r = unsafe { libc::kill(pid as libc::pid_t, 0) };
+            r == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, Write};
+
+    #[test]
+    fn pass_fd() -> io::Result<()> {
+        let (a, b) = UnixStream::pair()?;
+        let memfd = crate::mem::write_once(4096, "pass_fd", |x| x[3] = 9).unwrap();
+        let mut buf = [0u8; 16];
+        let mut fds = vec![];
+        assert_eq!(
+            try_recv_with_fds(&b, &mut buf, &mut fds)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::WouldBlock
+        );
+        send_with_fds(&a, b"memfd", &[memfd.as_raw_fd()])?;
+        assert_eq!(recv_with_fds(&b, &mut buf, &mut fds)?, 5);
+        assert_eq!(&buf[..5], b"memfd");
+        assert_eq!(fds.len(), 1);
+        let mut f = fds.pop().unwrap();
+        let mut v = vec![];
+        f.seek(std::io::SeekFrom::Start(0))?;
+        f.read_to_end(&mut v)?;
+        assert_eq!(v[3], 9);
+
+        (&a).write_all(b"no fds")?;
+        assert_eq!(recv_with_fds(&b, &mut buf, &mut fds)?, 6);
+        assert!(fds.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn peer_credentials() -> io::Result<()> {
+        let (a, b) = UnixStream::pair()?;
+        let cred = peer_cred(&b)?;
+        assert_eq!(cred.pid, std::process::id() as libc::pid_t);
+        assert!(cred.is_same_user());
+        send_with_fds(&a, b"x", &[a.as_raw_fd()])?;
+        let mut buf = [0u8; 1];
+        let mut fds = vec![];
+        let e = recv_with_fds_verified(&b, &mut buf, &mut fds, |c| c.uid != cred.uid);
+        assert_eq!(e.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert!(fds.is_empty());
+        let (c, n) = recv_with_fds_verified(&b, &mut buf, &mut fds, PeerCred::is_same_user)?;
+        assert_eq!((c, n, fds.len()), (cred, 1, 1));
+        Ok(())
+    }
+}
@@ -0,0 +1,354 @@
+//! Waiting for and signaling many channels through one io_uring, for processes that juggle
+//! hundreds of them and would otherwise spend most of their time in syscalls.
+//!
+//! A `Uring` keeps a multishot poll armed on every watched signal eventfd, typically
+//! `Receiver::empty_signal`, so that a single `io_uring_enter` waits for all of them. The
+//! eventfds that became readable are reset by reads queued on the same ring, and signals to
+//! the other side, e g `Sender::empty_signal` after sending, are queued as writes; all of
+//! them go to the kernel together on the next `wait` or `submit`.
+//!
+//! As with `Reactor`, watching is edge triggered: `wait` reports a channel once per signal,
+//! and the callback should then receive everything there is. The ring reads the watched
+//! eventfds itself, so do not also block on them, e g with `Receiver::block_until_readable`.
+//!
+//! Needs Linux 5.13 or later.
+//!
+//! # Example
+//! ```rust
+//! use shmem_ipc::{sharedring::{Receiver, Sender}, uring::Uring};
+//! let mut r: Receiver<u64> = Receiver::new(16).unwrap();
+//! let mut s = Sender::open(16, r.memfd().as_file().try_clone().unwrap(),
+//!     r.empty_signal().try_clone().unwrap(), r.full_signal().try_clone().unwrap()).unwrap();
+//! let mut ring = Uring::new(64).unwrap();
+//! let token = ring.watch(r.empty_signal()).unwrap();
+//! s.send_raw(|p, n| { unsafe { *p = 7 }; 1 }).unwrap();
+//! ring.wait(None, |t| {
+//!     assert_eq!(t, token);
+//!     r.receive_raw(|_, n| n).unwrap();
+//! }).unwrap();
+//! ```
+
+use crate::sync::futex;
+use crate::{Error, Op};
+use io_uring::{cqueue, opcode, squeue, types, IoUring};
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::time::Duration;
+
+/// The low bits of the user data of a submission say what it was for; the rest is the
+/// token.
+const OP_BITS: u64 = 2;
+const OP_POLL: u64 = 0;
+const OP_READ: u64 = 1;
+const OP_WRITE: u64 = 2;
+const OP_CANCEL: u64 = 3;
+
+/// What every signal writes; it outlives all submissions.
+static ONE: u64 = 1;
+
+/// A file descriptor added to a `Uring`, see `Uring::watch` and `Uring::add_signal`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Token(u64);
+
+struct Entry {
+    /// Our own duplicate, so that it stays open while the kernel uses it.
+    fd: File,
+    /// Where reads of a watched eventfd go.
+    buf: Box<u64>,
+    polling: bool,
+    reading: bool,
+    writes: u32,
+    removed: bool,
+}
+
+impl Entry {
+    fn idle(&self) -> bool {
+        !self.polling && !self.reading && self.writes == 0
+    }
+}
+
+/// An io_uring waiting for and signaling channels, see the module documentation.
+pub struct Uring {
+    ring: IoUring,
+    entries: HashMap<u64, Entry>,
+    next: u64,
+    /// The first signal that failed since the last `wait`.
+    failed: Option<std::io::Error>,
+}
+
+impl Uring {
+    /// Creates a ring with room for `entries` submissions between calls to `wait` or
+    /// `submit`; the ring submits by itself when it fills up.
+    pub fn new(entries: u32) -> Result<Self, Error> {
+        Ok(Uring {
+            ring: IoUring::new(entries).map_err(Error::os(Op::Create, None))?,
+            entries: HashMap::new(),
+            next: 0,
+            failed: None,
+        })
+    }
+
+    fn add<A: AsRawFd>(&mut self, fd: &A) -> Result<u64, Error> {
+        let fd = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            Err(std::io::Error::last_os_error())?
+        }
+        let token = self.next;
+        self.next += 1;
+        self.entries.insert(
+            token,
+            Entry {
+                fd: unsafe { File::from_raw_fd(fd) },
+                buf: Box::new(0),
+                polling: false,
+                reading: false,
+                writes: 0,
+                removed: false,
+            },
+        );
+        Ok(token)
+    }
+
+    fn push(&mut self, sqe: squeue::Entry) -> Result<(), Error> {
+        // The submission only refers to buffers and file descriptors that we keep until it
+        // has completed.
+        while unsafe { self.ring.submission().push(&sqe) }.is_err() {
+            self.ring.submit().map_err(Error::os(Op::Signal, None))?;
+        }
+        Ok(())
+    }
+
+    fn arm(&mut self, token: u64) -> Result<(), Error> {
+        let e = self.entries.get_mut(&token).unwrap();
+        e.polling = true;
+        let fd = types::Fd(e.fd.as_raw_fd());
+        let sqe = opcode::PollAdd::new(fd, libc::POLLIN as u32)
+            .multi(true)
+            .build()
+            .user_data(token << OP_BITS | OP_POLL);
+        self.push(sqe)
+    }
+
+    /// Starts watching `fd`, e g `Receiver::empty_signal`. `wait` passes the returned token
+    /// to its callback when `fd` has been signaled.
+    pub fn watch<A: AsRawFd>(&mut self, fd: &A) -> Result<Token, Error> {
+        let token = self.add(fd)?;
+        self.arm(token)?;
+        Ok(Token(token))
+    }
+
+    /// Adds `fd`, e g `Sender::empty_signal`, for signaling with `signal`.
+    pub fn add_signal<A: AsRawFd>(&mut self, fd: &A) -> Result<Token, Error> {
+        Ok(Token(self.add(fd)?))
+    }
+
+    /// Queues a signal to the file descriptor of `token`, which is written on the next
+    /// `wait` or `submit`. If the write fails, a later `wait` returns the error.
+    pub fn signal(&mut self, token: Token) -> Result<(), Error> {
+        let e = match self.entries.get_mut(&token.0) {
+            Some(e) if !e.removed => e,
+            _ => Err(Error::OutOfBounds)?,
+        };
+        e.writes += 1;
+        let fd = types::Fd(e.fd.as_raw_fd());
+        let sqe = opcode::Write::new(fd, &ONE as *const u64 as *const u8, 8)
+            .build()
+            .user_data(token.0 << OP_BITS | OP_WRITE);
+        self.push(sqe)
+    }
+
+    /// Stops watching or signaling the file descriptor of `token`. It is closed once the
+    /// kernel is done with it.
+    pub fn remove(&mut self, token: Token) -> Result<(), Error> {
+        let e = match self.entries.get_mut(&token.0) {
+            Some(e) if !e.removed => e,
+            _ => Err(Error::OutOfBounds)?,
+        };
+        e.removed = true;
+        let (polling, reading) = (e.polling, e.reading);
+        if e.idle() {
+            self.entries.remove(&token.0);
+        }
+        if polling {
+            let target = token.0 << OP_BITS | OP_POLL;
+            let sqe = opcode::PollRemove::new(target).build();
+            self.push(sqe.user_data(token.0 << OP_BITS | OP_CANCEL))?;
+        }
+        if reading {
+            let target = token.0 << OP_BITS | OP_READ;
+            let sqe = opcode::AsyncCancel::new(target).build();
+            self.push(sqe.user_data(token.0 << OP_BITS | OP_CANCEL))?;
+        }
+        Ok(())
+    }
+
+    /// Hands everything queued to the kernel without waiting.
+    pub fn submit(&mut self) -> Result<(), Error> {
+        self.ring.submit().map_err(Error::os(Op::Signal, None))?;
+        Ok(())
+    }
+
+    /// Handles what has completed, and returns the number of tokens passed to `f`.
+    fn reap<F: FnMut(Token)>(&mut self, f: &mut F) -> Result<usize, Error> {
+        let done: Vec<_> = self
+            .ring
+            .completion()
+            .map(|c| (c.user_data(), c.result(), c.flags()))
+            .collect();
+        let mut n = 0;
+        for (data, result, flags) in done {
+            let (token, op) = (data >> OP_BITS, data & ((1 << OP_BITS) - 1));
+            let e = match self.entries.get_mut(&token) {
+                Some(e) => e,
+                None => continue,
+            };
+            let mut rearm = false;
+            let mut read = false;
+            match op {
+                OP_POLL => {
+                    if !cqueue::more(flags) {
+                        e.polling = false;
+                        // The kernel may end a multishot poll, e g when the completion
+                        // queue overflows.
+                        rearm = !e.removed && result >= 0;
+                    }
+                    if !e.removed && result >= 0 {
+                        read = !e.reading;
+                        e.reading = true;
+                        f(Token(token));
+                        n += 1;
+                    }
+                }
+                OP_READ => e.reading = false,
+                OP_WRITE => {
+                    e.writes -= 1;
+                    if result < 0 && self.failed.is_none() {
+                        self.failed = Some(std::io::Error::from_raw_os_error(-result));
+                    }
+                }
+                _ => {}
+            }
+            let e = self.entries.get_mut(&token).unwrap();
+            if e.removed && e.idle() {
+                self.entries.remove(&token);
+                continue;
+            }
+            if read {
+                let fd = types::Fd(e.fd.as_raw_fd());
+                let buf = &mut *e.buf as *mut u64 as *mut u8;
+                let sqe = opcode::Read::new(fd, buf, 8).build();
+                self.push(sqe.user_data(token << OP_BITS | OP_READ))?;
+            }
+            if rearm {
+                self.arm(token)?;
+            }
+        }
+        Ok(n)
+    }
+
+    /// Submits everything queued, then waits until at least one watched file descriptor
+    /// has been signaled, or for the timeout, and calls `f` with the token of each that
+    /// has. Returns the number of calls, which is zero on timeout.
+    pub fn wait<F: FnMut(Token)>(
+        &mut self,
+        timeout: Option<Duration>,
+        mut f: F,
+    ) -> Result<usize, Error> {
+        let deadline = timeout.map(|t| crate::sim::instant() + t);
+        loop {
+            let n = self.reap(&mut f)?;
+            if let Some(e) = self.failed.take() {
+                Err(Error::os(Op::Signal, None)(e))?
+            }
+            if n > 0 {
+                return Ok(n);
+            }
+            let r = match futex::remaining(deadline) {
+                Some(left) if left == Duration::from_secs(0) => return Ok(0),
+                Some(left) => {
+                    let ts = types::Timespec::from(left);
+                    let args = types::SubmitArgs::new().timespec(&ts);
+                    self.ring.submitter().submit_with_args(1, &args)
+                }
+                None => self.ring.submit_and_wait(1),
+            };
+            match r {
+                Err(e) if e.raw_os_error() == Some(libc::ETIME) => {}
+                Err(e) if e.raw_os_error() == Some(libc::EINTR) => {}
+                r => {
+                    r.map_err(Error::os(Op::Signal, None))?;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Uring {
+    fn drop(&mut self) {
+        // The kernel cancels what is in flight only after the ring is gone, so leave it
+        // the buffers it may still write to.
+        for e in self.entries.values_mut() {
+            if e.reading {
+                std::mem::forget(std::mem::replace(&mut e.buf, Box::new(0)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sharedring::{Receiver, Sender};
+
+    #[test]
+    fn many_channels() {
+        let mut ring = Uring::new(8).unwrap();
+        let pairs: Vec<(Sender<u64>, Receiver<u64>, Token, Token)> = (0..20)
+            .map(|_| {
+                let r: Receiver<u64> = Receiver::new(16).unwrap();
+                let s = Sender::open(
+                    16,
+                    r.memfd().as_file().try_clone().unwrap(),
+                    r.empty_signal().try_clone().unwrap(),
+                    r.full_signal().try_clone().unwrap(),
+                )
+                .unwrap();
+                let watch = ring.watch(r.empty_signal()).unwrap();
+                let signal = ring.add_signal(s.empty_signal()).unwrap();
+                (s, r, watch, signal)
+            })
+            .collect();
+        assert_eq!(
+            ring.wait(Some(Duration::from_millis(10)), |_| panic!())
+                .unwrap(),
+            0
+        );
+        // Signal three of them through the ring, without the sender doing so itself.
+        for (_, _, _, signal) in pairs.iter().step_by(7) {
+            ring.signal(*signal).unwrap();
+        }
+        let mut got = vec![];
+        while got.len() < 3 {
+            ring.wait(Some(Duration::from_secs(10)), |t| got.push(t))
+                .unwrap();
+        }
+        got.sort_by_key(|t| t.0);
+        let expected: Vec<_> = pairs.iter().step_by(7).map(|p| p.2).collect();
+        assert_eq!(got, expected);
+        // Reset by the ring, so nothing more to report.
+        assert_eq!(
+            ring.wait(Some(Duration::from_millis(10)), |_| panic!())
+                .unwrap(),
+            0
+        );
+        ring.remove(pairs[0].2).unwrap();
+        ring.signal(pairs[0].3).unwrap();
+        assert_eq!(
+            ring.wait(Some(Duration::from_millis(10)), |_| panic!())
+                .unwrap(),
+            0
+        );
+        assert!(ring.remove(pairs[0].2).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+//! Typed access to read-only areas received from an untrusted peer.
+//!
+//! Turning a pointer into a mapping into a `&T` needs the range to be in bounds, the
+//! address to be aligned for `T`, every bit pattern to be a valid `T`, and the contents to
+//! never change while the reference is alive. An `UntrustedMap` checks all of these, so
+//! consumers of peer provided memfds need no unsafe code of their own.
+//!
+//! # Example
+//! ```rust
+//! use shmem_ipc::{mem, view::UntrustedMap};
+//! let memfd = mem::write_once(16, "example", |data| {
+//!     data[..8].copy_from_slice(&7u64.to_ne_bytes());
+//!     data[8..13].copy_from_slice(b"hello");
+//! })
+//! .unwrap();
+//! let map = UntrustedMap::open(&memfd).unwrap();
+//! assert_eq!(*map.get::<u64>(0).unwrap(), 7);
+//! assert_eq!(map.get_str(8, 5).unwrap(), "hello");
+//! assert!(map.get::<u64>(12).is_err());
+//! ```
+
+use crate::mem::{self, mfd, mmap};
+use crate::Error;
+use std::ffi::CStr;
+use std::mem::{align_of, size_of};
+
+/// A read-only mapping of a sealed memfd, with bounds and alignment checked accessors.
+pub struct UntrustedMap {
+    mmap: mmap::Mmap,
+}
+
+impl UntrustedMap {
+    /// Maps a memfd received from the peer, see `mem::read_memfd` for the seals it needs.
+    pub fn open(memfd: &mfd::Memfd) -> Result<Self, Error> {
+        Ok(UntrustedMap {
+            mmap: mem::read_memfd(memfd)?,
+        })
+    }
+
+    /// Wraps an existing mapping.
+    ///
+    /// # Safety
+    ///
+    /// The contents of the mapping must never change, e g because it was returned by
+    /// `mem::read_memfd`.
+    pub unsafe fn from_mmap(mmap: mmap::Mmap) -> Self {
+        UntrustedMap { mmap }
+    }
+
+    /// Size of the mapping in bytes.
+    pub fn len(&self) -> usize {
+        self.mmap.len()
+    }
+
+    /// Returns true if the mapping is empty.
+    pub fn is_empty(&self) -> bool {
+        self.mmap.is_empty()
+    }
+
+    /// The whole mapping as bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.mmap
+    }
+
+    /// Gives back the mapping.
+    pub fn into_inner(self) -> mmap::Mmap {
+        self.mmap
+    }
+
+    /// The bytes from `offset` to `offset + len`, or `OutOfBounds`.
+    pub fn get_bytes(&self, offset: usize, len: usize) -> Result<&[u8], Error> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.mmap.get(offset..end))
+            .ok_or(Error::OutOfBounds)
+    }
+
+    /// A reference to the `T` at `offset`.
+    ///
+    /// Fails with `OutOfBounds` if it does not fit into the mapping, and with `Misaligned`
+    /// if `offset` is not suitably aligned for `T`.
+    pub fn get<T: zerocopy::FromBytes>(&self, offset: usize) -> Result<&T, Error> {
+        let bytes = self.aligned::<T>(offset, size_of::<T>())?;
+        Ok(unsafe { &*(bytes.as_ptr() as *const T) })
+    }
+
+    /// A slice of `len` items of type `T` from `offset` on; fails like `get`.
+    pub fn get_slice<T: zerocopy::FromBytes>(
+        &self,
+        offset: usize,
+        len: usize,
+    ) -> Result<&[T], Error> {
+        let size = len.checked_mul(size_of::<T>()).ok_or(Error::OutOfBounds)?;
+        let bytes = self.aligned::<T>(offset, size)?;
+        Ok(unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const T, len) })
+    }
+
+    /// The UTF-8 string of `len` bytes from `offset` on.
+    ///
+    /// Fails with `OutOfBounds` like `get_bytes`, and with `Utf8` if it is not valid UTF-8.
+    pub fn get_str(&self, offset: usize, len: usize) -> Result<&str, Error> {
+        Ok(std::str::from_utf8(self.get_bytes(offset, len)?)?)
+    }
+
+    /// The nul terminated string from `offset` on.
+    ///
+    /// Fails with `OutOfBounds` if there is no nul byte before the end of the mapping.
+    pub fn get_cstr(&self, offset: usize) -> Result<&CStr, Error> {
+        let bytes = self.mmap.get(offset..).ok_or(Error::OutOfBounds)?;
+        let end = bytes
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or(Error::OutOfBounds)?;
+        Ok(CStr::from_bytes_with_nul(&bytes[..=end]).unwrap())
+    }
+
+    fn aligned<T>(&self, offset: usize, size: usize) -> Result<&[u8], Error> {
+        let bytes = self.get_bytes(offset, size)?;
+        if (bytes.as_ptr() as usize) % align_of::<T>() != 0 {
+            Err(Error::Misaligned)?
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked() {
+        let memfd = mem::write_once(32, "test-view", |data| {
+            data[..4].copy_from_slice(&[1, 0, 2, 0]);
+            data[4..8].copy_from_slice(b"ab\0c");
+            data[8] = 0xff;
+            data[31] = b'x';
+        })
+        .unwrap();
+        let map = UntrustedMap::open(&memfd).unwrap();
+        assert_eq!(
+            map.get_slice::<u16>(0, 2).unwrap(),
+            &[1u16.to_le(), 2u16.to_le()]
+        );
+        assert!(matches!(map.get::<u16>(1), Err(Error::Misaligned)));
+        assert!(matches!(map.get::<u64>(32), Err(Error::OutOfBounds)));
+        assert!(matches!(
+            map.get_slice::<u64>(8, usize::MAX),
+            Err(Error::OutOfBounds)
+        ));
+        assert_eq!(map.get_str(4, 2).unwrap(), "ab");
+        assert!(matches!(map.get_str(8, 1), Err(Error::Utf8(_))));
+        assert_eq!(map.get_cstr(4).unwrap().to_bytes(), b"ab");
+        assert!(matches!(map.get_cstr(31), Err(Error::OutOfBounds)));
+        assert!(matches!(map.get_cstr(33), Err(Error::OutOfBounds)));
+    }
+}
@@ -0,0 +1,107 @@
+//! Atomics with a fixed width and little-endian byte order in shared memory, so that peers
+//! on other architectures, or written in other languages, agree on the contents.
+//!
+//! On little-endian targets these compile down to the plain atomic operations; on
+//! big-endian targets, arithmetic goes through a compare-and-swap loop.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+macro_rules! atomic_le {
+    ($name:ident, $atomic:ty, $int:ty) => {
+        #[repr(transparent)]
+        #[derive(Default)]
+        pub(crate) struct $name($atomic);
+
+        // Not every width uses every operation.
+        #[allow(dead_code)]
+        impl $name {
+            /// The word as stored, e g for a futex; compare it against `to_le` values.
+            pub(crate) fn raw(&self) -> &$atomic {
+                &self.0
+            }
+
+            pub(crate) fn load(&self, order: Ordering) -> $int {
+                <$int>::from_le(self.0.load(order))
+            }
+
+            pub(crate) fn store(&self, value: $int, order: Ordering) {
+                self.0.store(value.to_le(), order)
+            }
+
+            pub(crate) fn swap(&self, value: $int, order: Ordering) -> $int {
+                <$int>::from_le(self.0.swap(value.to_le(), order))
+            }
+
+            pub(crate) fn compare_exchange(
+                &self,
+                current: $int,
+                new: $int,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<$int, $int> {
+                self.0
+                    .compare_exchange(current.to_le(), new.to_le(), success, failure)
+                    .map(<$int>::from_le)
+                    .map_err(<$int>::from_le)
+            }
+
+            /// Bitwise operations do not care about byte order.
+            pub(crate) fn fetch_or(&self, value: $int, order: Ordering) -> $int {
+                <$int>::from_le(self.0.fetch_or(value.to_le(), order))
+            }
+
+            #[cfg(target_endian = "little")]
+            pub(crate) fn fetch_add(&self, value: $int, order: Ordering) -> $int {
+                self.0.fetch_add(value, order)
+            }
+
+            #[cfg(target_endian = "little")]
+            pub(crate) fn fetch_sub(&self, value: $int, order: Ordering) -> $int {
+                self.0.fetch_sub(value, order)
+            }
+
+            #[cfg(target_endian = "big")]
+            pub(crate) fn fetch_add(&self, value: $int, order: Ordering) -> $int {
+                self.update(order, |x| x.wrapping_add(value))
+            }
+
+            #[cfg(target_endian = "big")]
+            pub(crate) fn fetch_sub(&self, value: $int, order: Ordering) -> $int {
+                self.update(order, |x| x.wrapping_sub(value))
+            }
+
+            #[cfg(target_endian = "big")]
+            fn update<F: Fn($int) -> $int>(&self, order: Ordering, f: F) -> $int {
+                let mut cur = self.0.load(Ordering::Relaxed);
+                loop {
+                    let new = f(<$int>::from_le(cur)).to_le();
+                    match self
+                        .0
+                        .compare_exchange_weak(cur, new, order, Ordering::Relaxed)
+                    {
+                        Ok(x) => return <$int>::from_le(x),
+                        Err(x) => cur = x,
+                    }
+                }
+            }
+        }
+    };
+}
+
+atomic_le!(AtomicLe32, AtomicU32, u32);
+atomic_le!(AtomicLe64, AtomicU64, u64);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_order() {
+        let a = AtomicLe64::default();
+        a.store(0x0102, Ordering::Relaxed);
+        assert_eq!(a.fetch_add(0x0100, Ordering::Relaxed), 0x0102);
+        assert_eq!(a.fetch_or(1, Ordering::Relaxed), 0x0202);
+        let bytes = a.raw().load(Ordering::Relaxed).to_ne_bytes();
+        assert_eq!(bytes, [3, 2, 0, 0, 0, 0, 0, 0]);
+    }
+}